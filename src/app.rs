@@ -1,30 +1,167 @@
-use crate::config::{Config, HotConfig, RewriteConfig, extract_hot_config, load_hot_config};
-use crate::context::{ContextEntry, ContextMessage, resolve_sender_name};
-use crate::llm::OpenAiClient;
-use crate::telegram::{TelegramBot, message_topic_root_id};
-use anyhow::{Context, Result};
+use crate::chat_command::{ChatCommand, parse_chat_command, status_text, usage_hint};
+use crate::config::{
+    ChatLink, Config, Delivery, HotConfig, LogFormat, LoggingConfig, NetworkConfig,
+    PRIMARY_ACCOUNT_NAME, ParseMode, ProviderConfig, ReloadConfig, RestoreDroppedLinks,
+    RewriteConfig, TelegramConfig, extract_hot_config, load_hot_config, resolve_saved_messages,
+};
+use crate::context::{
+    ContextEntry, ContextMessage, UNKNOWN_SENDER, reply_target_context, resolve_sender_name,
+    trim_to_token_budget,
+};
+use crate::formatting::MARKDOWN_PROMPT_SUFFIX;
+use crate::health::HealthState;
+use crate::language::{detect_language, language_matches};
+use crate::ledger::{
+    AccountLedger, ContextStore, RewriteLedger, ScheduledRewrite, ScheduledStore, ScopeSnapshot,
+};
+use crate::links::{append_links, dropped_links, extract_links};
+use crate::llm::{
+    BatchRewrite, FixedRewriter, LlmRewriter, Rewrite, SharedRewriterState, build_rewriter,
+    rewrite_batch, sanitize_rewrite_output,
+};
+use crate::log_file::RotatingFile;
+use crate::log_format::FlatJson;
+use crate::metrics::{FailedStage, Metrics};
+use crate::refusal::RefusalDetector;
+use crate::run_stats::{RunStats, RunSummary, SummarySignal};
+use crate::shutdown::{OsShutdownSignal, ShutdownCut, ShutdownSignal, within_shutdown_budget};
+use crate::status_server::StatusServer;
+use crate::telegram::{
+    EditFailure, FloodWait, ScheduledMessage, SentMessage, SlowModeAdmission, SlowModeQueue,
+    SlowModeWait, TelegramBot, TelegramRequests, TypingIndicator, channel_dialog_id,
+    chat_migration, context_text, is_channel_dialog_id, is_connection_lost, message_grouped_id,
+    message_hidden_links, message_is_channel_post, message_is_forwarded, message_is_from_scheduled,
+    message_is_own, message_is_service, message_markdown, message_reply_to_message_id,
+    message_topic_root_id, plain_text, reaction_trigger_target, scheduled_message,
+};
+use crate::undo::{UndoEntry, UndoHistory};
+use crate::usage::{TokenPricing, UsageTracker};
+use crate::watchdog::{StallAction, StallWatchdog};
+use anyhow::{Context, Result, bail};
+use axum::Router;
 use grammers_client::Client;
+use grammers_client::message::Message as TelegramMessage;
 use grammers_client::update::{Message as UpdateMessage, Update};
+use grammers_session::types::PeerRef;
 use notify::{
     Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
     event::{CreateKind, ModifyKind, RemoveKind},
 };
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::future::Future;
-use std::path::Path;
-use std::sync::{Arc, OnceLock};
+use std::fmt;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::Poll;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use tokio::sync::{mpsc, oneshot, watch};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Semaphore, mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+use tracing::Subscriber;
 use tracing::{debug, error, info, warn};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_log::LogTracer;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::JsonFields;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
 const TELEGRAM_MESSAGE_MAX_CHARS: usize = 4096;
 const DEDUPE_TTL_SECONDS: u64 = 300;
+/// Quiet period after the last queued catch-up message before its batch is sent.
+const CATCH_UP_BATCH_WINDOW: Duration = Duration::from_millis(500);
+/// Quiet period after the last item of a media album arrives before the album is handled.
+const ALBUM_WINDOW: Duration = Duration::from_millis(1_000);
+/// How often dialogs are reloaded while a monitored chat isn't one of them.
+const CHAT_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Rewrites waiting for their chat scope's worker; more are dropped.
+const REWRITE_QUEUE_LIMIT: usize = 32;
+/// How often the context cache is saved to `runtime.state_file`; it is also saved at shutdown.
+const CONTEXT_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Edits held back after a `FLOOD_WAIT` or transient error, or for edit spacing; further ones
+/// are dropped.
+const EDIT_RETRY_QUEUE_LIMIT: usize = 64;
+/// Scheduled messages remembered as rewritten until the schedule sends them; older ones are
+/// forgotten first.
+const SCHEDULED_REWRITES_LIMIT: usize = 256;
+/// Edit attempts per rewrite, including the first, before a flood-waited or transiently
+/// failing edit is dropped.
+const MAX_EDIT_ATTEMPTS: u32 = 3;
+/// Wait before requeued edits whose quick retries all hit transient errors are tried again.
+const TRANSIENT_EDIT_REQUEUE_DELAY: Duration = Duration::from_secs(10);
+/// Update stream errors in a row after which the connection is rebuilt, when none of them
+/// already said the connection was lost.
+const STREAM_ERROR_RECONNECT_THRESHOLD: u32 = 5;
+/// Wait before the first reconnect attempt; it doubles per failed attempt.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// How long the update state probe of a silent account may take.
+const STALL_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Overrides `logging.format` when set to `compact`, `pretty` or `json`.
+const LOG_FORMAT_ENV: &str = "BRAINROT_LOG_FORMAT";
+
+/// Set by [`init_tracing`] when logs are JSON lines, so multi-line payloads are logged as
+/// fields rather than as an indented block.
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MonitoredUpdateKind {
     NewMessage,
+    /// An edit to one of our own messages, handled with `rewrite.rewrite_edits`.
+    EditedMessage,
+    /// Our own `rewrite.trigger_reaction` on an existing message.
+    ReactionTrigger,
+    /// One of our messages was scheduled, handled with `rewrite.rewrite_scheduled`.
+    ScheduledMessage,
+}
+
+/// What became of the messages seen in one chat. Every seen message lands in at most one of
+/// the other counters; messages still in flight are in none yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChatCounters {
+    pub seen: u64,
+    /// Sent by someone else; only kept as context.
+    pub not_outgoing: u64,
+    pub deduped: u64,
+    /// No text to rewrite.
+    pub empty: u64,
+    /// Left out by a filter, a chat command, a pause, a deletion or the before-edit hook.
+    pub filtered: u64,
+    pub rate_limited: u64,
+    /// The provider failed or returned nothing.
+    pub llm_failed: u64,
+    pub refused: u64,
+    /// The rewrite matched the original or changed too little of it.
+    pub unchanged: u64,
+    /// The rewrite couldn't be applied to the chat.
+    pub edit_failed: u64,
+    pub rewritten: u64,
+}
+
+impl ChatCounters {
+    fn record(&mut self, stat: ChatStat) {
+        let counter = match stat {
+            ChatStat::Seen => &mut self.seen,
+            ChatStat::NotOutgoing => &mut self.not_outgoing,
+            ChatStat::Deduped => &mut self.deduped,
+            ChatStat::Empty => &mut self.empty,
+            ChatStat::Filtered | ChatStat::Vetoed => &mut self.filtered,
+            ChatStat::RateLimited => &mut self.rate_limited,
+            ChatStat::LlmFailed => &mut self.llm_failed,
+            ChatStat::Refused => &mut self.refused,
+            ChatStat::Unchanged => &mut self.unchanged,
+            ChatStat::EditFailed => &mut self.edit_failed,
+            ChatStat::Rewritten => &mut self.rewritten,
+        };
+        *counter += 1;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -33,37 +170,288 @@ pub enum RewriteEvent {
         catch_up_enabled: bool,
         skip_historical_catch_up_messages: bool,
         startup_unix: i64,
+        /// Rewrites are only logged and reported as `DryRunRewrite`, never applied.
+        dry_run: bool,
     },
     MonitoredUpdate {
         chat_id: i64,
         topic_root_id: Option<i32>,
+        /// The forum topic's name, when it is known.
+        topic: Option<String>,
         message_id: i32,
         outgoing: bool,
         kind: MonitoredUpdateKind,
     },
+    /// The provider returned a rewrite; token counts are `None` when it reports no usage.
+    RewriteSucceeded {
+        chat_id: i64,
+        message_id: i32,
+        input_tokens: Option<u64>,
+        output_tokens: Option<u64>,
+    },
     MessageEdited {
         chat_id: i64,
         message_id: i32,
+        /// Model that produced the rewrite; `rewrite_override` when the test override was used.
+        model: String,
+    },
+    /// Telegram answered the edit with `FLOOD_WAIT`; it is retried after `wait_seconds`.
+    EditDeferred {
+        chat_id: i64,
+        message_id: i32,
+        wait_seconds: u32,
+    },
+    /// The chat's slow mode holds the rewrite back for `wait_seconds`.
+    SlowModeDelayed {
+        chat_id: i64,
+        message_id: i32,
+        wait_seconds: u32,
+    },
+    /// `rewrite.delivery = "resend"` replaced `message_id` with `new_message_id`.
+    MessageResent {
+        chat_id: i64,
+        message_id: i32,
+        new_message_id: i32,
+        model: String,
+    },
+    /// The rewrite was sent as `new_message_id` but the original could not be deleted, so
+    /// both are in the chat.
+    ResendInconsistent {
+        chat_id: i64,
+        message_id: i32,
+        new_message_id: i32,
+    },
+    RateLimited {
+        chat_id: i64,
+        message_id: i32,
+    },
+    /// The model declined to rewrite; the original message was left as is.
+    RewriteRefused {
+        chat_id: i64,
+        message_id: i32,
+    },
+    /// Deletions that may affect a monitored chat. `chat_id` is only known for channels and
+    /// supergroups; other deletions carry `None` and are account-wide, reported while a
+    /// private chat or basic group is monitored.
+    MessagesDeleted {
+        chat_id: Option<i64>,
+        message_ids: Vec<i32>,
+    },
+    /// The message was deleted before its rewrite could be applied.
+    RewriteCancelled {
+        chat_id: i64,
+        message_id: i32,
+    },
+    ChatRewriteToggled {
+        chat_id: i64,
+        enabled: bool,
+    },
+    /// In a dry run, the rewrite that would have been applied to the message.
+    DryRunRewrite {
+        chat_id: i64,
+        message_id: i32,
+        original: String,
+        rewritten: String,
+    },
+    /// `.rw undo` put the original text back into the rewritten message.
+    RewriteUndone {
+        chat_id: i64,
+        message_id: i32,
+    },
+    /// A monitored chat that wasn't a dialog of the session became one and is rewritten
+    /// from now on.
+    ChatResolved {
+        chat_id: i64,
+    },
+    /// The monitored basic group `old_id` became the supergroup `new_id`, which is monitored
+    /// from now on. Update the config to list `new_id`.
+    ChatMigrated {
+        old_id: i64,
+        new_id: i64,
+    },
+    /// Message counts of `chat_id` over the last `window`, with `totals` since startup. Sent
+    /// every `runtime.chat_stats_interval_minutes` and at shutdown.
+    ChatStats {
+        chat_id: i64,
+        window: Duration,
+        counters: ChatCounters,
+        totals: ChatCounters,
+    },
+    /// The update stream kept failing; reconnect attempt `attempt` (from 1) is starting.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The connection was rebuilt; updates missed meanwhile are caught up.
+    Reconnected,
+    /// No update arrived for `silent_seconds`; Telegram is probed for its update state.
+    UpdateStreamSilent {
+        silent_seconds: u64,
+    },
+    /// Telegram answered the check: the account is only quiet.
+    UpdateStreamAlive {
+        silent_seconds: u64,
+    },
+    /// The probe failed too; the account reconnects, or with `exiting` the rewriter stops.
+    UpdateStreamStalled {
+        silent_seconds: u64,
+        exiting: bool,
+    },
+    /// Telegram revoked the session, for example after it was terminated from another
+    /// device. Its file was moved aside; from a terminal the account logs in again,
+    /// otherwise the rewriter stops with [`SessionRevoked`].
+    SessionRevoked,
+    /// Outcome of the provider health check, run at startup and when the model or key changes.
+    LlmHealth {
+        ok: bool,
+        error: Option<String>,
+    },
+    ConfigReloaded {
+        changes: Vec<String>,
+    },
+    ConfigReloadFailed {
+        error: String,
+    },
+    /// The provider request for the message is being sent or queued. It ends with
+    /// `MessageEdited`, `MessageResent`, `RewriteCancelled` or `RewriteFailed`; a catch-up
+    /// batch that falls back to one request per message starts its messages again.
+    RewriteStarted {
+        chat_id: i64,
+        message_id: i32,
+    },
+    /// Context was gathered for the message's rewrite: `count` messages from `source`.
+    ContextFetched {
+        chat_id: i64,
+        message_id: i32,
+        source: ContextSource,
+        count: usize,
+    },
+    /// A started rewrite won't be applied: the provider failed at `Llm`, the edit or resend
+    /// failed at `Edit`, or the answer was refused or unchanged at `Skipped`.
+    RewriteFailed {
+        chat_id: i64,
+        message_id: i32,
+        stage: RewriteStage,
+        reason: FailureReason,
+    },
+    /// The message won't be rewritten and no request was sent for it.
+    RewriteSkipped {
+        chat_id: i64,
+        message_id: i32,
+        reason: SkipReason,
+    },
+    /// Totals since startup, sent at shutdown and whenever SIGUSR1 asks for them.
+    RunSummary {
+        summary: RunSummary,
     },
     UnsupportedUpdateIgnored {
         update_kind: String,
     },
 }
 
+/// Where [`RewriteEvent::ContextFetched`] got its messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextSource {
+    /// The context cache, also when a Telegram fetch failed.
+    Cache,
+    Telegram,
+}
+
+/// Stage of [`RewriteEvent::RewriteFailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteStage {
+    Llm,
+    Edit,
+    /// The provider answered, but the answer wasn't applied.
+    Skipped,
+}
+
+/// Reason of [`RewriteEvent::RewriteFailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The provider failed or returned nothing.
+    ProviderFailed,
+    Refused,
+    /// The rewrite matched the original, changed too little or dropped its links.
+    Unchanged,
+    /// Telegram rejected the edit or resend.
+    EditFailed,
+    /// The `on_before_edit` hook skipped it.
+    Vetoed,
+}
+
+/// Reason of [`RewriteEvent::RewriteSkipped`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Sent by someone else; only kept as context.
+    NotOutgoing,
+    Deduped,
+    /// No text to rewrite.
+    Empty,
+    /// Left out by a filter, a chat command, a pause or a deletion.
+    Filtered,
+    /// Over `rewrite.max_per_minute`, or too many rewrites queued for the chat.
+    RateLimited,
+}
+
+type EventHandler = dyn Fn(Option<&str>, RewriteEvent) + Send + Sync;
+type BeforeEditHook = dyn Fn(&EditCandidate<'_>) -> EditDecision + Send + Sync;
+
+/// A rewrite about to be applied, as shown to [`RewriteHooks::with_before_edit`]. `rewritten`
+/// is sanitized and truncated, with dropped links restored.
+#[derive(Debug, Clone, Copy)]
+pub struct EditCandidate<'a> {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub original: &'a str,
+    pub rewritten: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditDecision {
+    Approve,
+    /// Leave the message as written.
+    Skip,
+    /// Apply this text instead, if it still passes the truncation and unchanged checks.
+    Replace(String),
+}
+
 #[derive(Default)]
 pub struct RewriteHooks {
-    on_event: Option<Arc<dyn Fn(RewriteEvent) + Send + Sync>>,
+    on_event: Option<Arc<EventHandler>>,
     on_client_ready: Option<oneshot::Sender<Client>>,
+    /// Set while `[metrics]` is served; events and the pipeline update it.
+    metrics: Option<Arc<Metrics>>,
+    /// Set while `[health]` is served; events and the update stream update it.
+    health: Option<Arc<RwLock<HealthState>>>,
+    /// Restarted with every run; events and the pipeline update it.
+    run_stats: Mutex<RunStats>,
+    on_before_edit: Option<Arc<BeforeEditHook>>,
 }
 
 impl RewriteHooks {
     pub fn with_event_handler<F>(handler: F) -> Self
     where
         F: Fn(RewriteEvent) + Send + Sync + 'static,
+    {
+        Self::with_account_event_handler(move |_, event| handler(event))
+    }
+
+    /// Like [`Self::with_event_handler`], with the event's account; `None` when process-wide.
+    pub fn with_account_event_handler<F>(handler: F) -> Self
+    where
+        F: Fn(Option<&str>, RewriteEvent) + Send + Sync + 'static,
     {
         Self {
             on_event: Some(Arc::new(handler)),
             on_client_ready: None,
+            metrics: None,
+            health: None,
+            run_stats: Mutex::default(),
+            on_before_edit: None,
         }
     }
 
@@ -72,9 +460,63 @@ impl RewriteHooks {
         self
     }
 
+    /// Consults `hook` before every edit, after the rewrite passed the usual checks. Without
+    /// it every such rewrite is applied.
+    pub fn with_before_edit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&EditCandidate<'_>) -> EditDecision + Send + Sync + 'static,
+    {
+        self.on_before_edit = Some(Arc::new(hook));
+        self
+    }
+
     fn emit(&self, event: RewriteEvent) {
+        self.emit_for(None, event);
+    }
+
+    fn emit_for(&self, account: Option<&str>, event: RewriteEvent) {
+        let reloaded = match &event {
+            RewriteEvent::ConfigReloaded { .. } => Some(true),
+            RewriteEvent::ConfigReloadFailed { .. } => Some(false),
+            _ => None,
+        };
+        if let Some(ok) = reloaded {
+            if let Some(metrics) = self.metrics.as_deref() {
+                metrics.config_reloaded(ok);
+            }
+            self.update_run_stats(|stats| stats.config_reloaded(ok));
+        }
+        match &event {
+            RewriteEvent::RuntimeReady { .. } => {
+                self.update_health(|health| health.mark_ready(Instant::now()));
+            }
+            RewriteEvent::Reconnected => {
+                self.update_health(|health| health.record_update(Instant::now()));
+            }
+            RewriteEvent::LlmHealth { ok, .. } => {
+                self.update_health(|health| health.record_llm_health(*ok));
+            }
+            _ => {}
+        }
         if let Some(handler) = self.on_event.as_ref() {
-            handler(event);
+            handler(account, event);
+        }
+    }
+
+    fn update_run_stats(&self, update: impl FnOnce(&mut RunStats)) {
+        update(&mut self.run_stats.lock().expect("run stats lock poisoned"));
+    }
+
+    fn update_health(&self, update: impl FnOnce(&mut HealthState)) {
+        if let Some(health) = self.health.as_ref() {
+            update(&mut health.write().expect("health state lock poisoned"));
+        }
+    }
+
+    fn for_account<'a>(&'a self, account: &'a str) -> AccountHooks<'a> {
+        AccountHooks {
+            hooks: self,
+            account,
         }
     }
 
@@ -85,42 +527,140 @@ impl RewriteHooks {
     }
 }
 
+/// [`RewriteHooks`] that label every event with one account's name.
+#[derive(Clone, Copy)]
+struct AccountHooks<'a> {
+    hooks: &'a RewriteHooks,
+    account: &'a str,
+}
+
+impl AccountHooks<'_> {
+    fn emit(&self, event: RewriteEvent) {
+        self.hooks.emit_for(Some(self.account), event);
+    }
+
+    fn metrics(&self) -> Option<&Metrics> {
+        self.hooks.metrics.as_deref()
+    }
+
+    /// The update stream produced something, so the account is alive.
+    fn record_update(&self) {
+        self.hooks
+            .update_health(|health| health.record_update(Instant::now()));
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RewriteRuntimeOptions {
     pub catch_up_enabled: bool,
     pub skip_historical_catch_up_messages: bool,
     pub rewrite_override: Option<String>,
+    /// `--dry-run`; `runtime.dry_run` turns it on as well.
+    pub dry_run: bool,
 }
 
-static TRACING_INIT: OnceLock<()> = OnceLock::new();
+/// Keeps the non-blocking file writer alive; dropping it flushes buffered log lines.
+pub struct LoggingGuard {
+    _file_writer_guard: Option<WorkerGuard>,
+}
 
-pub fn init_tracing() {
-    TRACING_INIT.get_or_init(|| {
-        let _ = LogTracer::init();
-        let env_filter =
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(env_filter)
-            .with_target(false)
-            .compact()
-            .try_init();
-    });
+/// Subscriber used before the config (and its `[logging]` section) has been loaded.
+pub fn fallback_tracing_subscriber() -> impl Subscriber + Send + Sync {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(LoggingConfig::default().level)),
+        )
+        .with_target(false)
+        .compact()
+        .finish()
+}
+
+pub fn init_tracing(logging: &LoggingConfig) -> Result<LoggingGuard> {
+    let format = match std::env::var(LOG_FORMAT_ENV) {
+        Ok(value) => parse_log_format(&value)
+            .with_context(|| format!("invalid {LOG_FORMAT_ENV}: {value}"))?,
+        Err(_) => logging.format,
+    };
+    JSON_LOGS.store(format == LogFormat::Json, Ordering::Relaxed);
+
+    let env_filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(logging.level.trim())
+            .with_context(|| format!("invalid logging.level filter: {}", logging.level))?,
+    };
+
+    let (file_layer, file_writer_guard) = match logging.file.as_deref() {
+        Some(path) => {
+            let (writer, guard) = open_log_file_writer(logging, path)?;
+            (Some(fmt_layer(format, writer, false)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let _ = LogTracer::init();
+    let _ = tracing_subscriber::registry()
+        .with(env_filter)
+        .with(fmt_layer(format, std::io::stdout, true))
+        .with(file_layer)
+        .try_init();
+
+    Ok(LoggingGuard {
+        _file_writer_guard: file_writer_guard,
+    })
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_target(false);
+    match format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .boxed(),
+    }
+}
+
+fn parse_log_format(value: &str) -> Option<LogFormat> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "compact" => Some(LogFormat::Compact),
+        "pretty" => Some(LogFormat::Pretty),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+/// The file is written from the non-blocking worker thread; the returned guard flushes it
+/// when dropped, after `run_rewrite_mode*` returns.
+fn open_log_file_writer(
+    logging: &LoggingConfig,
+    path: &Path,
+) -> Result<(NonBlocking, WorkerGuard)> {
+    let max_bytes = logging.max_size_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+    let file = RotatingFile::open(path, max_bytes, logging.keep_files)
+        .with_context(|| format!("failed to open log file: {}", path.display()))?;
+    Ok(tracing_appender::non_blocking(file))
 }
 
-pub async fn run_rewrite_mode(config: &Config, config_path: &Path) -> Result<()> {
+pub async fn run_rewrite_mode(config: &Config, config_path: &Path, dry_run: bool) -> Result<()> {
     run_rewrite_mode_with_shutdown_and_hooks(
         config,
         config_path,
-        async {
-            if let Err(err) = tokio::signal::ctrl_c().await {
-                warn!(error = %err, "failed to listen for Ctrl+C");
-            }
-        },
+        OsShutdownSignal::new(),
         RewriteHooks::default(),
         RewriteRuntimeOptions {
             catch_up_enabled: true,
             skip_historical_catch_up_messages: true,
             rewrite_override: None,
+            dry_run,
         },
     )
     .await
@@ -129,73 +669,473 @@ pub async fn run_rewrite_mode(config: &Config, config_path: &Path) -> Result<()>
 pub async fn run_rewrite_mode_with_shutdown_and_hooks<S>(
     config: &Config,
     config_path: &Path,
-    shutdown_signal: S,
+    mut shutdown_signal: S,
     mut hooks: RewriteHooks,
     runtime_options: RewriteRuntimeOptions,
 ) -> Result<()>
 where
-    S: Future<Output = ()> + Send,
+    S: ShutdownSignal,
 {
-    let timeout = Duration::from_secs(config.openai_required()?.timeout_seconds);
-    let mut active = ActiveRewriteState::from_hot_config(extract_hot_config(config)?, timeout)?;
+    hooks.run_stats = Mutex::new(RunStats::new(Instant::now()));
+    let rewrite_override = normalize_rewrite_override(runtime_options.rewrite_override);
+    let mut active = ActiveRewriteState::from_hot_config(
+        extract_hot_config(config)?,
+        &config.network,
+        None,
+        rewrite_override.as_deref(),
+    )?;
     let catch_up_enabled = runtime_options.catch_up_enabled;
     let skip_historical_catch_up_messages = runtime_options.skip_historical_catch_up_messages;
-    let rewrite_override = normalize_rewrite_override(runtime_options.rewrite_override);
+    let dry_run = runtime_options.dry_run || config.runtime.dry_run;
+    let status_servers = start_status_servers(config, &mut hooks).await?;
+    if let Err(err) = check_llm_health(&active, &hooks).await
+        && require_healthy_at_startup(&active.hot_config.provider)
+    {
+        return Err(err.context("llm health check failed at startup"));
+    }
 
-    let mut bot = TelegramBot::connect_for_rewrite(
-        &config.telegram,
-        active.monitored_chats.clone(),
-        catch_up_enabled,
-    )
-    .await?;
-    let mut dedupe_cache = DedupeCache::new(Duration::from_secs(DEDUPE_TTL_SECONDS));
-    let mut context_cache = ContextCache::new(active.hot_config.rewrite.context_messages);
-    let startup_unix = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs() as i64;
-
-    hooks.send_client(bot.client_clone());
+    let mut ledger = open_ledger(config).await?;
+    let mut accounts = connect_accounts(config, &active, catch_up_enabled).await?;
+    for account in &mut accounts {
+        account.state.dry_run = dry_run;
+    }
+    if let Some(ledger) = ledger.as_mut() {
+        for account in &mut accounts {
+            // Messages a dry run went through aren't recorded as rewritten.
+            if !dry_run {
+                account
+                    .state
+                    .dedupe_cache
+                    .attach_ledger(ledger.for_account(&account.name));
+            }
+            let (scopes, store) = ledger.context_for_account(&account.name);
+            account.state.context_cache.restore(scopes);
+            account
+                .state
+                .context_cache
+                .retain_chats(account.bot.monitored_chats());
+            account.state.context_store = Some(store);
+            if !dry_run {
+                let (rewritten, store) = ledger.scheduled_for_account(&account.name);
+                account
+                    .state
+                    .scheduled_rewrites
+                    .attach_store(rewritten, store);
+            }
+        }
+    }
+    let self_chat_ids: HashMap<String, i64> = accounts
+        .iter()
+        .filter_map(|account| Some((account.name.clone(), account.bot.self_chat_id()?)))
+        .collect();
+    active.resolve_saved_messages(&self_chat_ids);
+    let mut rate_limiter = RateLimiter::new(active.hot_config.rewrite.max_per_minute);
+    rate_limiter.set_chat_limits(active.hot_config.rewrite.chat_max_per_minute());
+    let mut usage_tracker = UsageTracker::new(token_pricing(&active.hot_config.provider));
+    let (mut rewrite_workers, mut rewrite_results) = RewriteWorkers::new(
+        Arc::clone(&active.llm),
+        config.runtime.max_concurrent_rewrites,
+    );
+    let startup_unix = accounts[0].startup_unix;
+    let historical_grace_seconds = config.runtime.historical_grace_seconds;
+    let catch_up_request_timeout = config
+        .runtime
+        .catch_up_request_timeout_seconds
+        .map(Duration::from_secs);
+
+    hooks.send_client(accounts[0].bot.client_clone());
     hooks.emit(RewriteEvent::RuntimeReady {
         catch_up_enabled,
         skip_historical_catch_up_messages,
         startup_unix,
+        dry_run,
     });
 
     let (hot_tx, mut hot_rx) = watch::channel(active.hot_config.clone());
-    let _watcher = spawn_config_watcher(config_path, hot_tx)?;
+    let (reload_error_tx, mut reload_error_rx) = mpsc::unbounded_channel();
+    let (health_tx, mut health_rx) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    spawn_reload_signal_listener(config_path, hot_tx.clone(), reload_error_tx.clone());
+    let _watcher = spawn_config_watcher(config_path, config.reload, hot_tx, reload_error_tx)?;
 
     info!(
         config_path = %config_path.display(),
+        accounts = accounts.len(),
         catch_up_enabled,
         skip_historical_catch_up_messages,
         startup_unix,
+        historical_grace_seconds,
+        dry_run,
         "brainrot rewriter started"
     );
-    tokio::pin!(shutdown_signal);
+    // Kept across iterations so a signal arriving mid-iteration isn't missed.
+    let mut first_shutdown_signal = Box::pin(shutdown_signal.recv());
+    let mut summary_signal = SummarySignal::new();
+    let mut chat_resolve_at = tokio::time::Instant::now() + CHAT_RESOLVE_INTERVAL;
+    let chat_stats_interval = Some(config.runtime.chat_stats_interval_minutes)
+        .filter(|&minutes| minutes > 0)
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    let mut chat_stats_at =
+        chat_stats_interval.map(|interval| tokio::time::Instant::now() + interval);
+    let mut context_save_at = tokio::time::Instant::now() + CONTEXT_SAVE_INTERVAL;
 
+    // Ends the loop with an error: a revoked session that can't log in again, or a stalled
+    // update stream with `runtime.exit_on_stall`.
+    let mut fatal = None;
     loop {
+        if let Some(account) = accounts
+            .iter_mut()
+            .find(|account| account.bot.session_revoked())
+            && let Err(err) = recover_revoked_session(account, &hooks).await
+        {
+            fatal = Some(err);
+            break;
+        }
+        let has_unresolved_chats = accounts
+            .iter()
+            .any(|account| account.bot.has_unresolved_chats());
+        let catch_up_flush_at = accounts
+            .iter()
+            .filter_map(|account| account.catch_up_batches.flush_at)
+            .min();
+        let edit_retry_at = accounts
+            .iter()
+            .filter_map(|account| account.state.edit_retries.next_retry_at())
+            .min();
+        let album_complete_at = accounts
+            .iter()
+            .filter_map(|account| account.albums.next_complete_at())
+            .min();
+        let slow_mode_ready_at = accounts
+            .iter()
+            .filter_map(|account| account.state.slow_mode.next_ready_at())
+            .min();
+        let reconnect_at = accounts
+            .iter()
+            .filter_map(|account| account.stream_recovery.reconnect_at)
+            .min();
+        let stall_probe_at = accounts
+            .iter()
+            .filter(|account| account.stream_recovery.reconnect_at.is_none())
+            .filter_map(|account| account.watchdog.as_ref())
+            .map(StallWatchdog::probe_at)
+            .min();
         tokio::select! {
-            () = &mut shutdown_signal => {
-                info!("shutdown signal received");
+            signal = &mut first_shutdown_signal => {
+                info!(signal, "shutdown signal received; shutting down gracefully");
+                for account in &accounts {
+                    if !account.catch_up_batches.is_empty() {
+                        warn!(
+                            account = %account.name,
+                            queued_messages = account.catch_up_batches.len(),
+                            "shutting down with catch-up messages still queued for batch rewrite"
+                        );
+                    }
+                    if !account.state.edit_retries.is_empty() {
+                        warn!(
+                            account = %account.name,
+                            queued_edits = account.state.edit_retries.len(),
+                            "shutting down with edits still queued for retry"
+                        );
+                    }
+                    if !account.state.slow_mode.is_empty() {
+                        warn!(
+                            account = %account.name,
+                            queued_resends = account.state.slow_mode.len(),
+                            "shutting down with rewrites still waiting for chat slow mode"
+                        );
+                    }
+                    if !account.albums.is_empty() {
+                        warn!(
+                            account = %account.name,
+                            queued_albums = account.albums.len(),
+                            "shutting down with media albums still waiting for their last items"
+                        );
+                    }
+                }
                 break;
             }
-            update_result = bot.next_update() => {
+            () = tokio::time::sleep_until(
+                edit_retry_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if edit_retry_at.is_some() => {
+                let now = tokio::time::Instant::now();
+                for account in &mut accounts {
+                    let due = account.state.edit_retries.take_due(now);
+                    if due.is_empty() {
+                        continue;
+                    }
+                    let mut runtime = account
+                        .state
+                        .runtime(
+                            &mut rate_limiter,
+                            &mut usage_tracker,
+                            hooks.for_account(&account.name),
+                            None,
+                        )
+                        .with_workers(&mut rewrite_workers);
+                    retry_deferred_edits(
+                        &account.bot,
+                        &active.hot_config.rewrite,
+                        due,
+                        &mut runtime,
+                    )
+                    .await;
+                }
+            }
+            () = tokio::time::sleep_until(
+                slow_mode_ready_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if slow_mode_ready_at.is_some() => {
+                let now = tokio::time::Instant::now();
+                for account in &mut accounts {
+                    let ready = account.state.slow_mode.take_ready(now);
+                    if ready.is_empty() {
+                        continue;
+                    }
+                    let mut runtime = account
+                        .state
+                        .runtime(
+                            &mut rate_limiter,
+                            &mut usage_tracker,
+                            hooks.for_account(&account.name),
+                            None,
+                        )
+                        .with_workers(&mut rewrite_workers);
+                    resend_slow_mode_ready(
+                        &account.bot,
+                        &active.hot_config.rewrite,
+                        ready,
+                        &mut runtime,
+                    )
+                    .await;
+                }
+            }
+            () = tokio::time::sleep_until(
+                reconnect_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if reconnect_at.is_some() => {
+                let now = tokio::time::Instant::now();
+                for account in &mut accounts {
+                    if !account.stream_recovery.is_due(now) {
+                        continue;
+                    }
+                    let attempt = account.stream_recovery.attempt;
+                    let account_hooks = hooks.for_account(&account.name);
+                    info!(account = %account.name, attempt, "reconnecting telegram update stream");
+                    account_hooks.emit(RewriteEvent::Reconnecting { attempt });
+                    // Inline: it swaps out the update stream the update arm reads from.
+                    match account.bot.reconnect().await {
+                        Ok(()) => {
+                            account.stream_recovery.reconnected();
+                            if let Some(watchdog) = account.watchdog.as_mut() {
+                                watchdog.record_heartbeat(tokio::time::Instant::now());
+                            }
+                            info!(account = %account.name, attempt, "telegram account reconnected");
+                            account_hooks.emit(RewriteEvent::Reconnected);
+                        }
+                        Err(err) => {
+                            let delay = account
+                                .stream_recovery
+                                .schedule_reconnect(tokio::time::Instant::now());
+                            warn!(
+                                account = %account.name,
+                                attempt,
+                                retry_in_ms = delay.as_millis() as u64,
+                                error = %err,
+                                "telegram reconnect failed"
+                            );
+                        }
+                    }
+                }
+            }
+            () = tokio::time::sleep_until(
+                stall_probe_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if stall_probe_at.is_some() => {
+                let mut stalled = None;
+                for account in &mut accounts {
+                    if let Err(err) = check_update_stream(account, &hooks).await {
+                        stalled = Some(err);
+                        break;
+                    }
+                }
+                if let Some(err) = stalled {
+                    fatal = Some(err);
+                    break;
+                }
+            }
+            () = tokio::time::sleep_until(
+                chat_stats_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if chat_stats_at.is_some() => {
+                for account in &mut accounts {
+                    report_chat_stats(&mut account.state.chat_stats, hooks.for_account(&account.name));
+                }
+                chat_stats_at = chat_stats_interval.map(|interval| tokio::time::Instant::now() + interval);
+            }
+            Some(done) = rewrite_results.recv() => {
+                apply_worker_done(
+                    &mut accounts,
+                    active.settings(),
+                    &mut rewrite_workers,
+                    done,
+                    &mut rate_limiter,
+                    &mut usage_tracker,
+                    &hooks,
+                )
+                .await;
+            }
+            () = tokio::time::sleep_until(context_save_at), if ledger.is_some() => {
+                for account in &accounts {
+                    account.state.save_context();
+                }
+                context_save_at = tokio::time::Instant::now() + CONTEXT_SAVE_INTERVAL;
+            }
+            () = tokio::time::sleep_until(chat_resolve_at), if has_unresolved_chats => {
+                for account in &mut accounts {
+                    // Inline: it changes the monitored set the update arm checks.
+                    for chat_id in account.bot.resolve_pending_chats().await {
+                        info!(account = %account.name, chat_id, "monitored chat resolved; rewriting it from now on");
+                        hooks.for_account(&account.name).emit(RewriteEvent::ChatResolved { chat_id });
+                    }
+                    account
+                        .state
+                        .context_cache
+                        .set_own_personas(account.bot.own_personas().clone());
+                }
+                chat_resolve_at = tokio::time::Instant::now() + CHAT_RESOLVE_INTERVAL;
+            }
+            () = tokio::time::sleep_until(
+                album_complete_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if album_complete_at.is_some() => {
+                let now = tokio::time::Instant::now();
+                for account in &mut accounts {
+                    for album in account.albums.take_complete(now) {
+                        let mut runtime = account
+                            .state
+                            .runtime(
+                                &mut rate_limiter,
+                                &mut usage_tracker,
+                                hooks.for_account(&account.name),
+                                None,
+                            )
+                            .with_workers(&mut rewrite_workers);
+                        process_album(
+                            &account.bot,
+                            active.settings(),
+                            album,
+                            catch_up_request_timeout,
+                            &mut runtime,
+                        )
+                        .await;
+                    }
+                }
+            }
+            () = tokio::time::sleep_until(
+                catch_up_flush_at.unwrap_or_else(tokio::time::Instant::now)
+            ), if catch_up_flush_at.is_some() => {
+                let now = tokio::time::Instant::now();
+                for account in &mut accounts {
+                    if account.catch_up_batches.flush_at.is_none_or(|flush_at| flush_at > now) {
+                        continue;
+                    }
+                    let mut runtime = account
+                        .state
+                        .runtime(
+                            &mut rate_limiter,
+                            &mut usage_tracker,
+                            hooks.for_account(&account.name),
+                            catch_up_request_timeout,
+                        )
+                        .with_workers(&mut rewrite_workers);
+                    flush_catch_up_batches(
+                        &account.bot,
+                        active.settings(),
+                        &mut account.catch_up_batches,
+                        catch_up_request_timeout,
+                        &mut runtime,
+                    )
+                    .await;
+                }
+            }
+            (index, update_result) = next_account_update(&mut accounts) => {
+                let AccountRuntime {
+                    name: account_name,
+                    bot,
+                    startup_unix,
+                    catch_up_batches,
+                    albums,
+                    state,
+                    stream_recovery,
+                    watchdog,
+                } = &mut accounts[index];
+                let startup_unix = *startup_unix;
+                let account_hooks = hooks.for_account(account_name);
+                if update_result.is_ok() {
+                    stream_recovery.record_update();
+                    if let Some(watchdog) = watchdog.as_mut() {
+                        watchdog.record_update(tokio::time::Instant::now());
+                    }
+                    account_hooks.record_update();
+                }
                 match update_result {
                     Ok(Update::NewMessage(message)) => {
                         let chat_id = message.peer_id().bot_api_dialog_id();
+                        if let Some((old_id, new_id)) = chat_migration(&message)
+                            && bot.migrate_chat(old_id, new_id)
+                        {
+                            warn!(
+                                account = %account_name,
+                                old_id,
+                                new_id,
+                                "monitored group migrated to a supergroup; monitoring the new id, update the config to list it"
+                            );
+                            state.context_cache.transfer_chat(old_id, new_id);
+                            if state.paused_chats.contains(&old_id) {
+                                state.paused_chats.insert(new_id);
+                            }
+                            account_hooks.emit(RewriteEvent::ChatMigrated { old_id, new_id });
+                        }
                         if bot.is_monitored_chat(chat_id) {
+                            bot.remember_topic_name(chat_id, &message);
                             let context_scope = ContextScope {
                                 chat_id,
                                 topic_root_id: message_topic_root_id(&message),
                             };
                             let message_id = message.id();
+                            if message_is_service(&message) {
+                                if active.hot_config.rewrite.include_service_messages_in_context {
+                                    state.context_cache.observe_update_message(context_scope, &message);
+                                }
+                                debug!(
+                                    account = %account_name,
+                                    chat_id,
+                                    message_id,
+                                    "skipping service message"
+                                );
+                                continue;
+                            }
                             let message_unix = message.date().timestamp();
-                            if skip_historical_catch_up_messages && is_historical_catch_up_message(
-                                message_unix,
-                                startup_unix
-                            ) {
+                            // Sent from the schedule: only as old as the moment it went out.
+                            let from_scheduled = message_is_from_scheduled(&message);
+                            if from_scheduled
+                                && state.scheduled_rewrites.take_sent(chat_id, message.text())
+                            {
+                                info!(
+                                    account = %account_name,
+                                    chat_id,
+                                    message_id,
+                                    "skipping message sent from the schedule; rewritten when scheduled"
+                                );
+                                state.context_cache.observe_update_message(context_scope, &message);
+                                continue;
+                            }
+                            if skip_historical_catch_up_messages
+                                && !from_scheduled
+                                && is_historical_catch_up_message(
+                                    message_unix,
+                                    startup_unix,
+                                    historical_grace_seconds,
+                                )
+                            {
                                 info!(
+                                    account = %account_name,
                                     chat_id,
                                     message_id,
                                     message_unix,
@@ -204,41 +1144,84 @@ where
                                 );
                                 continue;
                             }
+                            let topic = context_scope
+                                .topic_root_id
+                                .and_then(|root_id| bot.topic_name(chat_id, root_id));
                             info!(
+                                account = %account_name,
                                 chat_id,
                                 topic_root_id = ?context_scope.topic_root_id,
+                                topic = ?topic,
                                 update_kind = "new_message",
                                 message_id,
                                 outgoing = message.outgoing(),
                                 "received message update in monitored chat"
                             );
-                            hooks.emit(RewriteEvent::MonitoredUpdate {
+                            account_hooks.emit(RewriteEvent::MonitoredUpdate {
                                 chat_id,
                                 topic_root_id: context_scope.topic_root_id,
+                                topic,
                                 message_id,
                                 outgoing: message.outgoing(),
                                 kind: MonitoredUpdateKind::NewMessage,
                             });
-                            let mut runtime = ProcessMessageRuntime {
-                                dedupe_cache: &mut dedupe_cache,
-                                context_cache: &mut context_cache,
-                                rewrite_override: rewrite_override.as_deref(),
-                                hooks: &hooks,
-                            };
+                            let is_catch_up = message_unix < startup_unix && !from_scheduled;
+                            if let Some(grouped_id) = message_grouped_id(&message) {
+                                albums.push(
+                                    (chat_id, grouped_id),
+                                    AlbumMember {
+                                        context_scope,
+                                        is_catch_up,
+                                        message,
+                                    },
+                                    tokio::time::Instant::now(),
+                                );
+                                continue;
+                            }
+                            if is_catch_up
+                                && bot.is_own_message(&message)
+                                && active.hot_config.rewrite.batch_threshold.is_some()
+                            {
+                                catch_up_batches.push(
+                                    context_scope,
+                                    message,
+                                    tokio::time::Instant::now(),
+                                );
+                                continue;
+                            }
+                            let mut runtime = state
+                                .runtime(
+                                    &mut rate_limiter,
+                                    &mut usage_tracker,
+                                    account_hooks,
+                                    catch_up_request_timeout.filter(|_| is_catch_up),
+                                )
+                                .with_workers(&mut rewrite_workers);
+                            if bot.is_own_message(&message) && !catch_up_batches.is_empty() {
+                                // Queued catch-up messages are handed off first.
+                                flush_catch_up_batches(
+                                    bot,
+                                    active.settings(),
+                                    catch_up_batches,
+                                    catch_up_request_timeout,
+                                    &mut runtime,
+                                )
+                                .await;
+                            }
                             if let Err(err) = process_message(
-                                &bot,
-                                &active.llm,
-                                &active.hot_config.rewrite,
-                                message,
+                                bot,
+                                active.settings(),
+                                &message,
                                 context_scope,
                                 &mut runtime,
                             )
                             .await
                             {
-                                error!(error = %err, "failed to process message");
+                                error!(account = %account_name, error = %err, "failed to process message");
                             }
                         } else {
                             debug!(
+                                account = %account_name,
                                 chat_id,
                                 message_id = message.id(),
                                 outgoing = message.outgoing(),
@@ -246,32 +1229,285 @@ where
                             );
                         }
                     }
-                    Ok(update) => {
-                        let update_kind = update_kind_name(&update);
-                        debug!(
-                            update_kind,
-                            "ignoring unsupported telegram update type"
-                        );
-                        hooks.emit(RewriteEvent::UnsupportedUpdateIgnored {
-                            update_kind,
-                        });
-                    }
-                    Err(err) => warn!(error = %err, "telegram update stream error"),
+                    Ok(Update::MessageEdited(message))
+                        if active.hot_config.rewrite.rewrite_edits =>
+                    {
+                        let chat_id = message.peer_id().bot_api_dialog_id();
+                        let message_id = message.id();
+                        if !bot.is_monitored_chat(chat_id) {
+                            debug!(
+                                account = %account_name,
+                                chat_id,
+                                message_id, "ignoring edited message from unmonitored chat"
+                            );
+                            continue;
+                        }
+                        let context_scope = ContextScope {
+                            chat_id,
+                            topic_root_id: message_topic_root_id(&message),
+                        };
+                        if !bot.is_own_message(&message) {
+                            state.context_cache.upsert_update_message_text(
+                                context_scope,
+                                &message,
+                                message.text(),
+                            );
+                            continue;
+                        }
+                        if state.dedupe_cache.is_own_edit(chat_id, message_id, message.text())
+                            || rewrite_workers.is_applying(account_name, chat_id, message.text())
+                        {
+                            debug!(chat_id, message_id, "ignoring edit made by our own rewrite");
+                            continue;
+                        }
+                        // A rewrite still under way started from the old text; it must not
+                        // overwrite this edit.
+                        rewrite_workers.observe_text(
+                            &MessageKey::new(account_name, chat_id, message_id),
+                            message.text(),
+                        );
+                        let topic = context_scope
+                            .topic_root_id
+                            .and_then(|root_id| bot.topic_name(chat_id, root_id));
+                        info!(
+                            account = %account_name,
+                            chat_id,
+                            topic_root_id = ?context_scope.topic_root_id,
+                            topic = ?topic,
+                            update_kind = "message_edited",
+                            message_id,
+                            "received edit of own message in monitored chat"
+                        );
+                        account_hooks.emit(RewriteEvent::MonitoredUpdate {
+                            chat_id,
+                            topic_root_id: context_scope.topic_root_id,
+                            topic,
+                            message_id,
+                            outgoing: true,
+                            kind: MonitoredUpdateKind::EditedMessage,
+                        });
+                        // A manual edit replaces the text we rewrote, so it is fair game again.
+                        state.dedupe_cache.forget(chat_id, message_id);
+                        let mut runtime = state
+                            .runtime(
+                                &mut rate_limiter,
+                                &mut usage_tracker,
+                                account_hooks,
+                                None,
+                            )
+                            .with_workers(&mut rewrite_workers);
+                        if let Err(err) = process_message(
+                            bot,
+                            active.settings(),
+                            &message,
+                            context_scope,
+                            &mut runtime,
+                        )
+                        .await
+                        {
+                            error!(account = %account_name, error = %err, "failed to process edited message");
+                        }
+                    }
+                    Ok(Update::MessageDeleted(deletion)) => {
+                        let chat_id = deletion.channel_id().map(channel_dialog_id);
+                        let monitored = bot
+                            .monitored_chats()
+                            .iter()
+                            .copied()
+                            .filter(|&monitored| bot.is_monitored_chat(monitored));
+                        if !deletion_in_monitored_chats(chat_id, monitored) {
+                            continue;
+                        }
+                        let message_ids = deletion.messages().to_vec();
+                        for &message_id in &message_ids {
+                            let deleted = DeletedMessage { chat_id, message_id };
+                            state.deleted_messages.insert(deleted);
+                            state.dedupe_cache.remove(deleted);
+                            for key in rewrite_workers.cancel_deleted(account_name, deleted) {
+                                debug!(
+                                    account = %account_name,
+                                    chat_id = key.chat_id,
+                                    message_id = key.message_id,
+                                    "cancelled the rewrite of a deleted message"
+                                );
+                                account_hooks.emit(RewriteEvent::RewriteCancelled {
+                                    chat_id: key.chat_id,
+                                    message_id: key.message_id,
+                                });
+                            }
+                        }
+                        state.context_cache.remove_messages(chat_id, &message_ids);
+                        debug!(
+                            account = %account_name,
+                            chat_id = ?chat_id,
+                            message_ids = ?message_ids,
+                            "messages deleted"
+                        );
+                        account_hooks.emit(RewriteEvent::MessagesDeleted {
+                            chat_id,
+                            message_ids,
+                        });
+                    }
+                    Ok(update) => {
+                        // With rewrite.rewrite_edits off, an edit still makes a rewrite of the
+                        // old text stale.
+                        if let Update::MessageEdited(message) = &update
+                            && bot.is_own_message(message)
+                        {
+                            let chat_id = message.peer_id().bot_api_dialog_id();
+                            let message_id = message.id();
+                            if !state.dedupe_cache.is_own_edit(chat_id, message_id, message.text())
+                                && !rewrite_workers.is_applying(account_name, chat_id, message.text())
+                            {
+                                rewrite_workers.observe_text(
+                                    &MessageKey::new(account_name, chat_id, message_id),
+                                    message.text(),
+                                );
+                            }
+                        }
+                        if active.hot_config.rewrite.rewrite_scheduled
+                            && let Some(scheduled) = scheduled_message(&update)
+                        {
+                            let mut runtime = state
+                                .runtime(&mut rate_limiter, &mut usage_tracker, account_hooks, None)
+                                .with_workers(&mut rewrite_workers);
+                            process_scheduled_message(
+                                bot,
+                                active.settings(),
+                                scheduled,
+                                &mut runtime,
+                            )
+                            .await;
+                            continue;
+                        }
+                        if let Some(trigger) = active.hot_config.rewrite.trigger_reaction.as_deref()
+                            && let Some((chat_id, message_id)) =
+                                reaction_trigger_target(&update, trigger)
+                        {
+                            let mut runtime = state
+                                .runtime(&mut rate_limiter, &mut usage_tracker, account_hooks, None)
+                                .with_workers(&mut rewrite_workers);
+                            process_reaction_trigger(
+                                bot,
+                                active.settings(),
+                                chat_id,
+                                message_id,
+                                &mut runtime,
+                            )
+                            .await;
+                            continue;
+                        }
+                        let update_kind = update_kind_name(&update);
+                        debug!(
+                            account = %account_name,
+                            update_kind,
+                            "ignoring unsupported telegram update type"
+                        );
+                        account_hooks.emit(RewriteEvent::UnsupportedUpdateIgnored {
+                            update_kind,
+                        });
+                    }
+                    Err(err) => {
+                        if bot.session_revoked() {
+                            // Handled at the top of the loop.
+                            continue;
+                        }
+                        let now = tokio::time::Instant::now();
+                        match stream_recovery.record_error(is_connection_lost(&err), now) {
+                            Some(delay) => warn!(
+                                account = %account_name,
+                                error = %err,
+                                consecutive_errors = stream_recovery.consecutive_errors,
+                                reconnect_in_ms = delay.as_millis() as u64,
+                                "telegram update stream failed; reconnecting"
+                            ),
+                            None => warn!(
+                                account = %account_name,
+                                error = %err,
+                                consecutive_errors = stream_recovery.consecutive_errors,
+                                "telegram update stream error"
+                            ),
+                        }
+                    }
                 }
             }
+            () = summary_signal.recv() => {
+                report_run_summary(&hooks, &usage_tracker);
+            }
+            Some((model, result)) = health_rx.recv() => {
+                // Failures are logged and reported; the reload itself stands.
+                let _ = report_llm_health(&model, result, &hooks);
+            }
+            Some(error) = reload_error_rx.recv() => {
+                error!(error = %error, "config reload failed after retries; keeping previous config");
+                hooks.emit(RewriteEvent::ConfigReloadFailed { error });
+            }
             Ok(()) = hot_rx.changed() => {
                 let new_hot = hot_rx.borrow_and_update().clone();
-                match ActiveRewriteState::from_hot_config(new_hot, timeout) {
-                    Ok(new_active) => {
-                        bot.update_monitored_chats(new_active.monitored_chats.clone());
-                        context_cache.retain_chats(&new_active.monitored_chats);
-                        context_cache.set_per_chat_limit(new_active.hot_config.rewrite.context_messages);
-                        info!(
-                            model = %new_active.hot_config.openai_model,
-                            chats = ?new_active.hot_config.rewrite.chats,
-                            "config reloaded"
+                match ActiveRewriteState::from_hot_config(
+                    new_hot,
+                    &config.network,
+                    Some(&active),
+                    rewrite_override.as_deref(),
+                ) {
+                    Ok(mut new_active) => {
+                        new_active.resolve_saved_messages(&self_chat_ids);
+                        let llm_target_changed = llm_target_changed(
+                            &active.hot_config.provider,
+                            &new_active.hot_config.provider,
                         );
+                        for account in &mut accounts {
+                            let chats = new_active.monitored_chats(&account.name);
+                            account
+                                .state
+                                .context_cache
+                                .set_per_chat_limit(new_active.hot_config.rewrite.context_messages);
+                            account
+                                .state
+                                .undo_history
+                                .set_limit(new_active.hot_config.rewrite.undo_history);
+                            let chat_links = new_active.chat_links(&account.name);
+                            // Inline, like resolving pending chats: updates after the reload
+                            // must see the new monitored set.
+                            for chat_id in account.bot.update_monitored_chats(chats, chat_links).await {
+                                info!(account = %account.name, chat_id, "monitored chat resolved; rewriting it from now on");
+                                hooks.for_account(&account.name).emit(RewriteEvent::ChatResolved { chat_id });
+                            }
+                            // The bot's set has bare channel ids in Bot API form.
+                            account
+                                .state
+                                .context_cache
+                                .retain_chats(account.bot.monitored_chats());
+                            account
+                                .state
+                                .context_cache
+                                .set_own_personas(account.bot.own_personas().clone());
+                        }
+                        for name in new_active.hot_config.account_chats.keys() {
+                            if !accounts.iter().any(|account| account.name == *name) {
+                                warn!(account = %name, "account added to config; restart to connect it");
+                            }
+                        }
+                        rate_limiter.retain_chats(&new_active.all_monitored_chats());
+                        rate_limiter.set_max_per_minute(new_active.hot_config.rewrite.max_per_minute);
+                        rate_limiter.set_chat_limits(new_active.hot_config.rewrite.chat_max_per_minute());
+                        usage_tracker.set_pricing(token_pricing(&new_active.hot_config.provider));
+                        let changes = active.hot_config.diff(&new_active.hot_config);
+                        info!(changed_fields = changes.len(), "config reloaded");
+                        for change in &changes {
+                            info!(change = %change, "config change applied");
+                        }
+                        hooks.emit(RewriteEvent::ConfigReloaded { changes });
                         active = new_active;
+                        rewrite_workers.set_llm(Arc::clone(&active.llm));
+                        if llm_target_changed {
+                            let llm = Arc::clone(&active.llm);
+                            let model = active.hot_config.provider.model().to_owned();
+                            let health_tx = health_tx.clone();
+                            tokio::spawn(async move {
+                                let _ = health_tx.send((model, llm.health_check().await));
+                            });
+                        }
                     }
                     Err(err) => {
                         warn!(error = %err, "ignoring config reload; keeping previous active config");
@@ -281,699 +1517,7180 @@ where
         }
     }
 
-    bot.shutdown().await?;
-
-    Ok(())
-}
+    drop(first_shutdown_signal);
+    let shutdown_timeout = Duration::from_secs(config.runtime.shutdown_timeout_seconds);
+    let drain_deadline = tokio::time::Instant::now() + shutdown_timeout;
+    rewrite_workers.close();
+    if !rewrite_workers.is_empty() {
+        info!(
+            in_flight = rewrite_workers.len(),
+            "waiting for rewrites in flight before shutting down"
+        );
+    }
+    while !rewrite_workers.is_empty() {
+        let finished =
+            within_shutdown_budget(rewrite_results.recv(), drain_deadline, &mut shutdown_signal)
+                .await;
+        let Ok(Some(done)) = finished else {
+            warn!(
+                in_flight = rewrite_workers.len(),
+                "dropping rewrites still in flight to shut down"
+            );
+            rewrite_workers.abort();
+            break;
+        };
+        apply_worker_done(
+            &mut accounts,
+            active.settings(),
+            &mut rewrite_workers,
+            done,
+            &mut rate_limiter,
+            &mut usage_tracker,
+            &hooks,
+        )
+        .await;
+    }
 
-struct ActiveRewriteState {
-    hot_config: HotConfig,
-    monitored_chats: HashSet<i64>,
-    llm: OpenAiClient,
+    usage_tracker.log_summary();
+    for account in &mut accounts {
+        report_chat_stats(
+            &mut account.state.chat_stats,
+            hooks.for_account(&account.name),
+        );
+    }
+    for account in &accounts {
+        account.state.save_context();
+    }
+    report_run_summary(&hooks, &usage_tracker);
+    let result =
+        shutdown_accounts_within(&mut accounts, shutdown_timeout, &mut shutdown_signal).await;
+    drop(accounts);
+    if let Some(ledger) = ledger {
+        ledger.close().await;
+    }
+    for server in status_servers {
+        server.stop().await;
+    }
+    fatal.map_or(result, Err)
 }
 
-impl ActiveRewriteState {
-    fn from_hot_config(hot_config: HotConfig, timeout: Duration) -> Result<Self> {
-        let monitored_chats: HashSet<i64> = hot_config.rewrite.chats.iter().copied().collect();
-        let llm = OpenAiClient::new(
-            hot_config.openai_api_key.clone(),
-            hot_config.openai_model.clone(),
-            timeout,
-        )?;
-
-        Ok(Self {
-            hot_config,
-            monitored_chats,
-            llm,
-        })
+/// Starts the `[metrics]` and `[health]` endpoints, on one server when they share an address,
+/// and points `hooks` at the state they report.
+async fn start_status_servers(
+    config: &Config,
+    hooks: &mut RewriteHooks,
+) -> Result<Vec<StatusServer>> {
+    let mut routes: Vec<(SocketAddr, Router)> = Vec::new();
+    if let Some(metrics_config) = config.metrics {
+        let metrics = Arc::new(Metrics::new()?);
+        hooks.metrics = Some(Arc::clone(&metrics));
+        routes.push((metrics_config.listen, crate::metrics::routes(metrics)));
+    }
+    if let Some(health_config) = config.health {
+        let health = Arc::new(RwLock::new(HealthState::default()));
+        hooks.health = Some(Arc::clone(&health));
+        let health_routes = crate::health::routes(
+            health,
+            Duration::from_secs(health_config.max_update_age_seconds),
+        );
+        match routes
+            .iter_mut()
+            .find(|(listen, _)| *listen == health_config.listen)
+        {
+            Some((_, shared)) => *shared = std::mem::take(shared).merge(health_routes),
+            None => routes.push((health_config.listen, health_routes)),
+        }
     }
+    let mut servers = Vec::with_capacity(routes.len());
+    for (listen, routes) in routes {
+        servers.push(StatusServer::start(listen, routes).await?);
+    }
+    Ok(servers)
 }
 
-fn is_relevant_config_event_kind(kind: &EventKind) -> bool {
-    matches!(
-        kind,
-        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
-            | EventKind::Create(CreateKind::File | CreateKind::Any)
-            | EventKind::Remove(RemoveKind::File | RemoveKind::Any)
-            | EventKind::Any
-    )
+/// Telegram revoked the session of `account`; the rewriter stopped instead of failing every
+/// request. `main` exits with a distinct status for it.
+#[derive(Debug)]
+pub struct SessionRevoked {
+    pub account: String,
+    /// Where the dead session file was moved.
+    pub retired_session_file: PathBuf,
 }
 
-fn path_targets_watched_config(candidate: &Path, watched_path: &Path) -> bool {
-    if candidate == watched_path {
-        return true;
+impl fmt::Display for SessionRevoked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "telegram session of account `{}` was revoked; it was moved to {}, start the \
+             rewriter from a terminal to log in again",
+            self.account,
+            self.retired_session_file.display()
+        )
     }
-    candidate
-        .canonicalize()
-        .map(|canonical| canonical == watched_path)
-        .unwrap_or(false)
-}
-
-fn event_targets_watched_config(event: &Event, watched_path: &Path) -> bool {
-    event
-        .paths
-        .iter()
-        .any(|path| path_targets_watched_config(path, watched_path))
 }
 
-fn spawn_config_watcher(
-    config_path: &Path,
-    hot_tx: watch::Sender<HotConfig>,
-) -> Result<RecommendedWatcher> {
-    let canonical = config_path.canonicalize().with_context(|| {
-        format!(
-            "failed to canonicalize config path: {}",
-            config_path.display()
-        )
-    })?;
-    let parent = canonical
-        .parent()
-        .context("config path has no parent directory")?
-        .to_owned();
+impl std::error::Error for SessionRevoked {}
 
-    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel::<()>();
+/// Moves the revoked session file of `account` aside. From a terminal, logs in again and
+/// resumes; otherwise returns [`SessionRevoked`].
+async fn recover_revoked_session(account: &mut AccountRuntime, hooks: &RewriteHooks) -> Result<()> {
+    error!(account = %account.name, "telegram session was revoked");
+    hooks
+        .for_account(&account.name)
+        .emit(RewriteEvent::SessionRevoked);
+    let retired_session_file = account.bot.retire_session_file()?;
+    warn!(
+        account = %account.name,
+        retired_session_file = %retired_session_file.display(),
+        "moved the revoked session file aside"
+    );
+    if !std::io::stdin().is_terminal() {
+        return Err(SessionRevoked {
+            account: account.name.clone(),
+            retired_session_file,
+        }
+        .into());
+    }
+    info!(account = %account.name, "logging in again");
+    account
+        .bot
+        .sign_in_again()
+        .await
+        .with_context(|| format!("failed to log account `{}` in again", account.name))?;
+    account.stream_recovery.reconnected();
+    if let Some(watchdog) = account.watchdog.as_mut() {
+        watchdog.record_heartbeat(tokio::time::Instant::now());
+    }
+    info!(account = %account.name, "logged in again; resuming");
+    Ok(())
+}
 
-    let watched_path = canonical.clone();
-    let mut watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| {
-        let event = match res {
-            Ok(ev) => ev,
-            Err(err) => {
-                warn!(error = %err, "filesystem watcher error");
-                return;
+/// Probes a silent `account`, reconnecting it or erroring out when the probe fails.
+async fn check_update_stream(account: &mut AccountRuntime, hooks: &RewriteHooks) -> Result<()> {
+    if account.stream_recovery.reconnect_at.is_some() {
+        return Ok(());
+    }
+    let Some(watchdog) = account.watchdog.as_mut() else {
+        return Ok(());
+    };
+    let Some(silent) = watchdog.due(tokio::time::Instant::now()) else {
+        return Ok(());
+    };
+    let silent_seconds = silent.as_secs();
+    let account_hooks = hooks.for_account(&account.name);
+    warn!(
+        account = %account.name,
+        silent_seconds,
+        "no telegram updates for a while; checking the connection"
+    );
+    account_hooks.emit(RewriteEvent::UpdateStreamSilent { silent_seconds });
+    let error =
+        match tokio::time::timeout(STALL_PROBE_TIMEOUT, account.bot.server_unix_time()).await {
+            Ok(Ok(_)) => {
+                watchdog.probe_answered(tokio::time::Instant::now());
+                info!(
+                    account = %account.name,
+                    silent_seconds,
+                    "telegram answered; the update stream is only quiet"
+                );
+                account_hooks.emit(RewriteEvent::UpdateStreamAlive { silent_seconds });
+                return Ok(());
             }
+            Ok(Err(err)) => format!("{err:#}"),
+            Err(_) => format!("no answer within {}s", STALL_PROBE_TIMEOUT.as_secs()),
         };
-
-        if !is_relevant_config_event_kind(&event.kind) {
-            return;
+    let now = tokio::time::Instant::now();
+    match watchdog.probe_failed(now) {
+        StallAction::Exit => {
+            error!(
+                account = %account.name,
+                silent_seconds,
+                error = %error,
+                "telegram update stream stalled; exiting"
+            );
+            account_hooks.emit(RewriteEvent::UpdateStreamStalled {
+                silent_seconds,
+                exiting: true,
+            });
+            bail!(
+                "telegram update stream of account `{}` stalled after {silent_seconds}s \
+                 without updates: {error}",
+                account.name
+            );
         }
-
-        if !event_targets_watched_config(&event, &watched_path) {
-            return;
+        StallAction::Reconnect => {
+            let delay = account.stream_recovery.schedule_reconnect(now);
+            warn!(
+                account = %account.name,
+                silent_seconds,
+                retry_in_ms = delay.as_millis() as u64,
+                error = %error,
+                "telegram update stream stalled; reconnecting"
+            );
+            account_hooks.emit(RewriteEvent::UpdateStreamStalled {
+                silent_seconds,
+                exiting: false,
+            });
         }
+    }
+    Ok(())
+}
 
-        let _ = notify_tx.send(());
-    })
-    .context("failed to create filesystem watcher")?;
-
-    watcher
-        .watch(&parent, RecursiveMode::NonRecursive)
-        .with_context(|| format!("failed to watch directory: {}", parent.display()))?;
+/// The ledger in `runtime.state_file`, when one is configured.
+async fn open_ledger(config: &Config) -> Result<Option<RewriteLedger>> {
+    let Some(path) = config.runtime.state_file.as_deref() else {
+        return Ok(None);
+    };
+    let retention = Duration::from_secs(config.runtime.state_retention_hours * 60 * 60);
+    let context_max_age = Duration::from_secs(config.runtime.context_max_age_minutes * 60);
+    RewriteLedger::open(path, retention, context_max_age)
+        .await
+        .map(Some)
+}
 
-    let reload_path = canonical;
-    tokio::spawn(async move {
-        while notify_rx.recv().await.is_some() {
-            while notify_rx.try_recv().is_ok() {}
+/// What `--backfill` did with the messages it went through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackfillReport {
+    pub rewritten: u64,
+    /// Left as they were: filtered, deduplicated, rate limited, refused or unchanged.
+    pub skipped: u64,
+    /// The provider failed or the edit couldn't be applied.
+    pub failed: u64,
+    /// With `--dry-run`, the original and rewritten text of every message that would have
+    /// been edited, oldest first.
+    pub previews: Vec<BackfillPreview>,
+}
 
-            tokio::time::sleep(Duration::from_millis(50)).await;
-            while notify_rx.try_recv().is_ok() {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackfillPreview {
+    pub message_id: i32,
+    pub before: String,
+    pub after: String,
+}
 
-            match load_hot_config(&reload_path) {
-                Ok(new_cfg) => {
-                    hot_tx.send_if_modified(|current| {
-                        if *current != new_cfg {
-                            *current = new_cfg;
-                            true
-                        } else {
-                            false
-                        }
-                    });
-                }
-                Err(err) => {
-                    warn!(error = %err, "config reload failed; keeping previous config");
-                }
-            }
+impl BackfillReport {
+    fn from_counters(counters: ChatCounters, previews: Vec<BackfillPreview>) -> Self {
+        let rewritten = counters.rewritten;
+        let failed = counters.llm_failed + counters.edit_failed;
+        Self {
+            rewritten,
+            skipped: counters.seen.saturating_sub(rewritten + failed),
+            failed,
+            previews,
         }
-    });
-
-    Ok(watcher)
+    }
 }
 
-fn is_historical_catch_up_message(message_unix: i64, startup_unix: i64) -> bool {
-    message_unix < startup_unix
+/// Rewrites our last `count` messages in `chat_id` from the primary account, oldest first,
+/// each with the context that preceded it. Edits are throttled like in rewrite mode, and
+/// the ones deferred by flood waits or slow mode are waited for before returning. With
+/// `dry_run` nothing is edited.
+pub async fn run_backfill_mode(
+    config: &Config,
+    chat_id: i64,
+    count: usize,
+    dry_run: bool,
+) -> Result<BackfillReport> {
+    let mut hot_config = extract_hot_config(config)?;
+    if dry_run {
+        hot_config.rewrite.show_typing = false;
+    }
+    let active = ActiveRewriteState::from_hot_config(hot_config, &config.network, None, None)?;
+    let mut ledger = open_ledger(config).await?;
+    let mut bot = TelegramBot::connect_for_rewrite(
+        &config.telegram,
+        config.network.telegram_proxy.as_deref(),
+        HashSet::from([chat_id]),
+        &[],
+        false,
+    )
+    .await
+    .context("failed to connect to telegram")?;
+    let account_ledger = ledger
+        .as_mut()
+        .map(|ledger| ledger.for_account(PRIMARY_ACCOUNT_NAME));
+    let result = backfill_chat(&bot, &active, account_ledger, chat_id, count, dry_run).await;
+    if let Err(err) = bot.shutdown().await {
+        warn!(error = %err, "telegram client did not shut down cleanly after backfill");
+    }
+    if let Some(ledger) = ledger {
+        ledger.close().await;
+    }
+    result
 }
 
-fn update_kind_name(update: &Update) -> String {
-    match update {
-        Update::NewMessage(_) => "new_message".to_owned(),
-        Update::MessageEdited(_) => "message_edited".to_owned(),
-        Update::MessageDeleted(_) => "message_deleted".to_owned(),
-        Update::CallbackQuery(_) => "callback_query".to_owned(),
-        Update::InlineQuery(_) => "inline_query".to_owned(),
-        Update::InlineSend(_) => "inline_send".to_owned(),
-        Update::Raw(raw) => {
-            let tl_update: &grammers_client::tl::enums::Update = raw;
-            let rendered = format!("{tl_update:?}");
-            let tl_name = rendered
-                .split_once('(')
-                .map(|(name, _)| name)
-                .unwrap_or(&rendered);
-            format!("raw/{tl_name}")
+async fn backfill_chat(
+    bot: &TelegramBot,
+    active: &ActiveRewriteState,
+    ledger: Option<AccountLedger>,
+    chat_id: i64,
+    count: usize,
+    dry_run: bool,
+) -> Result<BackfillReport> {
+    let settings = active.settings();
+    let rewrite = settings.rewrite;
+    let messages = bot.recent_own_messages(chat_id, count).await?;
+    info!(
+        chat_id,
+        messages = messages.len(),
+        dry_run,
+        "backfilling own messages"
+    );
+
+    let hooks = RewriteHooks::default();
+    let mut rate_limiter = RateLimiter::new(rewrite.max_per_minute);
+    rate_limiter.set_chat_limits(rewrite.chat_max_per_minute());
+    let mut usage_tracker = UsageTracker::new(token_pricing(&active.hot_config.provider));
+    let mut state = AccountState::new(rewrite);
+    if let Some(ledger) = ledger {
+        state.dedupe_cache.attach_ledger(ledger);
+    }
+    let mut previews = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        info!(
+            chat_id,
+            message_id = message.id(),
+            progress = %format!("{}/{}", index + 1, messages.len()),
+            "backfilling message"
+        );
+        // A fresh cache makes the context load fetch what preceded this message instead of
+        // reusing messages that came after it.
+        state.context_cache = ContextCache::new(rewrite.context_messages);
+        state
+            .context_cache
+            .set_own_personas(bot.own_personas().clone());
+        let context_scope = ContextScope {
+            chat_id,
+            topic_root_id: message_topic_root_id(message),
+        };
+        let mut runtime = state.runtime(
+            &mut rate_limiter,
+            &mut usage_tracker,
+            hooks.for_account(PRIMARY_ACCOUNT_NAME),
+            None,
+        );
+        if dry_run {
+            previews
+                .extend(preview_rewrite(bot, settings, message, context_scope, &mut runtime).await);
+        } else {
+            process_message(bot, settings, message, context_scope, &mut runtime).await?;
+        }
+    }
+
+    loop {
+        let next = [
+            state.edit_retries.next_retry_at(),
+            state.slow_mode.next_ready_at(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let Some(next) = next else {
+            break;
+        };
+        tokio::time::sleep_until(next).await;
+        let now = tokio::time::Instant::now();
+        let due = state.edit_retries.take_due(now);
+        let ready = state.slow_mode.take_ready(now);
+        let mut runtime = state.runtime(
+            &mut rate_limiter,
+            &mut usage_tracker,
+            hooks.for_account(PRIMARY_ACCOUNT_NAME),
+            None,
+        );
+        if !due.is_empty() {
+            retry_deferred_edits(bot, rewrite, due, &mut runtime).await;
+        }
+        if !ready.is_empty() {
+            resend_slow_mode_ready(bot, rewrite, ready, &mut runtime).await;
         }
-        _ => "unknown".to_owned(),
     }
+
+    usage_tracker.log_summary();
+    let (_, rows) = state.chat_stats.take_window(tokio::time::Instant::now());
+    let totals = rows
+        .into_iter()
+        .find(|&(id, _, _)| id == chat_id)
+        .map(|(_, _, totals)| totals)
+        .unwrap_or_default();
+    Ok(BackfillReport::from_counters(totals, previews))
 }
 
-async fn process_message(
+/// Runs `message` through the rewrite checks and the provider like [`process_message`],
+/// but returns the rewrite instead of applying it.
+async fn preview_rewrite(
     bot: &TelegramBot,
-    llm: &OpenAiClient,
-    rewrite: &RewriteConfig,
-    message: UpdateMessage,
+    settings: RewriteSettings<'_>,
+    message: &TelegramMessage,
     context_scope: ContextScope,
     runtime: &mut ProcessMessageRuntime<'_>,
-) -> Result<()> {
+) -> Option<BackfillPreview> {
     let chat_id = context_scope.chat_id;
-    let topic_root_id = context_scope.topic_root_id;
-    if !message.outgoing() {
-        runtime
-            .context_cache
-            .observe_update_message(context_scope, &message);
-        return Ok(());
-    }
-
     let message_id = message.id();
-    if runtime.dedupe_cache.contains(chat_id, message_id) {
-        info!(chat_id, message_id, "skipping deduped message");
-        return Ok(());
+    let original =
+        rewrite_candidate(bot, settings.rewrite, message, context_scope, runtime).await?;
+    let outcome =
+        request_with_context(bot, settings, message, context_scope, &original, runtime).await;
+    let outcome = check_dropped_links(
+        settings.rewrite,
+        &original,
+        &message_hidden_links(message),
+        outcome,
+        chat_id,
+        message_id,
+    );
+    match outcome {
+        RewriteOutcome::Edit { text, .. } => {
+            runtime.record_stat(chat_id, message_id, ChatStat::Rewritten);
+            Some(BackfillPreview {
+                message_id,
+                before: original,
+                after: text,
+            })
+        }
+        outcome => {
+            if let Some(stat) = ChatStat::for_outcome(&outcome) {
+                runtime.record_stat(chat_id, message_id, stat);
+            }
+            info!(chat_id, message_id, outcome = ?outcome, "dry run: message would be left as it is");
+            None
+        }
     }
+}
 
-    let original = message.text().trim().to_owned();
-    if original.is_empty() {
-        info!(chat_id, message_id, "skipping non-text or empty message");
-        return Ok(());
-    }
+/// The account signed in through `[telegram]`, followed by the `[[accounts]]` entries.
+fn account_logins(config: &Config) -> impl Iterator<Item = (&str, &TelegramConfig)> {
+    std::iter::once((PRIMARY_ACCOUNT_NAME, &config.telegram)).chain(
+        config
+            .accounts
+            .iter()
+            .map(|account| (account.name.as_str(), &account.telegram)),
+    )
+}
 
-    let mut context =
-        runtime
-            .context_cache
-            .recent_before(context_scope, message_id, rewrite.context_messages);
-    if runtime
-        .context_cache
-        .should_backfill(context_scope, rewrite.context_messages, context.len())
-    {
-        info!(
-            chat_id,
-            topic_root_id = ?topic_root_id,
-            message_id,
-            requested_context_messages = rewrite.context_messages,
-            cached_context_messages = context.len(),
-            "fetching context messages from telegram"
-        );
-        match bot
-            .fetch_context(&message, rewrite.context_messages, topic_root_id)
-            .await
-        {
-            Ok(fetched) => {
-                info!(
-                    chat_id,
-                    topic_root_id = ?topic_root_id,
-                    message_id,
-                    fetched_context_messages = fetched.len(),
-                    "fetched context messages from telegram"
-                );
-                runtime.context_cache.mark_hydrated(context_scope);
-                context = fetched.iter().map(|entry| entry.message.clone()).collect();
-                runtime.context_cache.backfill(context_scope, fetched);
+/// Connects every account in turn, shutting the connected ones down when one fails.
+async fn connect_accounts(
+    config: &Config,
+    active: &ActiveRewriteState,
+    catch_up_enabled: bool,
+) -> Result<Vec<AccountRuntime>> {
+    let mut accounts = Vec::with_capacity(1 + config.accounts.len());
+    for (name, telegram) in account_logins(config) {
+        let connected = TelegramBot::connect_for_rewrite(
+            telegram,
+            config.network.telegram_proxy.as_deref(),
+            active.monitored_chats(name),
+            active.chat_links(name),
+            catch_up_enabled,
+        )
+        .await
+        .with_context(|| format!("failed to connect telegram account `{name}`"));
+        let bot = match connected {
+            Ok(bot) => bot,
+            Err(err) => {
+                let _ = shutdown_accounts(&mut accounts).await;
+                return Err(err);
             }
+        };
+        let startup_unix = match bot.server_unix_time().await {
+            Ok(server_unix) => server_unix,
             Err(err) => {
-                warn!(
-                    chat_id,
-                    topic_root_id = ?topic_root_id,
-                    message_id,
-                    requested_context_messages = rewrite.context_messages,
-                    error = %err,
-                    "failed to fetch context messages; using cached context only"
-                );
+                warn!(account = name, error = %err, "failed to fetch telegram server time; using local clock");
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs() as i64
             }
-        }
+        };
+        info!(account = name, "telegram account connected");
+        let mut state = AccountState::new(&active.hot_config.rewrite);
+        state
+            .context_cache
+            .set_own_personas(bot.own_personas().clone());
+        let watchdog = Some(config.runtime.stall_threshold_seconds)
+            .filter(|&seconds| seconds > 0)
+            .map(|seconds| {
+                let action = if config.runtime.exit_on_stall {
+                    StallAction::Exit
+                } else {
+                    StallAction::Reconnect
+                };
+                StallWatchdog::new(
+                    Duration::from_secs(seconds),
+                    action,
+                    tokio::time::Instant::now(),
+                )
+            });
+        accounts.push(AccountRuntime {
+            name: name.to_owned(),
+            bot,
+            startup_unix,
+            catch_up_batches: CatchUpBatches::new(),
+            albums: AlbumBuffer::new(),
+            state,
+            stream_recovery: StreamRecovery::default(),
+            watchdog,
+        });
     }
+    Ok(accounts)
+}
 
-    let llm_context: Vec<String> = context
-        .iter()
-        .map(ContextMessage::as_llm_user_content)
+/// Waits for the next update from any connected account, with the account's index.
+async fn next_account_update(accounts: &mut [AccountRuntime]) -> (usize, Result<Update>) {
+    let mut pending: Vec<_> = accounts
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, account)| account.stream_recovery.reconnect_at.is_none())
+        .map(|(index, account)| (index, Box::pin(account.bot.next_update())))
         .collect();
-    let pretty_system_prompt = rewrite.system_prompt.replace('\n', "\n    ");
-    let pretty_input = original.replace('\n', "\n    ");
-    let pretty_context = if llm_context.is_empty() {
-        "    (none)".to_owned()
-    } else {
-        llm_context
-            .iter()
-            .enumerate()
-            .map(|(idx, entry)| {
-                let entry = entry.replace('\n', "\n         ");
-                format!("    {:02}. {}", idx + 1, entry)
-            })
-            .collect::<Vec<_>>()
-            .join("\n")
-    };
-    info!(
-        chat_id,
-        topic_root_id = ?topic_root_id,
-        message_id,
-        context_messages = llm_context.len(),
-        model_call_enabled = runtime.rewrite_override.is_none(),
-        "prepared rewrite payload\n  system_prompt:\n    {}\n  context:\n{}\n  input:\n    {}",
-        pretty_system_prompt,
-        pretty_context,
-        pretty_input
-    );
-
-    let rewritten = if let Some(override_text) = runtime.rewrite_override {
-        debug!(chat_id, message_id, "using test rewrite override text");
-        override_text.to_owned()
-    } else {
-        match llm
-            .rewrite(&rewrite.system_prompt, &context, &original)
-            .await
-        {
-            Ok(text) => text,
-            Err(err) => {
-                warn!(
-                    chat_id,
-                    message_id,
-                    error = %err,
-                    "openai rewrite failed; leaving original message unchanged"
-                );
-                runtime
-                    .context_cache
-                    .observe_update_message(context_scope, &message);
-                return Ok(());
+    std::future::poll_fn(|cx| {
+        for (index, update) in &mut pending {
+            if let Poll::Ready(result) = update.as_mut().poll(cx) {
+                return Poll::Ready((*index, result));
             }
         }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Shuts the accounts down gracefully for up to `timeout`, or until another shutdown signal,
+/// then aborts whatever is still running. A forced shutdown is returned as an error.
+async fn shutdown_accounts_within(
+    accounts: &mut [AccountRuntime],
+    timeout: Duration,
+    signal: &mut impl ShutdownSignal,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let reason = match within_shutdown_budget(shutdown_accounts(accounts), deadline, signal).await {
+        Ok(result) => return result,
+        Err(ShutdownCut::TimedOut) => {
+            format!(
+                "graceful shutdown did not finish within {}s",
+                timeout.as_secs()
+            )
+        }
+        Err(ShutdownCut::Signal(name)) => format!("{name} received again during shutdown"),
     };
+    let abandoned: Vec<&str> = accounts
+        .iter_mut()
+        .filter_map(|account| account.bot.abort().then_some(account.name.as_str()))
+        .collect();
+    error!(
+        reason = %reason,
+        abandoned_accounts = ?abandoned,
+        "forcing shutdown; aborted telegram connections that were still closing"
+    );
+    bail!("shutdown was not graceful: {reason}")
+}
 
-    let rewritten = truncate_to_telegram_limit(rewritten.trim(), TELEGRAM_MESSAGE_MAX_CHARS);
-    if rewritten.is_empty() {
-        info!(chat_id, message_id, "skipping empty rewrite result");
-        runtime
-            .context_cache
-            .observe_update_message(context_scope, &message);
-        return Ok(());
-    }
-    if rewritten == original {
-        info!(chat_id, message_id, "skipping unchanged rewrite result");
-        runtime
-            .context_cache
-            .observe_update_message(context_scope, &message);
-        return Ok(());
+/// Quits every account's sender pool, carrying on past failures; the first one is returned.
+async fn shutdown_accounts(accounts: &mut [AccountRuntime]) -> Result<()> {
+    let mut result = Ok(());
+    for account in accounts {
+        if let Err(err) = account.bot.shutdown().await {
+            error!(account = %account.name, error = %err, "failed to shut down telegram account");
+            result = result.and(Err(err));
+        }
     }
+    result
+}
+
+/// Logs the provider health check result and reports it as [`RewriteEvent::LlmHealth`].
+async fn check_llm_health(active: &ActiveRewriteState, hooks: &RewriteHooks) -> Result<()> {
+    let result = active.llm.health_check().await;
+    report_llm_health(active.hot_config.provider.model(), result, hooks)
+}
 
-    match bot.edit_message(&message, rewritten).await {
+fn report_llm_health(model: &str, result: Result<()>, hooks: &RewriteHooks) -> Result<()> {
+    match result {
         Ok(()) => {
-            runtime
-                .context_cache
-                .upsert_update_message_text(context_scope, &message, rewritten);
-            runtime.dedupe_cache.insert(chat_id, message_id);
-            info!(chat_id, message_id, "rewrote and edited message");
-            runtime.hooks.emit(RewriteEvent::MessageEdited {
-                chat_id,
-                message_id,
+            info!(model = %model, "llm health check passed");
+            hooks.emit(RewriteEvent::LlmHealth {
+                ok: true,
+                error: None,
             });
+            Ok(())
         }
         Err(err) => {
-            warn!(
-                chat_id,
-                message_id,
-                original_text = %original,
-                rewritten_text = %rewritten,
-                error = %err,
-                "failed to edit message; continuing"
+            let error = format!("{err:#}");
+            error!(
+                model = %model,
+                error = %error,
+                "llm health check failed; rewrites will likely fail"
             );
-            runtime
-                .context_cache
-                .observe_update_message(context_scope, &message);
+            hooks.emit(RewriteEvent::LlmHealth {
+                ok: false,
+                error: Some(error),
+            });
+            Err(err)
         }
     }
+}
 
-    Ok(())
+fn require_healthy_at_startup(provider: &ProviderConfig) -> bool {
+    matches!(provider, ProviderConfig::OpenAi(openai) if openai.require_healthy_at_startup)
 }
 
-struct ProcessMessageRuntime<'a> {
-    dedupe_cache: &'a mut DedupeCache,
-    context_cache: &'a mut ContextCache,
-    rewrite_override: Option<&'a str>,
-    hooks: &'a RewriteHooks,
+/// Whether a reload points rewrites at a different model or credentials.
+fn llm_target_changed(old: &ProviderConfig, new: &ProviderConfig) -> bool {
+    match (old, new) {
+        (ProviderConfig::OpenAi(old), ProviderConfig::OpenAi(new)) => {
+            old.model != new.model || old.api_key != new.api_key || old.api_base != new.api_base
+        }
+        _ => old.provider() != new.provider() || old.model() != new.model(),
+    }
 }
 
-fn normalize_rewrite_override(rewrite_override: Option<String>) -> Option<String> {
-    rewrite_override
-        .map(|value| value.trim().to_owned())
-        .filter(|value| !value.is_empty())
+fn token_pricing(provider: &ProviderConfig) -> Option<TokenPricing> {
+    match provider {
+        ProviderConfig::OpenAi(openai) => openai
+            .cost_per_million_input
+            .zip(openai.cost_per_million_output)
+            .map(|(input_per_million, output_per_million)| TokenPricing {
+                input_per_million,
+                output_per_million,
+            }),
+        _ => None,
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-struct ContextScope {
-    chat_id: i64,
-    topic_root_id: Option<i32>,
+/// The active backend and rewrite settings one message is processed with.
+#[derive(Clone, Copy)]
+struct RewriteSettings<'a> {
+    llm: &'a dyn LlmRewriter,
+    refusals: &'a RefusalDetector,
+    rewrite: &'a RewriteConfig,
 }
 
-struct ContextCache {
-    per_chat_limit: usize,
-    entries: HashMap<ContextScope, VecDeque<ContextEntry>>,
-    hydrated_scopes: HashSet<ContextScope>,
+struct ActiveRewriteState {
+    hot_config: HotConfig,
+    /// Chats to rewrite, by account name.
+    monitored_chats: HashMap<String, HashSet<i64>>,
+    llm: Arc<dyn LlmRewriter>,
+    refusals: RefusalDetector,
+    shared: SharedRewriterState,
 }
 
-impl ContextCache {
-    fn new(per_chat_limit: usize) -> Self {
-        Self {
-            per_chat_limit,
-            entries: HashMap::new(),
-            hydrated_scopes: HashSet::new(),
-        }
+impl ActiveRewriteState {
+    /// `previous` carries the rate-limit budget and response cache over reloads that keep
+    /// their settings. `rewrite_override` replaces the provider with fixed text for tests.
+    fn from_hot_config(
+        hot_config: HotConfig,
+        network: &NetworkConfig,
+        previous: Option<&ActiveRewriteState>,
+        rewrite_override: Option<&str>,
+    ) -> Result<Self> {
+        let mut monitored_chats = HashMap::from([(
+            PRIMARY_ACCOUNT_NAME.to_owned(),
+            hot_config.rewrite.chats.iter().copied().collect(),
+        )]);
+        monitored_chats.extend(
+            hot_config
+                .account_chats
+                .iter()
+                .map(|(name, chats)| (name.clone(), chats.iter().copied().collect())),
+        );
+        let shared = SharedRewriterState::for_provider(
+            &hot_config.provider,
+            previous.map(|previous| &previous.shared),
+        );
+        let llm: Arc<dyn LlmRewriter> = match rewrite_override {
+            Some(text) => Arc::new(FixedRewriter::new(text.to_owned())),
+            None => Arc::from(build_rewriter(
+                &hot_config.provider,
+                network,
+                TELEGRAM_MESSAGE_MAX_CHARS,
+                hot_config.rewrite.two_stage,
+                &shared,
+            )?),
+        };
+        let refusals = RefusalDetector::new(&hot_config.rewrite.refusal_patterns)?;
+
+        Ok(Self {
+            hot_config,
+            monitored_chats,
+            llm,
+            refusals,
+            shared,
+        })
     }
 
-    fn set_per_chat_limit(&mut self, per_chat_limit: usize) {
-        self.per_chat_limit = per_chat_limit;
-        for messages in self.entries.values_mut() {
-            while messages.len() > self.per_chat_limit {
-                messages.pop_front();
-            }
+    fn settings(&self) -> RewriteSettings<'_> {
+        RewriteSettings {
+            llm: self.llm.as_ref(),
+            refusals: &self.refusals,
+            rewrite: &self.hot_config.rewrite,
         }
     }
 
-    fn retain_chats(&mut self, chats: &HashSet<i64>) {
-        self.entries
-            .retain(|scope, _| chats.contains(&scope.chat_id));
-        self.hydrated_scopes
-            .retain(|scope| chats.contains(&scope.chat_id));
+    /// Empty for an account no longer in the config, which stops its rewrites.
+    fn monitored_chats(&self, account: &str) -> HashSet<i64> {
+        self.monitored_chats
+            .get(account)
+            .cloned()
+            .unwrap_or_default()
     }
 
-    fn observe_update_message(&mut self, scope: ContextScope, message: &UpdateMessage) {
-        let text = message.text().trim().to_owned();
-        if text.is_empty() {
-            return;
+    /// Invite links and usernames from `rewrite.chats`, which only the primary account joins.
+    fn chat_links(&self, account: &str) -> &[ChatLink] {
+        if account == PRIMARY_ACCOUNT_NAME {
+            &self.hot_config.rewrite.chat_links
+        } else {
+            &[]
         }
-
-        let peer_name = message.sender().and_then(|p| p.name().map(str::to_owned));
-        let sender_name = resolve_sender_name(message.outgoing(), peer_name.as_deref());
-        self.record_message(scope, message.id(), ContextMessage { sender_name, text });
     }
 
-    fn upsert_update_message_text(
-        &mut self,
-        scope: ContextScope,
-        message: &UpdateMessage,
-        text: &str,
-    ) {
-        let text = text.trim().to_owned();
-        if text.is_empty() {
-            return;
-        }
-
-        let peer_name = message.sender().and_then(|p| p.name().map(str::to_owned));
-        let sender_name = resolve_sender_name(message.outgoing(), peer_name.as_deref());
-        self.upsert_message(scope, message.id(), ContextMessage { sender_name, text });
+    fn all_monitored_chats(&self) -> HashSet<i64> {
+        self.monitored_chats.values().flatten().copied().collect()
     }
 
-    fn record_message(&mut self, scope: ContextScope, message_id: i32, message: ContextMessage) {
-        let chat_messages = self.entries.entry(scope).or_default();
-        if chat_messages
-            .iter()
-            .any(|entry| entry.message_id == message_id)
-        {
-            return;
-        }
-        chat_messages.push_back(ContextEntry {
-            message_id,
-            message,
-        });
-        while chat_messages.len() > self.per_chat_limit {
-            chat_messages.pop_front();
+    /// Swaps `"me"` in each account's chats for the chat id of that account's own user.
+    fn resolve_saved_messages(&mut self, self_chat_ids: &HashMap<String, i64>) {
+        for (account, chats) in &mut self.monitored_chats {
+            if let Some(&self_chat_id) = self_chat_ids.get(account) {
+                resolve_saved_messages(chats, self_chat_id);
+            }
         }
     }
+}
 
-    fn upsert_message(&mut self, scope: ContextScope, message_id: i32, message: ContextMessage) {
-        let chat_messages = self.entries.entry(scope).or_default();
-        if let Some(entry) = chat_messages
-            .iter_mut()
-            .find(|entry| entry.message_id == message_id)
-        {
-            entry.message = message;
-            return;
-        }
-        chat_messages.push_back(ContextEntry {
-            message_id,
-            message,
-        });
-        while chat_messages.len() > self.per_chat_limit {
-            chat_messages.pop_front();
-        }
-    }
+/// One connected Telegram account and the state of its rewrites.
+struct AccountRuntime {
+    name: String,
+    bot: TelegramBot,
+    /// Telegram server time when the account connected; older messages are catch-up.
+    startup_unix: i64,
+    catch_up_batches: CatchUpBatches<UpdateMessage>,
+    albums: AlbumBuffer<AlbumMember>,
+    state: AccountState,
+    stream_recovery: StreamRecovery,
+    /// Without `runtime.stall_threshold_seconds`, a silent stream is never checked.
+    watchdog: Option<StallWatchdog>,
+}
 
-    fn backfill(&mut self, scope: ContextScope, messages: Vec<ContextEntry>) {
-        let fresh: VecDeque<ContextEntry> = messages.into_iter().collect();
-        self.entries.insert(scope, fresh);
+/// Update stream failures of one account, and when its connection is rebuilt next.
+#[derive(Debug, Default)]
+struct StreamRecovery {
+    /// Stream errors since the last update that arrived.
+    consecutive_errors: u32,
+    /// Reconnect attempts since the last update that arrived.
+    attempt: u32,
+    /// Set while the account waits to reconnect; its stream isn't polled meanwhile.
+    reconnect_at: Option<tokio::time::Instant>,
+}
+
+impl StreamRecovery {
+    fn record_update(&mut self) {
+        self.consecutive_errors = 0;
+        self.attempt = 0;
     }
 
-    fn recent_before(
-        &self,
-        scope: ContextScope,
-        message_id: i32,
-        count: usize,
-    ) -> Vec<ContextMessage> {
-        if count == 0 {
-            return Vec::new();
-        }
+    /// Counts a stream error, scheduling a reconnect when the connection was lost or errors
+    /// keep coming. Returns the wait before it.
+    fn record_error(
+        &mut self,
+        connection_lost: bool,
+        now: tokio::time::Instant,
+    ) -> Option<Duration> {
+        self.consecutive_errors += 1;
+        let persistent =
+            connection_lost || self.consecutive_errors >= STREAM_ERROR_RECONNECT_THRESHOLD;
+        (persistent && self.reconnect_at.is_none()).then(|| self.schedule_reconnect(now))
+    }
 
-        let mut recent = Vec::with_capacity(count);
-        if let Some(messages) = self.entries.get(&scope) {
-            for entry in messages.iter().rev() {
-                if entry.message_id == message_id {
-                    continue;
-                }
-                recent.push(entry.message.clone());
-                if recent.len() >= count {
-                    break;
-                }
-            }
-        }
-        recent.reverse();
-        recent
+    /// Schedules the next reconnect attempt with exponential backoff.
+    fn schedule_reconnect(&mut self, now: tokio::time::Instant) -> Duration {
+        self.attempt += 1;
+        let delay = reconnect_backoff(self.attempt);
+        self.reconnect_at = Some(now + delay);
+        delay
     }
 
-    fn should_backfill(&self, scope: ContextScope, count: usize, cached_count: usize) -> bool {
-        count > 0 && cached_count < count && !self.hydrated_scopes.contains(&scope)
+    fn is_due(&self, now: tokio::time::Instant) -> bool {
+        self.reconnect_at
+            .is_some_and(|reconnect_at| reconnect_at <= now)
     }
 
-    fn mark_hydrated(&mut self, scope: ContextScope) {
-        self.hydrated_scopes.insert(scope);
+    /// The stream is polled again; errors count from zero, the backoff only resets once an
+    /// update arrives.
+    fn reconnected(&mut self) {
+        self.consecutive_errors = 0;
+        self.reconnect_at = None;
     }
 }
 
-fn truncate_to_telegram_limit(input: &str, max_chars: usize) -> &str {
-    let mut char_count = 0;
-    for (byte_offset, _) in input.char_indices() {
-        char_count += 1;
-        if char_count > max_chars {
-            return &input[..byte_offset];
-        }
-    }
-    input
+/// Wait before reconnect attempt `attempt` (from 1): doubling from [`RECONNECT_BACKOFF`],
+/// capped at [`RECONNECT_BACKOFF_MAX`].
+fn reconnect_backoff(attempt: u32) -> Duration {
+    RECONNECT_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RECONNECT_BACKOFF_MAX)
 }
 
-struct DedupeCache {
-    entries: HashMap<(i64, i32), Instant>,
-    ttl: Duration,
+/// A media album item held in [`AlbumBuffer`] with what processing it needs.
+struct AlbumMember {
+    context_scope: ContextScope,
+    is_catch_up: bool,
+    message: UpdateMessage,
 }
 
-impl DedupeCache {
-    fn new(ttl: Duration) -> Self {
+/// Per-account caches; message ids are only unique within an account.
+struct AccountState {
+    dedupe_cache: DedupeCache,
+    deleted_messages: DeletedMessages,
+    edit_retries: EditRetries<PendingEdit>,
+    edit_throttle: EditThrottle,
+    slow_mode: SlowModeQueue<PendingResend>,
+    scheduled_rewrites: ScheduledRewrites,
+    context_cache: ContextCache,
+    paused_chats: HashSet<i64>,
+    chat_stats: ChatStats,
+    undo_history: UndoHistory,
+    /// Where `context_cache` is saved, with `runtime.state_file`.
+    context_store: Option<ContextStore>,
+    /// Set for the whole run from `--dry-run` or `runtime.dry_run`.
+    dry_run: bool,
+}
+
+impl AccountState {
+    fn new(rewrite: &RewriteConfig) -> Self {
         Self {
-            entries: HashMap::new(),
-            ttl,
+            dedupe_cache: DedupeCache::new(Duration::from_secs(DEDUPE_TTL_SECONDS)),
+            deleted_messages: DeletedMessages::new(Duration::from_secs(DEDUPE_TTL_SECONDS)),
+            edit_retries: EditRetries::new(EDIT_RETRY_QUEUE_LIMIT),
+            edit_throttle: EditThrottle::default(),
+            slow_mode: SlowModeQueue::new(),
+            scheduled_rewrites: ScheduledRewrites::default(),
+            context_cache: ContextCache::new(rewrite.context_messages),
+            paused_chats: HashSet::new(),
+            chat_stats: ChatStats::new(tokio::time::Instant::now()),
+            undo_history: UndoHistory::new(rewrite.undo_history),
+            context_store: None,
+            dry_run: false,
         }
     }
 
-    fn contains(&mut self, chat_id: i64, message_id: i32) -> bool {
-        self.evict_expired();
-        self.entries.contains_key(&(chat_id, message_id))
+    fn save_context(&self) {
+        if let Some(store) = self.context_store.as_ref() {
+            store.save(self.context_cache.snapshot());
+        }
     }
 
-    fn insert(&mut self, chat_id: i64, message_id: i32) {
-        self.entries.insert((chat_id, message_id), Instant::now());
+    fn runtime<'a>(
+        &'a mut self,
+        rate_limiter: &'a mut RateLimiter,
+        usage_tracker: &'a mut UsageTracker,
+        hooks: AccountHooks<'a>,
+        rewrite_deadline: Option<Duration>,
+    ) -> ProcessMessageRuntime<'a> {
+        ProcessMessageRuntime {
+            dedupe_cache: &mut self.dedupe_cache,
+            deleted_messages: &self.deleted_messages,
+            edit_retries: &mut self.edit_retries,
+            edit_throttle: &mut self.edit_throttle,
+            slow_mode: &mut self.slow_mode,
+            scheduled_rewrites: &mut self.scheduled_rewrites,
+            context_cache: &mut self.context_cache,
+            rate_limiter,
+            paused_chats: &mut self.paused_chats,
+            chat_stats: &mut self.chat_stats,
+            undo_history: &mut self.undo_history,
+            usage_tracker,
+            rewrite_deadline,
+            hooks,
+            workers: None,
+            dry_run: self.dry_run,
+        }
+    }
+}
+
+fn is_relevant_config_event_kind(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
+            | EventKind::Create(CreateKind::File | CreateKind::Any)
+            | EventKind::Remove(RemoveKind::File | RemoveKind::Any)
+            | EventKind::Any
+    )
+}
+
+fn path_targets_watched_config(candidate: &Path, watched_path: &Path) -> bool {
+    if candidate == watched_path {
+        return true;
+    }
+    candidate
+        .canonicalize()
+        .map(|canonical| canonical == watched_path)
+        .unwrap_or(false)
+}
+
+fn event_targets_watched_config(event: &Event, watched: &WatchedConfigPaths) -> bool {
+    event.paths.iter().any(|path| watched.matches(path))
+}
+
+/// The config path as given (possibly a symlink) and the real file it currently resolves to.
+/// Both parents are watched: edits land next to the target, link swaps next to the link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct WatchedConfigPaths {
+    link: PathBuf,
+    target: PathBuf,
+}
+
+impl WatchedConfigPaths {
+    fn resolve(config_path: &Path) -> Result<Self> {
+        let target = config_path.canonicalize().with_context(|| {
+            format!(
+                "failed to canonicalize config path: {}",
+                config_path.display()
+            )
+        })?;
+        let file_name = config_path
+            .file_name()
+            .context("config path has no file name")?;
+        let link_parent = match config_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize(),
+            _ => std::env::current_dir(),
+        }
+        .with_context(|| {
+            format!(
+                "failed to resolve config directory: {}",
+                config_path.display()
+            )
+        })?;
+        Ok(Self {
+            link: link_parent.join(file_name),
+            target,
+        })
+    }
+
+    fn matches(&self, candidate: &Path) -> bool {
+        path_targets_watched_config(candidate, &self.link)
+            || path_targets_watched_config(candidate, &self.target)
+    }
+
+    fn parent_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = [&self.link, &self.target]
+            .into_iter()
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        dirs.dedup();
+        dirs
+    }
+}
+
+/// Keeps the filesystem watcher alive; dropping it stops config reloads.
+struct ConfigWatcher {
+    _watcher: Arc<Mutex<RecommendedWatcher>>,
+}
+
+fn spawn_config_watcher(
+    config_path: &Path,
+    reload: ReloadConfig,
+    hot_tx: watch::Sender<HotConfig>,
+    reload_error_tx: mpsc::UnboundedSender<String>,
+) -> Result<ConfigWatcher> {
+    let paths = WatchedConfigPaths::resolve(config_path)?;
+    let watched = Arc::new(Mutex::new(paths.clone()));
+
+    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel::<()>();
+
+    let callback_watched = Arc::clone(&watched);
+    let mut watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| {
+        let event = match res {
+            Ok(ev) => ev,
+            Err(err) => {
+                warn!(error = %err, "filesystem watcher error");
+                return;
+            }
+        };
+
+        if !is_relevant_config_event_kind(&event.kind) {
+            return;
+        }
+
+        let targets_config = callback_watched
+            .lock()
+            .map(|watched| event_targets_watched_config(&event, &watched))
+            .unwrap_or(false);
+        if !targets_config {
+            return;
+        }
+
+        let _ = notify_tx.send(());
+    })
+    .context("failed to create filesystem watcher")?;
+
+    let mut watched_dirs = HashSet::new();
+    for dir in paths.parent_dirs() {
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("failed to watch directory: {}", dir.display()))?;
+        watched_dirs.insert(dir);
+    }
+
+    let watcher = Arc::new(Mutex::new(watcher));
+    let task_watcher = Arc::downgrade(&watcher);
+    let reload_path = paths.link.clone();
+    tokio::spawn(async move {
+        while notify_rx.recv().await.is_some() {
+            while notify_rx.try_recv().is_ok() {}
+
+            tokio::time::sleep(Duration::from_millis(reload.debounce_ms)).await;
+            while notify_rx.try_recv().is_ok() {}
+
+            if let Some(watcher) = task_watcher.upgrade() {
+                follow_config_symlink(&reload_path, &watched, &watcher, &mut watched_dirs);
+            }
+
+            match load_hot_config_with_retries(&reload_path, reload).await {
+                Ok(new_cfg) => {
+                    hot_tx.send_if_modified(|current| {
+                        if *current != new_cfg {
+                            *current = new_cfg;
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                }
+                Err(err) => {
+                    let _ = reload_error_tx.send(format!("{err:#}"));
+                }
+            }
+            // Events caused by our own retries' wait window are already covered.
+            while notify_rx.try_recv().is_ok() {}
+        }
+    });
+
+    Ok(ConfigWatcher { _watcher: watcher })
+}
+
+/// Reloads the config on every SIGHUP, for filesystems where the watcher misses events. Stops
+/// once the update loop drops its receiver.
+#[cfg(unix)]
+fn spawn_reload_signal_listener(
+    config_path: &Path,
+    hot_tx: watch::Sender<HotConfig>,
+    reload_error_tx: mpsc::UnboundedSender<String>,
+) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(error = %err, "failed to listen for SIGHUP; reload on signal disabled");
+            return;
+        }
+    };
+    let config_path = config_path.to_owned();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                () = hot_tx.closed() => break,
+                received = hangup.recv() => {
+                    if received.is_none() {
+                        break;
+                    }
+                    info!(config_path = %config_path.display(), "SIGHUP received; reloading config");
+                    reload_config_now(&config_path, &hot_tx, &reload_error_tx);
+                }
+            }
+        }
+    });
+}
+
+/// Loads the config once and hands it to the update loop even when unchanged, so a manual
+/// reload is always confirmed by a `ConfigReloaded` (or `ConfigReloadFailed`) event.
+fn reload_config_now(
+    config_path: &Path,
+    hot_tx: &watch::Sender<HotConfig>,
+    reload_error_tx: &mpsc::UnboundedSender<String>,
+) {
+    match load_hot_config(config_path) {
+        Ok(new_cfg) => hot_tx.send_modify(|current| *current = new_cfg),
+        Err(err) => {
+            let _ = reload_error_tx.send(format!("{err:#}"));
+        }
+    }
+}
+
+/// Editors that write via temp file, rename and chmod can leave a half-written config
+/// visible briefly, so a failed load is retried with doubling backoff before giving up.
+async fn load_hot_config_with_retries(path: &Path, reload: ReloadConfig) -> Result<HotConfig> {
+    let mut backoff = Duration::from_millis(reload.retry_backoff_ms);
+    let mut retry = 0;
+    loop {
+        match load_hot_config(path) {
+            Ok(config) => return Ok(config),
+            Err(err) if retry < reload.max_retries => {
+                retry += 1;
+                warn!(
+                    retry,
+                    max_retries = reload.max_retries,
+                    delay_ms = backoff.as_millis() as u64,
+                    error = %err,
+                    "config reload failed; retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Re-resolves the config symlink (e.g. after GNU stow re-points it) and starts watching
+/// the new target's directory.
+fn follow_config_symlink(
+    config_path: &Path,
+    watched: &Mutex<WatchedConfigPaths>,
+    watcher: &Mutex<RecommendedWatcher>,
+    watched_dirs: &mut HashSet<PathBuf>,
+) {
+    let Ok(resolved) = WatchedConfigPaths::resolve(config_path) else {
+        return;
+    };
+    let Ok(current) = watched.lock().map(|current| current.clone()) else {
+        return;
+    };
+    if current == resolved {
+        return;
+    }
+    info!(
+        old_target = %current.target.display(),
+        new_target = %resolved.target.display(),
+        "config symlink target changed"
+    );
+    // The watched paths lock is not held here: the notify callback takes it while
+    // `watch` may wait on the notify event loop.
+    for dir in resolved.parent_dirs() {
+        if watched_dirs.contains(&dir) {
+            continue;
+        }
+        let result = match watcher.lock() {
+            Ok(mut watcher) => watcher.watch(&dir, RecursiveMode::NonRecursive),
+            Err(_) => return,
+        };
+        match result {
+            Ok(()) => {
+                watched_dirs.insert(dir);
+            }
+            Err(err) => {
+                warn!(dir = %dir.display(), error = %err, "failed to watch new config directory");
+            }
+        }
+    }
+    if let Ok(mut current) = watched.lock() {
+        *current = resolved;
+    }
+}
+
+/// Messages sent within `grace_seconds` before startup are still treated as live.
+fn is_historical_catch_up_message(
+    message_unix: i64,
+    startup_unix: i64,
+    grace_seconds: u64,
+) -> bool {
+    let grace_seconds = i64::try_from(grace_seconds).unwrap_or(i64::MAX);
+    message_unix < startup_unix.saturating_sub(grace_seconds)
+}
+
+fn update_kind_name(update: &Update) -> String {
+    match update {
+        Update::NewMessage(_) => "new_message".to_owned(),
+        Update::MessageEdited(_) => "message_edited".to_owned(),
+        Update::MessageDeleted(_) => "message_deleted".to_owned(),
+        Update::CallbackQuery(_) => "callback_query".to_owned(),
+        Update::InlineQuery(_) => "inline_query".to_owned(),
+        Update::InlineSend(_) => "inline_send".to_owned(),
+        Update::Raw(raw) => {
+            let tl_update: &grammers_client::tl::enums::Update = raw;
+            let rendered = format!("{tl_update:?}");
+            let tl_name = rendered
+                .split_once('(')
+                .map(|(name, _)| name)
+                .unwrap_or(&rendered);
+            format!("raw/{tl_name}")
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+async fn process_message(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> Result<()> {
+    let Some(original) =
+        rewrite_candidate(bot, settings.rewrite, message, context_scope, runtime).await
+    else {
+        return Ok(());
+    };
+    rewrite_and_apply(bot, settings, message, context_scope, original, runtime).await;
+    Ok(())
+}
+
+/// Rewrites an existing message of ours after `rewrite.trigger_reaction` was put on it, then
+/// takes the reaction back off.
+async fn process_reaction_trigger(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    chat_id: i64,
+    message_id: i32,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    if !bot.is_monitored_chat(chat_id) {
+        debug!(
+            chat_id,
+            message_id, "ignoring trigger reaction in unmonitored chat"
+        );
+        return;
+    }
+    let key = runtime.message_key(chat_id, message_id);
+    let fetched = match runtime.workers.as_deref_mut() {
+        Some(workers) => {
+            let requests = bot.requests().clone();
+            let peer = bot.dialog_peer(chat_id);
+            workers.spawn_request(key, None, async move {
+                let fetched = async { requests.get_message(peer?, message_id).await }.await;
+                RequestDone::ReactionTarget {
+                    chat_id,
+                    message_id,
+                    fetched,
+                }
+            });
+            return;
+        }
+        None => bot.get_message(chat_id, message_id).await,
+    };
+    resume_reaction_trigger(bot, settings, chat_id, message_id, fetched, runtime).await;
+}
+
+/// Rewrites the message a trigger reaction was put on, once it was fetched.
+async fn resume_reaction_trigger(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    chat_id: i64,
+    message_id: i32,
+    fetched: Result<Option<TelegramMessage>>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let message = match fetched {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            info!(
+                chat_id,
+                message_id, "reacted message no longer exists; nothing to rewrite"
+            );
+            return;
+        }
+        Err(err) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "failed to fetch message for trigger reaction"
+            );
+            return;
+        }
+    };
+    if !bot.is_own_message(&message) {
+        debug!(
+            chat_id,
+            message_id, "ignoring trigger reaction on someone else's message"
+        );
+        return;
+    }
+
+    let context_scope = ContextScope {
+        chat_id,
+        topic_root_id: message_topic_root_id(&message),
+    };
+    let topic = context_scope
+        .topic_root_id
+        .and_then(|root_id| bot.topic_name(chat_id, root_id));
+    info!(
+        chat_id,
+        topic_root_id = ?context_scope.topic_root_id,
+        topic = ?topic,
+        update_kind = "reaction_trigger",
+        message_id,
+        "rewrite requested by reaction"
+    );
+    runtime.hooks.emit(RewriteEvent::MonitoredUpdate {
+        chat_id,
+        topic_root_id: context_scope.topic_root_id,
+        topic,
+        message_id,
+        outgoing: true,
+        kind: MonitoredUpdateKind::ReactionTrigger,
+    });
+    if let Err(err) = process_message(bot, settings, &message, context_scope, runtime).await {
+        error!(error = %err, "failed to process reaction-triggered message");
+    }
+    let key = runtime.message_key(chat_id, message_id);
+    let requests = bot.requests().clone();
+    let peer = bot.dialog_peer(chat_id);
+    let clear = async move { requests.clear_reaction(peer?, message_id).await };
+    match runtime.workers.as_deref_mut() {
+        Some(workers) => workers.spawn_request(key, None, async move {
+            RequestDone::ReactionCleared {
+                chat_id,
+                message_id,
+                result: clear.await,
+            }
+        }),
+        None => finish_clear_reaction(chat_id, message_id, clear.await),
+    }
+}
+
+fn finish_clear_reaction(chat_id: i64, message_id: i32, result: Result<()>) {
+    if let Err(err) = result {
+        warn!(
+            chat_id,
+            message_id,
+            error = %err,
+            "failed to remove trigger reaction"
+        );
+    }
+}
+
+/// Rewrites one of our messages right after it was scheduled, so the rewrite is what gets
+/// sent. There is no sent message yet, so the rewrite goes without context. Rewritten ones
+/// are remembered in `runtime.scheduled_rewrites` so they aren't rewritten again when sent.
+async fn process_scheduled_message(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    scheduled: ScheduledMessage,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let rewrite = settings.rewrite;
+    let chat_id = scheduled.chat_id;
+    let message_id = scheduled.message_id;
+    if !bot.is_monitored_chat(chat_id) {
+        debug!(
+            chat_id,
+            message_id, "ignoring scheduled message in unmonitored chat"
+        );
+        return;
+    }
+    runtime.record_stat(chat_id, message_id, ChatStat::Seen);
+    if runtime.paused_chats.contains(&chat_id) {
+        info!(
+            chat_id,
+            message_id, "skipping scheduled message; rewriting paused by chat command"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+        return;
+    }
+    let original = if rewrite.preserve_formatting {
+        &scheduled.markdown
+    } else {
+        &scheduled.text
+    };
+    let original = original.trim().to_owned();
+    if original.is_empty() {
+        info!(
+            chat_id,
+            message_id, "skipping non-text or empty scheduled message"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::Empty);
+        return;
+    }
+    if !runtime.rate_limiter.try_acquire(chat_id, Instant::now()) {
+        info!(
+            chat_id,
+            message_id,
+            max_per_minute = rewrite.max_per_minute,
+            "skipping scheduled rewrite; per-chat rate limit reached"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::RateLimited);
+        runtime.hooks.emit(RewriteEvent::RateLimited {
+            chat_id,
+            message_id,
+        });
+        return;
+    }
+
+    info!(
+        chat_id,
+        update_kind = "scheduled_message",
+        message_id,
+        schedule_unix = scheduled.schedule_unix,
+        "received scheduled message in monitored chat"
+    );
+    runtime.hooks.emit(RewriteEvent::MonitoredUpdate {
+        chat_id,
+        topic_root_id: None,
+        topic: None,
+        message_id,
+        outgoing: true,
+        kind: MonitoredUpdateKind::ScheduledMessage,
+    });
+    let chat_metadata = rewrite
+        .include_chat_metadata
+        .then(|| bot.chat_metadata(chat_id, None))
+        .flatten();
+    let key = runtime.message_key(chat_id, message_id);
+    let deadline = runtime.rewrite_deadline;
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        let outcome = request_rewrite(
+            settings,
+            chat_metadata.as_deref(),
+            &[],
+            &original,
+            chat_id,
+            message_id,
+            runtime,
+        )
+        .await;
+        finish_scheduled_rewrite(bot, rewrite, scheduled, &original, outcome, runtime).await;
+        return;
+    };
+    let llm = workers.llm();
+    let system_prompt = system_prompt(rewrite).into_owned();
+    workers.spawn_provider_request(key, async move {
+        let requested_at = Instant::now();
+        let result = llm
+            .rewrite_with_deadline(
+                &system_prompt,
+                chat_metadata.as_deref(),
+                &[],
+                &original,
+                deadline,
+            )
+            .await;
+        RequestDone::Scheduled {
+            scheduled,
+            original,
+            result,
+            elapsed: requested_at.elapsed(),
+        }
+    });
+    runtime.hooks.emit(RewriteEvent::RewriteStarted {
+        chat_id,
+        message_id,
+    });
+}
+
+/// Edits the rewrite of a scheduled message in, once the provider answered.
+async fn finish_scheduled_rewrite(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    scheduled: ScheduledMessage,
+    original: &str,
+    outcome: RewriteOutcome,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = scheduled.chat_id;
+    let message_id = scheduled.message_id;
+    // The Markdown form shows the URLs behind text links too.
+    let outcome = check_dropped_links(
+        rewrite,
+        original,
+        &extract_links(&scheduled.markdown),
+        outcome,
+        chat_id,
+        message_id,
+    );
+    let outcome = review_edit(
+        rewrite,
+        runtime.hooks,
+        original,
+        outcome,
+        chat_id,
+        message_id,
+    );
+    let (text, model) = match outcome {
+        RewriteOutcome::Edit { text, model } => (text, model),
+        outcome => {
+            info!(
+                chat_id,
+                message_id,
+                outcome = ?outcome,
+                "leaving scheduled message as it is"
+            );
+            if let Some(stat) = ChatStat::for_outcome(&outcome) {
+                runtime.record_stat(chat_id, message_id, stat);
+            }
+            return;
+        }
+    };
+    if runtime.dry_run {
+        report_dry_run(chat_id, message_id, original, text, &model, runtime.hooks);
+        return;
+    }
+    let key = runtime.message_key(chat_id, message_id);
+    let peer = bot.dialog_peer(chat_id);
+    let parse_mode = rewrite.output_parse_mode();
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        let result = async {
+            bot.requests()
+                .edit_scheduled_message(peer?, &scheduled, &text, parse_mode)
+                .await
+        }
+        .await;
+        finish_scheduled_edit(&scheduled, model, result, runtime);
+        return;
+    };
+    let requests = bot.requests().clone();
+    workers.spawn_request(key, None, async move {
+        let result = async {
+            requests
+                .edit_scheduled_message(peer?, &scheduled, &text, parse_mode)
+                .await
+        }
+        .await;
+        RequestDone::ScheduledEdit {
+            scheduled,
+            model,
+            result,
+        }
+    });
+}
+
+/// Records the outcome of the edit of a scheduled message into its rewrite.
+fn finish_scheduled_edit(
+    scheduled: &ScheduledMessage,
+    model: String,
+    result: Result<String>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = scheduled.chat_id;
+    let message_id = scheduled.message_id;
+    match result {
+        Ok(applied) => {
+            info!(
+                chat_id,
+                message_id,
+                model = %model,
+                "rewrote scheduled message"
+            );
+            runtime
+                .scheduled_rewrites
+                .insert(chat_id, message_id, applied);
+            runtime.record_stat(chat_id, message_id, ChatStat::Rewritten);
+            runtime.hooks.emit(RewriteEvent::MessageEdited {
+                chat_id,
+                message_id,
+                model,
+            });
+        }
+        Err(err) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "failed to edit scheduled message; it will be sent as written"
+            );
+            runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+        }
+    }
+}
+
+/// Runs the checks that decide whether a message gets rewritten. Returns its trimmed text,
+/// or `None` once the message has been handled some other way.
+async fn rewrite_candidate(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> Option<String> {
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    runtime.record_stat(chat_id, message_id, ChatStat::Seen);
+    if !bot.is_own_message(message) {
+        observe_others_message(bot, message, context_scope, runtime).await;
+        runtime.record_stat(chat_id, message_id, ChatStat::NotOutgoing);
+        return None;
+    }
+
+    if runtime
+        .dedupe_cache
+        .contains(chat_id, message_id, message.text())
+    {
+        info!(chat_id, message_id, "skipping deduped message");
+        runtime.record_stat(chat_id, message_id, ChatStat::Deduped);
+        return None;
+    }
+
+    if let Some(workers) = runtime.workers.as_deref() {
+        let key = runtime.message_key(chat_id, message_id);
+        let skip = if workers.is_applying(&key.account, chat_id, message.text()) {
+            Some("our own rewrite being applied")
+        } else if workers.is_rewriting(&key, message.text()) {
+            Some("already being rewritten")
+        } else {
+            None
+        };
+        if let Some(reason) = skip {
+            info!(
+                chat_id,
+                message_id, reason, "skipping message already in the rewrite pipeline"
+            );
+            runtime.record_stat(chat_id, message_id, ChatStat::Deduped);
+            return None;
+        }
+    }
+
+    if runtime.deleted_messages.contains(chat_id, message_id) {
+        info!(
+            chat_id,
+            message_id, "skipping message deleted before its rewrite"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+        runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+            chat_id,
+            message_id,
+        });
+        return None;
+    }
+
+    if let Some(command) = parse_chat_command(message.text(), &rewrite.command_prefix) {
+        handle_chat_command(bot, message, chat_id, command, rewrite, runtime).await;
+        runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+        return None;
+    }
+
+    if runtime.paused_chats.contains(&chat_id) {
+        info!(
+            chat_id,
+            message_id, "skipping message; rewriting paused by chat command"
+        );
+        runtime
+            .context_cache
+            .observe_update_message(context_scope, message);
+        runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+        return None;
+    }
+
+    if let Some(reason) = filter_skip_reason(rewrite, message) {
+        info!(
+            chat_id,
+            message_id, reason, "skipping message excluded by rewrite filters"
+        );
+        runtime
+            .context_cache
+            .observe_update_message(context_scope, message);
+        runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+        return None;
+    }
+
+    if let Some(allowed) = rewrite.reply_allow_list(chat_id) {
+        let replied_to = lookup_reply_sender(bot, message, context_scope, runtime).await?;
+        if !reply_allowed(allowed, replied_to, message, context_scope, runtime) {
+            return None;
+        }
+    }
+
+    admit_candidate(rewrite, message, context_scope, runtime)
+}
+
+/// Keeps someone else's message in the context cache.
+async fn observe_others_message(
+    bot: &TelegramBot,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let requests = bot.requests();
+    let message_id = message.id();
+    let key = runtime.message_key(context_scope.chat_id, message_id);
+    let sender_name = match (
+        requests.known_sender_name(message),
+        runtime.workers.as_deref_mut(),
+    ) {
+        (Some(sender_name), _) => sender_name,
+        (None, Some(workers)) => {
+            let requests = requests.clone();
+            let message = message.clone();
+            workers.spawn_request(key, None, async move {
+                RequestDone::SenderName {
+                    context_scope,
+                    message_id,
+                    sender_name: requests.sender_name(&message).await,
+                }
+            });
+            UNKNOWN_SENDER.to_owned()
+        }
+        (None, None) => requests.sender_name(message).await,
+    };
+    runtime
+        .context_cache
+        .observe_named_update_message(context_scope, message, sender_name);
+}
+
+/// Who `message` replied to, for `only_when_replying_to`; `None` while it is looked up.
+async fn lookup_reply_sender(
+    bot: &TelegramBot,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> Option<Option<i64>> {
+    let Some(reply_to_id) = message_reply_to_message_id(message) else {
+        return Some(None);
+    };
+    let chat_id = context_scope.chat_id;
+    let requests = bot.requests();
+    if let Some(cached) = requests.cached_reply_sender_id(chat_id, reply_to_id) {
+        return Some(cached);
+    }
+    let key = runtime.message_key(chat_id, message.id());
+    let rewrite_deadline = runtime.rewrite_deadline;
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        return Some(reply_sender(requests, message, chat_id, reply_to_id).await);
+    };
+    let requests = requests.clone();
+    let message = message.clone();
+    workers.spawn_request(key, None, async move {
+        let replied_to = reply_sender(&requests, &message, chat_id, reply_to_id).await;
+        RequestDone::ReplySender {
+            message,
+            context_scope,
+            replied_to,
+            rewrite_deadline,
+        }
+    });
+    None
+}
+
+/// The sender of the replied-to message; a failed lookup counts as no one.
+async fn reply_sender(
+    requests: &TelegramRequests,
+    message: &TelegramMessage,
+    chat_id: i64,
+    reply_to_id: i32,
+) -> Option<i64> {
+    match requests.reply_sender_id(message, reply_to_id).await {
+        Ok(sender) => sender,
+        Err(err) => {
+            warn!(
+                chat_id,
+                message_id = message.id(),
+                reply_to_id,
+                error = %err,
+                "failed to look up who was replied to; skipping message"
+            );
+            None
+        }
+    }
+}
+
+/// Whether `message` replies to a user in `only_when_replying_to`; otherwise counts
+/// it as filtered.
+fn reply_allowed(
+    allowed: &[i64],
+    replied_to: Option<i64>,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> bool {
+    if replied_to.is_some_and(|sender| allowed.contains(&sender)) {
+        return true;
+    }
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    info!(
+        chat_id,
+        message_id,
+        replied_to = ?replied_to,
+        "skipping message; not a reply to a user in only_when_replying_to"
+    );
+    runtime
+        .context_cache
+        .observe_update_message(context_scope, message);
+    runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+    false
+}
+
+/// The last checks before a rewrite: the text, its language and the chat's rate limit.
+fn admit_candidate(
+    rewrite: &RewriteConfig,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> Option<String> {
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    let original = message_original(rewrite, message);
+    if original.is_empty() {
+        info!(chat_id, message_id, "skipping non-text or empty message");
+        runtime.record_stat(chat_id, message_id, ChatStat::Empty);
+        return None;
+    }
+
+    if !rewrite.languages.is_empty() {
+        let detected = detect_language(&original);
+        if !language_matches(detected.as_ref(), &rewrite.languages) {
+            info!(
+                chat_id,
+                message_id,
+                language = detected.map(|d| d.lang.code()),
+                confidence = detected.map(|d| d.confidence),
+                allowed_languages = ?rewrite.languages,
+                "skipping message in a language outside rewrite.languages"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.record_stat(chat_id, message_id, ChatStat::Filtered);
+            return None;
+        }
+    }
+
+    if !runtime.rate_limiter.try_acquire(chat_id, Instant::now()) {
+        info!(
+            chat_id,
+            message_id,
+            max_per_minute = rewrite.max_per_minute,
+            "skipping rewrite; per-chat rate limit reached"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::RateLimited);
+        runtime.hooks.emit(RewriteEvent::RateLimited {
+            chat_id,
+            message_id,
+        });
+        runtime
+            .context_cache
+            .observe_update_message(context_scope, message);
+        return None;
+    }
+
+    Some(original)
+}
+
+/// Picks a message back up once [`lookup_reply_sender`] found who it replied to.
+async fn resume_after_reply_lookup(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    replied_to: Option<i64>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let rewrite = settings.rewrite;
+    if let Some(allowed) = rewrite.reply_allow_list(context_scope.chat_id)
+        && !reply_allowed(allowed, replied_to, message, context_scope, runtime)
+    {
+        return;
+    }
+    let Some(original) = admit_candidate(rewrite, message, context_scope, runtime) else {
+        return;
+    };
+    rewrite_and_apply(bot, settings, message, context_scope, original, runtime).await;
+}
+
+/// Asks the model to rewrite one message and edits it with the result.
+async fn rewrite_and_apply(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    if runtime.workers.is_some() {
+        queue_rewrite(
+            bot,
+            settings.rewrite,
+            message,
+            context_scope,
+            original,
+            runtime,
+        );
+        return;
+    }
+    let outcome =
+        request_with_context(bot, settings, message, context_scope, &original, runtime).await;
+    apply_outcome(
+        bot,
+        settings.rewrite,
+        message,
+        context_scope,
+        &original,
+        None,
+        outcome,
+        runtime,
+    )
+    .await;
+}
+
+/// Loads the context for `message` and asks the provider to rewrite `original`, showing
+/// the typing action meanwhile when enabled.
+async fn request_with_context(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    original: &str,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> RewriteOutcome {
+    let inputs = request_inputs(
+        bot,
+        settings.rewrite,
+        message,
+        context_scope,
+        original.to_owned(),
+        runtime,
+    );
+    let PreparedRequest {
+        request,
+        context,
+        typing: _typing,
+    } = prepare_request(bot.requests().clone(), inputs).await;
+    record_context(context_scope, message.id(), context, runtime);
+    request_rewrite(
+        settings,
+        request.chat_metadata.as_deref(),
+        &request.context,
+        original,
+        context_scope.chat_id,
+        message.id(),
+        runtime,
+    )
+    .await
+}
+
+/// Hands the rewrite of `message` to the worker of its scope, superseding an older one.
+fn queue_rewrite(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    let key = runtime.message_key(chat_id, message_id);
+    let inputs = request_inputs(
+        bot,
+        rewrite,
+        message,
+        context_scope,
+        original.clone(),
+        runtime,
+    );
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        return;
+    };
+    if workers.is_closed() {
+        info!(
+            chat_id,
+            message_id, "shutting down; leaving message unchanged"
+        );
+        runtime
+            .context_cache
+            .observe_update_message(context_scope, message);
+        return;
+    }
+    let superseded = workers.contains(&key);
+    let queued = workers.submit(
+        key,
+        context_scope,
+        message.text(),
+        InFlightRewrite {
+            message: message.clone(),
+            context_scope,
+            original,
+        },
+        prepare_request(bot.requests().clone(), inputs),
+    );
+    if !queued {
+        warn!(
+            chat_id,
+            topic_root_id = ?context_scope.topic_root_id,
+            message_id,
+            queue_limit = REWRITE_QUEUE_LIMIT,
+            "too many rewrites queued for this chat; leaving message unchanged"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::RateLimited);
+        return;
+    }
+    if superseded {
+        info!(
+            chat_id,
+            message_id, "message changed while it was being rewritten; rewriting the new text"
+        );
+    }
+    runtime.hooks.emit(RewriteEvent::RewriteStarted {
+        chat_id,
+        message_id,
+    });
+}
+
+/// Handles what a worker handed back: a provider answer or a Telegram request's outcome.
+async fn apply_worker_done(
+    accounts: &mut [AccountRuntime],
+    settings: RewriteSettings<'_>,
+    workers: &mut RewriteWorkers,
+    done: WorkerDone,
+    rate_limiter: &mut RateLimiter,
+    usage_tracker: &mut UsageTracker,
+    hooks: &RewriteHooks,
+) {
+    match done {
+        WorkerDone::Rewrite(done) => {
+            apply_finished_rewrite(
+                accounts,
+                settings,
+                workers,
+                done,
+                rate_limiter,
+                usage_tracker,
+                hooks,
+            )
+            .await;
+        }
+        WorkerDone::Request { account, done } => {
+            workers.request_done();
+            let Some(account) = accounts.iter_mut().find(|runtime| runtime.name == account) else {
+                return;
+            };
+            let rewrite_deadline = match &done {
+                RequestDone::ReplySender {
+                    rewrite_deadline, ..
+                } => *rewrite_deadline,
+                _ => None,
+            };
+            let mut runtime = account
+                .state
+                .runtime(
+                    rate_limiter,
+                    usage_tracker,
+                    hooks.for_account(&account.name),
+                    rewrite_deadline,
+                )
+                .with_workers(workers);
+            apply_finished_request(&account.bot, settings, done, &mut runtime).await;
+        }
+    }
+}
+
+/// Applies the answer to a rewrite from [`queue_rewrite`] unless a newer one superseded it.
+#[allow(clippy::too_many_arguments)]
+async fn apply_finished_rewrite(
+    accounts: &mut [AccountRuntime],
+    settings: RewriteSettings<'_>,
+    workers: &mut RewriteWorkers,
+    done: RewriteDone,
+    rate_limiter: &mut RateLimiter,
+    usage_tracker: &mut UsageTracker,
+    hooks: &RewriteHooks,
+) {
+    let RewriteDone {
+        key,
+        id,
+        context_scope,
+        context,
+        typing: _typing,
+        result,
+        elapsed,
+    } = done;
+    let in_flight = workers.complete(&key, id);
+    let Some(account) = accounts
+        .iter_mut()
+        .find(|account| account.name == key.account)
+    else {
+        workers.finish(&key, id);
+        return;
+    };
+    let mut runtime = account
+        .state
+        .runtime(
+            rate_limiter,
+            usage_tracker,
+            hooks.for_account(&account.name),
+            None,
+        )
+        .with_workers(workers);
+    record_context(context_scope, key.message_id, context, &mut runtime);
+    let Some(in_flight) = in_flight else {
+        debug!(
+            chat_id = key.chat_id,
+            message_id = key.message_id,
+            "dropping the answer to a rewrite superseded by a newer one"
+        );
+        runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+            chat_id: key.chat_id,
+            message_id: key.message_id,
+        });
+        return;
+    };
+    let outcome = finish_request(
+        settings,
+        result,
+        elapsed,
+        &in_flight.original,
+        key.chat_id,
+        key.message_id,
+        &mut runtime,
+    );
+    apply_outcome(
+        &account.bot,
+        settings.rewrite,
+        &in_flight.message,
+        in_flight.context_scope,
+        &in_flight.original,
+        Some(id),
+        outcome,
+        &mut runtime,
+    )
+    .await;
+}
+
+/// Does the bookkeeping for a Telegram request that ran off the update loop.
+async fn apply_finished_request(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    done: RequestDone,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    match done {
+        RequestDone::SenderName {
+            context_scope,
+            message_id,
+            sender_name,
+        } => {
+            runtime
+                .context_cache
+                .set_sender_name(context_scope, message_id, sender_name);
+        }
+        RequestDone::ReplySender {
+            message,
+            context_scope,
+            replied_to,
+            rewrite_deadline: _,
+        } => {
+            resume_after_reply_lookup(bot, settings, &message, context_scope, replied_to, runtime)
+                .await;
+        }
+        RequestDone::Edit {
+            pending,
+            result,
+            elapsed,
+        } => finish_edit(settings.rewrite, pending, result, elapsed, runtime),
+        RequestDone::Resend {
+            pending,
+            sent,
+            deleted,
+        } => finish_resend(bot, settings.rewrite, pending, sent, deleted, runtime),
+        RequestDone::ReactionTarget {
+            chat_id,
+            message_id,
+            fetched,
+        } => {
+            resume_reaction_trigger(bot, settings, chat_id, message_id, fetched, runtime).await;
+        }
+        RequestDone::ReactionCleared {
+            chat_id,
+            message_id,
+            result,
+        } => finish_clear_reaction(chat_id, message_id, result),
+        RequestDone::Scheduled {
+            scheduled,
+            original,
+            result,
+            elapsed,
+        } => {
+            let outcome = finish_request(
+                settings,
+                result,
+                elapsed,
+                &original,
+                scheduled.chat_id,
+                scheduled.message_id,
+                runtime,
+            );
+            finish_scheduled_rewrite(
+                bot,
+                settings.rewrite,
+                scheduled,
+                &original,
+                outcome,
+                runtime,
+            )
+            .await;
+        }
+        RequestDone::ScheduledEdit {
+            scheduled,
+            model,
+            result,
+        } => finish_scheduled_edit(&scheduled, model, result, runtime),
+        RequestDone::CatchUpBatch(done) => {
+            finish_catch_up_batch(bot, settings, done, runtime).await;
+        }
+        RequestDone::Undo {
+            chat_id,
+            command,
+            entry,
+            target,
+            restored,
+        } => {
+            runtime.applied(chat_id, entry.message_id);
+            let undone = finish_undo(chat_id, &entry, target, restored, runtime);
+            let reply = command_ack(
+                ChatCommand::Undo,
+                Some(undone),
+                chat_id,
+                command.id(),
+                settings.rewrite,
+                runtime,
+            );
+            acknowledge_command(bot, &command, chat_id, reply, settings.rewrite, runtime).await;
+        }
+        RequestDone::Acknowledged {
+            chat_id,
+            message_id,
+            result,
+        } => finish_acknowledge(chat_id, message_id, result),
+    }
+}
+
+/// Runs rewrites off the update loop, one worker per chat scope.
+struct RewriteWorkers<T = InFlightRewrite> {
+    llm: Arc<dyn LlmRewriter>,
+    queues: HashMap<(String, ContextScope), mpsc::Sender<RewriteJob>>,
+    /// Messages from the moment their rewrite is queued until it is applied or dropped.
+    in_flight: HashMap<MessageKey, InFlight<T>>,
+    /// Texts our edits and resends are putting into messages, while they are under way.
+    applying: HashMap<MessageKey, String>,
+    next_id: u64,
+    results: mpsc::UnboundedSender<WorkerDone>,
+    permits: Arc<Semaphore>,
+    tasks: JoinSet<()>,
+    /// Requests from [`Self::spawn_request`] whose outcome hasn't been handled yet.
+    requests: usize,
+    closed: bool,
+}
+
+/// A message in the rewrite pipeline; ids are only unique within a chat and account.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MessageKey {
+    account: String,
+    chat_id: i64,
+    message_id: i32,
+}
+
+/// A message tracked by [`RewriteWorkers`].
+struct InFlight<T> {
+    /// The rewrite that applies; a rewrite queued for a newer text of the message replaces it.
+    id: u64,
+    /// The message text the rewrite started from.
+    text: String,
+    /// What the update loop keeps to apply the answer, until the answer comes back.
+    rewrite: Option<T>,
+    /// Set once the message was edited after the rewrite started, which makes it stale.
+    edited: bool,
+    /// Cancels the rewrite's context load and provider request.
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// A rewrite, run by the worker of its scope.
+struct RewriteJob {
+    key: MessageKey,
+    id: u64,
+    context_scope: ContextScope,
+    llm: Arc<dyn LlmRewriter>,
+    /// Loads the context and builds the provider request.
+    prepare: Pin<Box<dyn Future<Output = PreparedRequest> + Send>>,
+    cancelled: oneshot::Receiver<()>,
+}
+
+struct RewriteRequest {
+    system_prompt: String,
+    chat_metadata: Option<String>,
+    context: Vec<ContextMessage>,
+    original: String,
+    deadline: Option<Duration>,
+}
+
+/// What a worker hands back to the update loop.
+enum WorkerDone {
+    Rewrite(RewriteDone),
+    /// The outcome of a request from [`RewriteWorkers::spawn_request`] made with `account`.
+    Request {
+        account: String,
+        done: RequestDone,
+    },
+}
+
+/// The provider's answer to a [`RewriteJob`].
+struct RewriteDone {
+    key: MessageKey,
+    id: u64,
+    context_scope: ContextScope,
+    context: LoadedContext,
+    /// Shows "typing" until the outcome is applied.
+    typing: Option<TypingIndicator>,
+    result: Result<Rewrite>,
+    elapsed: Duration,
+}
+
+/// A Telegram request that ran off the update loop, with what the loop needs to finish up.
+enum RequestDone {
+    /// The name of someone else's message's sender, for the context cache.
+    SenderName {
+        context_scope: ContextScope,
+        message_id: i32,
+        sender_name: String,
+    },
+    /// Who a message replied to, for `only_when_replying_to`.
+    ReplySender {
+        message: TelegramMessage,
+        context_scope: ContextScope,
+        replied_to: Option<i64>,
+        rewrite_deadline: Option<Duration>,
+    },
+    Edit {
+        pending: PendingEdit,
+        result: Result<String>,
+        elapsed: Duration,
+    },
+    /// The send of a resent rewrite and, when it went out, the delete of the original.
+    Resend {
+        pending: PendingResend,
+        sent: Result<SentMessage>,
+        deleted: Option<Result<()>>,
+    },
+    /// The message a trigger reaction was put on.
+    ReactionTarget {
+        chat_id: i64,
+        message_id: i32,
+        fetched: Result<Option<TelegramMessage>>,
+    },
+    ReactionCleared {
+        chat_id: i64,
+        message_id: i32,
+        result: Result<()>,
+    },
+    /// The provider's answer for a scheduled message.
+    Scheduled {
+        scheduled: ScheduledMessage,
+        original: String,
+        result: Result<Rewrite>,
+        elapsed: Duration,
+    },
+    ScheduledEdit {
+        scheduled: ScheduledMessage,
+        model: String,
+        result: Result<String>,
+    },
+    CatchUpBatch(CatchUpBatchDone),
+    /// The fetch of the rewrite `.rw undo` restores and, when it exists, the restoring edit.
+    Undo {
+        chat_id: i64,
+        command: TelegramMessage,
+        entry: UndoEntry,
+        target: Result<Option<TelegramMessage>>,
+        restored: Option<Result<String>>,
+    },
+    /// The edit of a chat command into its acknowledgment.
+    Acknowledged {
+        chat_id: i64,
+        message_id: i32,
+        result: Result<String>,
+    },
+}
+
+/// The provider's answer for a catch-up batch, see [`request_catch_up_batch`].
+struct CatchUpBatchDone {
+    context_scope: ContextScope,
+    /// The batched messages with their originals and rewrite ids.
+    candidates: Vec<(TelegramMessage, String, Option<u64>)>,
+    context: LoadedContext,
+    result: Result<BatchRewrite>,
+    elapsed: Duration,
+}
+
+/// What the update loop keeps of a queued rewrite to apply its outcome.
+struct InFlightRewrite {
+    message: TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+}
+
+impl MessageKey {
+    fn new(account: &str, chat_id: i64, message_id: i32) -> Self {
+        Self {
+            account: account.to_owned(),
+            chat_id,
+            message_id,
+        }
+    }
+}
+
+impl<T> RewriteWorkers<T> {
+    fn new(
+        llm: Arc<dyn LlmRewriter>,
+        max_concurrent: usize,
+    ) -> (Self, mpsc::UnboundedReceiver<WorkerDone>) {
+        let (results, finished) = mpsc::unbounded_channel();
+        let workers = Self {
+            llm,
+            queues: HashMap::new(),
+            in_flight: HashMap::new(),
+            applying: HashMap::new(),
+            next_id: 0,
+            results,
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            tasks: JoinSet::new(),
+            requests: 0,
+            closed: false,
+        };
+        (workers, finished)
+    }
+
+    /// The rewrites queued from now on use `llm`, after a reload replaced it.
+    fn set_llm(&mut self, llm: Arc<dyn LlmRewriter>) {
+        self.llm = llm;
+    }
+
+    fn llm(&self) -> Arc<dyn LlmRewriter> {
+        Arc::clone(&self.llm)
+    }
+
+    /// Rewrites waiting for their answer plus requests waiting for their outcome.
+    fn len(&self) -> usize {
+        self.in_flight
+            .values()
+            .filter(|in_flight| in_flight.rewrite.is_some())
+            .count()
+            + self.requests
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues a rewrite behind the earlier ones of its scope; false when the queue is full.
+    fn submit(
+        &mut self,
+        key: MessageKey,
+        context_scope: ContextScope,
+        text: &str,
+        rewrite: T,
+        prepare: impl Future<Output = PreparedRequest> + Send + 'static,
+    ) -> bool {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (cancel, cancelled) = oneshot::channel();
+        let mut job = RewriteJob {
+            key: key.clone(),
+            id,
+            context_scope,
+            llm: Arc::clone(&self.llm),
+            prepare: Box::pin(prepare),
+            cancelled,
+        };
+        let queue_key = (key.account.clone(), context_scope);
+        loop {
+            let queue = self.queues.entry(queue_key.clone()).or_insert_with(|| {
+                let (queue, jobs) = mpsc::channel(REWRITE_QUEUE_LIMIT);
+                self.tasks.spawn(rewrite_worker(
+                    jobs,
+                    self.results.clone(),
+                    Arc::clone(&self.permits),
+                ));
+                queue
+            });
+            match queue.try_send(job) {
+                Ok(()) => break,
+                Err(TrySendError::Full(_)) => return false,
+                // The worker is gone; the next pass starts a new one.
+                Err(TrySendError::Closed(returned)) => {
+                    self.queues.remove(&queue_key);
+                    job = returned;
+                }
+            }
+        }
+        self.in_flight.insert(
+            key,
+            InFlight {
+                id,
+                text: text.to_owned(),
+                rewrite: Some(rewrite),
+                edited: false,
+                cancel: Some(cancel),
+            },
+        );
+        true
+    }
+
+    /// Drops the rewrites still waiting for an answer of messages `deleted` covers, cancelling
+    /// their provider requests. Returns their keys.
+    fn cancel_deleted(&mut self, account: &str, deleted: DeletedMessage) -> Vec<MessageKey> {
+        let keys: Vec<MessageKey> = self
+            .in_flight
+            .iter()
+            .filter(|(key, in_flight)| {
+                key.account == account
+                    && deleted.matches(key.chat_id, key.message_id)
+                    && in_flight.rewrite.is_some()
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            if let Some(cancel) = self
+                .in_flight
+                .remove(key)
+                .and_then(|in_flight| in_flight.cancel)
+            {
+                let _ = cancel.send(());
+            }
+        }
+        keys
+    }
+
+    fn contains(&self, key: &MessageKey) -> bool {
+        self.in_flight.contains_key(key)
+    }
+
+    /// Tracks a rewrite that runs outside the scope queues and returns its id.
+    fn track(&mut self, key: MessageKey, text: &str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.in_flight.insert(
+            key,
+            InFlight {
+                id,
+                text: text.to_owned(),
+                rewrite: None,
+                edited: false,
+                cancel: None,
+            },
+        );
+        id
+    }
+
+    /// Whether a rewrite of the message from this very text is already under way.
+    fn is_rewriting(&self, key: &MessageKey, text: &str) -> bool {
+        self.in_flight
+            .get(key)
+            .is_some_and(|in_flight| !in_flight.edited && in_flight.text == text)
+    }
+
+    /// Notes the text an update shows, making a rewrite of another text stale.
+    fn observe_text(&mut self, key: &MessageKey, text: &str) {
+        if let Some(in_flight) = self.in_flight.get_mut(key)
+            && in_flight.text != text
+        {
+            in_flight.edited = true;
+        }
+    }
+
+    /// Whether rewrite `id` of the message may still be applied; untracked messages may.
+    fn is_current(&self, key: &MessageKey, id: u64) -> bool {
+        self.in_flight
+            .get(key)
+            .is_none_or(|in_flight| in_flight.id == id && !in_flight.edited)
+    }
+
+    /// Takes what the update loop kept for rewrite `id`; `None` once it was superseded.
+    fn complete(&mut self, key: &MessageKey, id: u64) -> Option<T> {
+        self.in_flight
+            .get_mut(key)
+            .filter(|in_flight| in_flight.id == id)?
+            .rewrite
+            .take()
+    }
+
+    /// Stops tracking the message once rewrite `id` was applied or dropped.
+    fn finish(&mut self, key: &MessageKey, id: u64) {
+        if self
+            .in_flight
+            .get(key)
+            .is_some_and(|in_flight| in_flight.id == id)
+        {
+            self.in_flight.remove(key);
+        }
+    }
+
+    /// Whether `text` is what one of our edits or resends in the chat is putting out.
+    fn is_applying(&self, account: &str, chat_id: i64, text: &str) -> bool {
+        self.applying.iter().any(|(key, applying)| {
+            key.account == account && key.chat_id == chat_id && applying.trim() == text.trim()
+        })
+    }
+
+    /// Runs `request` as a task; until it is done, updates showing `applying` are our own.
+    fn spawn_request(
+        &mut self,
+        key: MessageKey,
+        applying: Option<String>,
+        request: impl Future<Output = RequestDone> + Send + 'static,
+    ) {
+        let account = key.account.clone();
+        if let Some(applying) = applying {
+            self.applying.insert(key, applying);
+        }
+        self.requests += 1;
+        let results = self.results.clone();
+        self.tasks.spawn(async move {
+            let done = request.await;
+            let _ = results.send(WorkerDone::Request { account, done });
+        });
+    }
+
+    /// Like [`Self::spawn_request`], within the provider concurrency cap.
+    fn spawn_provider_request(
+        &mut self,
+        key: MessageKey,
+        request: impl Future<Output = RequestDone> + Send + 'static,
+    ) {
+        let permits = Arc::clone(&self.permits);
+        self.spawn_request(key, None, async move {
+            let _permit = permits.acquire().await;
+            request.await
+        });
+    }
+
+    /// Counts the outcome of a request from [`Self::spawn_request`] as handled.
+    fn request_done(&mut self) {
+        self.requests = self.requests.saturating_sub(1);
+    }
+
+    /// Forgets the text an edit or resend of the message was putting out, once it is done.
+    fn applied(&mut self, key: &MessageKey) {
+        self.applying.remove(key);
+    }
+
+    /// Takes no more rewrites; workers finish the ones already queued and stop.
+    fn close(&mut self) {
+        self.closed = true;
+        self.queues.clear();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Stops the workers and requests, dropping the rewrites still in flight.
+    fn abort(&mut self) {
+        self.tasks.abort_all();
+        self.in_flight.clear();
+        self.applying.clear();
+        self.requests = 0;
+    }
+}
+
+async fn rewrite_worker(
+    mut jobs: mpsc::Receiver<RewriteJob>,
+    results: mpsc::UnboundedSender<WorkerDone>,
+    permits: Arc<Semaphore>,
+) {
+    while let Some(job) = jobs.recv().await {
+        let RewriteJob {
+            key,
+            id,
+            context_scope,
+            llm,
+            prepare,
+            cancelled,
+        } = job;
+        let run = async {
+            // Loading the context doesn't hold up other scopes' provider requests.
+            let PreparedRequest {
+                request,
+                context,
+                typing,
+            } = prepare.await;
+            let _permit = permits.acquire().await.ok()?;
+            let started = Instant::now();
+            let result = llm
+                .rewrite_with_deadline(
+                    &request.system_prompt,
+                    request.chat_metadata.as_deref(),
+                    &request.context,
+                    &request.original,
+                    request.deadline,
+                )
+                .await;
+            Some(RewriteDone {
+                key,
+                id,
+                context_scope,
+                context,
+                typing,
+                result,
+                elapsed: started.elapsed(),
+            })
+        };
+        let done = tokio::select! {
+            done = run => done,
+            // A dropped sender only means the rewrite was replaced; its answer is still wanted
+            // for the context it loaded.
+            Ok(()) = cancelled => continue,
+        };
+        let Some(done) = done else {
+            return;
+        };
+        if results.send(WorkerDone::Rewrite(done)).is_err() {
+            return;
+        }
+    }
+}
+
+/// What [`prepare_request`] takes from the update loop, so it can run off it.
+struct RequestInputs {
+    message: TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+    /// Context from the cache, replaced by the messages fetched when `fetch_context` is set.
+    cached_context: Vec<ContextMessage>,
+    fetch_context: bool,
+    /// The replied-to message, when the cache has it.
+    cached_reply_target: Option<ContextMessage>,
+    chat_metadata: Option<String>,
+    system_prompt: String,
+    context_messages: usize,
+    include_service_messages: bool,
+    context_token_budget: Option<usize>,
+    show_typing: bool,
+    deadline: Option<Duration>,
+}
+
+/// A provider request, with how its context was loaded and the typing action when enabled.
+struct PreparedRequest {
+    request: RewriteRequest,
+    context: LoadedContext,
+    /// Shows "typing" until the outcome is applied.
+    typing: Option<TypingIndicator>,
+}
+
+/// How [`prepare_request`] got a request's context, for [`record_context`].
+struct LoadedContext {
+    fetch: ContextFetch,
+    /// Context messages before the `rewrite.context_token_budget` trim.
+    count: usize,
+}
+
+enum ContextFetch {
+    /// The cache was used as it was.
+    Skipped,
+    /// Fetched from Telegram because the cache was cold.
+    Fetched(Vec<ContextEntry>),
+    /// The fetch failed; the cache was used instead.
+    Failed,
+}
+
+/// Takes what [`prepare_request`] needs for `message` from the caches and the config.
+fn request_inputs(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+    runtime: &ProcessMessageRuntime<'_>,
+) -> RequestInputs {
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    let cached_context =
+        runtime
+            .context_cache
+            .recent_before(context_scope, message_id, rewrite.context_messages);
+    let fetch_context = runtime.context_cache.should_backfill(
+        context_scope,
+        rewrite.context_messages,
+        cached_context.len(),
+    );
+    let cached_reply_target = message_reply_to_message_id(message)
+        .and_then(|reply_to_id| runtime.context_cache.find(chat_id, reply_to_id));
+    let chat_metadata = rewrite
+        .include_chat_metadata
+        .then(|| bot.chat_metadata(chat_id, context_scope.topic_root_id))
+        .flatten();
+    RequestInputs {
+        message: message.clone(),
+        context_scope,
+        original,
+        cached_context,
+        fetch_context,
+        cached_reply_target,
+        chat_metadata,
+        system_prompt: system_prompt(rewrite).into_owned(),
+        context_messages: rewrite.context_messages,
+        include_service_messages: rewrite.include_service_messages_in_context,
+        context_token_budget: rewrite.context_token_budget,
+        show_typing: rewrite.show_typing && !runtime.dry_run,
+        deadline: runtime.rewrite_deadline,
+    }
+}
+
+/// Loads the context, logs the payload and starts the typing action when enabled.
+async fn prepare_request(requests: TelegramRequests, mut inputs: RequestInputs) -> PreparedRequest {
+    let (context, dropped_context_messages, loaded) = load_context(&requests, &mut inputs).await;
+    let RequestInputs {
+        message,
+        context_scope,
+        original,
+        chat_metadata,
+        system_prompt,
+        show_typing,
+        deadline,
+        ..
+    } = inputs;
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    log_payload(
+        context_scope,
+        message_id,
+        &context,
+        dropped_context_messages,
+        chat_metadata.as_deref(),
+        &system_prompt,
+        &original,
+    );
+
+    let typing = if show_typing {
+        match requests
+            .start_typing(&message, context_scope.topic_root_id)
+            .await
+        {
+            Ok(typing) => Some(typing),
+            Err(err) => {
+                debug!(chat_id, message_id, error = %err, "failed to start typing action");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    PreparedRequest {
+        request: RewriteRequest {
+            system_prompt,
+            chat_metadata,
+            context,
+            original,
+            deadline,
+        },
+        context: loaded,
+        typing,
+    }
+}
+
+/// Recent messages before `message` in its scope, backfilled from Telegram when the cache
+/// is cold, and the number dropped to fit `rewrite.context_token_budget`.
+async fn load_context(
+    requests: &TelegramRequests,
+    inputs: &mut RequestInputs,
+) -> (Vec<ContextMessage>, usize, LoadedContext) {
+    let message = &inputs.message;
+    let chat_id = inputs.context_scope.chat_id;
+    let topic_root_id = inputs.context_scope.topic_root_id;
+    let message_id = message.id();
+    let mut context = std::mem::take(&mut inputs.cached_context);
+    let mut fetch = ContextFetch::Skipped;
+    if inputs.fetch_context {
+        info!(
+            chat_id,
+            topic_root_id = ?topic_root_id,
+            message_id,
+            requested_context_messages = inputs.context_messages,
+            cached_context_messages = context.len(),
+            "fetching context messages from telegram"
+        );
+        match requests
+            .fetch_context(
+                message,
+                inputs.context_messages,
+                topic_root_id,
+                inputs.include_service_messages,
+            )
+            .await
+        {
+            Ok(fetched) => {
+                info!(
+                    chat_id,
+                    topic_root_id = ?topic_root_id,
+                    message_id,
+                    fetched_context_messages = fetched.len(),
+                    "fetched context messages from telegram"
+                );
+                context = fetched.iter().map(|entry| entry.message.clone()).collect();
+                fetch = ContextFetch::Fetched(fetched);
+            }
+            Err(err) => {
+                warn!(
+                    chat_id,
+                    topic_root_id = ?topic_root_id,
+                    message_id,
+                    requested_context_messages = inputs.context_messages,
+                    error = %err,
+                    "failed to fetch context messages; using cached context only"
+                );
+                fetch = ContextFetch::Failed;
+            }
+        }
+    }
+    let count = context.len();
+
+    let dropped_context_messages = inputs
+        .context_token_budget
+        .map_or(0, |budget| trim_to_token_budget(&mut context, budget));
+
+    // Added after the budget trim so an old reply target is never the first thing dropped.
+    if let Some(reply_to_id) = message_reply_to_message_id(message) {
+        let target = match inputs.cached_reply_target.take() {
+            Some(cached) => Some(cached),
+            None => reply_target(requests, message, chat_id, reply_to_id).await,
+        };
+        if let Some(target) = target
+            && !context.contains(&target)
+        {
+            context.insert(0, reply_target_context(&target));
+        }
+    }
+
+    (
+        context,
+        dropped_context_messages,
+        LoadedContext { fetch, count },
+    )
+}
+
+fn log_payload(
+    context_scope: ContextScope,
+    message_id: i32,
+    context: &[ContextMessage],
+    dropped_context_messages: usize,
+    chat_metadata: Option<&str>,
+    system_prompt: &str,
+    original: &str,
+) {
+    let chat_id = context_scope.chat_id;
+    let topic_root_id = context_scope.topic_root_id;
+    let llm_context: Vec<String> = context
+        .iter()
+        .map(ContextMessage::as_llm_user_content)
+        .collect();
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        info!(
+            chat_id,
+            topic_root_id = ?topic_root_id,
+            message_id,
+            context_messages = llm_context.len(),
+            dropped_context_messages,
+            chat_metadata = ?chat_metadata,
+            system_prompt = %system_prompt,
+            context = %serde_json::json!(llm_context),
+            input = %original,
+            "prepared rewrite payload"
+        );
+    } else {
+        let pretty_system_prompt = system_prompt.replace('\n', "\n    ");
+        let pretty_input = original.replace('\n', "\n    ");
+        let pretty_context = if llm_context.is_empty() {
+            "    (none)".to_owned()
+        } else {
+            llm_context
+                .iter()
+                .enumerate()
+                .map(|(idx, entry)| {
+                    let entry = entry.replace('\n', "\n         ");
+                    format!("    {:02}. {}", idx + 1, entry)
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        info!(
+            chat_id,
+            topic_root_id = ?topic_root_id,
+            message_id,
+            context_messages = llm_context.len(),
+            dropped_context_messages,
+            chat_metadata = ?chat_metadata,
+            "prepared rewrite payload\n  system_prompt:\n    {}\n  context:\n{}\n  input:\n    {}",
+            pretty_system_prompt,
+            pretty_context,
+            pretty_input
+        );
+    }
+}
+
+/// Keeps the context [`prepare_request`] fetched and reports where it came from.
+fn record_context(
+    context_scope: ContextScope,
+    message_id: i32,
+    context: LoadedContext,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let source = match context.fetch {
+        ContextFetch::Skipped => ContextSource::Cache,
+        ContextFetch::Fetched(fetched) => {
+            if let Some(metrics) = runtime.hooks.metrics() {
+                metrics.context_fetched(true);
+            }
+            runtime.context_cache.mark_hydrated(context_scope);
+            runtime.context_cache.backfill(context_scope, fetched);
+            ContextSource::Telegram
+        }
+        ContextFetch::Failed => {
+            if let Some(metrics) = runtime.hooks.metrics() {
+                metrics.context_fetched(false);
+            }
+            ContextSource::Cache
+        }
+    };
+    runtime.hooks.emit(RewriteEvent::ContextFetched {
+        chat_id: context_scope.chat_id,
+        message_id,
+        source,
+        count: context.count,
+    });
+}
+
+/// The replied-to message, fetched from Telegram. Fetch failures only cost the reply context.
+async fn reply_target(
+    requests: &TelegramRequests,
+    message: &TelegramMessage,
+    chat_id: i64,
+    reply_to_id: i32,
+) -> Option<ContextMessage> {
+    match requests.fetch_reply_target(message, reply_to_id).await {
+        Ok(target) => target,
+        Err(err) => {
+            warn!(
+                chat_id,
+                message_id = message.id(),
+                reply_to_id,
+                error = %err,
+                "failed to fetch replied-to message; rewriting without it"
+            );
+            None
+        }
+    }
+}
+
+/// Edits the message for a successful rewrite; otherwise logs why it stays as sent.
+async fn apply_outcome(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    message: &TelegramMessage,
+    context_scope: ContextScope,
+    original: &str,
+    rewrite_id: Option<u64>,
+    outcome: RewriteOutcome,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = context_scope.chat_id;
+    let message_id = message.id();
+    let outcome = check_dropped_links(
+        rewrite,
+        original,
+        &message_hidden_links(message),
+        outcome,
+        chat_id,
+        message_id,
+    );
+    let outcome = review_edit(
+        rewrite,
+        runtime.hooks,
+        original,
+        outcome,
+        chat_id,
+        message_id,
+    );
+    if let Some(stat) = ChatStat::for_outcome(&outcome) {
+        runtime.record_stat(chat_id, message_id, stat);
+    }
+    let (rewritten, model) = match outcome {
+        RewriteOutcome::Edit { text, model } => (text, model),
+        RewriteOutcome::Failed(err) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "llm rewrite failed; leaving original message unchanged"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::Refused(refusal) => {
+            warn!(
+                chat_id,
+                message_id,
+                refusal = %refusal,
+                "model refused to rewrite; leaving original message unchanged"
+            );
+            runtime.hooks.emit(RewriteEvent::RewriteRefused {
+                chat_id,
+                message_id,
+            });
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::Empty => {
+            info!(chat_id, message_id, "skipping empty rewrite result");
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::Unchanged => {
+            info!(chat_id, message_id, "skipping unchanged rewrite result");
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::BelowChangeRatio(ratio) => {
+            info!(
+                chat_id,
+                message_id,
+                change_ratio = ratio,
+                "skipping rewrite below rewrite.min_change_ratio"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::DroppedLinks(dropped) => {
+            warn!(
+                chat_id,
+                message_id,
+                ?dropped,
+                "rewrite dropped links or mentions; leaving original message unchanged"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        RewriteOutcome::Vetoed => {
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+    };
+
+    if runtime.deleted_messages.contains(chat_id, message_id) {
+        info!(
+            chat_id,
+            message_id, "message deleted during rewrite; dropping result"
+        );
+        runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+            chat_id,
+            message_id,
+        });
+        runtime.end_rewrite(chat_id, message_id, rewrite_id);
+        return;
+    }
+
+    if runtime.dry_run {
+        // Remembered as if edited, so each message is only evaluated once.
+        runtime.dedupe_cache.insert(chat_id, message_id, original);
+        runtime
+            .context_cache
+            .observe_update_message(context_scope, message);
+        report_dry_run(
+            chat_id,
+            message_id,
+            original,
+            rewritten,
+            &model,
+            runtime.hooks,
+        );
+        runtime.end_rewrite(chat_id, message_id, rewrite_id);
+        return;
+    }
+
+    if rewrite.delivery_for(chat_id) == Delivery::Resend {
+        let pending = PendingResend {
+            message: message.clone(),
+            context_scope,
+            rewritten,
+            model,
+            rewrite_id,
+        };
+        resend_rewrite(bot, rewrite, pending, runtime).await;
+        return;
+    }
+
+    let pending = PendingEdit {
+        message: message.clone(),
+        context_scope,
+        original: original.to_owned(),
+        rewritten,
+        model,
+        rewrite_id,
+        attempt: 0,
+        slot_reserved: false,
+    };
+    edit_rewrite(bot, rewrite, pending, runtime).await;
+}
+
+/// Logs the rewrite a dry run leaves unapplied and reports it as [`RewriteEvent::DryRunRewrite`].
+fn report_dry_run(
+    chat_id: i64,
+    message_id: i32,
+    original: &str,
+    rewritten: String,
+    model: &str,
+    hooks: AccountHooks<'_>,
+) {
+    info!(
+        chat_id,
+        message_id,
+        model,
+        original_text = %original,
+        rewritten_text = %rewritten,
+        "dry run: message would be rewritten"
+    );
+    hooks.emit(RewriteEvent::DryRunRewrite {
+        chat_id,
+        message_id,
+        original: original.to_owned(),
+        rewritten,
+    });
+}
+
+/// Applies the rewrite as an edit unless it went stale; see [`finish_edit`].
+async fn edit_rewrite(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    mut pending: PendingEdit,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let context_scope = pending.context_scope;
+    let chat_id = context_scope.chat_id;
+    let message_id = pending.message.id();
+    if !runtime.is_current_rewrite(chat_id, message_id, pending.rewrite_id) {
+        drop_stale_rewrite(chat_id, message_id, pending.rewrite_id, runtime);
+        return;
+    }
+    if !std::mem::take(&mut pending.slot_reserved) {
+        let now = tokio::time::Instant::now();
+        let wait = runtime.edit_throttle.reserve(
+            chat_id,
+            Duration::from_millis(rewrite.min_edit_interval_ms),
+            now,
+        );
+        if !wait.is_zero() {
+            debug!(
+                chat_id,
+                message_id,
+                wait_ms = wait.as_millis() as u64,
+                "spacing edit per rewrite.min_edit_interval_ms"
+            );
+            pending.slot_reserved = true;
+            if let Err(pending) = runtime.edit_retries.push(now + wait, pending) {
+                warn!(
+                    chat_id,
+                    message_id,
+                    queued_edits = runtime.edit_retries.len(),
+                    "edit retry queue is full while spacing edits; dropping rewrite"
+                );
+                runtime
+                    .context_cache
+                    .observe_update_message(context_scope, &pending.message);
+                runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+                runtime.end_rewrite(chat_id, message_id, pending.rewrite_id);
+            }
+            return;
+        }
+    }
+    pending.attempt += 1;
+    let parse_mode = rewrite.output_parse_mode();
+    let link_preview = rewrite.link_preview;
+    let key = runtime.message_key(chat_id, message_id);
+    if let Some(workers) = runtime.workers.as_deref_mut() {
+        let requests = bot.requests().clone();
+        let applying = plain_text(&pending.rewritten, parse_mode);
+        workers.spawn_request(key, Some(applying), async move {
+            let edit_started = Instant::now();
+            let result = requests
+                .edit_message(
+                    &pending.message,
+                    &pending.rewritten,
+                    parse_mode,
+                    link_preview,
+                )
+                .await;
+            RequestDone::Edit {
+                pending,
+                result,
+                elapsed: edit_started.elapsed(),
+            }
+        });
+        return;
+    }
+    let edit_started = Instant::now();
+    let result = bot
+        .requests()
+        .edit_message(
+            &pending.message,
+            &pending.rewritten,
+            parse_mode,
+            link_preview,
+        )
+        .await;
+    finish_edit(rewrite, pending, result, edit_started.elapsed(), runtime);
+}
+
+/// Logs and reports a rewrite that was superseded or whose message was edited.
+fn drop_stale_rewrite(
+    chat_id: i64,
+    message_id: i32,
+    rewrite_id: Option<u64>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    info!(
+        chat_id,
+        message_id, "message changed during rewrite; dropping result"
+    );
+    runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+        chat_id,
+        message_id,
+    });
+    runtime.end_rewrite(chat_id, message_id, rewrite_id);
+}
+
+/// Records the outcome of an edit. A `FLOOD_WAIT` or `SLOWMODE_WAIT` parks the edit in
+/// `runtime.edit_retries` until the wait is over, and so does a transient error that outlasted
+/// the bot's quick retries, for up to `MAX_EDIT_ATTEMPTS` attempts. Permanent errors drop the
+/// rewrite.
+fn finish_edit(
+    rewrite: &RewriteConfig,
+    pending: PendingEdit,
+    edited: Result<String>,
+    elapsed: Duration,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let context_scope = pending.context_scope;
+    let chat_id = context_scope.chat_id;
+    let message_id = pending.message.id();
+    let rewrite_id = pending.rewrite_id;
+    if let Some(metrics) = runtime.hooks.metrics() {
+        metrics.observe_edit_latency(elapsed);
+    }
+    let err = match edited {
+        Ok(applied) => {
+            runtime.context_cache.upsert_update_message_text(
+                context_scope,
+                &pending.message,
+                &applied,
+            );
+            runtime.dedupe_cache.insert(chat_id, message_id, &applied);
+            runtime.applied(chat_id, message_id);
+            record_undo(rewrite, chat_id, &pending.message, message_id, runtime);
+            info!(
+                chat_id,
+                message_id,
+                model = %pending.model,
+                "rewrote and edited message"
+            );
+            runtime.record_stat(chat_id, message_id, ChatStat::Rewritten);
+            runtime.hooks.emit(RewriteEvent::MessageEdited {
+                chat_id,
+                message_id,
+                model: pending.model,
+            });
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        Err(err) => err,
+    };
+    runtime.applied(chat_id, message_id);
+
+    let wait = err
+        .downcast_ref::<FloodWait>()
+        .map(|wait| (wait.seconds, false))
+        .or_else(|| {
+            err.downcast_ref::<SlowModeWait>()
+                .map(|wait| (wait.seconds, true))
+        });
+    if let Some((seconds, slow_mode)) = wait
+        && pending.attempt < MAX_EDIT_ATTEMPTS
+    {
+        let attempt = pending.attempt;
+        let retry_at = tokio::time::Instant::now() + Duration::from_secs(u64::from(seconds));
+        match runtime.edit_retries.push(retry_at, pending) {
+            Ok(()) if slow_mode => {
+                info!(
+                    chat_id,
+                    message_id,
+                    wait_seconds = seconds,
+                    attempt,
+                    "edit hit chat slow mode; retrying after the wait"
+                );
+                runtime.hooks.emit(RewriteEvent::SlowModeDelayed {
+                    chat_id,
+                    message_id,
+                    wait_seconds: seconds,
+                });
+            }
+            Ok(()) => {
+                info!(
+                    chat_id,
+                    message_id,
+                    wait_seconds = seconds,
+                    attempt,
+                    "edit hit telegram flood wait; retrying after the wait"
+                );
+                runtime.hooks.emit(RewriteEvent::EditDeferred {
+                    chat_id,
+                    message_id,
+                    wait_seconds: seconds,
+                });
+            }
+            Err(pending) => {
+                warn!(
+                    chat_id,
+                    message_id,
+                    wait_seconds = seconds,
+                    queued_edits = runtime.edit_retries.len(),
+                    "edit hit telegram flood wait with the retry queue full; dropping rewrite"
+                );
+                runtime
+                    .context_cache
+                    .observe_update_message(context_scope, &pending.message);
+                runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+                runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            }
+        }
+        return;
+    }
+
+    match err.downcast_ref::<EditFailure>() {
+        Some(EditFailure::Transient) if pending.attempt < MAX_EDIT_ATTEMPTS => {
+            let attempt = pending.attempt;
+            let retry_at = tokio::time::Instant::now() + TRANSIENT_EDIT_REQUEUE_DELAY;
+            match runtime.edit_retries.push(retry_at, pending) {
+                Ok(()) => info!(
+                    chat_id,
+                    message_id,
+                    attempt,
+                    error = %err,
+                    "edit kept failing with transient errors; requeued for a later retry"
+                ),
+                Err(pending) => {
+                    warn!(
+                        chat_id,
+                        message_id,
+                        queued_edits = runtime.edit_retries.len(),
+                        error = %err,
+                        "edit failed with transient errors and the retry queue is full; dropping rewrite"
+                    );
+                    runtime
+                        .context_cache
+                        .observe_update_message(context_scope, &pending.message);
+                    runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+                    runtime.end_rewrite(chat_id, message_id, rewrite_id);
+                }
+            }
+            return;
+        }
+        Some(EditFailure::Permanent) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "message can no longer be edited; dropping rewrite"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, &pending.message);
+            runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+        _ => {}
+    }
+
+    warn!(
+        chat_id,
+        message_id,
+        original_text = %pending.original,
+        rewritten_text = %pending.rewritten,
+        attempt = pending.attempt,
+        error = %err,
+        "failed to edit message; continuing"
+    );
+    runtime
+        .context_cache
+        .observe_update_message(context_scope, &pending.message);
+    runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+    runtime.end_rewrite(chat_id, message_id, rewrite_id);
+}
+
+/// Retries edits taken from [`EditRetries::take_due`], dropping those whose message was
+/// deleted while they waited.
+async fn retry_deferred_edits(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    due: Vec<PendingEdit>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    for pending in due {
+        let chat_id = pending.context_scope.chat_id;
+        let message_id = pending.message.id();
+        if runtime.deleted_messages.contains(chat_id, message_id) {
+            info!(
+                chat_id,
+                message_id, "message deleted while its edit was deferred; dropping result"
+            );
+            runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+                chat_id,
+                message_id,
+            });
+            runtime.end_rewrite(chat_id, message_id, pending.rewrite_id);
+            continue;
+        }
+        edit_rewrite(bot, rewrite, pending, runtime).await;
+    }
+}
+
+/// Sends the rewrite as a new message in the same reply thread or topic, then deletes the
+/// original. In slow mode the rewrite waits in `runtime.slow_mode` for the chat's next window.
+async fn resend_rewrite(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    pending: PendingResend,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = pending.context_scope.chat_id;
+    let message_id = pending.message.id();
+    if !runtime.is_current_rewrite(chat_id, message_id, pending.rewrite_id) {
+        drop_stale_rewrite(chat_id, message_id, pending.rewrite_id, runtime);
+        return;
+    }
+    let window = bot.slow_mode_window(chat_id);
+    let pending =
+        match runtime
+            .slow_mode
+            .admit(chat_id, window, tokio::time::Instant::now(), pending)
+        {
+            SlowModeAdmission::Now(pending) => pending,
+            SlowModeAdmission::Queued {
+                ready_at,
+                superseded,
+            } => {
+                report_slow_mode_delay(ready_at, superseded, chat_id, runtime);
+                return;
+            }
+        };
+    let parse_mode = rewrite.output_parse_mode();
+    let link_preview = rewrite.link_preview;
+    let key = runtime.message_key(chat_id, message_id);
+    if let Some(workers) = runtime.workers.as_deref_mut() {
+        let requests = bot.requests().clone();
+        let applying = plain_text(&pending.rewritten, parse_mode);
+        workers.spawn_request(key, Some(applying), async move {
+            let sent = requests
+                .send_in_scope(
+                    &pending.message,
+                    &pending.rewritten,
+                    parse_mode,
+                    link_preview,
+                )
+                .await;
+            let deleted = match sent {
+                Ok(_) => Some(requests.delete_message(&pending.message).await),
+                Err(_) => None,
+            };
+            RequestDone::Resend {
+                pending,
+                sent,
+                deleted,
+            }
+        });
+        return;
+    }
+    let requests = bot.requests();
+    let sent = requests
+        .send_in_scope(
+            &pending.message,
+            &pending.rewritten,
+            parse_mode,
+            link_preview,
+        )
+        .await;
+    let deleted = match sent {
+        Ok(_) => Some(requests.delete_message(&pending.message).await),
+        Err(_) => None,
+    };
+    finish_resend(bot, rewrite, pending, sent, deleted, runtime);
+}
+
+/// Records the outcome of a resend. A failed send keeps the original; a failed delete leaves
+/// both and is reported.
+fn finish_resend(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    pending: PendingResend,
+    sent: Result<SentMessage>,
+    deleted: Option<Result<()>>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let chat_id = pending.context_scope.chat_id;
+    let PendingResend {
+        message,
+        context_scope,
+        rewritten,
+        model,
+        rewrite_id,
+    } = pending;
+    let message = &message;
+    let message_id = message.id();
+    runtime.applied(chat_id, message_id);
+    let sent = match sent {
+        Ok(sent) => sent,
+        Err(err) => {
+            if let Some(&SlowModeWait { seconds }) = err.downcast_ref::<SlowModeWait>() {
+                let now = tokio::time::Instant::now();
+                runtime.slow_mode.record_wait(
+                    chat_id,
+                    Duration::from_secs(u64::from(seconds)),
+                    now,
+                );
+                let pending = PendingResend {
+                    message: message.clone(),
+                    context_scope,
+                    rewritten,
+                    model,
+                    rewrite_id,
+                };
+                let window = bot.slow_mode_window(chat_id);
+                if let SlowModeAdmission::Queued {
+                    ready_at,
+                    superseded,
+                } = runtime.slow_mode.admit(chat_id, window, now, pending)
+                {
+                    report_slow_mode_delay(ready_at, superseded, chat_id, runtime);
+                }
+                return;
+            }
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "failed to send rewritten message; keeping the original"
+            );
+            runtime
+                .context_cache
+                .observe_update_message(context_scope, message);
+            runtime.record_stat(chat_id, message_id, ChatStat::EditFailed);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            return;
+        }
+    };
+    // The sent message comes back as an outgoing update and must not be rewritten again.
+    runtime.dedupe_cache.insert(chat_id, sent.id, &sent.text);
+    runtime.dedupe_cache.insert(chat_id, message_id, &sent.text);
+    runtime
+        .context_cache
+        .remove_messages(Some(chat_id), &[message_id]);
+    runtime.context_cache.upsert_message(
+        context_scope,
+        sent.id,
+        ContextMessage {
+            sender_name: resolve_sender_name(true, None),
+            text: sent.text.clone(),
+            is_own: true,
+        },
+    );
+    runtime.end_rewrite(chat_id, message_id, rewrite_id);
+
+    if let Some(Err(err)) = deleted {
+        warn!(
+            chat_id,
+            message_id,
+            new_message_id = sent.id,
+            error = %err,
+            "sent rewritten message but failed to delete the original; both are in the chat"
+        );
+        runtime.record_stat(chat_id, message_id, ChatStat::Rewritten);
+        runtime.hooks.emit(RewriteEvent::ResendInconsistent {
+            chat_id,
+            message_id,
+            new_message_id: sent.id,
+        });
+        return;
+    }
+    record_undo(rewrite, chat_id, message, sent.id, runtime);
+    info!(
+        chat_id,
+        message_id,
+        new_message_id = sent.id,
+        model = %model,
+        "rewrote message and resent it"
+    );
+    runtime.record_stat(chat_id, message_id, ChatStat::Rewritten);
+    runtime.hooks.emit(RewriteEvent::MessageResent {
+        chat_id,
+        message_id,
+        new_message_id: sent.id,
+        model,
+    });
+}
+
+/// Logs and reports a resend queued behind slow mode, dropping the one it superseded.
+fn report_slow_mode_delay(
+    ready_at: tokio::time::Instant,
+    superseded: Option<PendingResend>,
+    chat_id: i64,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    if let Some(superseded) = superseded {
+        info!(
+            chat_id,
+            message_id = superseded.message.id(),
+            "newer rewrite replaced one waiting for slow mode; dropping the older rewrite"
+        );
+        runtime
+            .context_cache
+            .observe_update_message(superseded.context_scope, &superseded.message);
+        runtime.end_rewrite(chat_id, superseded.message.id(), superseded.rewrite_id);
+    }
+    let Some(queued) = runtime.slow_mode.queued(chat_id) else {
+        return;
+    };
+    let message_id = queued.message.id();
+    let wait_seconds = ready_at
+        .saturating_duration_since(tokio::time::Instant::now())
+        .as_secs_f64()
+        .ceil() as u32;
+    info!(
+        chat_id,
+        message_id, wait_seconds, "chat is in slow mode; resending the rewrite when it allows"
+    );
+    runtime.hooks.emit(RewriteEvent::SlowModeDelayed {
+        chat_id,
+        message_id,
+        wait_seconds,
+    });
+}
+
+/// Resends rewrites whose slow mode window opened, dropping those of deleted messages.
+async fn resend_slow_mode_ready(
+    bot: &TelegramBot,
+    rewrite: &RewriteConfig,
+    ready: Vec<PendingResend>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    for pending in ready {
+        let chat_id = pending.context_scope.chat_id;
+        let message_id = pending.message.id();
+        if runtime.deleted_messages.contains(chat_id, message_id) {
+            info!(
+                chat_id,
+                message_id,
+                "message deleted while its resend waited for slow mode; dropping result"
+            );
+            runtime.hooks.emit(RewriteEvent::RewriteCancelled {
+                chat_id,
+                message_id,
+            });
+            runtime.end_rewrite(chat_id, message_id, pending.rewrite_id);
+            continue;
+        }
+        resend_rewrite(bot, rewrite, pending, runtime).await;
+    }
+}
+
+/// Rewrites every queued catch-up batch under the catch-up rewrite deadline.
+async fn flush_catch_up_batches(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    batches: &mut CatchUpBatches<UpdateMessage>,
+    catch_up_deadline: Option<Duration>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let deadline = std::mem::replace(&mut runtime.rewrite_deadline, catch_up_deadline);
+    for (context_scope, messages) in batches.take() {
+        process_catch_up_batch(bot, settings, messages, context_scope, runtime).await;
+    }
+    runtime.rewrite_deadline = deadline;
+}
+
+/// Rewrites catch-up messages queued for one scope. More than `rewrite.batch_threshold` of
+/// them share a single request; entries the batch answer misses are retried one by one.
+async fn process_catch_up_batch(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    messages: Vec<UpdateMessage>,
+    context_scope: ContextScope,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let rewrite = settings.rewrite;
+    let chat_id = context_scope.chat_id;
+    if rewrite
+        .batch_threshold
+        .is_none_or(|threshold| messages.len() <= threshold)
+    {
+        for message in messages {
+            if let Err(err) = process_message(bot, settings, &message, context_scope, runtime).await
+            {
+                error!(error = %err, "failed to process message");
+            }
+        }
+        return;
+    }
+
+    let mut candidates = Vec::with_capacity(messages.len());
+    for message in messages {
+        if let Some(original) =
+            rewrite_candidate(bot, rewrite, &message, context_scope, runtime).await
+        {
+            candidates.push((message, original));
+        }
+    }
+    let Some((first, first_original)) = candidates.first() else {
+        return;
+    };
+    let request = request_inputs(
+        bot,
+        rewrite,
+        first,
+        context_scope,
+        first_original.clone(),
+        runtime,
+    );
+    for (message, _) in &candidates {
+        runtime.hooks.emit(RewriteEvent::RewriteStarted {
+            chat_id,
+            message_id: message.id(),
+        });
+    }
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        let candidates = candidates
+            .into_iter()
+            .map(|(message, original)| (TelegramMessage::clone(&message), original, None))
+            .collect();
+        let done = request_catch_up_batch(bot.requests(), settings.llm, request, candidates).await;
+        finish_catch_up_batch(bot, settings, done, runtime).await;
+        return;
+    };
+    let key = MessageKey::new(runtime.hooks.account, chat_id, first.id());
+    let candidates = candidates
+        .into_iter()
+        .map(|(message, original)| {
+            let key = MessageKey::new(runtime.hooks.account, chat_id, message.id());
+            let id = workers.track(key, message.text());
+            (TelegramMessage::clone(&message), original, Some(id))
+        })
+        .collect();
+    let requests = bot.requests().clone();
+    let llm = workers.llm();
+    workers.spawn_provider_request(key, async move {
+        RequestDone::CatchUpBatch(
+            request_catch_up_batch(&requests, llm.as_ref(), request, candidates).await,
+        )
+    });
+}
+
+/// Asks the provider to rewrite all `candidates` with one request.
+async fn request_catch_up_batch(
+    requests: &TelegramRequests,
+    llm: &dyn LlmRewriter,
+    mut request: RequestInputs,
+    candidates: Vec<(TelegramMessage, String, Option<u64>)>,
+) -> CatchUpBatchDone {
+    let context_scope = request.context_scope;
+    let (context, _, loaded) = load_context(requests, &mut request).await;
+    let inputs: Vec<&str> = candidates
+        .iter()
+        .map(|(_, original, _)| original.as_str())
+        .collect();
+    info!(
+        chat_id = context_scope.chat_id,
+        topic_root_id = ?context_scope.topic_root_id,
+        batched_messages = inputs.len(),
+        context_messages = context.len(),
+        "requesting batch rewrite for catch-up messages"
+    );
+    let requested_at = Instant::now();
+    let result = rewrite_batch(
+        llm,
+        &request.system_prompt,
+        request.chat_metadata.as_deref(),
+        &context,
+        &inputs,
+        request.deadline,
+    )
+    .await;
+    CatchUpBatchDone {
+        context_scope,
+        candidates,
+        context: loaded,
+        result,
+        elapsed: requested_at.elapsed(),
+    }
+}
+
+/// Applies the answer to a catch-up batch. Entries it misses are retried one by one.
+async fn finish_catch_up_batch(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    done: CatchUpBatchDone,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let CatchUpBatchDone {
+        context_scope,
+        candidates,
+        context,
+        result,
+        elapsed,
+    } = done;
+    let chat_id = context_scope.chat_id;
+    if let Some((first, _, _)) = candidates.first() {
+        record_context(context_scope, first.id(), context, runtime);
+    }
+    if let Some(metrics) = runtime.hooks.metrics() {
+        for _ in &candidates {
+            metrics.rewrite_attempted();
+        }
+        metrics.observe_llm_latency(elapsed);
+    }
+    let (rewrites, model) = match result {
+        Ok(batch) => {
+            if let Some(usage) = batch.usage {
+                runtime.usage_tracker.record(chat_id, &batch.model, usage);
+            }
+            (batch.rewrites, batch.model)
+        }
+        Err(err) => {
+            warn!(
+                chat_id,
+                topic_root_id = ?context_scope.topic_root_id,
+                error = %err,
+                "batch rewrite failed; rewriting messages one by one"
+            );
+            (vec![None; candidates.len()], String::new())
+        }
+    };
+
+    for ((message, original, rewrite_id), text) in candidates.into_iter().zip(rewrites) {
+        let message_id = message.id();
+        let Some(text) = text else {
+            // A message edited meanwhile is rewritten from its new text instead.
+            let current = runtime.is_current_rewrite(chat_id, message_id, rewrite_id);
+            runtime.end_rewrite(chat_id, message_id, rewrite_id);
+            if current {
+                rewrite_and_apply(bot, settings, &message, context_scope, original, runtime).await;
+            }
+            continue;
+        };
+        runtime.hooks.emit(RewriteEvent::RewriteSucceeded {
+            chat_id,
+            message_id,
+            input_tokens: None,
+            output_tokens: None,
+        });
+        let outcome = finish_rewrite(settings, &original, &text, model.clone());
+        apply_outcome(
+            bot,
+            settings.rewrite,
+            &message,
+            context_scope,
+            &original,
+            rewrite_id,
+            outcome,
+            runtime,
+        )
+        .await;
+    }
+}
+
+/// The configured system prompt, with Markdown instructions under `rewrite.preserve_formatting`.
+fn system_prompt(rewrite: &RewriteConfig) -> Cow<'_, str> {
+    if rewrite.preserve_formatting {
+        Cow::Owned(format!(
+            "{}\n\n{MARKDOWN_PROMPT_SUFFIX}",
+            rewrite.system_prompt.trim_end()
+        ))
+    } else {
+        Cow::Borrowed(&rewrite.system_prompt)
+    }
+}
+
+/// What to do with an outgoing message once the model has answered.
+#[derive(Debug)]
+enum RewriteOutcome {
+    /// Sanitized and truncated text that differs from the original.
+    Edit {
+        text: String,
+        model: String,
+    },
+    Failed(anyhow::Error),
+    Refused(String),
+    Empty,
+    Unchanged,
+    /// Changes less than `rewrite.min_change_ratio` of the original.
+    BelowChangeRatio(f64),
+    /// Lost these URLs or mentions of the original, with `rewrite.restore_dropped_links = "skip"`.
+    DroppedLinks(Vec<String>),
+    /// Skipped by the `on_before_edit` hook.
+    Vetoed,
+}
+
+/// Asks the model for a rewrite, records its token usage and decides whether to edit.
+async fn request_rewrite(
+    settings: RewriteSettings<'_>,
+    chat_metadata: Option<&str>,
+    context: &[ContextMessage],
+    original: &str,
+    chat_id: i64,
+    message_id: i32,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> RewriteOutcome {
+    runtime.hooks.emit(RewriteEvent::RewriteStarted {
+        chat_id,
+        message_id,
+    });
+    let requested_at = Instant::now();
+    let result = settings
+        .llm
+        .rewrite_with_deadline(
+            &system_prompt(settings.rewrite),
+            chat_metadata,
+            context,
+            original,
+            runtime.rewrite_deadline,
+        )
+        .await;
+    finish_request(
+        settings,
+        result,
+        requested_at.elapsed(),
+        original,
+        chat_id,
+        message_id,
+        runtime,
+    )
+}
+
+/// Records the provider's answer and decides whether to edit.
+fn finish_request(
+    settings: RewriteSettings<'_>,
+    result: Result<Rewrite>,
+    elapsed: Duration,
+    original: &str,
+    chat_id: i64,
+    message_id: i32,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> RewriteOutcome {
+    if let Some(metrics) = runtime.hooks.metrics() {
+        metrics.rewrite_attempted();
+        metrics.observe_llm_latency(elapsed);
+    }
+    let result = match result {
+        Ok(result) => result,
+        Err(err) => return RewriteOutcome::Failed(err),
+    };
+    if let Some(usage) = result.usage {
+        runtime.usage_tracker.record(chat_id, &result.model, usage);
+    }
+    if let Some(refusal) = result.refusal {
+        return RewriteOutcome::Refused(refusal);
+    }
+    runtime.hooks.emit(RewriteEvent::RewriteSucceeded {
+        chat_id,
+        message_id,
+        input_tokens: result.usage.map(|usage| usage.input_tokens),
+        output_tokens: result.usage.map(|usage| usage.output_tokens),
+    });
+    finish_rewrite(settings, original, &result.text, result.model)
+}
+
+/// Applies `rewrite.restore_dropped_links` to an edit that lost URLs or mentions of the
+/// original, including the `hidden_links` behind its text links.
+fn check_dropped_links(
+    rewrite: &RewriteConfig,
+    original: &str,
+    hidden_links: &[String],
+    outcome: RewriteOutcome,
+    chat_id: i64,
+    message_id: i32,
+) -> RewriteOutcome {
+    let RewriteOutcome::Edit { text, model } = outcome else {
+        return outcome;
+    };
+    if rewrite.restore_dropped_links == RestoreDroppedLinks::Off {
+        return RewriteOutcome::Edit { text, model };
+    }
+    let mut links = extract_links(original);
+    links.extend_from_slice(hidden_links);
+    let dropped = dropped_links(&links, &text);
+    if dropped.is_empty() {
+        return RewriteOutcome::Edit { text, model };
+    }
+    match rewrite.restore_dropped_links {
+        RestoreDroppedLinks::Append => {
+            info!(
+                chat_id,
+                message_id,
+                ?dropped,
+                "rewrite dropped links or mentions; appending them"
+            );
+            RewriteOutcome::Edit {
+                text: append_links(&text, &dropped),
+                model,
+            }
+        }
+        RestoreDroppedLinks::Skip | RestoreDroppedLinks::Off => {
+            RewriteOutcome::DroppedLinks(dropped)
+        }
+    }
+}
+
+/// Sanitizes and truncates the model's text and decides whether it is worth an edit.
+fn finish_rewrite(
+    settings: RewriteSettings<'_>,
+    original: &str,
+    text: &str,
+    model: String,
+) -> RewriteOutcome {
+    let rewritten = sanitize_rewrite_output(text, &settings.rewrite.strip_prefixes);
+    if settings.refusals.is_refusal(original, &rewritten) {
+        return RewriteOutcome::Refused(rewritten);
+    }
+    check_rewrite(settings.rewrite, original, &rewritten, model)
+}
+
+/// Truncates `rewritten` to what Telegram accepts and decides whether it is worth an edit.
+fn check_rewrite(
+    rewrite: &RewriteConfig,
+    original: &str,
+    rewritten: &str,
+    model: String,
+) -> RewriteOutcome {
+    // Markup is cut once parsed, by the length of the text it renders to.
+    let rewritten = if rewrite.output_parse_mode() == ParseMode::Plain {
+        truncate_to_telegram_limit(rewritten, TELEGRAM_MESSAGE_MAX_CHARS)
+    } else {
+        rewritten
+    };
+    if rewritten.is_empty() {
+        RewriteOutcome::Empty
+    } else if rewritten == original {
+        RewriteOutcome::Unchanged
+    } else if rewrite.min_change_ratio > 0.0
+        && let ratio = change_ratio(original, rewritten)
+        && ratio < rewrite.min_change_ratio
+    {
+        RewriteOutcome::BelowChangeRatio(ratio)
+    } else {
+        RewriteOutcome::Edit {
+            text: rewritten.to_owned(),
+            model,
+        }
+    }
+}
+
+/// Lets `on_before_edit` approve, skip or replace an edit. A replacement goes through the
+/// same truncation and unchanged checks as the model's answer.
+fn review_edit(
+    rewrite: &RewriteConfig,
+    hooks: AccountHooks<'_>,
+    original: &str,
+    outcome: RewriteOutcome,
+    chat_id: i64,
+    message_id: i32,
+) -> RewriteOutcome {
+    let RewriteOutcome::Edit { text, model } = outcome else {
+        return outcome;
+    };
+    let Some(before_edit) = hooks.hooks.on_before_edit.as_deref() else {
+        return RewriteOutcome::Edit { text, model };
+    };
+    let decision = before_edit(&EditCandidate {
+        chat_id,
+        message_id,
+        original,
+        rewritten: &text,
+    });
+    match decision {
+        EditDecision::Approve => RewriteOutcome::Edit { text, model },
+        EditDecision::Skip => {
+            info!(chat_id, message_id, "edit vetoed by the before-edit hook");
+            RewriteOutcome::Vetoed
+        }
+        EditDecision::Replace(replacement) => {
+            info!(
+                chat_id,
+                message_id, "rewrite replaced by the before-edit hook"
+            );
+            check_rewrite(rewrite, original, &replacement, model)
+        }
+    }
+}
+
+async fn handle_chat_command(
+    bot: &TelegramBot,
+    message: &TelegramMessage,
+    chat_id: i64,
+    command: ChatCommand,
+    rewrite: &RewriteConfig,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let undone = if command == ChatCommand::Undo {
+        match undo_rewrite(bot, message, chat_id, rewrite, runtime).await {
+            Some(undone) => Some(undone),
+            // Acknowledged once the restore is done.
+            None => return,
+        }
+    } else {
+        None
+    };
+    let reply = command_ack(command, undone, chat_id, message.id(), rewrite, runtime);
+    acknowledge_command(bot, message, chat_id, reply, rewrite, runtime).await;
+}
+
+/// Edits the command `message` into `reply`.
+async fn acknowledge_command(
+    bot: &TelegramBot,
+    message: &TelegramMessage,
+    chat_id: i64,
+    reply: String,
+    rewrite: &RewriteConfig,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let message_id = message.id();
+    let link_preview = rewrite.link_preview;
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        let result = bot
+            .requests()
+            .edit_message(message, &reply, ParseMode::Plain, link_preview)
+            .await;
+        finish_acknowledge(chat_id, message_id, result);
+        return;
+    };
+    let key = MessageKey::new(runtime.hooks.account, chat_id, message_id);
+    let requests = bot.requests().clone();
+    let message = message.clone();
+    workers.spawn_request(key, None, async move {
+        let result = requests
+            .edit_message(&message, &reply, ParseMode::Plain, link_preview)
+            .await;
+        RequestDone::Acknowledged {
+            chat_id,
+            message_id,
+            result,
+        }
+    });
+}
+
+fn finish_acknowledge(chat_id: i64, message_id: i32, result: Result<String>) {
+    if let Err(err) = result {
+        warn!(
+            chat_id,
+            message_id,
+            error = %err,
+            "failed to acknowledge chat command"
+        );
+    }
+}
+
+/// The text the command message is edited into; `undone` is the result of an `undo`.
+fn command_ack(
+    command: ChatCommand,
+    undone: Option<&str>,
+    chat_id: i64,
+    message_id: i32,
+    rewrite: &RewriteConfig,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> String {
+    let reply = match command {
+        ChatCommand::On | ChatCommand::Off => {
+            let enabled = command == ChatCommand::On;
+            let changed = if enabled {
+                runtime.paused_chats.remove(&chat_id)
+            } else {
+                runtime.paused_chats.insert(chat_id)
+            };
+            if changed {
+                info!(chat_id, enabled, "rewriting toggled by chat command");
+                runtime
+                    .hooks
+                    .emit(RewriteEvent::ChatRewriteToggled { chat_id, enabled });
+            }
+            status_text(enabled).to_owned()
+        }
+        ChatCommand::Status => status_text(!runtime.paused_chats.contains(&chat_id)).to_owned(),
+        ChatCommand::Undo => undone.unwrap_or_default().to_owned(),
+        ChatCommand::Unknown(subcommand) => {
+            info!(chat_id, subcommand, "unknown chat command");
+            usage_hint(&rewrite.command_prefix)
+        }
+    };
+    // The ack comes back as an edit of our own message and must not be rewritten.
+    runtime.dedupe_cache.insert(chat_id, message_id, &reply);
+    reply
+}
+
+/// Edits the original text back into the rewrite `message` replies to, or into the chat's
+/// latest rewrite, and returns the acknowledgment; `None` while it runs off the update loop.
+async fn undo_rewrite(
+    bot: &TelegramBot,
+    message: &TelegramMessage,
+    chat_id: i64,
+    rewrite: &RewriteConfig,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> Option<&'static str> {
+    let reply_to_id = message_reply_to_message_id(message);
+    let Some(entry) = runtime.undo_history.take(chat_id, reply_to_id) else {
+        info!(chat_id, reply_to_id, "no rewrite to undo");
+        return Some(if reply_to_id.is_some() {
+            "that message has no rewrite to undo"
+        } else {
+            "nothing to undo in this chat"
+        });
+    };
+    let key = runtime.message_key(chat_id, entry.message_id);
+    let link_preview = rewrite.link_preview;
+    let Some(workers) = runtime.workers.as_deref_mut() else {
+        let (target, restored) = restore_original(
+            bot.requests(),
+            bot.dialog_peer(chat_id),
+            &entry,
+            link_preview,
+        )
+        .await;
+        return Some(finish_undo(chat_id, &entry, target, restored, runtime));
+    };
+    let requests = bot.requests().clone();
+    let peer = bot.dialog_peer(chat_id);
+    let command = message.clone();
+    let applying = plain_text(&entry.original, entry.parse_mode);
+    workers.spawn_request(key, Some(applying), async move {
+        let (target, restored) = restore_original(&requests, peer, &entry, link_preview).await;
+        RequestDone::Undo {
+            chat_id,
+            command,
+            entry,
+            target,
+            restored,
+        }
+    });
+    None
+}
+
+/// Fetches the rewritten message and, when it still exists, edits `entry.original` back in.
+async fn restore_original(
+    requests: &TelegramRequests,
+    peer: Result<PeerRef>,
+    entry: &UndoEntry,
+    link_preview: bool,
+) -> (Result<Option<TelegramMessage>>, Option<Result<String>>) {
+    let target = async { requests.get_message(peer?, entry.message_id).await }.await;
+    let restored = match &target {
+        Ok(Some(target)) => Some(
+            requests
+                .edit_message(target, &entry.original, entry.parse_mode, link_preview)
+                .await,
+        ),
+        _ => None,
+    };
+    (target, restored)
+}
+
+/// Records the outcome of an undo and returns the acknowledgment.
+fn finish_undo(
+    chat_id: i64,
+    entry: &UndoEntry,
+    target: Result<Option<TelegramMessage>>,
+    restored: Option<Result<String>>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) -> &'static str {
+    let message_id = entry.message_id;
+    let target = match target {
+        Ok(Some(target)) => target,
+        Ok(None) => {
+            info!(
+                chat_id,
+                message_id, "rewritten message no longer exists; nothing to undo"
+            );
+            return "the rewritten message no longer exists";
+        }
+        Err(err) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "failed to fetch rewritten message for undo"
+            );
+            return "failed to restore the original text";
+        }
+    };
+    match restored {
+        Some(Ok(applied)) => {
+            // The restore comes back as an edit of our own message and must not be rewritten.
+            runtime.dedupe_cache.insert(chat_id, message_id, &applied);
+            let context_scope = ContextScope {
+                chat_id,
+                topic_root_id: message_topic_root_id(&target),
+            };
+            runtime
+                .context_cache
+                .upsert_update_message_text(context_scope, &target, &applied);
+            info!(
+                chat_id,
+                message_id, "restored original text by chat command"
+            );
+            runtime.hooks.emit(RewriteEvent::RewriteUndone {
+                chat_id,
+                message_id,
+            });
+            "restored the original text"
+        }
+        Some(Err(err)) => {
+            warn!(
+                chat_id,
+                message_id,
+                error = %err,
+                "failed to restore original text"
+            );
+            "failed to restore the original text"
+        }
+        None => "failed to restore the original text",
+    }
+}
+
+/// The text of `message` as sent to the model: Markdown under `rewrite.preserve_formatting`,
+/// trimmed.
+fn message_original(rewrite: &RewriteConfig, message: &TelegramMessage) -> String {
+    let original = if rewrite.preserve_formatting {
+        message_markdown(message)
+    } else {
+        message.text().to_owned()
+    };
+    original.trim().to_owned()
+}
+
+/// Keeps what `original` said before it was rewritten, so `.rw undo` can restore it into
+/// `rewritten_id`.
+fn record_undo(
+    rewrite: &RewriteConfig,
+    chat_id: i64,
+    original: &TelegramMessage,
+    rewritten_id: i32,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let parse_mode = if rewrite.preserve_formatting {
+        ParseMode::Markdown
+    } else {
+        ParseMode::Plain
+    };
+    runtime.undo_history.record(
+        chat_id,
+        UndoEntry {
+            message_id: rewritten_id,
+            original: message_original(rewrite, original),
+            parse_mode,
+        },
+    );
+}
+
+fn filter_skip_reason(rewrite: &RewriteConfig, message: &TelegramMessage) -> Option<&'static str> {
+    if rewrite.skip_forwarded && message_is_forwarded(message) {
+        return Some("forwarded");
+    }
+    if rewrite.skip_replies && message_reply_to_message_id(message).is_some() {
+        return Some("reply");
+    }
+    if !rewrite.rewrite_channel_posts && message_is_channel_post(message) {
+        return Some("channel post");
+    }
+    None
+}
+
+struct ProcessMessageRuntime<'a> {
+    dedupe_cache: &'a mut DedupeCache,
+    deleted_messages: &'a DeletedMessages,
+    edit_retries: &'a mut EditRetries<PendingEdit>,
+    edit_throttle: &'a mut EditThrottle,
+    slow_mode: &'a mut SlowModeQueue<PendingResend>,
+    scheduled_rewrites: &'a mut ScheduledRewrites,
+    context_cache: &'a mut ContextCache,
+    rate_limiter: &'a mut RateLimiter,
+    paused_chats: &'a mut HashSet<i64>,
+    chat_stats: &'a mut ChatStats,
+    undo_history: &'a mut UndoHistory,
+    usage_tracker: &'a mut UsageTracker,
+    /// Set for catch-up messages from `runtime.catch_up_request_timeout_seconds`.
+    rewrite_deadline: Option<Duration>,
+    hooks: AccountHooks<'a>,
+    /// Set in rewrite mode, where requests run off the update loop.
+    workers: Option<&'a mut RewriteWorkers>,
+    /// Log rewrites instead of applying them.
+    dry_run: bool,
+}
+
+impl<'a> ProcessMessageRuntime<'a> {
+    fn with_workers(mut self, workers: &'a mut RewriteWorkers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    fn message_key(&self, chat_id: i64, message_id: i32) -> MessageKey {
+        MessageKey::new(self.hooks.account, chat_id, message_id)
+    }
+
+    /// Whether rewrite `rewrite_id` may still be applied, see [`RewriteWorkers::is_current`].
+    fn is_current_rewrite(&self, chat_id: i64, message_id: i32, rewrite_id: Option<u64>) -> bool {
+        let (Some(id), Some(workers)) = (rewrite_id, self.workers.as_deref()) else {
+            return true;
+        };
+        workers.is_current(&self.message_key(chat_id, message_id), id)
+    }
+
+    /// Stops tracking the message once its rewrite `rewrite_id` was applied or dropped.
+    fn end_rewrite(&mut self, chat_id: i64, message_id: i32, rewrite_id: Option<u64>) {
+        let key = self.message_key(chat_id, message_id);
+        if let (Some(id), Some(workers)) = (rewrite_id, self.workers.as_deref_mut()) {
+            workers.finish(&key, id);
+        }
+    }
+
+    /// Forgets the text an edit or resend of the message was putting out, once it is done.
+    fn applied(&mut self, chat_id: i64, message_id: i32) {
+        let key = self.message_key(chat_id, message_id);
+        if let Some(workers) = self.workers.as_deref_mut() {
+            workers.applied(&key);
+        }
+    }
+
+    /// Counts `stat` in the chat stats, the run stats and, when `[metrics]` is served, in the
+    /// metrics, and reports skips and failures as events.
+    fn record_stat(&mut self, chat_id: i64, message_id: i32, stat: ChatStat) {
+        self.chat_stats.record(chat_id, stat);
+        if let Some(event) = stat.lifecycle_event(chat_id, message_id) {
+            self.hooks.emit(event);
+        }
+        self.hooks.hooks.update_run_stats(|stats| match stat {
+            ChatStat::Seen => stats.message_seen(chat_id),
+            ChatStat::LlmFailed => stats.llm_failed(),
+            ChatStat::EditFailed => stats.edit_failed(),
+            ChatStat::Rewritten => stats.rewrite_applied(),
+            _ => {}
+        });
+        let Some(metrics) = self.hooks.metrics() else {
+            return;
+        };
+        match stat {
+            ChatStat::Seen => metrics.message_seen(chat_id),
+            ChatStat::Deduped => metrics.dedupe_hit(),
+            ChatStat::LlmFailed => metrics.rewrite_failed(FailedStage::Llm),
+            ChatStat::EditFailed => metrics.rewrite_failed(FailedStage::Edit),
+            ChatStat::Rewritten => metrics.rewrite_succeeded(),
+            _ => {}
+        }
+    }
+}
+
+fn normalize_rewrite_override(rewrite_override: Option<String>) -> Option<String> {
+    rewrite_override
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ContextScope {
+    chat_id: i64,
+    topic_root_id: Option<i32>,
+}
+
+struct ContextCache {
+    per_chat_limit: usize,
+    entries: HashMap<ContextScope, VecDeque<ContextEntry>>,
+    hydrated_scopes: HashSet<ContextScope>,
+    /// Peers we post as, from [`TelegramBot::own_personas`]; their messages are ours.
+    own_personas: HashSet<i64>,
+}
+
+impl ContextCache {
+    fn new(per_chat_limit: usize) -> Self {
+        Self {
+            per_chat_limit,
+            entries: HashMap::new(),
+            hydrated_scopes: HashSet::new(),
+            own_personas: HashSet::new(),
+        }
+    }
+
+    fn set_own_personas(&mut self, own_personas: HashSet<i64>) {
+        self.own_personas = own_personas;
+    }
+
+    fn set_per_chat_limit(&mut self, per_chat_limit: usize) {
+        self.per_chat_limit = per_chat_limit;
+        for messages in self.entries.values_mut() {
+            while messages.len() > self.per_chat_limit {
+                messages.pop_front();
+            }
+        }
+    }
+
+    fn retain_chats(&mut self, chats: &HashSet<i64>) {
+        self.entries
+            .retain(|scope, _| chats.contains(&scope.chat_id));
+        self.hydrated_scopes
+            .retain(|scope| chats.contains(&scope.chat_id));
+    }
+
+    /// Moves the context of `old_id` to `new_id`, for a group that migrated to a supergroup.
+    fn transfer_chat(&mut self, old_id: i64, new_id: i64) {
+        let moved: Vec<ContextScope> = self
+            .entries
+            .keys()
+            .filter(|scope| scope.chat_id == old_id)
+            .copied()
+            .collect();
+        for scope in moved {
+            if let Some(mut messages) = self.entries.remove(&scope) {
+                let new_scope = ContextScope {
+                    chat_id: new_id,
+                    ..scope
+                };
+                // The group's messages predate anything already seen in the supergroup.
+                messages.extend(self.entries.remove(&new_scope).unwrap_or_default());
+                while messages.len() > self.per_chat_limit {
+                    messages.pop_front();
+                }
+                self.entries.insert(new_scope, messages);
+            }
+        }
+        let hydrated: Vec<ContextScope> = self
+            .hydrated_scopes
+            .iter()
+            .filter(|scope| scope.chat_id == old_id)
+            .copied()
+            .collect();
+        for scope in hydrated {
+            self.hydrated_scopes.remove(&scope);
+            self.hydrated_scopes.insert(ContextScope {
+                chat_id: new_id,
+                ..scope
+            });
+        }
+    }
+
+    fn observe_update_message(&mut self, scope: ContextScope, message: &TelegramMessage) {
+        let peer_name = message.sender().and_then(|p| p.name().map(str::to_owned));
+        let is_own = message_is_own(message, &self.own_personas);
+        let sender_name = resolve_sender_name(is_own, peer_name.as_deref());
+        self.observe_named_update_message(scope, message, sender_name);
+    }
+
+    /// Like [`Self::observe_update_message`], with a sender name already resolved by the bot.
+    fn observe_named_update_message(
+        &mut self,
+        scope: ContextScope,
+        message: &TelegramMessage,
+        sender_name: String,
+    ) {
+        let text = context_text(message, message.text());
+        if text.is_empty() {
+            return;
+        }
+
+        self.record_message(
+            scope,
+            message.id(),
+            ContextMessage {
+                sender_name,
+                text,
+                is_own: message_is_own(message, &self.own_personas),
+            },
+        );
+    }
+
+    fn upsert_update_message_text(
+        &mut self,
+        scope: ContextScope,
+        message: &TelegramMessage,
+        text: &str,
+    ) {
+        let text = context_text(message, text);
+        if text.is_empty() {
+            return;
+        }
+
+        let peer_name = message.sender().and_then(|p| p.name().map(str::to_owned));
+        let is_own = message_is_own(message, &self.own_personas);
+        let sender_name = resolve_sender_name(is_own, peer_name.as_deref());
+        self.upsert_message(
+            scope,
+            message.id(),
+            ContextMessage {
+                sender_name,
+                text,
+                is_own,
+            },
+        );
+    }
+
+    fn record_message(&mut self, scope: ContextScope, message_id: i32, message: ContextMessage) {
+        let chat_messages = self.entries.entry(scope).or_default();
+        if chat_messages
+            .iter()
+            .any(|entry| entry.message_id == message_id)
+        {
+            return;
+        }
+        chat_messages.push_back(ContextEntry {
+            message_id,
+            message,
+        });
+        while chat_messages.len() > self.per_chat_limit {
+            chat_messages.pop_front();
+        }
+    }
+
+    fn upsert_message(&mut self, scope: ContextScope, message_id: i32, message: ContextMessage) {
+        let chat_messages = self.entries.entry(scope).or_default();
+        if let Some(entry) = chat_messages
+            .iter_mut()
+            .find(|entry| entry.message_id == message_id)
+        {
+            entry.message = message;
+            return;
+        }
+        chat_messages.push_back(ContextEntry {
+            message_id,
+            message,
+        });
+        while chat_messages.len() > self.per_chat_limit {
+            chat_messages.pop_front();
+        }
+    }
+
+    /// Replaces the scope's messages with fetched ones, keeping cached ones newer than all.
+    fn backfill(&mut self, scope: ContextScope, messages: Vec<ContextEntry>) {
+        let newest = messages.iter().map(|entry| entry.message_id).max();
+        let mut fresh: VecDeque<ContextEntry> = messages.into_iter().collect();
+        if let Some(cached) = self.entries.remove(&scope) {
+            fresh.extend(
+                cached
+                    .into_iter()
+                    .filter(|entry| newest.is_none_or(|newest| entry.message_id > newest)),
+            );
+        }
+        while fresh.len() > self.per_chat_limit {
+            fresh.pop_front();
+        }
+        self.entries.insert(scope, fresh);
+    }
+
+    /// Fills in the sender of a message observed before the name was looked up.
+    fn set_sender_name(&mut self, scope: ContextScope, message_id: i32, sender_name: String) {
+        if let Some(entry) = self.entries.get_mut(&scope).and_then(|messages| {
+            messages
+                .iter_mut()
+                .find(|entry| entry.message_id == message_id)
+        }) {
+            entry.message.sender_name = sender_name;
+        }
+    }
+
+    fn recent_before(
+        &self,
+        scope: ContextScope,
+        message_id: i32,
+        count: usize,
+    ) -> Vec<ContextMessage> {
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let mut recent = Vec::with_capacity(count);
+        if let Some(messages) = self.entries.get(&scope) {
+            for entry in messages.iter().rev() {
+                if entry.message_id == message_id {
+                    continue;
+                }
+                recent.push(entry.message.clone());
+                if recent.len() >= count {
+                    break;
+                }
+            }
+        }
+        recent.reverse();
+        recent
+    }
+
+    /// A cached message of the chat by id, from whichever topic scope holds it.
+    fn find(&self, chat_id: i64, message_id: i32) -> Option<ContextMessage> {
+        self.entries
+            .iter()
+            .filter(|(scope, _)| scope.chat_id == chat_id)
+            .flat_map(|(_, messages)| messages.iter())
+            .find(|entry| entry.message_id == message_id)
+            .map(|entry| entry.message.clone())
+    }
+
+    fn should_backfill(&self, scope: ContextScope, count: usize, cached_count: usize) -> bool {
+        count > 0 && cached_count < count && !self.hydrated_scopes.contains(&scope)
+    }
+
+    fn mark_hydrated(&mut self, scope: ContextScope) {
+        self.hydrated_scopes.insert(scope);
+    }
+
+    /// Every scope with cached messages or a finished backfill, for the state file.
+    fn snapshot(&self) -> Vec<ScopeSnapshot> {
+        let scopes: HashSet<ContextScope> = self
+            .entries
+            .keys()
+            .chain(&self.hydrated_scopes)
+            .copied()
+            .collect();
+        scopes
+            .into_iter()
+            .map(|scope| ScopeSnapshot {
+                chat_id: scope.chat_id,
+                topic_root_id: scope.topic_root_id,
+                hydrated: self.hydrated_scopes.contains(&scope),
+                messages: self
+                    .entries
+                    .get(&scope)
+                    .map(|messages| messages.iter().cloned().collect())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Loads scopes saved by an earlier run, keeping the newest `per_chat_limit` messages of
+    /// each.
+    fn restore(&mut self, scopes: Vec<ScopeSnapshot>) {
+        for snapshot in scopes {
+            let scope = ContextScope {
+                chat_id: snapshot.chat_id,
+                topic_root_id: snapshot.topic_root_id,
+            };
+            if snapshot.hydrated {
+                self.hydrated_scopes.insert(scope);
+            }
+            let skip = snapshot.messages.len().saturating_sub(self.per_chat_limit);
+            let messages: VecDeque<ContextEntry> =
+                snapshot.messages.into_iter().skip(skip).collect();
+            if !messages.is_empty() {
+                self.entries.insert(scope, messages);
+            }
+        }
+    }
+
+    /// Drops the messages from every topic scope of the chat; `chat_id` is `None` for
+    /// deletions in private chats and basic groups, see [`DeletedMessage`].
+    fn remove_messages(&mut self, chat_id: Option<i64>, message_ids: &[i32]) {
+        for (scope, messages) in &mut self.entries {
+            if deletion_applies_to_chat(chat_id, scope.chat_id) {
+                messages.retain(|entry| !message_ids.contains(&entry.message_id));
+            }
+        }
+    }
+}
+
+fn truncate_to_telegram_limit(input: &str, max_chars: usize) -> &str {
+    let mut char_count = 0;
+    for (byte_offset, _) in input.char_indices() {
+        char_count += 1;
+        if char_count > max_chars {
+            return &input[..byte_offset];
+        }
+    }
+    input
+}
+
+/// Levenshtein distance over chars divided by the longer text's char count, so 0.0 means
+/// identical and 1.0 means nothing in common.
+fn change_ratio(original: &str, rewritten: &str) -> f64 {
+    let original: Vec<char> = original.chars().collect();
+    let rewritten: Vec<char> = rewritten.chars().collect();
+    let longest = original.len().max(rewritten.len());
+    if longest == 0 {
+        return 0.0;
+    }
+
+    let mut previous: Vec<usize> = (0..=rewritten.len()).collect();
+    let mut current = vec![0; rewritten.len() + 1];
+    for (i, original_char) in original.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, rewritten_char) in rewritten.iter().enumerate() {
+            let substitution = previous[j] + usize::from(original_char != rewritten_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[rewritten.len()] as f64 / longest as f64
+}
+
+/// A [`ChatCounters`] counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChatStat {
+    Seen,
+    NotOutgoing,
+    Deduped,
+    Empty,
+    Filtered,
+    RateLimited,
+    LlmFailed,
+    Refused,
+    Unchanged,
+    EditFailed,
+    /// Skipped by the `on_before_edit` hook; counted as filtered.
+    Vetoed,
+    Rewritten,
+}
+
+impl ChatStat {
+    /// [`RewriteEvent::RewriteSkipped`] or [`RewriteEvent::RewriteFailed`] for a message that
+    /// ended up in this counter; `None` for the counters that already have their own events.
+    fn lifecycle_event(self, chat_id: i64, message_id: i32) -> Option<RewriteEvent> {
+        let skipped = |reason| RewriteEvent::RewriteSkipped {
+            chat_id,
+            message_id,
+            reason,
+        };
+        let failed = |stage, reason| RewriteEvent::RewriteFailed {
+            chat_id,
+            message_id,
+            stage,
+            reason,
+        };
+        Some(match self {
+            Self::Seen | Self::Rewritten => return None,
+            Self::NotOutgoing => skipped(SkipReason::NotOutgoing),
+            Self::Deduped => skipped(SkipReason::Deduped),
+            Self::Empty => skipped(SkipReason::Empty),
+            Self::Filtered => skipped(SkipReason::Filtered),
+            Self::RateLimited => skipped(SkipReason::RateLimited),
+            Self::LlmFailed => failed(RewriteStage::Llm, FailureReason::ProviderFailed),
+            Self::Refused => failed(RewriteStage::Skipped, FailureReason::Refused),
+            Self::Unchanged => failed(RewriteStage::Skipped, FailureReason::Unchanged),
+            Self::EditFailed => failed(RewriteStage::Edit, FailureReason::EditFailed),
+            Self::Vetoed => failed(RewriteStage::Skipped, FailureReason::Vetoed),
+        })
+    }
+
+    /// The counter for a rewrite that won't be applied; `None` for an edit.
+    fn for_outcome(outcome: &RewriteOutcome) -> Option<Self> {
+        match outcome {
+            RewriteOutcome::Edit { .. } => None,
+            RewriteOutcome::Failed(_) | RewriteOutcome::Empty => Some(Self::LlmFailed),
+            RewriteOutcome::Refused(_) => Some(Self::Refused),
+            RewriteOutcome::Unchanged
+            | RewriteOutcome::BelowChangeRatio(_)
+            | RewriteOutcome::DroppedLinks(_) => Some(Self::Unchanged),
+            RewriteOutcome::Vetoed => Some(Self::Vetoed),
+        }
+    }
+}
+
+/// [`ChatCounters`] by chat for the current stats window and since startup.
+struct ChatStats {
+    window_started: tokio::time::Instant,
+    window: HashMap<i64, ChatCounters>,
+    totals: HashMap<i64, ChatCounters>,
+}
+
+impl ChatStats {
+    fn new(now: tokio::time::Instant) -> Self {
+        Self {
+            window_started: now,
+            window: HashMap::new(),
+            totals: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, chat_id: i64, stat: ChatStat) {
+        self.window.entry(chat_id).or_default().record(stat);
+        self.totals.entry(chat_id).or_default().record(stat);
+    }
+
+    /// Closes the window: the window's length and, for every chat seen since startup, its
+    /// window counters and totals, sorted by chat id. The next window starts at `now`.
+    fn take_window(
+        &mut self,
+        now: tokio::time::Instant,
+    ) -> (Duration, Vec<(i64, ChatCounters, ChatCounters)>) {
+        let window = now.saturating_duration_since(self.window_started);
+        self.window_started = now;
+        let mut counts = std::mem::take(&mut self.window);
+        let mut rows: Vec<(i64, ChatCounters, ChatCounters)> = self
+            .totals
+            .iter()
+            .map(|(&chat_id, &totals)| {
+                (chat_id, counts.remove(&chat_id).unwrap_or_default(), totals)
+            })
+            .collect();
+        rows.sort_unstable_by_key(|&(chat_id, _, _)| chat_id);
+        (window, rows)
+    }
+}
+
+/// Logs the totals since startup and emits them as [`RewriteEvent::RunSummary`].
+fn report_run_summary(hooks: &RewriteHooks, usage_tracker: &UsageTracker) {
+    let summary = hooks
+        .run_stats
+        .lock()
+        .expect("run stats lock poisoned")
+        .summary(Instant::now(), usage_tracker.total());
+    info!("run summary\n{}", summary.render());
+    hooks.emit(RewriteEvent::RunSummary { summary });
+}
+
+/// Logs the closing stats window as a table and emits [`RewriteEvent::ChatStats`] per chat.
+fn report_chat_stats(stats: &mut ChatStats, hooks: AccountHooks<'_>) {
+    let (window, rows) = stats.take_window(tokio::time::Instant::now());
+    if rows.is_empty() {
+        return;
+    }
+    info!(
+        account = hooks.account,
+        window_seconds = window.as_secs(),
+        "chat stats for the last window (totals since startup in parentheses)\n{}",
+        chat_stats_table(&rows)
+    );
+    for (chat_id, counters, totals) in rows {
+        hooks.emit(RewriteEvent::ChatStats {
+            chat_id,
+            window,
+            counters,
+            totals,
+        });
+    }
+}
+
+fn chat_stats_table(rows: &[(i64, ChatCounters, ChatCounters)]) -> String {
+    const COLUMNS: [&str; 12] = [
+        "chat",
+        "seen",
+        "not_out",
+        "deduped",
+        "empty",
+        "filtered",
+        "rate_ltd",
+        "llm_fail",
+        "refused",
+        "unchanged",
+        "edit_fail",
+        "rewritten",
+    ];
+    let mut table = vec![COLUMNS.map(str::to_owned).to_vec()];
+    for (chat_id, counters, totals) in rows {
+        let cell = |window: u64, total: u64| format!("{window} ({total})");
+        table.push(vec![
+            chat_id.to_string(),
+            cell(counters.seen, totals.seen),
+            cell(counters.not_outgoing, totals.not_outgoing),
+            cell(counters.deduped, totals.deduped),
+            cell(counters.empty, totals.empty),
+            cell(counters.filtered, totals.filtered),
+            cell(counters.rate_limited, totals.rate_limited),
+            cell(counters.llm_failed, totals.llm_failed),
+            cell(counters.refused, totals.refused),
+            cell(counters.unchanged, totals.unchanged),
+            cell(counters.edit_failed, totals.edit_failed),
+            cell(counters.rewritten, totals.rewritten),
+        ]);
+    }
+    let widths: Vec<usize> = (0..COLUMNS.len())
+        .map(|column| table.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    table
+        .iter()
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!("{cell:>width$}"))
+                .collect();
+            format!("  {}", cells.join("  "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Per-chat token buckets holding up to `max_per_minute` rewrites, or the chat's own limit
+/// from `rewrite.chat_overrides`, refilled continuously.
+struct RateLimiter {
+    max_per_minute: Option<u32>,
+    chat_limits: HashMap<i64, u32>,
+    buckets: HashMap<i64, TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(max_per_minute: Option<u32>) -> Self {
+        Self {
+            max_per_minute,
+            chat_limits: HashMap::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Bucket state is only reset when the configured rate actually changes, and only in
+    /// chats without a limit of their own.
+    fn set_max_per_minute(&mut self, max_per_minute: Option<u32>) {
+        if self.max_per_minute != max_per_minute {
+            self.max_per_minute = max_per_minute;
+            let chat_limits = &self.chat_limits;
+            self.buckets
+                .retain(|chat_id, _| chat_limits.contains_key(chat_id));
+        }
+    }
+
+    /// Like `set_max_per_minute`, only resets the buckets of chats whose rate changes.
+    fn set_chat_limits(&mut self, chat_limits: HashMap<i64, u32>) {
+        let default = self.max_per_minute;
+        let previous = &self.chat_limits;
+        self.buckets.retain(|chat_id, _| {
+            previous.get(chat_id).copied().or(default)
+                == chat_limits.get(chat_id).copied().or(default)
+        });
+        self.chat_limits = chat_limits;
+    }
+
+    fn retain_chats(&mut self, chats: &HashSet<i64>) {
+        self.buckets.retain(|chat_id, _| chats.contains(chat_id));
+    }
+
+    fn try_acquire(&mut self, chat_id: i64, now: Instant) -> bool {
+        let Some(max_per_minute) = self
+            .chat_limits
+            .get(&chat_id)
+            .copied()
+            .or(self.max_per_minute)
+        else {
+            return true;
+        };
+        let capacity = f64::from(max_per_minute);
+        let bucket = self.buckets.entry(chat_id).or_insert(TokenBucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// When the last rewrite edit went out in each chat, to space edits by
+/// `rewrite.min_edit_interval_ms`.
+#[derive(Default)]
+struct EditThrottle {
+    last_edit: HashMap<i64, tokio::time::Instant>,
+}
+
+impl EditThrottle {
+    /// Books the next edit slot in `chat_id` and returns how long to wait for it. The first
+    /// edit in a chat, or one after a quiet spell, goes out right away.
+    fn reserve(
+        &mut self,
+        chat_id: i64,
+        min_interval: Duration,
+        now: tokio::time::Instant,
+    ) -> Duration {
+        if min_interval.is_zero() {
+            self.last_edit.remove(&chat_id);
+            return Duration::ZERO;
+        }
+        let slot = match self.last_edit.get(&chat_id) {
+            Some(&last) => now.max(last + min_interval),
+            None => now,
+        };
+        self.last_edit.insert(chat_id, slot);
+        slot - now
+    }
+}
+
+/// Scheduled messages rewritten by [`process_scheduled_message`], with the text the rewrite
+/// left them with. The message the schedule sends gets a new id, so it is recognized by its
+/// chat and text; one edited again after the rewrite doesn't match and is rewritten when sent.
+/// With a store attached, they are also kept in `runtime.state_file` until sent.
+#[derive(Default)]
+struct ScheduledRewrites {
+    /// Chat id, scheduled message id and rewritten text, oldest first.
+    entries: VecDeque<(i64, i32, String)>,
+    store: Option<ScheduledStore>,
+}
+
+impl ScheduledRewrites {
+    /// Takes over the rewrites an earlier run recorded in `store` and records new ones there.
+    fn attach_store(&mut self, loaded: Vec<ScheduledRewrite>, store: ScheduledStore) {
+        for rewrite in loaded {
+            self.remember(rewrite.chat_id, rewrite.message_id, rewrite.text);
+        }
+        self.store = Some(store);
+    }
+
+    /// Remembers that scheduled message `message_id` now reads `text`, replacing an earlier
+    /// rewrite of it.
+    fn insert(&mut self, chat_id: i64, message_id: i32, text: String) {
+        if let Some(store) = self.store.as_ref() {
+            store.record(ScheduledRewrite {
+                chat_id,
+                message_id,
+                text: text.clone(),
+            });
+        }
+        self.remember(chat_id, message_id, text);
+    }
+
+    fn remember(&mut self, chat_id: i64, message_id: i32, text: String) {
+        self.entries
+            .retain(|&(chat, message, _)| (chat, message) != (chat_id, message_id));
+        if self.entries.len() >= SCHEDULED_REWRITES_LIMIT
+            && let Some((chat, message, _)) = self.entries.pop_front()
+        {
+            self.forget(chat, message);
+        }
+        self.entries.push_back((chat_id, message_id, text));
+    }
+
+    /// Whether a message the schedule sent to `chat_id` as `text` was rewritten when it was
+    /// scheduled. A match is forgotten, since each scheduled message is sent once.
+    fn take_sent(&mut self, chat_id: i64, text: &str) -> bool {
+        let Some(index) = self
+            .entries
+            .iter()
+            .position(|(chat, _, rewritten)| *chat == chat_id && rewritten.trim() == text.trim())
+        else {
+            return false;
+        };
+        if let Some((chat, message, _)) = self.entries.remove(index) {
+            self.forget(chat, message);
+        }
+        true
+    }
+
+    fn forget(&self, chat_id: i64, message_id: i32) {
+        if let Some(store) = self.store.as_ref() {
+            store.forget(chat_id, message_id);
+        }
+    }
+}
+
+/// Handles a complete media album through its captioned item, the only one a rewrite
+/// applies to. The other items are marked as handled in the dedupe cache.
+async fn process_album(
+    bot: &TelegramBot,
+    settings: RewriteSettings<'_>,
+    album: Vec<AlbumMember>,
+    catch_up_request_timeout: Option<Duration>,
+    runtime: &mut ProcessMessageRuntime<'_>,
+) {
+    let album_size = album.len();
+    let (captioned, others) = split_album(album, |member| member.message.text());
+    for member in &others {
+        runtime.dedupe_cache.insert(
+            member.context_scope.chat_id,
+            member.message.id(),
+            member.message.text(),
+        );
+    }
+    let AlbumMember {
+        context_scope,
+        is_catch_up,
+        message,
+    } = captioned;
+    info!(
+        chat_id = context_scope.chat_id,
+        message_id = message.id(),
+        album_size,
+        "processing media album through its captioned item"
+    );
+    runtime.rewrite_deadline = catch_up_request_timeout.filter(|_| is_catch_up);
+    if let Err(err) = process_message(bot, settings, &message, context_scope, runtime).await {
+        error!(error = %err, "failed to process media album");
+    }
+}
+
+/// Splits an album into the item carrying the caption (the first with text, or the first
+/// item when none has any) and the rest.
+fn split_album<M>(mut members: Vec<M>, text: impl Fn(&M) -> &str) -> (M, Vec<M>) {
+    let captioned = members
+        .iter()
+        .position(|member| !text(member).trim().is_empty())
+        .unwrap_or(0);
+    let captioned = members.remove(captioned);
+    (captioned, members)
+}
+
+/// Media album items held back per album until no new item arrived for `ALBUM_WINDOW`.
+struct AlbumBuffer<M> {
+    albums: Vec<PendingAlbum<M>>,
+}
+
+struct PendingAlbum<M> {
+    /// `(chat_id, grouped_id)`.
+    key: (i64, i64),
+    members: Vec<M>,
+    complete_at: tokio::time::Instant,
+}
+
+impl<M> AlbumBuffer<M> {
+    fn new() -> Self {
+        Self { albums: Vec::new() }
+    }
+
+    fn push(&mut self, key: (i64, i64), member: M, now: tokio::time::Instant) {
+        let complete_at = now + ALBUM_WINDOW;
+        match self.albums.iter_mut().find(|album| album.key == key) {
+            Some(album) => {
+                album.members.push(member);
+                album.complete_at = complete_at;
+            }
+            None => self.albums.push(PendingAlbum {
+                key,
+                members: vec![member],
+                complete_at,
+            }),
+        }
+    }
+
+    fn next_complete_at(&self) -> Option<tokio::time::Instant> {
+        self.albums.iter().map(|album| album.complete_at).min()
+    }
+
+    /// Albums whose window ended by `now`, in arrival order.
+    fn take_complete(&mut self, now: tokio::time::Instant) -> Vec<Vec<M>> {
+        let (complete, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.albums)
+            .into_iter()
+            .partition(|album| album.complete_at <= now);
+        self.albums = pending;
+        complete.into_iter().map(|album| album.members).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.albums.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.albums.is_empty()
+    }
+}
+
+/// Outgoing catch-up messages held back per scope so a burst can share one request.
+struct CatchUpBatches<M> {
+    scopes: Vec<(ContextScope, Vec<M>)>,
+    flush_at: Option<tokio::time::Instant>,
+}
+
+impl<M> CatchUpBatches<M> {
+    fn new() -> Self {
+        Self {
+            scopes: Vec::new(),
+            flush_at: None,
+        }
+    }
+
+    /// Queues `message` and pushes the flush back to `CATCH_UP_BATCH_WINDOW` from `now`.
+    fn push(&mut self, scope: ContextScope, message: M, now: tokio::time::Instant) {
+        match self.scopes.iter_mut().find(|(queued, _)| *queued == scope) {
+            Some((_, messages)) => messages.push(message),
+            None => self.scopes.push((scope, vec![message])),
+        }
+        self.flush_at = Some(now + CATCH_UP_BATCH_WINDOW);
+    }
+
+    fn take(&mut self) -> Vec<(ContextScope, Vec<M>)> {
+        self.flush_at = None;
+        std::mem::take(&mut self.scopes)
+    }
+
+    fn len(&self) -> usize {
+        self.scopes.iter().map(|(_, messages)| messages.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.scopes.is_empty()
+    }
+}
+
+/// A rewrite to resend, waiting in the [`SlowModeQueue`] while the chat is in slow mode.
+struct PendingResend {
+    message: TelegramMessage,
+    context_scope: ContextScope,
+    rewritten: String,
+    model: String,
+    /// The tracked rewrite this resend applies, see [`RewriteWorkers`].
+    rewrite_id: Option<u64>,
+}
+
+/// A rewrite whose edit was answered with `FLOOD_WAIT` or a transient error, or which waits
+/// for its turn per `rewrite.min_edit_interval_ms`, waiting in [`EditRetries`].
+struct PendingEdit {
+    message: TelegramMessage,
+    context_scope: ContextScope,
+    original: String,
+    rewritten: String,
+    model: String,
+    /// The tracked rewrite this edit applies, see [`RewriteWorkers`].
+    rewrite_id: Option<u64>,
+    /// Edit attempts made so far.
+    attempt: u32,
+    /// Set while the edit waits for a slot already booked in the [`EditThrottle`].
+    slot_reserved: bool,
+}
+
+/// Deferred edits ordered by when they may be retried, holding at most `limit` entries.
+struct EditRetries<T> {
+    entries: Vec<(tokio::time::Instant, T)>,
+    limit: usize,
+}
+
+impl<T> EditRetries<T> {
+    fn new(limit: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            limit,
+        }
+    }
+
+    /// Queues `item` for `retry_at`, handing it back when the queue is full.
+    fn push(&mut self, retry_at: tokio::time::Instant, item: T) -> Result<(), T> {
+        if self.entries.len() >= self.limit {
+            return Err(item);
+        }
+        let index = self
+            .entries
+            .partition_point(|(queued_at, _)| *queued_at <= retry_at);
+        self.entries.insert(index, (retry_at, item));
+        Ok(())
+    }
+
+    fn next_retry_at(&self) -> Option<tokio::time::Instant> {
+        self.entries.first().map(|(retry_at, _)| *retry_at)
+    }
+
+    /// Removes and returns the entries due at `now`, earliest first.
+    fn take_due(&mut self, now: tokio::time::Instant) -> Vec<T> {
+        let due = self
+            .entries
+            .partition_point(|(retry_at, _)| *retry_at <= now);
+        self.entries.drain(..due).map(|(_, item)| item).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Rewritten messages, with the text we applied so our own edit coming back as
+/// `Update::MessageEdited` can be told apart from a manual one. With a ledger attached,
+/// rewrites are also recorded in `runtime.state_file` and those from earlier runs count.
+struct DedupeCache {
+    entries: HashMap<(i64, i32), DedupeEntry>,
+    ttl: Duration,
+    ledger: Option<AccountLedger>,
+}
+
+struct DedupeEntry {
+    seen_at: Instant,
+    applied_text: String,
+}
+
+impl DedupeCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            ledger: None,
+        }
+    }
+
+    fn attach_ledger(&mut self, ledger: AccountLedger) {
+        self.ledger = Some(ledger);
+    }
+
+    /// True when the message was rewritten in this run, or in an earlier one and still has
+    /// the rewritten `text`.
+    fn contains(&mut self, chat_id: i64, message_id: i32, text: &str) -> bool {
+        self.evict_expired();
+        self.entries.contains_key(&(chat_id, message_id))
+            || self.in_ledger(chat_id, message_id, text)
+    }
+
+    fn insert(&mut self, chat_id: i64, message_id: i32, applied_text: &str) {
+        if let Some(ledger) = self.ledger.as_mut() {
+            ledger.record(chat_id, message_id, applied_text);
+        }
+        self.entries.insert(
+            (chat_id, message_id),
+            DedupeEntry {
+                seen_at: Instant::now(),
+                applied_text: applied_text.trim().to_owned(),
+            },
+        );
+    }
+
+    /// True when `text` is exactly what we last applied to the message.
+    fn is_own_edit(&mut self, chat_id: i64, message_id: i32, text: &str) -> bool {
+        self.evict_expired();
+        self.entries
+            .get(&(chat_id, message_id))
+            .is_some_and(|entry| entry.applied_text == text.trim())
+            || self.in_ledger(chat_id, message_id, text)
+    }
+
+    fn in_ledger(&self, chat_id: i64, message_id: i32, text: &str) -> bool {
+        self.ledger
+            .as_ref()
+            .is_some_and(|ledger| ledger.is_rewritten(chat_id, message_id, text))
+    }
+
+    fn forget(&mut self, chat_id: i64, message_id: i32) {
+        self.entries.remove(&(chat_id, message_id));
+    }
+
+    fn remove(&mut self, deleted: DeletedMessage) {
+        self.entries
+            .retain(|&(chat_id, message_id), _| !deleted.matches(chat_id, message_id));
+    }
+
+    fn evict_expired(&mut self) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| entry.seen_at.elapsed() <= ttl);
+    }
+}
+
+/// A message id from `Update::MessageDeleted`. Telegram only names the chat for channels and
+/// supergroups; ids in private chats and basic groups are unique per account, so those
+/// deletions have no `chat_id` and match any non-channel chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DeletedMessage {
+    chat_id: Option<i64>,
+    message_id: i32,
+}
+
+impl DeletedMessage {
+    fn matches(self, chat_id: i64, message_id: i32) -> bool {
+        self.message_id == message_id && deletion_applies_to_chat(self.chat_id, chat_id)
+    }
+}
+
+fn deletion_applies_to_chat(deleted_in: Option<i64>, chat_id: i64) -> bool {
+    deleted_in.map_or(!is_channel_dialog_id(chat_id), |deleted_in| {
+        deleted_in == chat_id
+    })
+}
+
+/// Whether a deletion may be in one of `monitored`: its own chat, or for a deletion without
+/// a chat id, any private chat or basic group.
+fn deletion_in_monitored_chats(
+    deleted_in: Option<i64>,
+    mut monitored: impl Iterator<Item = i64>,
+) -> bool {
+    monitored.any(|chat_id| deletion_applies_to_chat(deleted_in, chat_id))
+}
+
+/// Recently deleted messages, checked before a rewrite starts and again right before the edit.
+struct DeletedMessages {
+    entries: HashMap<DeletedMessage, Instant>,
+    ttl: Duration,
+}
+
+impl DeletedMessages {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+        }
+    }
+
+    fn insert(&mut self, deleted: DeletedMessage) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, deleted_at| deleted_at.elapsed() <= ttl);
+        self.entries.insert(deleted, Instant::now());
+    }
+
+    fn contains(&self, chat_id: i64, message_id: i32) -> bool {
+        let in_chat = DeletedMessage {
+            chat_id: Some(chat_id),
+            message_id,
+        };
+        let unnamed_chat = DeletedMessage {
+            chat_id: None,
+            message_id,
+        };
+        self.entries.contains_key(&in_chat)
+            || (!is_channel_dialog_id(chat_id) && self.entries.contains_key(&unnamed_chat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ALBUM_WINDOW, ActiveRewriteState, AlbumBuffer, BackfillReport, CATCH_UP_BATCH_WINDOW,
+        CatchUpBatches, ChatCounters, ChatStat, ChatStats, ContextCache, ContextFetch,
+        ContextScope, DedupeCache, DeletedMessage, DeletedMessages, EDIT_RETRY_QUEUE_LIMIT,
+        EditCandidate, EditDecision, EditRetries, EditThrottle, FailureReason, LoadedContext,
+        MessageKey, PendingEdit, PendingResend, PreparedRequest, ProcessMessageRuntime,
+        RECONNECT_BACKOFF_MAX, RateLimiter, RequestDone, RewriteEvent, RewriteHooks,
+        RewriteOutcome, RewriteRequest, RewriteSettings, RewriteStage, RewriteWorkers,
+        SCHEDULED_REWRITES_LIMIT, STREAM_ERROR_RECONNECT_THRESHOLD, ScheduledRewrites,
+        SlowModeQueue, StreamRecovery, TELEGRAM_MESSAGE_MAX_CHARS, WatchedConfigPaths, WorkerDone,
+        change_ratio, channel_dialog_id, chat_stats_table, check_dropped_links, command_ack,
+        deletion_in_monitored_chats, event_targets_watched_config, is_historical_catch_up_message,
+        is_relevant_config_event_kind, llm_target_changed, load_hot_config_with_retries,
+        normalize_rewrite_override, reconnect_backoff, reload_config_now, report_dry_run,
+        request_rewrite, review_edit, spawn_config_watcher, split_album,
+        truncate_to_telegram_limit, update_kind_name,
+    };
+    use crate::chat_command::{ChatCommand, status_text};
+    use crate::config::{
+        HotConfig, NetworkConfig, OpenAiConfig, PRIMARY_ACCOUNT_NAME, ParseMode, ProviderConfig,
+        ReloadConfig, RestoreDroppedLinks, RewriteConfig, SAVED_MESSAGES_CHAT_ID, load_hot_config,
+    };
+    use crate::context::{ContextEntry, ContextMessage};
+    use crate::ledger::RewriteLedger;
+    use crate::llm::{LlmRewriter, Rewrite, RewriteFuture, TokenUsage};
+    use crate::metrics::{self, Metrics};
+    use crate::refusal::RefusalDetector;
+    use crate::status_server::StatusServer;
+    use crate::telegram::ScheduledMessage;
+    use crate::usage::UsageTracker;
+    use anyhow::anyhow;
+    use grammers_client::tl;
+    use grammers_client::update::Update;
+    use notify::{
+        Event, EventKind,
+        event::{AccessKind, CreateKind, ModifyKind, RemoveKind},
+    };
+    use std::collections::{HashMap, HashSet};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::{mpsc, watch};
+
+    fn watched_file(path: &Path) -> WatchedConfigPaths {
+        WatchedConfigPaths {
+            link: path.to_path_buf(),
+            target: path.to_path_buf(),
+        }
+    }
+
+    fn watcher_test_config(model: &str) -> String {
+        format!(
+            r#"
+[telegram]
+api_id = 12345
+api_hash = "hash"
+session_file = "session.bin"
+
+[openai]
+api_key = "sk-test"
+model = "{model}"
+
+[rewrite]
+chats = [-1001234567890]
+system_prompt = "rewrite this"
+"#
+        )
+    }
+
+    async fn wait_for_model(rx: &mut watch::Receiver<HotConfig>, model: &str) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if rx.borrow_and_update().provider.model() == model {
+                    return;
+                }
+                rx.changed()
+                    .await
+                    .expect("watcher should keep sender alive");
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("config reload to model {model} was not observed"));
+    }
+
+    #[test]
+    fn relevant_config_event_kinds_are_detected() {
+        assert!(is_relevant_config_event_kind(&EventKind::Modify(
+            ModifyKind::Any
+        )));
+        assert!(is_relevant_config_event_kind(&EventKind::Create(
+            CreateKind::Any
+        )));
+        assert!(is_relevant_config_event_kind(&EventKind::Remove(
+            RemoveKind::Any
+        )));
+        assert!(is_relevant_config_event_kind(&EventKind::Any));
+        assert!(!is_relevant_config_event_kind(&EventKind::Access(
+            AccessKind::Any
+        )));
+    }
+
+    #[test]
+    fn event_targets_watched_config_by_exact_path() {
+        let watched_parent = std::env::temp_dir().join("brainrot_watcher_exact_match");
+        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
+        let watched_path = watched_parent.join("config.toml");
+        let event = Event {
+            kind: EventKind::Modify(ModifyKind::Any),
+            paths: vec![watched_path.clone()],
+            attrs: Default::default(),
+        };
+        assert!(event_targets_watched_config(&event, &watched_path));
+        std::fs::remove_dir_all(&watched_parent).ok();
+    }
+
+    #[test]
+    fn event_targets_watched_config_by_normalized_parent_path() {
+        let watched_parent = std::env::temp_dir().join("brainrot_watcher_normalized_parent");
+        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
+        let watched_path = watched_parent.join("config.toml");
+        let path_with_dot = watched_parent.join(".").join("config.toml");
+        let event = Event {
+            kind: EventKind::Create(CreateKind::Any),
+            paths: vec![path_with_dot],
+            attrs: Default::default(),
+        };
+        assert!(event_targets_watched_config(&event, &watched_path));
+        std::fs::remove_dir_all(&watched_parent).ok();
+    }
+
+    #[test]
+    fn event_does_not_target_other_files() {
+        let watched_parent = std::env::temp_dir().join("brainrot_watcher_other_files");
+        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
+        let watched_path = watched_parent.join("config.toml");
+        let event = Event {
+            kind: EventKind::Modify(ModifyKind::Any),
+            paths: vec![watched_parent.join("other.toml")],
+            attrs: Default::default(),
+        };
+        assert!(!event_targets_watched_config(&event, &watched_path));
+        std::fs::remove_dir_all(&watched_parent).ok();
+    }
+
+    #[test]
+    fn event_targets_watched_config_through_symlink_or_target() {
+        let watched = WatchedConfigPaths {
+            link: Path::new("/home/user/app/config.toml").to_path_buf(),
+            target: Path::new("/home/user/dotfiles/app/config.toml").to_path_buf(),
+        };
+        for path in [&watched.link, &watched.target] {
+            let event = Event {
+                kind: EventKind::Modify(ModifyKind::Any),
+                paths: vec![path.clone()],
+                attrs: Default::default(),
+            };
+            assert!(event_targets_watched_config(&event, &watched));
+        }
+        assert_eq!(
+            watched.parent_dirs(),
+            vec![
+                Path::new("/home/user/app").to_path_buf(),
+                Path::new("/home/user/dotfiles/app").to_path_buf(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn config_watcher_reloads_when_symlink_target_changes() {
+        let root = std::env::temp_dir().join("brainrot_watcher_symlink_target");
+        std::fs::remove_dir_all(&root).ok();
+        let real_dir = root.join("dotfiles");
+        let link_dir = root.join("app");
+        std::fs::create_dir_all(&real_dir).expect("real dir should exist");
+        std::fs::create_dir_all(&link_dir).expect("link dir should exist");
+        let target = real_dir.join("config.toml");
+        let link = link_dir.join("config.toml");
+        std::fs::write(&target, watcher_test_config("model-a")).expect("write target");
+        std::os::unix::fs::symlink(&target, &link).expect("create symlink");
+
+        let initial = load_hot_config(&link).expect("initial config should load");
+        let (hot_tx, mut hot_rx) = watch::channel(initial);
+        let (error_tx, _error_rx) = mpsc::unbounded_channel();
+        let _watcher = spawn_config_watcher(&link, ReloadConfig::default(), hot_tx, error_tx)
+            .expect("watcher should start");
+
+        std::fs::write(&target, watcher_test_config("model-b")).expect("modify target");
+        wait_for_model(&mut hot_rx, "model-b").await;
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn config_watcher_follows_replaced_symlink() {
+        let root = std::env::temp_dir().join("brainrot_watcher_symlink_replaced");
+        std::fs::remove_dir_all(&root).ok();
+        let old_dir = root.join("old");
+        let new_dir = root.join("new");
+        let link_dir = root.join("app");
+        for dir in [&old_dir, &new_dir, &link_dir] {
+            std::fs::create_dir_all(dir).expect("dir should exist");
+        }
+        let old_target = old_dir.join("config.toml");
+        let new_target = new_dir.join("config.toml");
+        let link = link_dir.join("config.toml");
+        std::fs::write(&old_target, watcher_test_config("model-a")).expect("write old");
+        std::fs::write(&new_target, watcher_test_config("model-b")).expect("write new");
+        std::os::unix::fs::symlink(&old_target, &link).expect("create symlink");
+
+        let initial = load_hot_config(&link).expect("initial config should load");
+        let (hot_tx, mut hot_rx) = watch::channel(initial);
+        let (error_tx, _error_rx) = mpsc::unbounded_channel();
+        let _watcher = spawn_config_watcher(&link, ReloadConfig::default(), hot_tx, error_tx)
+            .expect("watcher should start");
+
+        std::fs::remove_file(&link).expect("remove old symlink");
+        std::os::unix::fs::symlink(&new_target, &link).expect("re-point symlink");
+        wait_for_model(&mut hot_rx, "model-b").await;
+
+        std::fs::write(&new_target, watcher_test_config("model-c")).expect("modify new target");
+        wait_for_model(&mut hot_rx, "model-c").await;
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn reload_retries_until_half_written_config_is_complete() {
+        let dir = std::env::temp_dir().join("brainrot_reload_retry_recovers");
+        std::fs::create_dir_all(&dir).expect("dir should exist");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[telegram]\napi_id = ").expect("write partial config");
+
+        let writer_path = path.clone();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            std::fs::write(&writer_path, watcher_test_config("model-a")).expect("finish write");
+        });
+        let reload = ReloadConfig {
+            debounce_ms: 0,
+            max_retries: 10,
+            retry_backoff_ms: 10,
+        };
+        let config = load_hot_config_with_retries(&path, reload)
+            .await
+            .expect("retry should pick up the completed config");
+        assert_eq!(config.provider.model(), "model-a");
+        writer.await.expect("writer task should finish");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn reload_gives_up_after_max_retries() {
+        let dir = std::env::temp_dir().join("brainrot_reload_retry_gives_up");
+        std::fs::create_dir_all(&dir).expect("dir should exist");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "not valid toml [").expect("write invalid config");
+
+        let reload = ReloadConfig {
+            debounce_ms: 0,
+            max_retries: 2,
+            retry_backoff_ms: 1,
+        };
+        load_hot_config_with_retries(&path, reload)
+            .await
+            .expect_err("persistently invalid config should fail");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn manual_reload_confirms_unchanged_config_and_reports_errors() {
+        let dir = std::env::temp_dir().join("brainrot_manual_reload");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).expect("dir should exist");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, watcher_test_config("model-a")).expect("write config");
+
+        let initial = load_hot_config(&path).expect("initial config should load");
+        let (hot_tx, mut hot_rx) = watch::channel(initial);
+        let (error_tx, mut error_rx) = mpsc::unbounded_channel();
+
+        reload_config_now(&path, &hot_tx, &error_tx);
+        assert!(hot_rx.has_changed().expect("sender should be open"));
+        assert_eq!(hot_rx.borrow_and_update().provider.model(), "model-a");
+
+        std::fs::write(&path, "[openai\n").expect("write broken config");
+        reload_config_now(&path, &hot_tx, &error_tx);
+        assert!(!hot_rx.has_changed().expect("sender should be open"));
+        let error = error_rx
+            .try_recv()
+            .expect("reload failure should be reported");
+        assert!(error.contains("failed to parse config.toml"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn config_watcher_reports_failure_after_retries() {
+        let dir = std::env::temp_dir().join("brainrot_watcher_reports_failure");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).expect("dir should exist");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, watcher_test_config("model-a")).expect("write config");
+
+        let initial = load_hot_config(&path).expect("initial config should load");
+        let (hot_tx, _hot_rx) = watch::channel(initial);
+        let (error_tx, mut error_rx) = mpsc::unbounded_channel();
+        let reload = ReloadConfig {
+            debounce_ms: 10,
+            max_retries: 1,
+            retry_backoff_ms: 10,
+        };
+        let _watcher =
+            spawn_config_watcher(&path, reload, hot_tx, error_tx).expect("watcher should start");
+
+        std::fs::write(&path, "[openai\n").expect("write broken config");
+        let error = tokio::time::timeout(Duration::from_secs(10), error_rx.recv())
+            .await
+            .expect("reload failure should be reported")
+            .expect("error channel should stay open");
+        assert!(error.contains("failed to parse config.toml"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn active_rewrite_state_rejects_empty_openai_api_key() {
+        let hot = HotConfig {
+            provider: ProviderConfig::OpenAi(OpenAiConfig {
+                api_key: "   ".into(),
+                model: "gpt-4.1-mini".to_owned(),
+                request_timeout_seconds: 5,
+                connect_timeout_seconds: 5,
+                retry: Default::default(),
+                fallback_models: vec![],
+                api_base: "https://api.openai.com/v1".to_owned(),
+                api_flavor: Default::default(),
+                stream: false,
+                structured_output: false,
+                rate_limit: None,
+                cost_per_million_input: None,
+                cost_per_million_output: None,
+                cache_entries: 0,
+                cache_ttl_seconds: 3_600,
+                require_healthy_at_startup: false,
+                context_style: Default::default(),
+                organization: None,
+                project: None,
+                extra_headers: Default::default(),
+                reasoning_effort: None,
+                stop: Vec::new(),
+            }),
+            rewrite: RewriteConfig {
+                chats: vec![-1001234567890],
+                system_prompt: "rewrite this".to_owned(),
+                ..Default::default()
+            },
+            account_chats: Default::default(),
+        };
+        let result =
+            ActiveRewriteState::from_hot_config(hot, &NetworkConfig::default(), None, None);
+        assert!(result.is_err(), "empty api key should fail");
+        let err = match result {
+            Ok(_) => unreachable!("checked above"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("api key"));
+    }
+
+    #[test]
+    fn active_rewrite_state_keeps_rate_limiter_across_unchanged_reload() {
+        let hot_config = |requests_per_minute, system_prompt: &str| HotConfig {
+            provider: ProviderConfig::OpenAi(OpenAiConfig {
+                api_key: "sk-test".into(),
+                model: "gpt-4.1-mini".to_owned(),
+                request_timeout_seconds: 5,
+                connect_timeout_seconds: 5,
+                retry: Default::default(),
+                fallback_models: vec![],
+                api_base: "https://api.openai.com/v1".to_owned(),
+                api_flavor: Default::default(),
+                stream: false,
+                structured_output: false,
+                rate_limit: Some(crate::config::RateLimitConfig {
+                    requests_per_minute,
+                    tokens_per_minute: None,
+                    log_wait_threshold_ms: 1_000,
+                }),
+                cost_per_million_input: None,
+                cost_per_million_output: None,
+                cache_entries: 0,
+                cache_ttl_seconds: 3_600,
+                require_healthy_at_startup: false,
+                context_style: Default::default(),
+                organization: None,
+                project: None,
+                extra_headers: Default::default(),
+                reasoning_effort: None,
+                stop: Vec::new(),
+            }),
+            rewrite: RewriteConfig {
+                chats: vec![-1001234567890],
+                system_prompt: system_prompt.to_owned(),
+                ..Default::default()
+            },
+            account_chats: Default::default(),
+        };
+        let network = NetworkConfig::default();
+        let active = ActiveRewriteState::from_hot_config(hot_config(60, "a"), &network, None, None)
+            .expect("state should build");
+        let reloaded =
+            ActiveRewriteState::from_hot_config(hot_config(60, "b"), &network, Some(&active), None)
+                .expect("state should build");
+        let changed = ActiveRewriteState::from_hot_config(
+            hot_config(30, "b"),
+            &network,
+            Some(&reloaded),
+            None,
+        )
+        .expect("state should build");
+
+        let limiter = |state: &ActiveRewriteState| {
+            Arc::clone(
+                state
+                    .shared
+                    .rate_limiter
+                    .as_ref()
+                    .expect("limiter should exist"),
+            )
+        };
+        assert!(Arc::ptr_eq(&limiter(&active), &limiter(&reloaded)));
+        assert!(!Arc::ptr_eq(&limiter(&reloaded), &limiter(&changed)));
+    }
+
+    #[test]
+    fn active_rewrite_state_keeps_chats_per_account() {
+        let hot = HotConfig {
+            provider: ProviderConfig::Ollama(crate::config::OllamaConfig {
+                url: "http://localhost:11434".to_owned(),
+                model: "llama3".to_owned(),
+                timeout_seconds: 120,
+            }),
+            rewrite: RewriteConfig {
+                chats: vec![-1001, -1002],
+                system_prompt: "rewrite this".to_owned(),
+                ..Default::default()
+            },
+            account_chats: [("work".to_owned(), vec![-1002, -1003])].into(),
+        };
+        let active = ActiveRewriteState::from_hot_config(
+            hot,
+            &NetworkConfig::default(),
+            None,
+            Some("fixed"),
+        )
+        .expect("state should build");
+
+        assert_eq!(
+            active.monitored_chats(PRIMARY_ACCOUNT_NAME),
+            HashSet::from([-1001, -1002])
+        );
+        assert_eq!(
+            active.monitored_chats("work"),
+            HashSet::from([-1002, -1003])
+        );
+        assert!(active.monitored_chats("removed").is_empty());
+        assert_eq!(
+            active.all_monitored_chats(),
+            HashSet::from([-1001, -1002, -1003])
+        );
+    }
+
+    #[test]
+    fn active_rewrite_state_resolves_saved_messages_per_account() {
+        let hot = HotConfig {
+            provider: ProviderConfig::Ollama(crate::config::OllamaConfig {
+                url: "http://localhost:11434".to_owned(),
+                model: "llama3".to_owned(),
+                timeout_seconds: 120,
+            }),
+            rewrite: RewriteConfig {
+                chats: vec![-1001, SAVED_MESSAGES_CHAT_ID],
+                system_prompt: "rewrite this".to_owned(),
+                ..Default::default()
+            },
+            account_chats: [("work".to_owned(), vec![SAVED_MESSAGES_CHAT_ID])].into(),
+        };
+        let mut active = ActiveRewriteState::from_hot_config(
+            hot,
+            &NetworkConfig::default(),
+            None,
+            Some("fixed"),
+        )
+        .expect("state should build");
+        active.resolve_saved_messages(&HashMap::from([
+            (PRIMARY_ACCOUNT_NAME.to_owned(), 111),
+            ("work".to_owned(), 222),
+        ]));
+
+        assert_eq!(
+            active.monitored_chats(PRIMARY_ACCOUNT_NAME),
+            HashSet::from([-1001, 111])
+        );
+        assert_eq!(active.monitored_chats("work"), HashSet::from([222]));
+    }
+
+    #[test]
+    fn llm_target_changes_only_with_model_key_or_endpoint() {
+        let base = OpenAiConfig {
+            api_key: "sk-test".into(),
+            model: "gpt-4.1-mini".to_owned(),
+            request_timeout_seconds: 5,
+            connect_timeout_seconds: 5,
+            retry: Default::default(),
+            fallback_models: vec![],
+            api_base: "https://api.openai.com/v1".to_owned(),
+            api_flavor: Default::default(),
+            stream: false,
+            structured_output: false,
+            rate_limit: None,
+            cost_per_million_input: None,
+            cost_per_million_output: None,
+            cache_entries: 0,
+            cache_ttl_seconds: 3_600,
+            require_healthy_at_startup: false,
+            context_style: Default::default(),
+            organization: None,
+            project: None,
+            extra_headers: Default::default(),
+            reasoning_effort: None,
+            stop: Vec::new(),
+        };
+        let original = ProviderConfig::OpenAi(base.clone());
+        let edited = |edit: fn(&mut OpenAiConfig)| {
+            let mut openai = base.clone();
+            edit(&mut openai);
+            ProviderConfig::OpenAi(openai)
+        };
+
+        assert!(!llm_target_changed(
+            &original,
+            &edited(|openai| openai.cache_entries = 10)
+        ));
+        assert!(llm_target_changed(
+            &original,
+            &edited(|openai| openai.model = "gpt-4o-mini".to_owned())
+        ));
+        assert!(llm_target_changed(
+            &original,
+            &edited(|openai| openai.api_key = "sk-other".into())
+        ));
+        assert!(llm_target_changed(
+            &original,
+            &edited(|openai| openai.api_base = "http://localhost:8080/v1".to_owned())
+        ));
+    }
+
+    #[test]
+    fn catch_up_batches_group_by_scope_and_extend_flush_window() {
+        let chat = ContextScope {
+            chat_id: -100,
+            topic_root_id: None,
+        };
+        let topic = ContextScope {
+            chat_id: -100,
+            topic_root_id: Some(7),
+        };
+        let start = tokio::time::Instant::now();
+        let mut batches = CatchUpBatches::new();
+        batches.push(chat, 1, start);
+        batches.push(topic, 2, start);
+        batches.push(chat, 3, start + Duration::from_millis(100));
+
+        assert_eq!(batches.len(), 3);
+        assert_eq!(
+            batches.flush_at,
+            Some(start + Duration::from_millis(100) + CATCH_UP_BATCH_WINDOW)
+        );
+        assert_eq!(batches.take(), vec![(chat, vec![1, 3]), (topic, vec![2])]);
+        assert!(batches.is_empty());
+        assert_eq!(batches.flush_at, None);
+    }
+
+    #[test]
+    fn albums_group_by_id_until_quiet_and_keep_only_the_caption() {
+        let start = tokio::time::Instant::now();
+        let mut albums = AlbumBuffer::new();
+        albums.push((-100, 9), (1, ""), start);
+        albums.push(
+            (-100, 9),
+            (2, "caption"),
+            start + Duration::from_millis(200),
+        );
+        albums.push((-200, 9), (5, ""), start + Duration::from_millis(300));
+        albums.push((-100, 9), (3, ""), start + Duration::from_millis(400));
+
+        assert_eq!(albums.len(), 2);
+        assert_eq!(
+            albums.next_complete_at(),
+            Some(start + Duration::from_millis(300) + ALBUM_WINDOW)
+        );
+        assert!(
+            albums
+                .take_complete(
+                    start + Duration::from_millis(300) + ALBUM_WINDOW - Duration::from_millis(1)
+                )
+                .is_empty()
+        );
+        let complete = albums.take_complete(start + Duration::from_millis(400) + ALBUM_WINDOW);
+        assert_eq!(
+            complete,
+            vec![vec![(1, ""), (2, "caption"), (3, "")], vec![(5, "")]]
+        );
+        assert!(albums.is_empty());
+
+        let (captioned, others) = split_album(complete[0].clone(), |(_, text)| *text);
+        assert_eq!(captioned, (2, "caption"));
+        assert_eq!(others, vec![(1, ""), (3, "")]);
+        let (captioned, others) = split_album(complete[1].clone(), |(_, text)| *text);
+        assert_eq!(captioned, (5, ""));
+        assert!(others.is_empty());
+    }
+
+    #[test]
+    fn edit_throttle_spaces_edits_per_chat() {
+        let interval = Duration::from_millis(500);
+        let start = tokio::time::Instant::now();
+        let mut throttle = EditThrottle::default();
+
+        assert_eq!(throttle.reserve(-100, interval, start), Duration::ZERO);
+        assert_eq!(throttle.reserve(-200, interval, start), Duration::ZERO);
+        assert_eq!(throttle.reserve(-100, interval, start), interval);
+        assert_eq!(
+            throttle.reserve(-100, interval, start + Duration::from_millis(100)),
+            Duration::from_millis(900)
+        );
+        assert_eq!(
+            throttle.reserve(-200, interval, start + Duration::from_secs(5)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            throttle.reserve(-100, Duration::ZERO, start),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn scheduled_rewrites_match_only_messages_rewritten_when_scheduled() {
+        let mut scheduled = ScheduledRewrites::default();
+        scheduled.insert(-100, 7, "Rewritten draft.".to_owned());
+
+        // Never rewritten: another draft in the chat, or the same text in another chat.
+        assert!(!scheduled.take_sent(-100, "draft that was never rewritten"));
+        assert!(!scheduled.take_sent(-200, "Rewritten draft."));
+        assert!(scheduled.take_sent(-100, "Rewritten draft."));
+        assert!(!scheduled.take_sent(-100, "Rewritten draft."));
+
+        scheduled.insert(-100, 8, "First rewrite.".to_owned());
+        scheduled.insert(-100, 8, "Second rewrite.".to_owned());
+        assert!(!scheduled.take_sent(-100, "First rewrite."));
+        assert!(scheduled.take_sent(-100, "Second rewrite."));
+
+        for message_id in 0..=SCHEDULED_REWRITES_LIMIT as i32 {
+            scheduled.insert(-100, message_id, format!("rewrite {message_id}"));
+        }
+        assert_eq!(scheduled.entries.len(), SCHEDULED_REWRITES_LIMIT);
+        assert!(!scheduled.take_sent(-100, "rewrite 0"));
+        assert!(scheduled.take_sent(-100, "rewrite 1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn edit_throttle_sleeps_between_rapid_edits_in_one_chat() {
+        let interval = Duration::from_millis(500);
+        let start = tokio::time::Instant::now();
+        let mut throttle = EditThrottle::default();
+        let mut edited_at = Vec::new();
+        for chat_id in [-100, -200, -100] {
+            let wait = throttle.reserve(chat_id, interval, tokio::time::Instant::now());
+            tokio::time::sleep(wait).await;
+            edited_at.push((chat_id, tokio::time::Instant::now() - start));
+        }
+
+        assert_eq!(
+            edited_at,
+            vec![
+                (-100, Duration::ZERO),
+                (-200, Duration::ZERO),
+                (-100, interval)
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_retries_release_due_entries_in_order_and_stay_bounded() {
+        let start = tokio::time::Instant::now();
+        let mut retries = EditRetries::new(3);
+        assert_eq!(retries.push(start + Duration::from_secs(30), 1), Ok(()));
+        assert_eq!(retries.push(start + Duration::from_secs(5), 2), Ok(()));
+        assert_eq!(retries.push(start + Duration::from_secs(5), 3), Ok(()));
+        assert_eq!(retries.push(start + Duration::from_secs(1), 4), Err(4));
+
+        assert_eq!(
+            retries.next_retry_at(),
+            Some(start + Duration::from_secs(5))
+        );
+        assert!(retries.take_due(start).is_empty());
+        assert_eq!(
+            retries.take_due(start + Duration::from_secs(10)),
+            vec![2, 3]
+        );
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries.take_due(start + Duration::from_secs(30)), vec![1]);
+        assert!(retries.is_empty());
+        assert_eq!(retries.next_retry_at(), None);
+    }
+
+    #[test]
+    fn chat_stats_reset_per_window_and_keep_totals() {
+        let start = tokio::time::Instant::now();
+        let mut stats = ChatStats::new(start);
+        for stat in [
+            ChatStat::Seen,
+            ChatStat::Rewritten,
+            ChatStat::Seen,
+            ChatStat::Deduped,
+        ] {
+            stats.record(-100, stat);
+        }
+        stats.record(5, ChatStat::Seen);
+        stats.record(5, ChatStat::NotOutgoing);
+
+        let (window, rows) = stats.take_window(start + Duration::from_secs(60));
+        assert_eq!(window, Duration::from_secs(60));
+        let expected_first = ChatCounters {
+            seen: 2,
+            deduped: 1,
+            rewritten: 1,
+            ..ChatCounters::default()
+        };
+        assert_eq!(rows[0], (-100, expected_first, expected_first));
+        assert_eq!(rows[1].0, 5);
+
+        stats.record(-100, ChatStat::Seen);
+        stats.record(-100, ChatStat::EditFailed);
+        let (window, rows) = stats.take_window(start + Duration::from_secs(90));
+        assert_eq!(window, Duration::from_secs(30));
+        assert_eq!(
+            rows[0].1,
+            ChatCounters {
+                seen: 1,
+                edit_failed: 1,
+                ..ChatCounters::default()
+            }
+        );
+        assert_eq!(rows[0].2.seen, 3);
+        assert_eq!(rows[0].2.rewritten, 1);
+        // Idle chats keep their totals with an empty window.
+        assert_eq!(rows[1].1, ChatCounters::default());
+        assert_eq!(rows[1].2.not_outgoing, 1);
+
+        let table = chat_stats_table(&rows);
+        assert!(table.contains("rewritten"), "{table}");
+        assert!(table.contains("1 (3)"), "{table}");
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_the_cap() {
+        assert_eq!(reconnect_backoff(1), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(2), Duration::from_secs(2));
+        assert_eq!(reconnect_backoff(4), Duration::from_secs(8));
+        assert_eq!(reconnect_backoff(7), RECONNECT_BACKOFF_MAX);
+        assert_eq!(reconnect_backoff(100), RECONNECT_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn stream_recovery_reconnects_on_lost_connections_or_repeated_errors() {
+        let start = tokio::time::Instant::now();
+        let mut recovery = StreamRecovery::default();
+        for _ in 1..STREAM_ERROR_RECONNECT_THRESHOLD {
+            assert_eq!(recovery.record_error(false, start), None);
+        }
+        assert_eq!(
+            recovery.record_error(false, start),
+            Some(Duration::from_secs(1))
+        );
+        assert!(!recovery.is_due(start));
+        assert!(recovery.is_due(start + Duration::from_secs(1)));
+        // Already waiting to reconnect: nothing new is scheduled.
+        assert_eq!(recovery.record_error(true, start), None);
+
+        recovery.reconnected();
+        assert_eq!(recovery.reconnect_at, None);
+        assert_eq!(
+            recovery.record_error(true, start),
+            Some(Duration::from_secs(2)),
+            "backoff keeps growing until an update arrives"
+        );
+
+        recovery.reconnected();
+        recovery.record_update();
+        assert_eq!(recovery.consecutive_errors, 0);
+        assert_eq!(
+            recovery.record_error(true, start),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn dedupe_cache_scopes_entries_by_chat_id() {
+        let mut cache = DedupeCache::new(Duration::from_secs(300));
+        let message_id = 42;
+
+        assert!(!cache.contains(1, message_id, "original"));
+        cache.insert(1, message_id, "rewritten");
+        assert!(cache.contains(1, message_id, "rewritten"));
+        assert!(
+            !cache.contains(2, message_id, "rewritten"),
+            "same message id in another chat must not dedupe"
+        );
     }
 
-    fn evict_expired(&mut self) {
-        let ttl = self.ttl;
-        self.entries.retain(|_, seen_at| seen_at.elapsed() <= ttl);
+    #[test]
+    fn dedupe_cache_recognizes_echo_of_applied_rewrite() {
+        let mut cache = DedupeCache::new(Duration::from_secs(300));
+        cache.insert(1, 42, "Hello there.\n");
+
+        assert!(cache.is_own_edit(1, 42, "Hello there."));
+        assert!(!cache.is_own_edit(1, 42, "Hello there!"));
+        assert!(!cache.is_own_edit(2, 42, "Hello there."));
+
+        cache.forget(1, 42);
+        assert!(!cache.contains(1, 42, "Hello there."));
+        assert!(!cache.is_own_edit(1, 42, "Hello there."));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        ActiveRewriteState, ContextCache, ContextScope, DedupeCache, event_targets_watched_config,
-        is_historical_catch_up_message, is_relevant_config_event_kind, normalize_rewrite_override,
-        truncate_to_telegram_limit, update_kind_name,
-    };
-    use crate::config::{HotConfig, RewriteConfig};
-    use crate::context::{ContextEntry, ContextMessage};
-    use grammers_client::tl;
-    use grammers_client::update::Update;
-    use notify::{
-        Event, EventKind,
-        event::{AccessKind, CreateKind, ModifyKind, RemoveKind},
-    };
-    use std::time::Duration;
+    #[tokio::test]
+    async fn dedupe_cache_remembers_rewrites_from_an_earlier_run() {
+        let path = std::env::temp_dir().join(format!(
+            "brainrot-dedupe-ledger-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let retention = Duration::from_secs(3600);
+        let mut ledger = RewriteLedger::open(&path, retention, retention)
+            .await
+            .expect("ledger should open");
+        let mut cache = DedupeCache::new(Duration::from_secs(300));
+        cache.attach_ledger(ledger.for_account(PRIMARY_ACCOUNT_NAME));
+        cache.insert(1, 42, "Hello there.");
+        drop(cache);
+        ledger.close().await;
+
+        let mut ledger = RewriteLedger::open(&path, retention, retention)
+            .await
+            .expect("ledger should reopen");
+        let mut cache = DedupeCache::new(Duration::from_secs(300));
+        cache.attach_ledger(ledger.for_account(PRIMARY_ACCOUNT_NAME));
+        assert!(cache.contains(1, 42, "Hello there."));
+        assert!(cache.is_own_edit(1, 42, "Hello there."));
+        assert!(
+            !cache.contains(1, 42, "hello there, edited by hand"),
+            "a manual edit after the restart is rewritten again"
+        );
+        drop(cache);
+        ledger.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn scheduled_rewrites_survive_a_restart_until_sent() {
+        let path = std::env::temp_dir().join(format!(
+            "brainrot-scheduled-ledger-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let retention = Duration::from_secs(3600);
+        let reopen = || async {
+            RewriteLedger::open(&path, retention, retention)
+                .await
+                .expect("ledger should open")
+        };
+
+        let mut ledger = reopen().await;
+        let mut scheduled = ScheduledRewrites::default();
+        let (loaded, store) = ledger.scheduled_for_account(PRIMARY_ACCOUNT_NAME);
+        scheduled.attach_store(loaded, store);
+        scheduled.insert(-100, 7, "Rewritten draft.".to_owned());
+        scheduled.insert(-100, 8, "Sent before the restart.".to_owned());
+        assert!(scheduled.take_sent(-100, "Sent before the restart."));
+        drop(scheduled);
+        ledger.close().await;
+
+        let mut ledger = reopen().await;
+        let mut scheduled = ScheduledRewrites::default();
+        let (loaded, store) = ledger.scheduled_for_account(PRIMARY_ACCOUNT_NAME);
+        scheduled.attach_store(loaded, store);
+        assert!(ledger.scheduled_for_account("work").0.is_empty());
+        assert!(!scheduled.take_sent(-100, "Sent before the restart."));
+        assert!(scheduled.take_sent(-100, "Rewritten draft."));
+        drop(scheduled);
+        ledger.close().await;
+
+        // Sending it once is all; the next run doesn't skip it again.
+        let mut ledger = reopen().await;
+        assert!(
+            ledger
+                .scheduled_for_account(PRIMARY_ACCOUNT_NAME)
+                .0
+                .is_empty()
+        );
+        ledger.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
 
     #[test]
-    fn relevant_config_event_kinds_are_detected() {
-        assert!(is_relevant_config_event_kind(&EventKind::Modify(
-            ModifyKind::Any
-        )));
-        assert!(is_relevant_config_event_kind(&EventKind::Create(
-            CreateKind::Any
-        )));
-        assert!(is_relevant_config_event_kind(&EventKind::Remove(
-            RemoveKind::Any
-        )));
-        assert!(is_relevant_config_event_kind(&EventKind::Any));
-        assert!(!is_relevant_config_event_kind(&EventKind::Access(
-            AccessKind::Any
-        )));
+    fn deleted_messages_match_named_channels_and_unnamed_private_chats() {
+        let channel = channel_dialog_id(1234567890);
+        assert_eq!(channel, -1001234567890);
+        let mut deleted = DeletedMessages::new(Duration::from_secs(300));
+        deleted.insert(DeletedMessage {
+            chat_id: Some(channel),
+            message_id: 5,
+        });
+        deleted.insert(DeletedMessage {
+            chat_id: None,
+            message_id: 9,
+        });
+
+        assert!(deleted.contains(channel, 5));
+        assert!(!deleted.contains(channel_dialog_id(42), 5));
+        assert!(!deleted.contains(777, 5));
+        assert!(deleted.contains(777, 9));
+        assert!(deleted.contains(-4242, 9));
+        assert!(
+            !deleted.contains(channel, 9),
+            "deletions without a chat id never apply to channels"
+        );
     }
 
     #[test]
-    fn event_targets_watched_config_by_exact_path() {
-        let watched_parent = std::env::temp_dir().join("brainrot_watcher_exact_match");
-        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
-        let watched_path = watched_parent.join("config.toml");
-        let event = Event {
-            kind: EventKind::Modify(ModifyKind::Any),
-            paths: vec![watched_path.clone()],
-            attrs: Default::default(),
+    fn deletions_without_a_chat_id_only_count_while_a_non_channel_chat_is_monitored() {
+        let channel = channel_dialog_id(4242);
+        assert!(deletion_in_monitored_chats(
+            Some(channel),
+            [channel].into_iter()
+        ));
+        assert!(!deletion_in_monitored_chats(
+            Some(channel_dialog_id(42)),
+            [channel, 777].into_iter()
+        ));
+        assert!(!deletion_in_monitored_chats(None, [channel].into_iter()));
+        assert!(deletion_in_monitored_chats(
+            None,
+            [channel, 777].into_iter()
+        ));
+    }
+
+    #[test]
+    fn deleted_messages_are_purged_from_dedupe_and_context_caches() {
+        let deleted = DeletedMessage {
+            chat_id: None,
+            message_id: 2,
         };
-        assert!(event_targets_watched_config(&event, &watched_path));
-        std::fs::remove_dir_all(&watched_parent).ok();
+        let mut dedupe = DedupeCache::new(Duration::from_secs(300));
+        dedupe.insert(777, 2, "two");
+        dedupe.insert(777, 3, "three");
+        dedupe.remove(deleted);
+        assert!(!dedupe.contains(777, 2, "two"));
+        assert!(dedupe.contains(777, 3, "three"));
+
+        let mut cache = ContextCache::new(10);
+        let scope = ContextScope {
+            chat_id: 777,
+            topic_root_id: None,
+        };
+        for message_id in 1..=2 {
+            cache.record_message(
+                scope,
+                message_id,
+                ContextMessage {
+                    sender_name: "Alice".to_owned(),
+                    text: format!("message {message_id}"),
+                    is_own: false,
+                },
+            );
+        }
+        cache.remove_messages(deleted.chat_id, &[deleted.message_id]);
+        let context = cache.recent_before(scope, 3, 10);
+        assert_eq!(
+            context
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["message 1"]
+        );
     }
 
     #[test]
-    fn event_targets_watched_config_by_normalized_parent_path() {
-        let watched_parent = std::env::temp_dir().join("brainrot_watcher_normalized_parent");
-        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
-        let watched_path = watched_parent.join("config.toml");
-        let path_with_dot = watched_parent.join(".").join("config.toml");
-        let event = Event {
-            kind: EventKind::Create(CreateKind::Any),
-            paths: vec![path_with_dot],
-            attrs: Default::default(),
+    fn context_cache_finds_reply_targets_in_any_topic_of_the_chat() {
+        let mut cache = ContextCache::new(10);
+        let topic = ContextScope {
+            chat_id: -100,
+            topic_root_id: Some(7),
         };
-        assert!(event_targets_watched_config(&event, &watched_path));
-        std::fs::remove_dir_all(&watched_parent).ok();
+        let message = ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "lunch?".to_owned(),
+            is_own: false,
+        };
+        cache.record_message(topic, 12, message.clone());
+
+        assert_eq!(cache.find(-100, 12), Some(message));
+        assert_eq!(cache.find(-100, 13), None);
+        assert_eq!(cache.find(-200, 12), None);
     }
 
     #[test]
-    fn event_does_not_target_other_files() {
-        let watched_parent = std::env::temp_dir().join("brainrot_watcher_other_files");
-        std::fs::create_dir_all(&watched_parent).expect("parent should exist");
-        let watched_path = watched_parent.join("config.toml");
-        let event = Event {
-            kind: EventKind::Modify(ModifyKind::Any),
-            paths: vec![watched_parent.join("other.toml")],
-            attrs: Default::default(),
+    fn context_cache_remove_messages_covers_every_topic_of_the_chat() {
+        let chat_id = channel_dialog_id(1234567890);
+        let scopes = [None, Some(7), Some(9)].map(|topic_root_id| ContextScope {
+            chat_id,
+            topic_root_id,
+        });
+        let other_chat = ContextScope {
+            chat_id: channel_dialog_id(42),
+            topic_root_id: None,
         };
-        assert!(!event_targets_watched_config(&event, &watched_path));
-        std::fs::remove_dir_all(&watched_parent).ok();
+        let mut cache = ContextCache::new(10);
+        for (index, scope) in scopes.into_iter().chain([other_chat]).enumerate() {
+            for message_id in [10, 20] {
+                cache.record_message(
+                    scope,
+                    message_id + index as i32,
+                    ContextMessage {
+                        sender_name: "Alice".to_owned(),
+                        text: format!("message {message_id}"),
+                        is_own: false,
+                    },
+                );
+            }
+        }
+
+        cache.remove_messages(Some(chat_id), &[10, 21, 22, 13]);
+
+        let texts = |scope| {
+            cache
+                .recent_before(scope, 0, 10)
+                .into_iter()
+                .map(|message| message.text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(scopes[0]), vec!["message 20"]);
+        assert_eq!(texts(scopes[1]), vec!["message 10"]);
+        assert_eq!(texts(scopes[2]), vec!["message 10"]);
+        assert_eq!(
+            texts(other_chat),
+            vec!["message 10", "message 20"],
+            "other chats keep messages with the same ids"
+        );
+    }
+
+    #[test]
+    fn rate_limiter_allows_burst_then_refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(Some(2));
+        assert!(limiter.try_acquire(1, start));
+        assert!(limiter.try_acquire(1, start));
+        assert!(!limiter.try_acquire(1, start));
+        assert!(!limiter.try_acquire(1, start + Duration::from_secs(29)));
+        assert!(limiter.try_acquire(1, start + Duration::from_secs(30)));
+        assert!(!limiter.try_acquire(1, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn rate_limiter_scopes_buckets_by_chat_id() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Some(1));
+        assert!(limiter.try_acquire(1, now));
+        assert!(!limiter.try_acquire(1, now));
+        assert!(limiter.try_acquire(2, now));
+    }
+
+    #[test]
+    fn rate_limiter_without_limit_always_allows() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(None);
+        for _ in 0..100 {
+            assert!(limiter.try_acquire(1, now));
+        }
+    }
+
+    #[test]
+    fn rate_limiter_keeps_state_when_reloaded_rate_is_unchanged() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Some(1));
+        assert!(limiter.try_acquire(1, now));
+        limiter.set_max_per_minute(Some(1));
+        limiter.retain_chats(&HashSet::from([1]));
+        assert!(!limiter.try_acquire(1, now));
+
+        limiter.set_max_per_minute(Some(2));
+        assert!(limiter.try_acquire(1, now));
+    }
+
+    #[test]
+    fn rate_limiter_applies_per_chat_overrides() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Some(1));
+        limiter.set_chat_limits(HashMap::from([(2, 3)]));
+        assert!(limiter.try_acquire(1, now));
+        assert!(!limiter.try_acquire(1, now));
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(2, now));
+        }
+        assert!(!limiter.try_acquire(2, now));
+
+        // Changing the default leaves the overridden chat's bucket alone, and an unchanged
+        // override keeps it across a reload.
+        limiter.set_max_per_minute(Some(5));
+        limiter.set_chat_limits(HashMap::from([(2, 3)]));
+        assert!(!limiter.try_acquire(2, now));
+        assert!(limiter.try_acquire(1, now));
+
+        limiter.set_chat_limits(HashMap::new());
+        assert!(limiter.try_acquire(2, now));
+    }
+
+    #[test]
+    fn catch_up_message_after_startup_is_not_historical() {
+        assert!(!is_historical_catch_up_message(105, 100, 0));
+    }
+
+    #[test]
+    fn catch_up_message_boundary_without_grace() {
+        assert!(!is_historical_catch_up_message(100, 100, 0));
+        assert!(is_historical_catch_up_message(99, 100, 0));
+    }
+
+    #[test]
+    fn catch_up_message_within_grace_window_is_not_historical() {
+        assert!(!is_historical_catch_up_message(98, 100, 5));
+        assert!(!is_historical_catch_up_message(95, 100, 5));
+        assert!(is_historical_catch_up_message(94, 100, 5));
+        assert!(!is_historical_catch_up_message(0, 100, u64::MAX));
+    }
+
+    #[test]
+    fn context_cache_returns_recent_messages_in_order_excluding_current() {
+        let mut cache = ContextCache::new(10);
+        let scope = ContextScope {
+            chat_id: -1001234567890,
+            topic_root_id: None,
+        };
+        cache.record_message(
+            scope,
+            1,
+            ContextMessage {
+                sender_name: "Alice".to_owned(),
+                text: "one".to_owned(),
+                is_own: false,
+            },
+        );
+        cache.record_message(
+            scope,
+            2,
+            ContextMessage {
+                sender_name: "Bob".to_owned(),
+                text: "two".to_owned(),
+                is_own: false,
+            },
+        );
+        cache.record_message(
+            scope,
+            3,
+            ContextMessage {
+                sender_name: "Me".to_owned(),
+                text: "three".to_owned(),
+                is_own: true,
+            },
+        );
+
+        let context = cache.recent_before(scope, 3, 2);
+        assert_eq!(
+            context,
+            vec![
+                ContextMessage {
+                    sender_name: "Alice".to_owned(),
+                    text: "one".to_owned(),
+                    is_own: false,
+                },
+                ContextMessage {
+                    sender_name: "Bob".to_owned(),
+                    text: "two".to_owned(),
+                    is_own: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn context_cache_marks_chat_hydrated_to_avoid_repeat_backfill() {
+        let mut cache = ContextCache::new(10);
+        let scope = ContextScope {
+            chat_id: -1001234567890,
+            topic_root_id: None,
+        };
+
+        assert!(cache.should_backfill(scope, 10, 0));
+        cache.mark_hydrated(scope);
+        assert!(!cache.should_backfill(scope, 10, 0));
+    }
+
+    #[test]
+    fn context_cache_snapshot_restores_into_a_smaller_cache() {
+        let mut cache = ContextCache::new(10);
+        let topic = ContextScope {
+            chat_id: -1001234567890,
+            topic_root_id: Some(7),
+        };
+        let hydrated_only = ContextScope {
+            chat_id: 5,
+            topic_root_id: None,
+        };
+        for (message_id, text) in [(1, "one"), (2, "two"), (3, "three")] {
+            cache.record_message(
+                topic,
+                message_id,
+                ContextMessage {
+                    sender_name: "Alice".to_owned(),
+                    text: text.to_owned(),
+                    is_own: false,
+                },
+            );
+        }
+        cache.mark_hydrated(hydrated_only);
+
+        let mut restored = ContextCache::new(2);
+        restored.restore(cache.snapshot());
+        assert_eq!(
+            restored
+                .recent_before(topic, 0, 10)
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["two", "three"]
+        );
+        assert!(restored.should_backfill(topic, 2, 0));
+        assert!(!restored.should_backfill(hydrated_only, 2, 0));
+    }
+
+    /// Echoes the input back after the given delay.
+    struct DelayedEcho(Duration);
+
+    impl LlmRewriter for DelayedEcho {
+        fn rewrite<'a>(
+            &'a self,
+            _system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            input: &'a str,
+        ) -> RewriteFuture<'a> {
+            Box::pin(async move {
+                tokio::time::sleep(self.0).await;
+                Ok(Rewrite {
+                    text: input.to_owned(),
+                    model: "echo".to_owned(),
+                    usage: None,
+                    refusal: None,
+                })
+            })
+        }
+    }
+
+    /// A request for `text` with no context to load.
+    fn ready_request(text: &str) -> std::future::Ready<PreparedRequest> {
+        std::future::ready(PreparedRequest {
+            request: RewriteRequest {
+                system_prompt: "prompt".to_owned(),
+                chat_metadata: None,
+                context: Vec::new(),
+                original: text.to_owned(),
+                deadline: None,
+            },
+            context: LoadedContext {
+                fetch: ContextFetch::Skipped,
+                count: 0,
+            },
+            typing: None,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rewrite_workers_keep_scope_order_and_cap_concurrency() {
+        let general = ContextScope {
+            chat_id: -100,
+            topic_root_id: None,
+        };
+        let topic = ContextScope {
+            chat_id: -100,
+            topic_root_id: Some(5),
+        };
+        let other_chat = ContextScope {
+            chat_id: -200,
+            topic_root_id: None,
+        };
+        let (mut workers, mut finished) =
+            RewriteWorkers::new(Arc::new(DelayedEcho(Duration::from_secs(10))), 2);
+        for (message_id, (scope, text)) in [
+            (general, "first"),
+            (general, "second"),
+            (topic, "topic"),
+            (other_chat, "other"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let key = MessageKey::new(PRIMARY_ACCOUNT_NAME, scope.chat_id, message_id as i32);
+            assert!(workers.submit(key, scope, text, text, ready_request(text)));
+        }
+        assert_eq!(workers.len(), 4);
+
+        let started = tokio::time::Instant::now();
+        let mut finished_at = Vec::new();
+        while !workers.is_empty() {
+            let Some(WorkerDone::Rewrite(done)) = finished.recv().await else {
+                panic!("workers should answer with a rewrite");
+            };
+            let text = workers
+                .complete(&done.key, done.id)
+                .expect("rewrite should be in flight");
+            assert_eq!(done.result.expect("echo should succeed").text, text);
+            finished_at.push((started.elapsed().as_secs(), text));
+        }
+        let finished_after = |text: &str| {
+            finished_at
+                .iter()
+                .find(|(_, finished)| *finished == text)
+                .map(|(secs, _)| *secs)
+        };
+
+        // Two permits: "first" runs alongside one other scope, "second" only after "first".
+        assert_eq!(finished_after("first"), Some(10));
+        assert_eq!(finished_after("second"), Some(20));
+        assert_eq!(
+            finished_at.iter().filter(|(secs, _)| *secs == 10).count(),
+            2
+        );
+        assert_eq!(
+            finished_at.iter().filter(|(secs, _)| *secs == 20).count(),
+            2
+        );
     }
 
-    #[test]
-    fn active_rewrite_state_rejects_empty_openai_api_key() {
-        let hot = HotConfig {
-            openai_api_key: "   ".to_owned(),
-            openai_model: "gpt-4.1-mini".to_owned(),
-            rewrite: RewriteConfig {
-                chats: vec![-1001234567890],
-                system_prompt: "rewrite this".to_owned(),
-                context_messages: 10,
-            },
+    #[tokio::test(start_paused = true)]
+    async fn rewrite_workers_drop_rewrites_of_an_outdated_text() {
+        let scope = ContextScope {
+            chat_id: -100,
+            topic_root_id: None,
         };
-        let result = ActiveRewriteState::from_hot_config(hot, Duration::from_secs(5));
-        assert!(result.is_err(), "empty api key should fail");
-        let err = match result {
-            Ok(_) => unreachable!("checked above"),
-            Err(err) => err,
+        let key = MessageKey::new(PRIMARY_ACCOUNT_NAME, scope.chat_id, 7);
+        let (mut workers, mut finished) =
+            RewriteWorkers::new(Arc::new(DelayedEcho(Duration::from_secs(10))), 2);
+        assert!(workers.submit(key.clone(), scope, "old", "old", ready_request("old")));
+        assert!(workers.is_rewriting(&key, "old"));
+        assert!(!workers.is_rewriting(&key, "new"));
+
+        // The same message in another account is another message.
+        let other_account = MessageKey::new("work", scope.chat_id, 7);
+        assert!(!workers.is_rewriting(&other_account, "old"));
+
+        workers.observe_text(&key, "new");
+        assert!(!workers.is_rewriting(&key, "old"));
+        assert!(workers.submit(key.clone(), scope, "new", "new", ready_request("new")));
+
+        let mut applied = Vec::new();
+        while !workers.is_empty() {
+            let Some(WorkerDone::Rewrite(done)) = finished.recv().await else {
+                panic!("workers should answer with a rewrite");
+            };
+            if let Some(text) = workers.complete(&done.key, done.id) {
+                assert!(workers.is_current(&done.key, done.id));
+                applied.push(text);
+                workers.finish(&done.key, done.id);
+            }
+        }
+        assert_eq!(applied, ["new"]);
+        assert!(!workers.is_rewriting(&key, "new"));
+
+        // An edit after the answer came back still makes the rewrite stale.
+        assert!(workers.submit(key.clone(), scope, "newer", "newer", ready_request("newer")));
+        let Some(WorkerDone::Rewrite(done)) = finished.recv().await else {
+            panic!("workers should answer with a rewrite");
         };
-        assert!(err.to_string().contains("api key"));
+        assert_eq!(workers.complete(&done.key, done.id), Some("newer"));
+        workers.observe_text(&key, "edited by hand");
+        assert!(!workers.is_current(&done.key, done.id));
     }
 
-    #[test]
-    fn dedupe_cache_scopes_entries_by_chat_id() {
-        let mut cache = DedupeCache::new(Duration::from_secs(300));
-        let message_id = 42;
+    /// Never answers; counts the requests dropped before they finished.
+    struct Hanging(Arc<AtomicUsize>);
 
-        assert!(!cache.contains(1, message_id));
-        cache.insert(1, message_id);
-        assert!(cache.contains(1, message_id));
-        assert!(
-            !cache.contains(2, message_id),
-            "same message id in another chat must not dedupe"
-        );
+    struct CountDrop(Arc<AtomicUsize>);
+
+    impl Drop for CountDrop {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
     }
 
-    #[test]
-    fn catch_up_message_after_startup_is_not_historical() {
-        assert!(!is_historical_catch_up_message(105, 100));
+    impl LlmRewriter for Hanging {
+        fn rewrite<'a>(
+            &'a self,
+            _system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            _input: &'a str,
+        ) -> RewriteFuture<'a> {
+            let dropped = CountDrop(Arc::clone(&self.0));
+            Box::pin(async move {
+                let _dropped = dropped;
+                std::future::pending().await
+            })
+        }
     }
 
-    #[test]
-    fn context_cache_returns_recent_messages_in_order_excluding_current() {
-        let mut cache = ContextCache::new(10);
+    #[tokio::test(start_paused = true)]
+    async fn rewrite_workers_cancel_the_request_of_a_deleted_message() {
         let scope = ContextScope {
-            chat_id: -1001234567890,
+            chat_id: -100,
             topic_root_id: None,
         };
-        cache.record_message(
-            scope,
-            1,
-            ContextMessage {
-                sender_name: "Alice".to_owned(),
-                text: "one".to_owned(),
-            },
-        );
-        cache.record_message(
-            scope,
-            2,
-            ContextMessage {
-                sender_name: "Bob".to_owned(),
-                text: "two".to_owned(),
-            },
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (mut workers, mut finished) =
+            RewriteWorkers::new(Arc::new(Hanging(Arc::clone(&dropped))), 2);
+        let key = MessageKey::new(PRIMARY_ACCOUNT_NAME, scope.chat_id, 7);
+        assert!(workers.submit(key.clone(), scope, "text", "text", ready_request("text")));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+
+        let elsewhere = DeletedMessage {
+            chat_id: Some(-200),
+            message_id: 7,
+        };
+        assert!(
+            workers
+                .cancel_deleted(PRIMARY_ACCOUNT_NAME, elsewhere)
+                .is_empty()
         );
-        cache.record_message(
-            scope,
-            3,
-            ContextMessage {
-                sender_name: "Me".to_owned(),
-                text: "three".to_owned(),
-            },
+        let deleted = DeletedMessage {
+            chat_id: Some(scope.chat_id),
+            message_id: 7,
+        };
+        assert!(workers.cancel_deleted("work", deleted).is_empty());
+        assert_eq!(
+            workers.cancel_deleted(PRIMARY_ACCOUNT_NAME, deleted),
+            [key.clone()]
         );
+        assert!(!workers.contains(&key));
+        assert!(workers.is_empty());
 
-        let context = cache.recent_before(scope, 3, 2);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert!(finished.try_recv().is_err());
+
+        // The scope's worker goes on with the next rewrite.
+        let next = MessageKey::new(PRIMARY_ACCOUNT_NAME, scope.chat_id, 8);
+        assert!(workers.submit(next.clone(), scope, "next", "next", ready_request("next")));
+        tokio::time::sleep(Duration::from_secs(1)).await;
         assert_eq!(
-            context,
-            vec![
-                ContextMessage {
-                    sender_name: "Alice".to_owned(),
-                    text: "one".to_owned(),
-                },
-                ContextMessage {
-                    sender_name: "Bob".to_owned(),
-                    text: "two".to_owned(),
-                },
-            ]
+            workers.cancel_deleted(
+                PRIMARY_ACCOUNT_NAME,
+                DeletedMessage {
+                    chat_id: None,
+                    message_id: 8,
+                }
+            ),
+            [next]
         );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(dropped.load(Ordering::SeqCst), 2);
     }
 
-    #[test]
-    fn context_cache_marks_chat_hydrated_to_avoid_repeat_backfill() {
-        let mut cache = ContextCache::new(10);
+    #[tokio::test(start_paused = true)]
+    async fn rewrite_workers_run_scheduled_rewrites_off_the_update_loop() {
+        let (mut workers, mut finished) =
+            RewriteWorkers::new(Arc::new(DelayedEcho(Duration::from_secs(10))), 1);
+        let scheduled = ScheduledMessage {
+            chat_id: -100,
+            message_id: 7,
+            text: "later".to_owned(),
+            markdown: "later".to_owned(),
+            schedule_unix: 0,
+        };
+        let llm = workers.llm();
+        let started = tokio::time::Instant::now();
+        workers.spawn_provider_request(
+            MessageKey::new(
+                PRIMARY_ACCOUNT_NAME,
+                scheduled.chat_id,
+                scheduled.message_id,
+            ),
+            async move {
+                let result = llm
+                    .rewrite_with_deadline("prompt", None, &[], &scheduled.text, None)
+                    .await;
+                RequestDone::Scheduled {
+                    original: scheduled.text.clone(),
+                    scheduled,
+                    result,
+                    elapsed: Duration::from_secs(10),
+                }
+            },
+        );
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // The next update is handled at once; its rewrite waits for the shared permit.
         let scope = ContextScope {
-            chat_id: -1001234567890,
+            chat_id: -200,
             topic_root_id: None,
         };
+        let next = MessageKey::new(PRIMARY_ACCOUNT_NAME, scope.chat_id, 8);
+        assert!(workers.submit(next, scope, "next", "next", ready_request("next")));
+        assert_eq!(workers.len(), 2);
 
-        assert!(cache.should_backfill(scope, 10, 0));
-        cache.mark_hydrated(scope);
-        assert!(!cache.should_backfill(scope, 10, 0));
+        let Some(WorkerDone::Request {
+            done: RequestDone::Scheduled { result, .. },
+            ..
+        }) = finished.recv().await
+        else {
+            panic!("the scheduled rewrite should answer first");
+        };
+        workers.request_done();
+        assert_eq!(result.expect("echo should succeed").text, "later");
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        let Some(WorkerDone::Rewrite(done)) = finished.recv().await else {
+            panic!("workers should answer with a rewrite");
+        };
+        assert_eq!(workers.complete(&done.key, done.id), Some("next"));
+        assert_eq!(started.elapsed(), Duration::from_secs(20));
     }
 
     #[test]
@@ -994,6 +8711,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Alice".to_owned(),
                 text: "general one".to_owned(),
+                is_own: false,
             },
         );
         cache.record_message(
@@ -1002,6 +8720,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Bob".to_owned(),
                 text: "topic one".to_owned(),
+                is_own: false,
             },
         );
         cache.record_message(
@@ -1010,6 +8729,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "topic two".to_owned(),
+                is_own: true,
             },
         );
 
@@ -1019,6 +8739,7 @@ mod tests {
             vec![ContextMessage {
                 sender_name: "Bob".to_owned(),
                 text: "topic one".to_owned(),
+                is_own: false,
             }]
         );
         let general_context = cache.recent_before(general_scope, 1, 5);
@@ -1080,6 +8801,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Alice".to_owned(),
                 text: "first".to_owned(),
+                is_own: false,
             },
         );
         cache.record_message(
@@ -1088,6 +8810,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Bob".to_owned(),
                 text: "second".to_owned(),
+                is_own: false,
             },
         );
         cache.record_message(
@@ -1096,6 +8819,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Alice".to_owned(),
                 text: "first again".to_owned(),
+                is_own: false,
             },
         );
 
@@ -1109,6 +8833,53 @@ mod tests {
         assert_eq!(context[1].text, "second");
     }
 
+    #[test]
+    fn backfill_report_counts_failures_apart_from_skips() {
+        let counters = ChatCounters {
+            seen: 6,
+            deduped: 1,
+            refused: 1,
+            llm_failed: 1,
+            edit_failed: 1,
+            rewritten: 2,
+            ..ChatCounters::default()
+        };
+        let report = BackfillReport::from_counters(counters, Vec::new());
+        assert_eq!((report.rewritten, report.skipped, report.failed), (2, 2, 2));
+    }
+
+    #[test]
+    fn transfer_chat_moves_group_context_ahead_of_supergroup_messages() {
+        let mut cache = ContextCache::new(10);
+        let group_scope = ContextScope {
+            chat_id: -4242,
+            topic_root_id: None,
+        };
+        let supergroup_scope = ContextScope {
+            chat_id: -1004242,
+            topic_root_id: None,
+        };
+        let message = |text: &str| ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: text.to_owned(),
+            is_own: false,
+        };
+        cache.record_message(group_scope, 50, message("in the group"));
+        cache.mark_hydrated(group_scope);
+        cache.record_message(supergroup_scope, 1, message("in the supergroup"));
+
+        cache.transfer_chat(group_scope.chat_id, supergroup_scope.chat_id);
+
+        let context = cache.recent_before(supergroup_scope, 99, 10);
+        assert_eq!(
+            context,
+            vec![message("in the group"), message("in the supergroup")]
+        );
+        assert!(cache.recent_before(group_scope, 99, 10).is_empty());
+        assert!(!cache.should_backfill(supergroup_scope, 5, 2));
+        assert!(cache.should_backfill(group_scope, 5, 0));
+    }
+
     #[test]
     fn context_cache_reobserve_after_backfill_preserves_current_message() {
         let mut cache = ContextCache::new(10);
@@ -1123,6 +8894,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "current".to_owned(),
+                is_own: true,
             },
         );
         cache.backfill(
@@ -1133,6 +8905,7 @@ mod tests {
                     message: ContextMessage {
                         sender_name: "Alice".to_owned(),
                         text: "old one".to_owned(),
+                        is_own: false,
                     },
                 },
                 ContextEntry {
@@ -1140,6 +8913,7 @@ mod tests {
                     message: ContextMessage {
                         sender_name: "Bob".to_owned(),
                         text: "old two".to_owned(),
+                        is_own: false,
                     },
                 },
             ],
@@ -1150,6 +8924,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "current".to_owned(),
+                is_own: true,
             },
         );
 
@@ -1178,6 +8953,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "original".to_owned(),
+                is_own: true,
             },
         );
         cache.upsert_message(
@@ -1186,6 +8962,7 @@ mod tests {
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "rewritten".to_owned(),
+                is_own: true,
             },
         );
 
@@ -1217,4 +8994,582 @@ mod tests {
         let update = Update::Raw(raw);
         assert_eq!(update_kind_name(&update), "raw/Config");
     }
+
+    /// Scripted [`LlmRewriter`] for exercising the rewrite flow without a network.
+    struct MockRewriter {
+        reply: Result<String, String>,
+        refusal: Option<String>,
+    }
+
+    impl MockRewriter {
+        fn replying(text: &str) -> Self {
+            Self {
+                reply: Ok(text.to_owned()),
+                refusal: None,
+            }
+        }
+
+        fn failing(error: &str) -> Self {
+            Self {
+                reply: Err(error.to_owned()),
+                refusal: None,
+            }
+        }
+
+        fn refusing(refusal: &str) -> Self {
+            Self {
+                reply: Ok(String::new()),
+                refusal: Some(refusal.to_owned()),
+            }
+        }
+    }
+
+    impl LlmRewriter for MockRewriter {
+        fn rewrite<'a>(
+            &'a self,
+            _system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            _input: &'a str,
+        ) -> RewriteFuture<'a> {
+            Box::pin(async move {
+                let text = self.reply.clone().map_err(|error| anyhow!(error))?;
+                Ok(Rewrite {
+                    text,
+                    model: "mock-model".to_owned(),
+                    usage: Some(TokenUsage {
+                        input_tokens: 12,
+                        output_tokens: 3,
+                    }),
+                    refusal: self.refusal.clone(),
+                })
+            })
+        }
+    }
+
+    struct RewriteFixture {
+        dedupe_cache: DedupeCache,
+        deleted_messages: DeletedMessages,
+        edit_retries: EditRetries<PendingEdit>,
+        edit_throttle: EditThrottle,
+        slow_mode: SlowModeQueue<PendingResend>,
+        scheduled_rewrites: ScheduledRewrites,
+        context_cache: ContextCache,
+        rate_limiter: RateLimiter,
+        paused_chats: HashSet<i64>,
+        chat_stats: ChatStats,
+        undo_history: UndoHistory,
+        usage_tracker: UsageTracker,
+        hooks: RewriteHooks,
+        events: Arc<Mutex<Vec<RewriteEvent>>>,
+        rewrite: RewriteConfig,
+        refusals: RefusalDetector,
+    }
+
+    impl RewriteFixture {
+        fn new() -> Self {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&events);
+            let rewrite = RewriteConfig::default();
+            let refusals = RefusalDetector::new(&rewrite.refusal_patterns)
+                .expect("default refusal patterns should compile");
+            Self {
+                dedupe_cache: DedupeCache::new(Duration::from_secs(60)),
+                deleted_messages: DeletedMessages::new(Duration::from_secs(60)),
+                edit_retries: EditRetries::new(EDIT_RETRY_QUEUE_LIMIT),
+                edit_throttle: EditThrottle::default(),
+                slow_mode: SlowModeQueue::new(),
+                scheduled_rewrites: ScheduledRewrites::default(),
+                context_cache: ContextCache::new(10),
+                rate_limiter: RateLimiter::new(None),
+                paused_chats: HashSet::new(),
+                chat_stats: ChatStats::new(tokio::time::Instant::now()),
+                undo_history: UndoHistory::new(0),
+                usage_tracker: UsageTracker::new(None),
+                hooks: RewriteHooks::with_event_handler(move |event| {
+                    sink.lock().expect("events mutex poisoned").push(event);
+                }),
+                events,
+                rewrite,
+                refusals,
+            }
+        }
+
+        async fn run(&mut self, llm: &dyn LlmRewriter, original: &str) -> RewriteOutcome {
+            let settings = RewriteSettings {
+                llm,
+                refusals: &self.refusals,
+                rewrite: &self.rewrite,
+            };
+            let mut runtime = ProcessMessageRuntime {
+                dedupe_cache: &mut self.dedupe_cache,
+                deleted_messages: &self.deleted_messages,
+                edit_retries: &mut self.edit_retries,
+                edit_throttle: &mut self.edit_throttle,
+                slow_mode: &mut self.slow_mode,
+                scheduled_rewrites: &mut self.scheduled_rewrites,
+                context_cache: &mut self.context_cache,
+                rate_limiter: &mut self.rate_limiter,
+                paused_chats: &mut self.paused_chats,
+                chat_stats: &mut self.chat_stats,
+                undo_history: &mut self.undo_history,
+                usage_tracker: &mut self.usage_tracker,
+                rewrite_deadline: None,
+                hooks: self.hooks.for_account(PRIMARY_ACCOUNT_NAME),
+                workers: None,
+                dry_run: false,
+            };
+            request_rewrite(settings, None, &[], original, -100, 7, &mut runtime).await
+        }
+
+        fn runtime(&mut self) -> ProcessMessageRuntime<'_> {
+            ProcessMessageRuntime {
+                dedupe_cache: &mut self.dedupe_cache,
+                deleted_messages: &self.deleted_messages,
+                edit_retries: &mut self.edit_retries,
+                edit_throttle: &mut self.edit_throttle,
+                slow_mode: &mut self.slow_mode,
+                scheduled_rewrites: &mut self.scheduled_rewrites,
+                context_cache: &mut self.context_cache,
+                rate_limiter: &mut self.rate_limiter,
+                paused_chats: &mut self.paused_chats,
+                chat_stats: &mut self.chat_stats,
+                undo_history: &mut self.undo_history,
+                usage_tracker: &mut self.usage_tracker,
+                rewrite_deadline: None,
+                hooks: self.hooks.for_account(PRIMARY_ACCOUNT_NAME),
+                workers: None,
+                dry_run: false,
+            }
+        }
+
+        /// Rewrites `original` and passes the outcome to the before-edit hook, as
+        /// `apply_outcome` does.
+        async fn run_reviewed(&mut self, llm: &dyn LlmRewriter, original: &str) -> RewriteOutcome {
+            let outcome = self.run(llm, original).await;
+            let hooks = self.hooks.for_account(PRIMARY_ACCOUNT_NAME);
+            review_edit(&self.rewrite, hooks, original, outcome, -100, 7)
+        }
+
+        fn with_before_edit(
+            &mut self,
+            hook: impl Fn(&EditCandidate<'_>) -> EditDecision + Send + Sync + 'static,
+        ) {
+            self.hooks = std::mem::take(&mut self.hooks).with_before_edit(hook);
+        }
+
+        fn succeeded_events(&self) -> usize {
+            self.events
+                .lock()
+                .expect("events mutex poisoned")
+                .iter()
+                .filter(|event| matches!(event, RewriteEvent::RewriteSucceeded { .. }))
+                .count()
+        }
+    }
+
+    #[test]
+    fn chat_command_ack_echo_is_not_taken_for_a_manual_edit() {
+        let mut fixture = RewriteFixture::new();
+        let rewrite = fixture.rewrite.clone();
+        let mut runtime = fixture.runtime();
+        let ack = command_ack(ChatCommand::Off, None, -100, 7, &rewrite, &mut runtime);
+        assert_eq!(ack, status_text(false));
+        let undo = command_ack(
+            ChatCommand::Undo,
+            Some("nothing to undo in this chat"),
+            -100,
+            8,
+            &rewrite,
+            &mut runtime,
+        );
+        assert_eq!(undo, "nothing to undo in this chat");
+
+        assert!(fixture.dedupe_cache.is_own_edit(-100, 7, &ack));
+        assert!(fixture.dedupe_cache.is_own_edit(-100, 8, &undo));
+        assert!(!fixture.dedupe_cache.is_own_edit(-100, 7, ".rw off"));
+        assert!(fixture.paused_chats.contains(&-100));
+    }
+
+    #[tokio::test]
+    async fn rewrite_lifecycle_events_carry_stable_reasons() {
+        let mut fixture = RewriteFixture::new();
+        fixture.run(&MockRewriter::failing("boom"), "evening").await;
+        assert!(
+            fixture
+                .events
+                .lock()
+                .expect("events mutex poisoned")
+                .iter()
+                .any(|event| matches!(
+                    event,
+                    RewriteEvent::RewriteStarted {
+                        chat_id: -100,
+                        message_id: 7
+                    }
+                ))
+        );
+
+        let Some(RewriteEvent::RewriteFailed { stage, reason, .. }) =
+            ChatStat::LlmFailed.lifecycle_event(-100, 7)
+        else {
+            panic!("llm failure should be reported as RewriteFailed");
+        };
+        assert_eq!(stage, RewriteStage::Llm);
+        assert_eq!(
+            serde_json::to_string(&reason).expect("reason should serialize"),
+            "\"provider_failed\""
+        );
+        assert!(matches!(
+            ChatStat::Unchanged.lifecycle_event(-100, 7),
+            Some(RewriteEvent::RewriteFailed {
+                stage: RewriteStage::Skipped,
+                reason: FailureReason::Unchanged,
+                ..
+            })
+        ));
+        let Some(RewriteEvent::RewriteSkipped { reason, .. }) =
+            ChatStat::NotOutgoing.lifecycle_event(-100, 7)
+        else {
+            panic!("a message from someone else should be reported as RewriteSkipped");
+        };
+        assert_eq!(
+            serde_json::to_string(&reason).expect("reason should serialize"),
+            "\"not_outgoing\""
+        );
+        assert!(ChatStat::Rewritten.lifecycle_event(-100, 7).is_none());
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_returns_sanitized_edit_and_records_usage() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture
+            .run(
+                &MockRewriter::replying("\"Rewritten: Good evening\""),
+                "evening",
+            )
+            .await;
+
+        match outcome {
+            RewriteOutcome::Edit { text, model } => {
+                assert_eq!(text, "Good evening");
+                assert_eq!(model, "mock-model");
+            }
+            other => panic!("expected an edit, got {other:?}"),
+        }
+        assert_eq!(fixture.usage_tracker.total().input_tokens, 12);
+        assert_eq!(
+            fixture.usage_tracker.chat_totals(-100).map(|t| t.rewrites),
+            Some(1)
+        );
+        assert_eq!(fixture.succeeded_events(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_truncates_to_telegram_limit() {
+        let long = "ы".repeat(TELEGRAM_MESSAGE_MAX_CHARS + 10);
+        let mut fixture = RewriteFixture::new();
+        match fixture.run(&MockRewriter::replying(&long), "short").await {
+            RewriteOutcome::Edit { text, .. } => {
+                assert_eq!(text.chars().count(), TELEGRAM_MESSAGE_MAX_CHARS);
+            }
+            other => panic!("expected an edit, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_counts_rewrites_and_reloads() {
+        let metrics = Arc::new(Metrics::new().expect("metrics should register"));
+        let server = StatusServer::start(
+            "127.0.0.1:0".parse().unwrap(),
+            metrics::routes(Arc::clone(&metrics)),
+        )
+        .await
+        .expect("metrics server should start");
+        let url = format!("http://{}/metrics", server.local_addr());
+        let scrape = || async {
+            reqwest::get(&url)
+                .await
+                .expect("scrape should succeed")
+                .text()
+                .await
+                .expect("scrape should have a body")
+        };
+        let mut fixture = RewriteFixture::new();
+        fixture.hooks.metrics = Some(Arc::clone(&metrics));
+
+        let before = scrape().await;
+        assert!(
+            before.contains("brainrot_rewrites_attempted_total 0"),
+            "{before}"
+        );
+
+        fixture
+            .run(&MockRewriter::replying("Hello there."), "hello there")
+            .await;
+        fixture.hooks.emit(RewriteEvent::ConfigReloaded {
+            changes: vec!["rewrite.system_prompt".to_owned()],
+        });
+        let after = scrape().await;
+        assert!(
+            after.contains("brainrot_rewrites_attempted_total 1"),
+            "{after}"
+        );
+        assert!(
+            after.contains("brainrot_llm_request_duration_seconds_count 1"),
+            "{after}"
+        );
+        assert!(
+            after.contains("brainrot_config_reloads_total{result=\"ok\"} 1"),
+            "{after}"
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_leaves_markup_to_be_cut_after_parsing() {
+        let long = format!("**{}**", "ы".repeat(TELEGRAM_MESSAGE_MAX_CHARS));
+        let mut fixture = RewriteFixture::new();
+        fixture.rewrite.parse_mode = ParseMode::Markdown;
+        match fixture.run(&MockRewriter::replying(&long), "short").await {
+            RewriteOutcome::Edit { text, .. } => assert_eq!(text, long),
+            other => panic!("expected an edit, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_skips_empty_result() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture
+            .run(&MockRewriter::replying("  \"\"  "), "hello")
+            .await;
+        assert!(matches!(outcome, RewriteOutcome::Empty), "{outcome:?}");
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_skips_unchanged_result() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture.run(&MockRewriter::replying("hello"), "hello").await;
+        assert!(matches!(outcome, RewriteOutcome::Unchanged), "{outcome:?}");
+    }
+
+    #[test]
+    fn dropped_links_are_appended_or_skip_the_edit() {
+        let edit = || RewriteOutcome::Edit {
+            text: "Kindly read the guide, @bobby.".to_owned(),
+            model: "model".to_owned(),
+        };
+        let original = "read https://example.com/guide @bobby";
+        let hidden = ["https://example.com/hidden".to_owned()];
+        let mut rewrite = RewriteConfig::default();
+
+        let outcome = check_dropped_links(&rewrite, original, &hidden, edit(), -100, 7);
+        assert!(
+            matches!(&outcome, RewriteOutcome::Edit { text, .. } if text == "Kindly read the guide, @bobby."),
+            "{outcome:?}"
+        );
+
+        rewrite.restore_dropped_links = RestoreDroppedLinks::Append;
+        match check_dropped_links(&rewrite, original, &hidden, edit(), -100, 7) {
+            RewriteOutcome::Edit { text, .. } => assert_eq!(
+                text,
+                "Kindly read the guide, @bobby.\nhttps://example.com/guide https://example.com/hidden"
+            ),
+            other => panic!("expected an edit, got {other:?}"),
+        }
+
+        rewrite.restore_dropped_links = RestoreDroppedLinks::Skip;
+        match check_dropped_links(&rewrite, original, &[], edit(), -100, 7) {
+            RewriteOutcome::DroppedLinks(dropped) => {
+                assert_eq!(dropped, vec!["https://example.com/guide"]);
+            }
+            other => panic!("expected a skipped edit, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_the_rewrite_instead_of_applying_it() {
+        let fixture = RewriteFixture::new();
+        report_dry_run(
+            -100,
+            7,
+            "evening",
+            "Good evening.".to_owned(),
+            "mock-model",
+            fixture.hooks.for_account(PRIMARY_ACCOUNT_NAME),
+        );
+        let events = fixture.events.lock().expect("events mutex poisoned");
+        assert!(
+            matches!(
+                events.as_slice(),
+                [RewriteEvent::DryRunRewrite {
+                    chat_id: -100,
+                    message_id: 7,
+                    original,
+                    rewritten,
+                }] if original == "evening" && rewritten == "Good evening."
+            ),
+            "{events:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn before_edit_hook_defaults_to_applying_the_rewrite() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        assert!(
+            matches!(&outcome, RewriteOutcome::Edit { text, .. } if text == "Good evening."),
+            "{outcome:?}"
+        );
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        fixture.with_before_edit(move |candidate| {
+            sink.lock().expect("candidates mutex poisoned").push((
+                candidate.chat_id,
+                candidate.message_id,
+                candidate.original.to_owned(),
+                candidate.rewritten.to_owned(),
+            ));
+            EditDecision::Approve
+        });
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        assert!(
+            matches!(&outcome, RewriteOutcome::Edit { text, .. } if text == "Good evening."),
+            "{outcome:?}"
+        );
+        assert_eq!(
+            *seen.lock().expect("candidates mutex poisoned"),
+            vec![(-100, 7, "evening".to_owned(), "Good evening.".to_owned())]
+        );
+
+        // Rewrites that are skipped anyway never reach the hook.
+        fixture
+            .run_reviewed(&MockRewriter::replying("evening"), "evening")
+            .await;
+        assert_eq!(seen.lock().expect("candidates mutex poisoned").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn before_edit_hook_can_skip_the_edit() {
+        let mut fixture = RewriteFixture::new();
+        fixture.with_before_edit(|_| EditDecision::Skip);
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        assert!(matches!(outcome, RewriteOutcome::Vetoed), "{outcome:?}");
+        assert_eq!(ChatStat::for_outcome(&outcome), Some(ChatStat::Vetoed));
+    }
+
+    #[tokio::test]
+    async fn before_edit_replacement_goes_through_the_usual_checks() {
+        let mut fixture = RewriteFixture::new();
+        fixture.with_before_edit(|candidate| {
+            EditDecision::Replace(candidate.rewritten.to_uppercase())
+        });
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        match outcome {
+            RewriteOutcome::Edit { text, model } => {
+                assert_eq!(text, "GOOD EVENING.");
+                assert_eq!(model, "mock-model");
+            }
+            other => panic!("expected an edit, got {other:?}"),
+        }
+
+        let mut fixture = RewriteFixture::new();
+        fixture.with_before_edit(|candidate| EditDecision::Replace(candidate.original.to_owned()));
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        assert!(matches!(outcome, RewriteOutcome::Unchanged), "{outcome:?}");
+
+        let mut fixture = RewriteFixture::new();
+        fixture.with_before_edit(|_| {
+            EditDecision::Replace("x".repeat(TELEGRAM_MESSAGE_MAX_CHARS + 10))
+        });
+        let outcome = fixture
+            .run_reviewed(&MockRewriter::replying("Good evening."), "evening")
+            .await;
+        match outcome {
+            RewriteOutcome::Edit { text, .. } => {
+                assert_eq!(text.chars().count(), TELEGRAM_MESSAGE_MAX_CHARS);
+            }
+            other => panic!("expected a truncated edit, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_skips_result_below_min_change_ratio() {
+        let mut fixture = RewriteFixture::new();
+        fixture.rewrite.min_change_ratio = 0.1;
+        let outcome = fixture
+            .run(&MockRewriter::replying("Hello, world."), "Hello world.")
+            .await;
+        match outcome {
+            RewriteOutcome::BelowChangeRatio(ratio) => assert!(ratio < 0.1, "{ratio}"),
+            other => panic!("expected a skipped edit, got {other:?}"),
+        }
+
+        let outcome = fixture
+            .run(&MockRewriter::replying("Greetings, world."), "Hello world.")
+            .await;
+        assert!(
+            matches!(outcome, RewriteOutcome::Edit { .. }),
+            "{outcome:?}"
+        );
+    }
+
+    #[test]
+    fn change_ratio_counts_chars_not_bytes() {
+        assert_eq!(change_ratio("", ""), 0.0);
+        assert_eq!(change_ratio("привет", "привет"), 0.0);
+        assert_eq!(change_ratio("привет", "привед"), 1.0 / 6.0);
+        assert_eq!(change_ratio("🙂🙂", "🙂"), 0.5);
+    }
+
+    #[test]
+    fn change_ratio_is_one_for_disjoint_or_empty_sides() {
+        assert_eq!(change_ratio("", "abc"), 1.0);
+        assert_eq!(change_ratio("abc", ""), 1.0);
+        assert_eq!(change_ratio("abc", "xyz"), 1.0);
+        assert_eq!(change_ratio("kitten", "sitting"), 3.0 / 7.0);
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_reports_llm_error() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture
+            .run(&MockRewriter::failing("upstream unavailable"), "hello")
+            .await;
+        match outcome {
+            RewriteOutcome::Failed(err) => assert_eq!(err.to_string(), "upstream unavailable"),
+            other => panic!("expected a failure, got {other:?}"),
+        }
+        assert_eq!(fixture.usage_tracker.total().rewrites, 0);
+        assert_eq!(fixture.succeeded_events(), 0);
+    }
+
+    #[tokio::test]
+    async fn request_rewrite_treats_api_and_text_refusals_alike() {
+        let mut fixture = RewriteFixture::new();
+        let outcome = fixture
+            .run(&MockRewriter::refusing("I can't help with that."), "hello")
+            .await;
+        assert!(matches!(outcome, RewriteOutcome::Refused(_)), "{outcome:?}");
+        assert_eq!(fixture.usage_tracker.total().rewrites, 1);
+
+        let outcome = fixture
+            .run(&MockRewriter::replying("I can't help with that."), "hello")
+            .await;
+        match outcome {
+            RewriteOutcome::Refused(refusal) => assert_eq!(refusal, "I can't help with that."),
+            other => panic!("expected a refusal, got {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,84 @@
+/// In-chat control commands typed from the user's own account, e.g. `.rw off`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatCommand {
+    On,
+    Off,
+    Status,
+    /// Restore the original of the rewrite replied to, or of the chat's latest rewrite.
+    Undo,
+    Unknown(String),
+}
+
+/// Parses `text` as a command when it is exactly `prefix` or `prefix` followed by whitespace.
+pub fn parse_chat_command(text: &str, prefix: &str) -> Option<ChatCommand> {
+    let rest = text.trim().strip_prefix(prefix)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let command = match rest.trim().to_lowercase().as_str() {
+        "on" => ChatCommand::On,
+        "off" => ChatCommand::Off,
+        "status" => ChatCommand::Status,
+        "undo" => ChatCommand::Undo,
+        other => ChatCommand::Unknown(other.to_owned()),
+    };
+    Some(command)
+}
+
+pub fn usage_hint(prefix: &str) -> String {
+    format!("usage: {prefix} on | off | status | undo")
+}
+
+pub fn status_text(enabled: bool) -> &'static str {
+    if enabled {
+        "rewriting is on in this chat"
+    } else {
+        "rewriting is paused in this chat"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatCommand, parse_chat_command, usage_hint};
+
+    #[test]
+    fn parses_known_subcommands_case_insensitively() {
+        assert_eq!(parse_chat_command(".rw on", ".rw"), Some(ChatCommand::On));
+        assert_eq!(
+            parse_chat_command(" .rw OFF ", ".rw"),
+            Some(ChatCommand::Off)
+        );
+        assert_eq!(
+            parse_chat_command(".rw   status", ".rw"),
+            Some(ChatCommand::Status)
+        );
+        assert_eq!(
+            parse_chat_command(".rw Undo", ".rw"),
+            Some(ChatCommand::Undo)
+        );
+    }
+
+    #[test]
+    fn unknown_or_missing_subcommand_is_reported() {
+        assert_eq!(
+            parse_chat_command(".rw pause", ".rw"),
+            Some(ChatCommand::Unknown("pause".to_owned()))
+        );
+        assert_eq!(
+            parse_chat_command(".rw", ".rw"),
+            Some(ChatCommand::Unknown(String::new()))
+        );
+    }
+
+    #[test]
+    fn ordinary_messages_are_not_commands() {
+        assert_eq!(parse_chat_command("hello .rw off", ".rw"), None);
+        assert_eq!(parse_chat_command(".rwx off", ".rw"), None);
+        assert_eq!(parse_chat_command("", ".rw"), None);
+    }
+
+    #[test]
+    fn usage_hint_uses_configured_prefix() {
+        assert_eq!(usage_hint("!bot"), "usage: !bot on | off | status | undo");
+    }
+}
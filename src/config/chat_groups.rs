@@ -0,0 +1,270 @@
+use super::{ChatLink, SAVED_MESSAGES_CHAT_ID};
+use anyhow::{Result, bail};
+
+const GROUP_REFERENCE_PREFIX: &str = "@group:";
+/// Names for the signed-in account's own chat, Saved Messages.
+const SAVED_MESSAGES_ALIASES: &[&str] = &["me", "self"];
+
+/// Chat id lists that may contain `"@group:<name>"` references into `[chat_groups]`.
+const REFERENCE_SITES: &[(&str, &str)] = &[("rewrite", "chats")];
+
+/// Replaces `"@group:<name>"` entries with the ids of the named `[chat_groups]` entry,
+/// in place, so the expanded lists go through normal deserialization and validation.
+/// `"me"` and `"self"` become [`SAVED_MESSAGES_CHAT_ID`], here and in `[[accounts]]` chats.
+/// Invite links and usernames are taken out of the lists and returned.
+pub(super) fn expand_chat_group_references(table: &mut toml::Table) -> Result<Vec<ChatLink>> {
+    let mut errors = Vec::new();
+    let mut links = Vec::new();
+    let groups = table
+        .get("chat_groups")
+        .map(|groups| parse_chat_groups(groups, &mut errors))
+        .unwrap_or_default();
+
+    for (section, key) in REFERENCE_SITES {
+        let Some(toml::Value::Array(entries)) = table
+            .get_mut(*section)
+            .and_then(|section| section.get_mut(*key))
+        else {
+            continue;
+        };
+        expand_entries(
+            entries,
+            &format!("{section}.{key}"),
+            &groups,
+            &mut links,
+            &mut errors,
+        );
+    }
+    if let Some(toml::Value::Array(accounts)) = table.get_mut("accounts") {
+        for (index, account) in accounts.iter_mut().enumerate() {
+            if let Some(toml::Value::Array(entries)) = account.get_mut("chats") {
+                let field = format!("accounts[{index}].chats");
+                expand_saved_messages_aliases(entries, &field, &mut errors);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "invalid chat group references:\n  - {}",
+            errors.join("\n  - ")
+        );
+    }
+    Ok(links)
+}
+
+fn expand_entries(
+    entries: &mut Vec<toml::Value>,
+    field: &str,
+    groups: &[(String, Vec<i64>)],
+    links: &mut Vec<ChatLink>,
+    errors: &mut Vec<String>,
+) {
+    let mut expanded = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let site = format!("{field}[{index}]");
+        match entry {
+            toml::Value::String(reference) if is_saved_messages_alias(reference) => {
+                expanded.push(toml::Value::Integer(SAVED_MESSAGES_CHAT_ID));
+            }
+            toml::Value::String(reference) => {
+                let Some(name) = reference.strip_prefix(GROUP_REFERENCE_PREFIX) else {
+                    match ChatLink::parse(reference) {
+                        Some(link) => links.push(link),
+                        None => errors.push(format!(
+                            "{site} must be a chat id, \"me\", an invite link, an @username or a \"{GROUP_REFERENCE_PREFIX}<name>\" reference, got \"{reference}\""
+                        )),
+                    }
+                    continue;
+                };
+                match groups.iter().find(|(group, _)| group == name) {
+                    Some((_, ids)) => {
+                        expanded.extend(ids.iter().map(|id| toml::Value::Integer(*id)))
+                    }
+                    None => errors.push(format!("{site} references unknown chat group `{name}`")),
+                }
+            }
+            toml::Value::Integer(SAVED_MESSAGES_CHAT_ID) => {
+                errors.push(format!("{site} is not a valid Telegram chat id"));
+            }
+            other => expanded.push(other.clone()),
+        }
+    }
+    *entries = expanded;
+}
+
+/// Like [`expand_entries`] without group references, which only `rewrite.chats` takes.
+fn expand_saved_messages_aliases(
+    entries: &mut [toml::Value],
+    field: &str,
+    errors: &mut Vec<String>,
+) {
+    for (index, entry) in entries.iter_mut().enumerate() {
+        match entry {
+            toml::Value::String(alias) if is_saved_messages_alias(alias) => {
+                *entry = toml::Value::Integer(SAVED_MESSAGES_CHAT_ID);
+            }
+            toml::Value::Integer(SAVED_MESSAGES_CHAT_ID) => {
+                errors.push(format!("{field}[{index}] is not a valid Telegram chat id"));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_saved_messages_alias(value: &str) -> bool {
+    SAVED_MESSAGES_ALIASES.contains(&value.trim().to_lowercase().as_str())
+}
+
+fn parse_chat_groups(groups: &toml::Value, errors: &mut Vec<String>) -> Vec<(String, Vec<i64>)> {
+    let Some(groups) = groups.as_table() else {
+        errors.push("chat_groups must be a table of name = [chat ids]".to_owned());
+        return Vec::new();
+    };
+    groups
+        .iter()
+        .filter_map(|(name, ids)| {
+            let ids: Option<Vec<i64>> = ids
+                .as_array()
+                .and_then(|ids| ids.iter().map(toml::Value::as_integer).collect());
+            if ids.is_none() {
+                errors.push(format!("chat_groups.{name} must be an array of chat ids"));
+            }
+            ids.map(|ids| (name.clone(), ids))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_chat_group_references;
+    use crate::config::{ChatLink, SAVED_MESSAGES_CHAT_ID};
+
+    fn expand(raw: &str) -> anyhow::Result<toml::Table> {
+        let mut table: toml::Table = toml::from_str(raw).expect("test TOML should parse");
+        expand_chat_group_references(&mut table)?;
+        Ok(table)
+    }
+
+    #[test]
+    fn group_references_expand_in_place() {
+        let table = expand(
+            r#"
+[chat_groups]
+friends = [1, 2]
+
+[rewrite]
+chats = [5, "@group:friends", 6]
+"#,
+        )
+        .expect("references should expand");
+        let chats: Vec<i64> = table["rewrite"]["chats"]
+            .as_array()
+            .expect("chats should be an array")
+            .iter()
+            .filter_map(toml::Value::as_integer)
+            .collect();
+        assert_eq!(chats, vec![5, 1, 2, 6]);
+    }
+
+    #[test]
+    fn me_and_self_stand_for_saved_messages_in_rewrite_and_account_chats() {
+        let table = expand(
+            r#"
+[rewrite]
+chats = ["me", -1001]
+
+[[accounts]]
+name = "work"
+chats = [-1002, "Self"]
+"#,
+        )
+        .expect("aliases should expand");
+        let ids = |chats: &toml::Value| -> Vec<i64> {
+            chats
+                .as_array()
+                .expect("chats should be an array")
+                .iter()
+                .filter_map(toml::Value::as_integer)
+                .collect()
+        };
+        assert_eq!(
+            ids(&table["rewrite"]["chats"]),
+            vec![SAVED_MESSAGES_CHAT_ID, -1001]
+        );
+        assert_eq!(
+            ids(&table["accounts"][0]["chats"]),
+            vec![-1002, SAVED_MESSAGES_CHAT_ID]
+        );
+
+        let err = expand(&format!("[rewrite]\nchats = [{SAVED_MESSAGES_CHAT_ID}]\n"))
+            .expect_err("the placeholder id itself should be rejected");
+        assert!(
+            err.to_string()
+                .contains("rewrite.chats[0] is not a valid Telegram chat id")
+        );
+    }
+
+    #[test]
+    fn unknown_group_names_the_reference_site() {
+        let err = expand(
+            r#"
+[chat_groups]
+friends = [1]
+
+[rewrite]
+chats = [5, "@group:foes"]
+"#,
+        )
+        .expect_err("unknown group should fail");
+        assert!(
+            err.to_string()
+                .contains("rewrite.chats[1] references unknown chat group `foes`")
+        );
+    }
+
+    #[test]
+    fn invite_links_and_usernames_are_taken_out_of_the_list() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+[rewrite]
+chats = [5, "https://t.me/+AbCd-Ef_1", "@publicgroup", "t.me/joinchat/XyZ", 6]
+"#,
+        )
+        .expect("test TOML should parse");
+        let links = expand_chat_group_references(&mut table).expect("links should parse");
+
+        assert_eq!(
+            links,
+            vec![
+                ChatLink::Invite("AbCd-Ef_1".to_owned()),
+                ChatLink::Username("publicgroup".to_owned()),
+                ChatLink::Invite("XyZ".to_owned()),
+            ]
+        );
+        let chats: Vec<i64> = table["rewrite"]["chats"]
+            .as_array()
+            .expect("chats should stay an array")
+            .iter()
+            .map(|value| value.as_integer().expect("chat ids are integers"))
+            .collect();
+        assert_eq!(chats, vec![5, 6]);
+    }
+
+    #[test]
+    fn non_reference_strings_and_bad_groups_are_rejected() {
+        let err = expand(
+            r#"
+[chat_groups]
+broken = ["x"]
+
+[rewrite]
+chats = ["friends"]
+"#,
+        )
+        .expect_err("invalid entries should fail");
+        let rendered = err.to_string();
+        assert!(rendered.contains("chat_groups.broken must be an array of chat ids"));
+        assert!(rendered.contains("rewrite.chats[0] must be a chat id"));
+    }
+}
@@ -0,0 +1,144 @@
+use serde::Deserialize;
+use serde::de::{self, Deserializer, Visitor};
+
+/// Describes a key that the config structs do not recognize, e.g.
+/// "`context_message` in [rewrite]; did you mean `context_messages`?".
+pub(super) fn describe_unknown_key(
+    path: &str,
+    known_keys: fn(&str) -> &'static [&'static str],
+) -> String {
+    let (table, key) = path.rsplit_once('.').unwrap_or(("", path));
+    let location = if table.is_empty() {
+        "at the top level".to_owned()
+    } else {
+        format!("in [{table}]")
+    };
+    match closest_key(key, known_keys(table)) {
+        Some(suggestion) => format!("`{key}` {location}; did you mean `{suggestion}`?"),
+        None => format!("`{key}` {location}"),
+    }
+}
+
+/// Returns the serde field names of a derived `Deserialize` struct.
+pub(super) fn struct_fields<T>() -> &'static [&'static str]
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames {
+        fields: &mut fields,
+    });
+    fields
+}
+
+fn closest_key(key: &str, candidates: &[&'static str]) -> Option<&'static str> {
+    let max_distance = (key.chars().count() / 3).max(2);
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(left: &str, right: &str) -> usize {
+    let right: Vec<char> = right.chars().collect();
+    let mut previous: Vec<usize> = (0..=right.len()).collect();
+    for (i, left_char) in left.chars().enumerate() {
+        let mut current = Vec::with_capacity(right.len() + 1);
+        current.push(i + 1);
+        for (j, right_char) in right.iter().enumerate() {
+            let substitution = previous[j] + usize::from(left_char != *right_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[right.len()]
+}
+
+struct FieldNames<'a> {
+    fields: &'a mut &'static [&'static str],
+}
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(de::Error::custom("only struct field names are collected"))
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        *self.fields = fields;
+        Err(de::Error::custom("only struct field names are collected"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest_key, describe_unknown_key, edit_distance, struct_fields};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Sample {
+        chats: Vec<i64>,
+        context_messages: usize,
+    }
+
+    fn sample_keys(table: &str) -> &'static [&'static str] {
+        match table {
+            "rewrite" => struct_fields::<Sample>(),
+            _ => &[],
+        }
+    }
+
+    #[test]
+    fn struct_fields_lists_serde_field_names() {
+        assert_eq!(struct_fields::<Sample>(), &["chats", "context_messages"]);
+    }
+
+    #[test]
+    fn edit_distance_counts_single_char_changes() {
+        assert_eq!(edit_distance("context_message", "context_messages"), 1);
+        assert_eq!(edit_distance("chats", "chats"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn closest_key_ignores_unrelated_candidates() {
+        assert_eq!(closest_key("chat", &["chats", "model"]), Some("chats"));
+        assert_eq!(
+            closest_key("completely_different", &["chats", "model"]),
+            None
+        );
+    }
+
+    #[test]
+    fn describe_unknown_key_suggests_nearest_field_in_table() {
+        assert_eq!(
+            describe_unknown_key("rewrite.context_message", sample_keys),
+            "`context_message` in [rewrite]; did you mean `context_messages`?"
+        );
+        assert_eq!(
+            describe_unknown_key("verbose", sample_keys),
+            "`verbose` at the top level"
+        );
+    }
+}
@@ -1,7 +1,11 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct ContextMessage {
     pub sender_name: String,
     pub text: String,
+    /// Sent by the account being rewritten for, i.e. an outgoing message.
+    pub is_own: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -16,13 +20,147 @@ impl ContextMessage {
     }
 }
 
-pub fn resolve_sender_name(outgoing: bool, peer_name: Option<&str>) -> String {
-    if outgoing {
+/// Marks the message being replied to, which is placed ahead of the recent context. It is
+/// always a user turn so the label survives the `roles` context style.
+pub fn reply_target_context(target: &ContextMessage) -> ContextMessage {
+    ContextMessage {
+        sender_name: format!("(replying to) {}", target.sender_name),
+        text: target.text.clone(),
+        is_own: false,
+    }
+}
+
+/// Sender name for messages whose sender has no known name.
+pub const UNKNOWN_SENDER: &str = "Unknown";
+
+/// "Me" for our own messages, including posts made as a channel we administer, whose
+/// `peer_name` is the channel's; otherwise the sender's name.
+pub fn resolve_sender_name(is_own: bool, peer_name: Option<&str>) -> String {
+    if is_own {
         "Me".to_owned()
     } else {
         peer_name
             .filter(|name| !name.trim().is_empty())
             .map(|name| name.to_owned())
-            .unwrap_or_else(|| "Unknown".to_owned())
+            .unwrap_or_else(|| UNKNOWN_SENDER.to_owned())
+    }
+}
+
+/// The `Chat: <title>, Topic: <name>` line sent with `rewrite.include_chat_metadata`; `None`
+/// when neither name is known.
+pub fn chat_metadata_line(chat_title: Option<&str>, topic_name: Option<&str>) -> Option<String> {
+    let parts: Vec<String> = [("Chat", chat_title), ("Topic", topic_name)]
+        .into_iter()
+        .filter_map(|(label, name)| {
+            let name = name?.trim();
+            (!name.is_empty()).then(|| format!("{label}: {name}"))
+        })
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Rough token count for budgeting: about four characters per token, rounded up.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Drops the oldest messages until the context fits `token_budget`, always keeping the most
+/// recent one. Returns how many messages were dropped.
+pub fn trim_to_token_budget(context: &mut Vec<ContextMessage>, token_budget: usize) -> usize {
+    let mut total: usize = context
+        .iter()
+        .map(|message| estimate_tokens(&message.as_llm_user_content()))
+        .sum();
+    let mut dropped = 0;
+    while total > token_budget && context.len() - dropped > 1 {
+        total -= estimate_tokens(&context[dropped].as_llm_user_content());
+        dropped += 1;
+    }
+    context.drain(..dropped);
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ContextMessage, chat_metadata_line, estimate_tokens, reply_target_context,
+        trim_to_token_budget,
+    };
+
+    /// `"Me: "` plus `text` so each message costs exactly `tokens` estimated tokens.
+    fn message(tokens: usize) -> ContextMessage {
+        ContextMessage {
+            sender_name: "Me".to_owned(),
+            text: "x".repeat(tokens * 4 - 4),
+            is_own: true,
+        }
+    }
+
+    #[test]
+    fn chat_metadata_line_names_chat_and_topic_when_known() {
+        assert_eq!(
+            chat_metadata_line(Some("Work – Infra team"), Some("Deploys")).as_deref(),
+            Some("Chat: Work – Infra team, Topic: Deploys")
+        );
+        assert_eq!(
+            chat_metadata_line(Some("Shitposting"), None).as_deref(),
+            Some("Chat: Shitposting")
+        );
+        assert_eq!(
+            chat_metadata_line(Some(" "), Some("Deploys")).as_deref(),
+            Some("Topic: Deploys")
+        );
+        assert_eq!(chat_metadata_line(None, None), None);
+    }
+
+    #[test]
+    fn estimate_tokens_rounds_up_per_four_chars() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("привет"), 2);
+    }
+
+    #[test]
+    fn trim_keeps_context_that_exactly_fits_budget() {
+        let mut context = vec![message(2), message(3), message(5)];
+        assert_eq!(trim_to_token_budget(&mut context, 10), 0);
+        assert_eq!(context.len(), 3);
+    }
+
+    #[test]
+    fn trim_drops_oldest_messages_one_token_over_budget() {
+        let mut context = vec![message(2), message(3), message(5)];
+        let newest = context[2].clone();
+        assert_eq!(trim_to_token_budget(&mut context, 9), 1);
+        assert_eq!(context, vec![message(3), newest]);
+
+        let mut context = vec![message(2), message(3), message(5)];
+        assert_eq!(trim_to_token_budget(&mut context, 7), 2);
+        assert_eq!(context, vec![message(5)]);
+    }
+
+    #[test]
+    fn trim_always_keeps_most_recent_message() {
+        let mut context = vec![message(2), message(50)];
+        assert_eq!(trim_to_token_budget(&mut context, 1), 1);
+        assert_eq!(context, vec![message(50)]);
+
+        let mut empty = Vec::new();
+        assert_eq!(trim_to_token_budget(&mut empty, 1), 0);
+    }
+
+    #[test]
+    fn reply_target_context_is_a_labeled_user_turn() {
+        let own = ContextMessage {
+            sender_name: "Me".to_owned(),
+            text: "see you at 5".to_owned(),
+            is_own: true,
+        };
+        assert_eq!(
+            reply_target_context(&own).as_llm_user_content(),
+            "(replying to) Me: see you at 5"
+        );
+        assert!(!reply_target_context(&own).is_own);
     }
 }
@@ -0,0 +1,532 @@
+use grammers_client::tl;
+
+/// Appended to the system prompt with `rewrite.preserve_formatting`.
+pub const MARKDOWN_PROMPT_SUFFIX: &str = "The message is written in Markdown: **bold**, \
+__italic__, ~~strikethrough~~, `code`, ```code blocks``` and [text](url) links. Keep every \
+link URL and formatting marker intact in the rewrite.";
+
+const MENTION_URL_PREFIX: &str = "tg://user?id=";
+
+/// Renders `text` with its Telegram entities as the Markdown understood by
+/// [`parse_markdown`]. Entity kinds without a Markdown form are dropped.
+pub fn entities_to_markdown(text: &str, entities: &[tl::enums::MessageEntity]) -> String {
+    // (UTF-16 offset, closes before opens, inner before outer, marker)
+    let mut markers: Vec<(usize, u8, i64, String)> = Vec::new();
+    for entity in entities {
+        let Some((offset, length, open, close)) = entity_markers(entity) else {
+            continue;
+        };
+        let start = offset.max(0) as usize;
+        let end = start + length.max(0) as usize;
+        markers.push((start, 1, -i64::from(length), open));
+        markers.push((end, 0, -(start as i64), close));
+    }
+    markers.sort_by_key(|(offset, order, nesting, _)| (*offset, *order, *nesting));
+
+    let mut markdown = String::with_capacity(text.len() + markers.len() * 2);
+    let mut markers = markers.into_iter().peekable();
+    let mut utf16_offset = 0;
+    for ch in text.chars() {
+        while let Some((_, _, _, marker)) =
+            markers.next_if(|(offset, _, _, _)| *offset <= utf16_offset)
+        {
+            markdown.push_str(&marker);
+        }
+        markdown.push(ch);
+        utf16_offset += ch.len_utf16();
+    }
+    for (_, _, _, marker) in markers {
+        markdown.push_str(&marker);
+    }
+    markdown
+}
+
+/// `(offset, length, opening marker, closing marker)` of an entity with a Markdown form.
+fn entity_markers(entity: &tl::enums::MessageEntity) -> Option<(i32, i32, String, String)> {
+    use tl::enums::MessageEntity;
+    let pair =
+        |offset, length, marker: &str| Some((offset, length, marker.to_owned(), marker.to_owned()));
+    match entity {
+        MessageEntity::Bold(bold) => pair(bold.offset, bold.length, "**"),
+        MessageEntity::Italic(italic) => pair(italic.offset, italic.length, "__"),
+        MessageEntity::Strike(strike) => pair(strike.offset, strike.length, "~~"),
+        MessageEntity::Code(code) => pair(code.offset, code.length, "`"),
+        MessageEntity::Pre(pre) => Some((
+            pre.offset,
+            pre.length,
+            format!("```{}\n", pre.language),
+            "\n```".to_owned(),
+        )),
+        MessageEntity::TextUrl(link) => Some((
+            link.offset,
+            link.length,
+            "[".to_owned(),
+            format!("]({})", link.url),
+        )),
+        MessageEntity::MentionName(mention) => Some((
+            mention.offset,
+            mention.length,
+            "[".to_owned(),
+            format!("]({MENTION_URL_PREFIX}{})", mention.user_id),
+        )),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Span {
+    Bold,
+    Italic,
+    Underline,
+    Strike,
+    Link,
+}
+
+const SPAN_MARKERS: [(&str, Span); 3] = [
+    ("**", Span::Bold),
+    ("__", Span::Italic),
+    ("~~", Span::Strike),
+];
+
+/// Splits Markdown into plain text and Telegram entities. Returns `None` when a marker is
+/// left open, so the caller can send the text as is instead.
+pub fn parse_markdown(markdown: &str) -> Option<(String, Vec<tl::enums::MessageEntity>)> {
+    let mut text = String::with_capacity(markdown.len());
+    // Spans are pushed when they close, so inner ones come first until sorted below.
+    let mut entities: Vec<(i32, tl::enums::MessageEntity)> = Vec::new();
+    let mut open: Vec<(Span, i32)> = Vec::new();
+    let mut offset: i32 = 0;
+    let mut rest = markdown;
+
+    while let Some(ch) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("```") {
+            let (inner, remaining) = after.split_once("```")?;
+            let (language, body) = match inner.split_once('\n') {
+                Some((language, body)) if !language.contains(char::is_whitespace) => {
+                    (language, body.strip_suffix('\n').unwrap_or(body))
+                }
+                _ => ("", inner),
+            };
+            let length = utf16_len(body);
+            entities.push((
+                offset,
+                tl::types::MessageEntityPre {
+                    offset,
+                    length,
+                    language: language.to_owned(),
+                }
+                .into(),
+            ));
+            text.push_str(body);
+            offset += length;
+            rest = remaining;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('`') {
+            let (body, remaining) = after.split_once('`')?;
+            let length = utf16_len(body);
+            entities.push((
+                offset,
+                tl::types::MessageEntityCode { offset, length }.into(),
+            ));
+            text.push_str(body);
+            offset += length;
+            rest = remaining;
+            continue;
+        }
+        if let Some((marker, span)) = SPAN_MARKERS
+            .iter()
+            .find(|(marker, _)| rest.starts_with(marker))
+        {
+            if open.last().is_some_and(|(top, _)| top == span) {
+                let (_, start) = open.pop()?;
+                push_span(&mut entities, *span, start, offset, "");
+            } else {
+                open.push((*span, offset));
+            }
+            rest = &rest[marker.len()..];
+            continue;
+        }
+        if ch == '[' && rest[1..].contains("](") {
+            open.push((Span::Link, offset));
+            rest = &rest[1..];
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("](")
+            && open.last().is_some_and(|(top, _)| *top == Span::Link)
+        {
+            let (url, remaining) = after.split_once(')')?;
+            let (_, start) = open.pop()?;
+            push_span(&mut entities, Span::Link, start, offset, url);
+            rest = remaining;
+            continue;
+        }
+        text.push(ch);
+        offset += ch.len_utf16() as i32;
+        rest = &rest[ch.len_utf8()..];
+    }
+
+    if !open.is_empty() {
+        return None;
+    }
+    entities.sort_by_key(|(offset, _)| *offset);
+    Some((
+        text,
+        entities.into_iter().map(|(_, entity)| entity).collect(),
+    ))
+}
+
+fn push_span(
+    entities: &mut Vec<(i32, tl::enums::MessageEntity)>,
+    span: Span,
+    start: i32,
+    end: i32,
+    url: &str,
+) {
+    let (offset, length) = (start, end - start);
+    if length == 0 {
+        return;
+    }
+    let entity = match span {
+        Span::Bold => tl::types::MessageEntityBold { offset, length }.into(),
+        Span::Italic => tl::types::MessageEntityItalic { offset, length }.into(),
+        Span::Underline => tl::types::MessageEntityUnderline { offset, length }.into(),
+        Span::Strike => tl::types::MessageEntityStrike { offset, length }.into(),
+        Span::Link => match url
+            .strip_prefix(MENTION_URL_PREFIX)
+            .and_then(|id| id.parse().ok())
+        {
+            Some(user_id) => tl::types::MessageEntityMentionName {
+                offset,
+                length,
+                user_id,
+            }
+            .into(),
+            None => tl::types::MessageEntityTextUrl {
+                offset,
+                length,
+                url: url.to_owned(),
+            }
+            .into(),
+        },
+    };
+    entities.push((offset, entity));
+}
+
+/// An open tag of [`parse_html`], with what its closing tag needs.
+enum HtmlTag {
+    Span(&'static str, Span, String),
+    Code,
+    Pre(String),
+    /// The `<code>` wrapped in a `<pre>`, which only names the block's language.
+    PreCode,
+}
+
+/// Splits Telegram-style HTML into plain text and entities; `None` for unknown tags.
+pub fn parse_html(html: &str) -> Option<(String, Vec<tl::enums::MessageEntity>)> {
+    let mut text = String::with_capacity(html.len());
+    let mut entities: Vec<(i32, tl::enums::MessageEntity)> = Vec::new();
+    let mut open: Vec<(HtmlTag, i32)> = Vec::new();
+    let mut offset: i32 = 0;
+    let mut rest = html;
+
+    while let Some(ch) = rest.chars().next() {
+        if ch == '<' {
+            let (tag, remaining) = rest[1..].split_once('>')?;
+            rest = remaining;
+            if let Some(name) = tag.strip_prefix('/') {
+                let (tag, start) = open.pop()?;
+                let name = html_tag_name(name.trim())?;
+                let length = offset - start;
+                match tag {
+                    HtmlTag::Span(open_name, span, url) if open_name == name => {
+                        push_span(&mut entities, span, start, offset, &url);
+                    }
+                    HtmlTag::Code if name == "code" && length > 0 => entities.push((
+                        start,
+                        tl::types::MessageEntityCode {
+                            offset: start,
+                            length,
+                        }
+                        .into(),
+                    )),
+                    HtmlTag::Pre(language) if name == "pre" && length > 0 => entities.push((
+                        start,
+                        tl::types::MessageEntityPre {
+                            offset: start,
+                            length,
+                            language,
+                        }
+                        .into(),
+                    )),
+                    HtmlTag::Code | HtmlTag::PreCode if name == "code" => {}
+                    HtmlTag::Pre(_) if name == "pre" => {}
+                    _ => return None,
+                }
+                continue;
+            }
+            let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let tag = match html_tag_name(name)? {
+                "b" => HtmlTag::Span("b", Span::Bold, String::new()),
+                "i" => HtmlTag::Span("i", Span::Italic, String::new()),
+                "u" => HtmlTag::Span("u", Span::Underline, String::new()),
+                "s" => HtmlTag::Span("s", Span::Strike, String::new()),
+                "a" => HtmlTag::Span("a", Span::Link, html_attribute(attributes, "href")?),
+                "pre" => HtmlTag::Pre(String::new()),
+                _ => match open.last_mut() {
+                    Some((HtmlTag::Pre(language), start)) if *start == offset => {
+                        if let Some(class) = html_attribute(attributes, "class") {
+                            *language =
+                                class.strip_prefix("language-").unwrap_or(&class).to_owned();
+                        }
+                        HtmlTag::PreCode
+                    }
+                    _ => HtmlTag::Code,
+                },
+            };
+            open.push((tag, offset));
+            continue;
+        }
+        if ch == '&'
+            && let Some((escape, remaining)) = rest[1..].split_once(';')
+            && let Some(unescaped) = html_unescape(escape)
+        {
+            text.push(unescaped);
+            offset += 1;
+            rest = remaining;
+            continue;
+        }
+        text.push(ch);
+        offset += ch.len_utf16() as i32;
+        rest = &rest[ch.len_utf8()..];
+    }
+
+    if !open.is_empty() {
+        return None;
+    }
+    entities.sort_by_key(|(offset, _)| *offset);
+    Some((
+        text,
+        entities.into_iter().map(|(_, entity)| entity).collect(),
+    ))
+}
+
+/// The tag name Telegram's HTML accepts, with aliases such as `strong` folded onto `b`.
+fn html_tag_name(name: &str) -> Option<&'static str> {
+    match name.to_ascii_lowercase().as_str() {
+        "b" | "strong" => Some("b"),
+        "i" | "em" => Some("i"),
+        "u" | "ins" => Some("u"),
+        "s" | "strike" | "del" => Some("s"),
+        "code" => Some("code"),
+        "pre" => Some("pre"),
+        "a" => Some("a"),
+        _ => None,
+    }
+}
+
+/// The double-quoted value of `name` among a tag's attributes.
+fn html_attribute(attributes: &str, name: &str) -> Option<String> {
+    let prefix = format!("{name}=\"");
+    let start = attributes.find(&prefix)? + prefix.len();
+    let (value, _) = attributes[start..].split_once('"')?;
+    Some(value.replace("&amp;", "&"))
+}
+
+fn html_unescape(escape: &str) -> Option<char> {
+    match escape {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        _ => None,
+    }
+}
+
+/// Cuts parsed text to `max_utf16` UTF-16 code units, shortening entities past the cut.
+pub fn truncate_rendered(
+    text: String,
+    entities: Vec<tl::enums::MessageEntity>,
+    max_utf16: usize,
+) -> (String, Vec<tl::enums::MessageEntity>) {
+    let mut utf16_offset = 0;
+    let Some(cut) = text.char_indices().find_map(|(byte_offset, ch)| {
+        utf16_offset += ch.len_utf16();
+        (utf16_offset > max_utf16).then_some(byte_offset)
+    }) else {
+        return (text, entities);
+    };
+    let limit = max_utf16 as i32;
+    let entities = entities
+        .into_iter()
+        .filter_map(|entity| clip_entity(entity, limit))
+        .collect();
+    (text[..cut].to_owned(), entities)
+}
+
+/// The entity cut to end by `limit`, or `None` if it starts there or later.
+fn clip_entity(
+    mut entity: tl::enums::MessageEntity,
+    limit: i32,
+) -> Option<tl::enums::MessageEntity> {
+    use tl::enums::MessageEntity;
+    let (offset, length) = match &mut entity {
+        MessageEntity::Bold(e) => (e.offset, &mut e.length),
+        MessageEntity::Italic(e) => (e.offset, &mut e.length),
+        MessageEntity::Underline(e) => (e.offset, &mut e.length),
+        MessageEntity::Strike(e) => (e.offset, &mut e.length),
+        MessageEntity::Code(e) => (e.offset, &mut e.length),
+        MessageEntity::Pre(e) => (e.offset, &mut e.length),
+        MessageEntity::TextUrl(e) => (e.offset, &mut e.length),
+        MessageEntity::MentionName(e) => (e.offset, &mut e.length),
+        _ => return None,
+    };
+    if offset >= limit {
+        return None;
+    }
+    *length = (*length).min(limit - offset);
+    Some(entity)
+}
+
+fn utf16_len(text: &str) -> i32 {
+    text.encode_utf16().count() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entities_to_markdown, parse_html, parse_markdown, truncate_rendered};
+    use grammers_client::tl;
+
+    fn text_url(offset: i32, length: i32, url: &str) -> tl::enums::MessageEntity {
+        tl::types::MessageEntityTextUrl {
+            offset,
+            length,
+            url: url.to_owned(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn links_and_mentions_round_trip_through_markdown() {
+        let text = "see this doc, thanks Alice";
+        let entities = vec![
+            text_url(4, 8, "https://example.com/doc"),
+            tl::types::MessageEntityMentionName {
+                offset: 21,
+                length: 5,
+                user_id: 42,
+            }
+            .into(),
+        ];
+
+        let markdown = entities_to_markdown(text, &entities);
+        assert_eq!(
+            markdown,
+            "see [this doc](https://example.com/doc), thanks [Alice](tg://user?id=42)"
+        );
+        assert_eq!(parse_markdown(&markdown), Some((text.to_owned(), entities)));
+    }
+
+    #[test]
+    fn nested_spans_and_utf16_offsets_round_trip() {
+        let text = "🙂 bold link and x";
+        let entities: Vec<tl::enums::MessageEntity> = vec![
+            tl::types::MessageEntityBold {
+                offset: 3,
+                length: 9,
+            }
+            .into(),
+            text_url(8, 4, "https://example.com"),
+            tl::types::MessageEntityCode {
+                offset: 17,
+                length: 1,
+            }
+            .into(),
+        ];
+
+        let markdown = entities_to_markdown(text, &entities);
+        assert_eq!(markdown, "🙂 **bold [link](https://example.com)** and `x`");
+        assert_eq!(parse_markdown(&markdown), Some((text.to_owned(), entities)));
+    }
+
+    #[test]
+    fn code_blocks_keep_their_language() {
+        let (text, entities) =
+            parse_markdown("run:\n```sh\ncargo test\n```").expect("markdown should parse");
+        assert_eq!(text, "run:\ncargo test");
+        assert_eq!(
+            entities,
+            vec![
+                tl::types::MessageEntityPre {
+                    offset: 5,
+                    length: 10,
+                    language: "sh".to_owned(),
+                }
+                .into()
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_are_rejected_and_plain_brackets_kept() {
+        assert_eq!(parse_markdown("**bold without end"), None);
+        assert_eq!(parse_markdown("a `stray tick"), None);
+        assert_eq!(
+            parse_markdown("see [1] and 2 * 3"),
+            Some(("see [1] and 2 * 3".to_owned(), Vec::new()))
+        );
+    }
+
+    #[test]
+    fn html_tags_and_escapes_become_entities() {
+        let (text, entities) = parse_html(
+            "<b>bold</b> &lt;3 <a href=\"https://example.com/?a=1&amp;b=2\">link</a> & \
+             <pre><code class=\"language-sh\">ls</code></pre>",
+        )
+        .expect("html should parse");
+        assert_eq!(text, "bold <3 link & ls");
+        assert_eq!(
+            entities,
+            vec![
+                tl::types::MessageEntityBold {
+                    offset: 0,
+                    length: 4,
+                }
+                .into(),
+                text_url(8, 4, "https://example.com/?a=1&b=2"),
+                tl::types::MessageEntityPre {
+                    offset: 15,
+                    length: 2,
+                    language: "sh".to_owned(),
+                }
+                .into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_or_unknown_html_tags_are_rejected() {
+        assert_eq!(parse_html("<b>bold"), None);
+        assert_eq!(parse_html("<b><i>crossed</b></i>"), None);
+        assert_eq!(parse_html("<blink>no</blink>"), None);
+        assert_eq!(parse_html("a < b"), None);
+    }
+
+    #[test]
+    fn truncation_counts_rendered_utf16_and_clips_entities() {
+        let (text, entities) =
+            parse_markdown("🙂 **bold** and `code`").expect("markdown should parse");
+        let (text, entities) = truncate_rendered(text, entities, 6);
+        assert_eq!(text, "🙂 bol");
+        assert_eq!(
+            entities,
+            vec![
+                tl::types::MessageEntityBold {
+                    offset: 3,
+                    length: 3,
+                }
+                .into()
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use axum::Router;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// What `/healthz` and `/readyz` report, updated by the main loop.
+#[derive(Debug, Default)]
+pub struct HealthState {
+    /// Set at the `RuntimeReady` point: every account is signed in and preflighted.
+    ready: bool,
+    last_update_at: Option<Instant>,
+    /// Result of the latest LLM health check; `None` before the first one.
+    llm_ok: Option<bool>,
+}
+
+/// Outcome of a probe; failures carry the reason, which is also the response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Pass,
+    Fail(&'static str),
+}
+
+impl HealthState {
+    /// Startup is over; the update stream counts as fresh from `now`.
+    pub fn mark_ready(&mut self, now: Instant) {
+        self.ready = true;
+        self.last_update_at = Some(now);
+    }
+
+    pub fn record_update(&mut self, now: Instant) {
+        self.last_update_at = Some(now);
+    }
+
+    pub fn record_llm_health(&mut self, ok: bool) {
+        self.llm_ok = Some(ok);
+    }
+
+    /// Fails when the last LLM health check failed or, once ready, when no update arrived
+    /// within `max_update_age`. Startup, which can wait on a login, only fails on the former.
+    pub fn liveness(&self, now: Instant, max_update_age: Duration) -> Probe {
+        if self.llm_ok == Some(false) {
+            return Probe::Fail("llm health check failed");
+        }
+        if !self.ready {
+            return Probe::Pass;
+        }
+        match self.last_update_at {
+            Some(at) if now.saturating_duration_since(at) <= max_update_age => Probe::Pass,
+            _ => Probe::Fail("no telegram updates within health.max_update_age_seconds"),
+        }
+    }
+
+    pub fn readiness(&self) -> Probe {
+        if self.ready {
+            Probe::Pass
+        } else {
+            Probe::Fail("starting")
+        }
+    }
+}
+
+impl IntoResponse for Probe {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Probe::Pass => (StatusCode::OK, "ok").into_response(),
+            Probe::Fail(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Probes {
+    state: Arc<RwLock<HealthState>>,
+    max_update_age: Duration,
+}
+
+/// The `/healthz` and `/readyz` routes, to be served by a
+/// [`StatusServer`](crate::status_server::StatusServer).
+pub fn routes(state: Arc<RwLock<HealthState>>, max_update_age: Duration) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(Probes {
+            state,
+            max_update_age,
+        })
+}
+
+async fn healthz(State(probes): State<Probes>) -> Probe {
+    probes
+        .state
+        .read()
+        .expect("health state lock poisoned")
+        .liveness(Instant::now(), probes.max_update_age)
+}
+
+async fn readyz(State(probes): State<Probes>) -> Probe {
+    probes
+        .state
+        .read()
+        .expect("health state lock poisoned")
+        .readiness()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HealthState, Probe};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn probes_follow_startup_updates_and_llm_health() {
+        let max_age = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut state = HealthState::default();
+        assert_eq!(state.readiness(), Probe::Fail("starting"));
+        assert_eq!(state.liveness(start, max_age), Probe::Pass);
+
+        state.record_llm_health(true);
+        state.mark_ready(start);
+        assert_eq!(state.readiness(), Probe::Pass);
+        assert_eq!(
+            state.liveness(start + Duration::from_secs(60), max_age),
+            Probe::Pass
+        );
+        assert!(matches!(
+            state.liveness(start + Duration::from_secs(61), max_age),
+            Probe::Fail(_)
+        ));
+
+        state.record_update(start + Duration::from_secs(90));
+        assert_eq!(
+            state.liveness(start + Duration::from_secs(120), max_age),
+            Probe::Pass
+        );
+
+        state.record_llm_health(false);
+        assert_eq!(
+            state.liveness(start + Duration::from_secs(120), max_age),
+            Probe::Fail("llm health check failed")
+        );
+        assert_eq!(state.readiness(), Probe::Pass);
+    }
+}
@@ -0,0 +1,166 @@
+use whatlang::Lang;
+
+/// Detections below this confidence are too unreliable (usually very short messages)
+/// to justify skipping a rewrite, so they are treated as matching any language.
+const MIN_LANGUAGE_CONFIDENCE: f64 = 0.5;
+
+/// ISO 639-1 aliases for the ISO 639-3 codes used by whatlang.
+const ISO_639_1_TO_3: &[(&str, &str)] = &[
+    ("af", "afr"),
+    ("ak", "aka"),
+    ("am", "amh"),
+    ("ar", "ara"),
+    ("az", "aze"),
+    ("be", "bel"),
+    ("bg", "bul"),
+    ("bn", "ben"),
+    ("ca", "cat"),
+    ("cs", "ces"),
+    ("cy", "cym"),
+    ("da", "dan"),
+    ("de", "deu"),
+    ("el", "ell"),
+    ("en", "eng"),
+    ("eo", "epo"),
+    ("es", "spa"),
+    ("et", "est"),
+    ("fa", "pes"),
+    ("fi", "fin"),
+    ("fr", "fra"),
+    ("gu", "guj"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hr", "hrv"),
+    ("hu", "hun"),
+    ("hy", "hye"),
+    ("id", "ind"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("jv", "jav"),
+    ("ka", "kat"),
+    ("km", "khm"),
+    ("kn", "kan"),
+    ("ko", "kor"),
+    ("la", "lat"),
+    ("lt", "lit"),
+    ("lv", "lav"),
+    ("mk", "mkd"),
+    ("ml", "mal"),
+    ("mr", "mar"),
+    ("my", "mya"),
+    ("nb", "nob"),
+    ("ne", "nep"),
+    ("nl", "nld"),
+    ("or", "ori"),
+    ("pa", "pan"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "ron"),
+    ("ru", "rus"),
+    ("si", "sin"),
+    ("sk", "slk"),
+    ("sl", "slv"),
+    ("sn", "sna"),
+    ("sr", "srp"),
+    ("sv", "swe"),
+    ("ta", "tam"),
+    ("te", "tel"),
+    ("th", "tha"),
+    ("tk", "tuk"),
+    ("tl", "tgl"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("ur", "urd"),
+    ("uz", "uzb"),
+    ("vi", "vie"),
+    ("yi", "yid"),
+    ("zh", "cmn"),
+    ("zu", "zul"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedLanguage {
+    pub lang: Lang,
+    pub confidence: f64,
+}
+
+/// Parses an ISO 639-1 (`en`) or ISO 639-3 (`eng`) language code.
+pub fn parse_language_code(code: &str) -> Option<Lang> {
+    let code = code.trim().to_ascii_lowercase();
+    let code = ISO_639_1_TO_3
+        .iter()
+        .find(|(short, _)| *short == code)
+        .map_or(code.as_str(), |(_, long)| long);
+    Lang::from_code(code)
+}
+
+pub fn detect_language(text: &str) -> Option<DetectedLanguage> {
+    whatlang::detect(text).map(|info| DetectedLanguage {
+        lang: info.lang(),
+        confidence: info.confidence(),
+    })
+}
+
+/// Returns whether a message should be rewritten given the configured language list.
+/// Undetected and low-confidence detections always match to avoid false skips.
+pub fn language_matches(detected: Option<&DetectedLanguage>, allowed: &[String]) -> bool {
+    let Some(detected) = detected else {
+        return true;
+    };
+    if detected.confidence < MIN_LANGUAGE_CONFIDENCE {
+        return true;
+    }
+    allowed
+        .iter()
+        .any(|code| parse_language_code(code) == Some(detected.lang))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DetectedLanguage, detect_language, language_matches, parse_language_code};
+    use whatlang::Lang;
+
+    #[test]
+    fn parse_language_code_accepts_two_and_three_letter_codes() {
+        assert_eq!(parse_language_code("en"), Some(Lang::Eng));
+        assert_eq!(parse_language_code("eng"), Some(Lang::Eng));
+        assert_eq!(parse_language_code(" UK "), Some(Lang::Ukr));
+        assert_eq!(parse_language_code("xx"), None);
+    }
+
+    #[test]
+    fn detect_language_distinguishes_english_and_ukrainian() {
+        let english = detect_language("I will be a little late to the meeting this afternoon")
+            .expect("english text should be detected");
+        assert_eq!(english.lang, Lang::Eng);
+        let ukrainian = detect_language("Я трохи запізнюся на зустріч сьогодні після обіду")
+            .expect("ukrainian text should be detected");
+        assert_eq!(ukrainian.lang, Lang::Ukr);
+    }
+
+    #[test]
+    fn language_matches_only_listed_languages_when_confident() {
+        let allowed = vec!["en".to_owned()];
+        let english = DetectedLanguage {
+            lang: Lang::Eng,
+            confidence: 0.9,
+        };
+        let ukrainian = DetectedLanguage {
+            lang: Lang::Ukr,
+            confidence: 0.9,
+        };
+        assert!(language_matches(Some(&english), &allowed));
+        assert!(!language_matches(Some(&ukrainian), &allowed));
+    }
+
+    #[test]
+    fn language_matches_low_confidence_and_undetected_text() {
+        let allowed = vec!["en".to_owned()];
+        let unsure = DetectedLanguage {
+            lang: Lang::Ukr,
+            confidence: 0.1,
+        };
+        assert!(language_matches(Some(&unsure), &allowed));
+        assert!(language_matches(None, &allowed));
+    }
+}
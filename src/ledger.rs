@@ -0,0 +1,728 @@
+use crate::context::{ContextEntry, ContextMessage};
+use anyhow::{Context, Result};
+use libsql::{Builder, Connection, params};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS rewritten_messages (
+    account TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    text_hash INTEGER NOT NULL,
+    rewritten_at INTEGER NOT NULL,
+    PRIMARY KEY (account, chat_id, message_id)
+);
+CREATE TABLE IF NOT EXISTS scheduled_rewrites (
+    account TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    rewritten_at INTEGER NOT NULL,
+    PRIMARY KEY (account, chat_id, message_id)
+);
+CREATE TABLE IF NOT EXISTS context_scopes (
+    account TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    topic_root_id INTEGER,
+    hydrated INTEGER NOT NULL,
+    saved_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS context_messages (
+    account TEXT NOT NULL,
+    chat_id INTEGER NOT NULL,
+    topic_root_id INTEGER,
+    position INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    sender_name TEXT NOT NULL,
+    text TEXT NOT NULL,
+    is_own INTEGER NOT NULL,
+    saved_at INTEGER NOT NULL
+);
+";
+
+/// Messages rewritten in earlier runs, kept in `runtime.state_file` so catch-up after a
+/// restart doesn't rewrite them a second time, along with scheduled messages rewritten but
+/// not sent yet and the last saved context cache.
+/// Lookups use the records loaded at startup; new records are written by a background task,
+/// so the update loop never waits on the disk.
+pub struct RewriteLedger {
+    loaded: HashMap<String, HashMap<(i64, i32), u64>>,
+    loaded_context: HashMap<String, Vec<ScopeSnapshot>>,
+    loaded_scheduled: HashMap<String, Vec<ScheduledRewrite>>,
+    writes: mpsc::UnboundedSender<LedgerWrite>,
+    writer: JoinHandle<()>,
+}
+
+/// A scheduled message and the text its rewrite left it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledRewrite {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub text: String,
+}
+
+/// The cached context of one chat or forum topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeSnapshot {
+    pub chat_id: i64,
+    pub topic_root_id: Option<i32>,
+    /// History was already fetched from Telegram for it, or deliberately not.
+    pub hydrated: bool,
+    /// Oldest first.
+    pub messages: Vec<ContextEntry>,
+}
+
+enum LedgerWrite {
+    Rewritten(LedgerRecord),
+    /// Replaces every saved context scope of `account`.
+    Context {
+        account: String,
+        scopes: Vec<ScopeSnapshot>,
+        saved_at: i64,
+    },
+    Scheduled {
+        account: String,
+        rewrite: ScheduledRewrite,
+        rewritten_at: i64,
+    },
+    /// The scheduled message was sent, or is no longer remembered.
+    ScheduledDone {
+        account: String,
+        chat_id: i64,
+        message_id: i32,
+    },
+}
+
+struct LedgerRecord {
+    account: String,
+    chat_id: i64,
+    message_id: i32,
+    text_hash: u64,
+    rewritten_at: i64,
+}
+
+impl RewriteLedger {
+    /// Opens or creates the file at `path`, prunes records older than `retention` and context
+    /// saved longer than `context_max_age` ago, and loads the rest.
+    pub async fn open(path: &Path, retention: Duration, context_max_age: Duration) -> Result<Self> {
+        let connection = Builder::new_local(path)
+            .build()
+            .await
+            .and_then(|database| database.connect())
+            .with_context(|| format!("failed to open state file {}", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .await
+            .with_context(|| format!("failed to set up state file {}", path.display()))?;
+        let cutoff = unix_now().saturating_sub(retention.as_secs() as i64);
+        let pruned = connection
+            .execute(
+                "DELETE FROM rewritten_messages WHERE rewritten_at < ?1",
+                params![cutoff],
+            )
+            .await
+            .context("failed to prune old records from the state file")?;
+        connection
+            .execute(
+                "DELETE FROM scheduled_rewrites WHERE rewritten_at < ?1",
+                params![cutoff],
+            )
+            .await
+            .context("failed to prune old records from the state file")?;
+        let loaded = load_records(&connection)
+            .await
+            .with_context(|| format!("failed to read state file {}", path.display()))?;
+        let context_cutoff = unix_now().saturating_sub(context_max_age.as_secs() as i64);
+        for table in ["context_scopes", "context_messages"] {
+            connection
+                .execute(
+                    &format!("DELETE FROM {table} WHERE saved_at < ?1"),
+                    params![context_cutoff],
+                )
+                .await
+                .context("failed to prune stale context from the state file")?;
+        }
+        let loaded_context = load_context(&connection)
+            .await
+            .with_context(|| format!("failed to read context from {}", path.display()))?;
+        let loaded_scheduled = load_scheduled(&connection)
+            .await
+            .with_context(|| format!("failed to read state file {}", path.display()))?;
+        info!(
+            path = %path.display(),
+            records = loaded.values().map(HashMap::len).sum::<usize>(),
+            pruned,
+            context_scopes = loaded_context.values().map(Vec::len).sum::<usize>(),
+            "loaded rewritten messages from state file"
+        );
+
+        let (writes, records) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_records(connection, records));
+        Ok(Self {
+            loaded,
+            loaded_context,
+            loaded_scheduled,
+            writes,
+            writer,
+        })
+    }
+
+    /// The records of `account`, which it adds to as it rewrites.
+    pub fn for_account(&mut self, account: &str) -> AccountLedger {
+        AccountLedger {
+            account: account.to_owned(),
+            rewritten: self.loaded.remove(account).unwrap_or_default(),
+            writes: self.writes.clone(),
+        }
+    }
+
+    /// The context `account` saved last run, oldest message first per scope, and where to
+    /// save it this run.
+    pub fn context_for_account(&mut self, account: &str) -> (Vec<ScopeSnapshot>, ContextStore) {
+        let store = ContextStore {
+            account: account.to_owned(),
+            writes: self.writes.clone(),
+        };
+        (
+            self.loaded_context.remove(account).unwrap_or_default(),
+            store,
+        )
+    }
+
+    /// The scheduled messages `account` rewrote in earlier runs that weren't sent yet, oldest
+    /// first, and where to record them this run.
+    pub fn scheduled_for_account(
+        &mut self,
+        account: &str,
+    ) -> (Vec<ScheduledRewrite>, ScheduledStore) {
+        let store = ScheduledStore {
+            account: account.to_owned(),
+            writes: self.writes.clone(),
+        };
+        (
+            self.loaded_scheduled.remove(account).unwrap_or_default(),
+            store,
+        )
+    }
+
+    /// Waits for queued records to be written. Every [`AccountLedger`] must be dropped
+    /// first, or this waits for them.
+    pub async fn close(self) {
+        drop(self.writes);
+        if let Err(err) = self.writer.await {
+            warn!(error = %err, "state file writer stopped unexpectedly");
+        }
+    }
+}
+
+/// One account's view of the [`RewriteLedger`].
+pub struct AccountLedger {
+    account: String,
+    rewritten: HashMap<(i64, i32), u64>,
+    writes: mpsc::UnboundedSender<LedgerWrite>,
+}
+
+impl AccountLedger {
+    /// True when `text` is what a recorded rewrite left in the message. A message edited by
+    /// hand since doesn't match.
+    pub fn is_rewritten(&self, chat_id: i64, message_id: i32, text: &str) -> bool {
+        self.rewritten.get(&(chat_id, message_id)) == Some(&text_hash(text))
+    }
+
+    /// Records that the message now holds `applied_text`, and queues the record for the
+    /// state file.
+    pub fn record(&mut self, chat_id: i64, message_id: i32, applied_text: &str) {
+        let text_hash = text_hash(applied_text);
+        self.rewritten.insert((chat_id, message_id), text_hash);
+        let record = LedgerRecord {
+            account: self.account.clone(),
+            chat_id,
+            message_id,
+            text_hash,
+            rewritten_at: unix_now(),
+        };
+        if self.writes.send(LedgerWrite::Rewritten(record)).is_err() {
+            warn!(
+                chat_id,
+                message_id, "state file writer has stopped; rewrite not recorded"
+            );
+        }
+    }
+}
+
+/// Where one account's context cache is saved for the next run.
+pub struct ContextStore {
+    account: String,
+    writes: mpsc::UnboundedSender<LedgerWrite>,
+}
+
+impl ContextStore {
+    /// Queues `scopes` to replace what was saved before.
+    pub fn save(&self, scopes: Vec<ScopeSnapshot>) {
+        let write = LedgerWrite::Context {
+            account: self.account.clone(),
+            scopes,
+            saved_at: unix_now(),
+        };
+        if self.writes.send(write).is_err() {
+            warn!(account = %self.account, "state file writer has stopped; context not saved");
+        }
+    }
+}
+
+/// Where one account's rewritten scheduled messages are recorded until they are sent.
+pub struct ScheduledStore {
+    account: String,
+    writes: mpsc::UnboundedSender<LedgerWrite>,
+}
+
+impl ScheduledStore {
+    pub fn record(&self, rewrite: ScheduledRewrite) {
+        let write = LedgerWrite::Scheduled {
+            account: self.account.clone(),
+            rewrite,
+            rewritten_at: unix_now(),
+        };
+        if self.writes.send(write).is_err() {
+            warn!(account = %self.account, "state file writer has stopped; scheduled rewrite not recorded");
+        }
+    }
+
+    pub fn forget(&self, chat_id: i64, message_id: i32) {
+        let write = LedgerWrite::ScheduledDone {
+            account: self.account.clone(),
+            chat_id,
+            message_id,
+        };
+        if self.writes.send(write).is_err() {
+            warn!(account = %self.account, "state file writer has stopped; scheduled rewrite not forgotten");
+        }
+    }
+}
+
+async fn load_records(
+    connection: &Connection,
+) -> libsql::Result<HashMap<String, HashMap<(i64, i32), u64>>> {
+    let mut rows = connection
+        .query(
+            "SELECT account, chat_id, message_id, text_hash FROM rewritten_messages",
+            (),
+        )
+        .await?;
+    let mut loaded: HashMap<String, HashMap<(i64, i32), u64>> = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        let account: String = row.get(0)?;
+        let chat_id: i64 = row.get(1)?;
+        let message_id: i32 = row.get(2)?;
+        // SQLite integers are signed; the hash is stored with its bits unchanged.
+        let text_hash: i64 = row.get(3)?;
+        loaded
+            .entry(account)
+            .or_default()
+            .insert((chat_id, message_id), text_hash as u64);
+    }
+    Ok(loaded)
+}
+
+async fn load_scheduled(
+    connection: &Connection,
+) -> libsql::Result<HashMap<String, Vec<ScheduledRewrite>>> {
+    let mut rows = connection
+        .query(
+            "SELECT account, chat_id, message_id, text FROM scheduled_rewrites \
+             ORDER BY rewritten_at",
+            (),
+        )
+        .await?;
+    let mut loaded: HashMap<String, Vec<ScheduledRewrite>> = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        let account: String = row.get(0)?;
+        loaded.entry(account).or_default().push(ScheduledRewrite {
+            chat_id: row.get(1)?,
+            message_id: row.get(2)?,
+            text: row.get(3)?,
+        });
+    }
+    Ok(loaded)
+}
+
+async fn load_context(
+    connection: &Connection,
+) -> libsql::Result<HashMap<String, Vec<ScopeSnapshot>>> {
+    type ScopeKey = (String, i64, Option<i32>);
+    let mut scopes: HashMap<ScopeKey, ScopeSnapshot> = HashMap::new();
+    let mut rows = connection
+        .query(
+            "SELECT account, chat_id, topic_root_id, hydrated FROM context_scopes",
+            (),
+        )
+        .await?;
+    while let Some(row) = rows.next().await? {
+        let key: ScopeKey = (row.get(0)?, row.get(1)?, row.get(2)?);
+        let hydrated: i64 = row.get(3)?;
+        scopes.insert(
+            key.clone(),
+            ScopeSnapshot {
+                chat_id: key.1,
+                topic_root_id: key.2,
+                hydrated: hydrated != 0,
+                messages: Vec::new(),
+            },
+        );
+    }
+    let mut rows = connection
+        .query(
+            "SELECT account, chat_id, topic_root_id, message_id, sender_name, text, is_own \
+             FROM context_messages ORDER BY position",
+            (),
+        )
+        .await?;
+    while let Some(row) = rows.next().await? {
+        let key: ScopeKey = (row.get(0)?, row.get(1)?, row.get(2)?);
+        let is_own: i64 = row.get(6)?;
+        // Messages of a scope whose row is gone (pruned separately) are dropped with it.
+        if let Some(scope) = scopes.get_mut(&key) {
+            scope.messages.push(ContextEntry {
+                message_id: row.get(3)?,
+                message: ContextMessage {
+                    sender_name: row.get(4)?,
+                    text: row.get(5)?,
+                    is_own: is_own != 0,
+                },
+            });
+        }
+    }
+    let mut loaded: HashMap<String, Vec<ScopeSnapshot>> = HashMap::new();
+    for ((account, _, _), scope) in scopes {
+        loaded.entry(account).or_default().push(scope);
+    }
+    Ok(loaded)
+}
+
+async fn write_records(connection: Connection, mut writes: mpsc::UnboundedReceiver<LedgerWrite>) {
+    while let Some(write) = writes.recv().await {
+        match write {
+            LedgerWrite::Rewritten(record) => write_record(&connection, record).await,
+            LedgerWrite::Context {
+                account,
+                scopes,
+                saved_at,
+            } => {
+                if let Err(err) = write_context(&connection, &account, &scopes, saved_at).await {
+                    warn!(account = %account, error = %err, "failed to save context to state file");
+                }
+            }
+            LedgerWrite::Scheduled {
+                account,
+                rewrite,
+                rewritten_at,
+            } => {
+                let written = connection
+                    .execute(
+                        "INSERT OR REPLACE INTO scheduled_rewrites \
+                         (account, chat_id, message_id, text, rewritten_at) \
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            account,
+                            rewrite.chat_id,
+                            rewrite.message_id,
+                            rewrite.text,
+                            rewritten_at,
+                        ],
+                    )
+                    .await;
+                if let Err(err) = written {
+                    warn!(
+                        chat_id = rewrite.chat_id,
+                        message_id = rewrite.message_id,
+                        error = %err,
+                        "failed to record scheduled rewrite in state file"
+                    );
+                }
+            }
+            LedgerWrite::ScheduledDone {
+                account,
+                chat_id,
+                message_id,
+            } => {
+                let deleted = connection
+                    .execute(
+                        "DELETE FROM scheduled_rewrites \
+                         WHERE account = ?1 AND chat_id = ?2 AND message_id = ?3",
+                        params![account, chat_id, message_id],
+                    )
+                    .await;
+                if let Err(err) = deleted {
+                    warn!(
+                        chat_id,
+                        message_id,
+                        error = %err,
+                        "failed to remove scheduled rewrite from state file"
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn write_context(
+    connection: &Connection,
+    account: &str,
+    scopes: &[ScopeSnapshot],
+    saved_at: i64,
+) -> libsql::Result<()> {
+    let transaction = connection.transaction().await?;
+    transaction
+        .execute(
+            "DELETE FROM context_scopes WHERE account = ?1",
+            params![account],
+        )
+        .await?;
+    transaction
+        .execute(
+            "DELETE FROM context_messages WHERE account = ?1",
+            params![account],
+        )
+        .await?;
+    for scope in scopes {
+        transaction
+            .execute(
+                "INSERT INTO context_scopes (account, chat_id, topic_root_id, hydrated, saved_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    account,
+                    scope.chat_id,
+                    scope.topic_root_id,
+                    i64::from(scope.hydrated),
+                    saved_at,
+                ],
+            )
+            .await?;
+        for (position, entry) in scope.messages.iter().enumerate() {
+            transaction
+                .execute(
+                    "INSERT INTO context_messages \
+                     (account, chat_id, topic_root_id, position, message_id, sender_name, text, \
+                     is_own, saved_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        account,
+                        scope.chat_id,
+                        scope.topic_root_id,
+                        position as i64,
+                        entry.message_id,
+                        entry.message.sender_name.as_str(),
+                        entry.message.text.as_str(),
+                        i64::from(entry.message.is_own),
+                        saved_at,
+                    ],
+                )
+                .await?;
+        }
+    }
+    transaction.commit().await
+}
+
+async fn write_record(connection: &Connection, record: LedgerRecord) {
+    let written = connection
+        .execute(
+            "INSERT OR REPLACE INTO rewritten_messages \
+             (account, chat_id, message_id, text_hash, rewritten_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.account,
+                record.chat_id,
+                record.message_id,
+                record.text_hash as i64,
+                record.rewritten_at,
+            ],
+        )
+        .await;
+    if let Err(err) = written {
+        warn!(
+            chat_id = record.chat_id,
+            message_id = record.message_id,
+            error = %err,
+            "failed to record rewritten message in state file"
+        );
+    }
+}
+
+/// FNV-1a of the trimmed text. Unlike `DefaultHasher`, it stays the same across Rust
+/// releases, so records written by an older build still match.
+fn text_hash(text: &str) -> u64 {
+    text.trim()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RewriteLedger, ScopeSnapshot, text_hash, unix_now};
+    use crate::context::{ContextEntry, ContextMessage};
+    use libsql::{Builder, params};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn state_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "brainrot-ledger-{name}-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn rewrites_survive_reopening_per_account() {
+        let path = state_path("reopen");
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should open");
+        let mut primary = ledger.for_account("primary");
+        primary.record(-100123, 7, "Rewritten text\n");
+        assert!(primary.is_rewritten(-100123, 7, "Rewritten text"));
+        drop(primary);
+        ledger.close().await;
+
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should reopen");
+        let primary = ledger.for_account("primary");
+        assert!(primary.is_rewritten(-100123, 7, "Rewritten text"));
+        assert!(
+            !primary.is_rewritten(-100123, 7, "edited by hand"),
+            "a manual edit since the rewrite should not match"
+        );
+        assert!(!primary.is_rewritten(-100123, 8, "Rewritten text"));
+        let other = ledger.for_account("work");
+        assert!(!other.is_rewritten(-100123, 7, "Rewritten text"));
+        drop((primary, other));
+        ledger.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn records_past_retention_are_pruned_on_open() {
+        let path = state_path("prune");
+        RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should open")
+            .close()
+            .await;
+        let connection = Builder::new_local(&path)
+            .build()
+            .await
+            .and_then(|database| database.connect())
+            .expect("state file should open");
+        for (message_id, age) in [(1, 7200), (2, 60)] {
+            connection
+                .execute(
+                    "INSERT INTO rewritten_messages VALUES ('primary', 5, ?1, ?2, ?3)",
+                    params![message_id, text_hash("done") as i64, unix_now() - age],
+                )
+                .await
+                .expect("record should insert");
+        }
+        drop(connection);
+
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should reopen");
+        let primary = ledger.for_account("primary");
+        assert!(!primary.is_rewritten(5, 1, "done"));
+        assert!(primary.is_rewritten(5, 2, "done"));
+        drop(primary);
+        ledger.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn saved_context_is_loaded_until_it_goes_stale() {
+        let path = state_path("context");
+        let scope = ScopeSnapshot {
+            chat_id: -100123,
+            topic_root_id: Some(42),
+            hydrated: true,
+            messages: vec![
+                ContextEntry {
+                    message_id: 1,
+                    message: ContextMessage {
+                        sender_name: "Alice".to_owned(),
+                        text: "first".to_owned(),
+                        is_own: false,
+                    },
+                },
+                ContextEntry {
+                    message_id: 2,
+                    message: ContextMessage {
+                        sender_name: "me".to_owned(),
+                        text: "second".to_owned(),
+                        is_own: true,
+                    },
+                },
+            ],
+        };
+        let empty_scope = ScopeSnapshot {
+            chat_id: 5,
+            topic_root_id: None,
+            hydrated: true,
+            messages: Vec::new(),
+        };
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should open");
+        let (loaded, store) = ledger.context_for_account("primary");
+        assert!(loaded.is_empty());
+        store.save(vec![scope.clone(), empty_scope.clone()]);
+        drop(store);
+        ledger.close().await;
+
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should reopen");
+        let (mut loaded, store) = ledger.context_for_account("primary");
+        loaded.sort_unstable_by_key(|scope| scope.chat_id);
+        assert_eq!(loaded, vec![scope, empty_scope]);
+        assert!(ledger.context_for_account("work").0.is_empty());
+        drop(store);
+        ledger.close().await;
+
+        let connection = Builder::new_local(&path)
+            .build()
+            .await
+            .and_then(|database| database.connect())
+            .expect("state file should open");
+        for table in ["context_scopes", "context_messages"] {
+            connection
+                .execute(
+                    &format!("UPDATE {table} SET saved_at = ?1"),
+                    params![unix_now() - 7200],
+                )
+                .await
+                .expect("saved_at should update");
+        }
+        drop(connection);
+        let mut ledger = RewriteLedger::open(&path, HOUR, HOUR)
+            .await
+            .expect("ledger should reopen");
+        assert!(ledger.context_for_account("primary").0.is_empty());
+        ledger.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
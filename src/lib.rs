@@ -1,5 +1,23 @@
 pub mod app;
+pub mod chat_command;
 pub mod config;
 pub mod context;
+pub mod formatting;
+pub mod health;
+pub mod language;
+pub mod ledger;
+pub mod links;
 pub mod llm;
+pub mod log_file;
+pub mod log_format;
+pub mod metrics;
+pub mod refusal;
+pub mod run_stats;
+pub mod secret;
+pub mod send_test;
+pub mod shutdown;
+pub mod status_server;
 pub mod telegram;
+pub mod undo;
+pub mod usage;
+pub mod watchdog;
@@ -0,0 +1,123 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+static URL_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\b(?:https?://|www\.)[^\s<>()\[\]"']+"#).expect("url pattern is valid")
+});
+/// `@username` not preceded by a word character, `@`, `/` or `.`, so e-mail addresses and
+/// URL paths don't count. Telegram usernames are 4 to 32 characters long.
+static MENTION_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|[^\w@/.])(@[A-Za-z][A-Za-z0-9_]{3,31})\b").expect("mention pattern is valid")
+});
+
+/// URLs and `@mentions` in `text`, in order of appearance and without repeats.
+pub fn extract_links(text: &str) -> Vec<String> {
+    let urls = URL_PATTERN.find_iter(text).map(|url| {
+        url.as_str()
+            .trim_end_matches(['.', ',', ';', ':', '!', '?'])
+    });
+    let mentions = MENTION_PATTERN
+        .captures_iter(text)
+        .filter_map(|captures| captures.get(1))
+        .map(|mention| mention.as_str());
+    let mut seen = HashSet::new();
+    urls.chain(mentions)
+        .filter(|link| seen.insert(normalize_link(link)))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The entries of `original_links` that `rewritten` no longer contains.
+pub fn dropped_links(original_links: &[String], rewritten: &str) -> Vec<String> {
+    let kept: HashSet<String> = extract_links(rewritten)
+        .iter()
+        .map(|link| normalize_link(link))
+        .collect();
+    let mut seen = HashSet::new();
+    original_links
+        .iter()
+        .filter(|link| {
+            let normalized = normalize_link(link);
+            !kept.contains(&normalized) && seen.insert(normalized)
+        })
+        .cloned()
+        .collect()
+}
+
+/// `rewritten` with `dropped` links put back on a last line of their own.
+pub fn append_links(rewritten: &str, dropped: &[String]) -> String {
+    format!("{}\n{}", rewritten.trim_end(), dropped.join(" "))
+}
+
+/// Case-folds usernames and the scheme and host of URLs, and drops trailing slashes, so
+/// `https://Example.com/` and `https://example.com` compare equal. Paths keep their case.
+fn normalize_link(link: &str) -> String {
+    if let Some(username) = link.strip_prefix('@') {
+        return format!("@{}", username.to_lowercase());
+    }
+    let (scheme, rest) = match link.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => (String::new(), link),
+    };
+    let host_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(host_end);
+    format!(
+        "{scheme}://{}{}",
+        host.to_lowercase(),
+        path.trim_end_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append_links, dropped_links, extract_links};
+
+    #[test]
+    fn extracts_urls_and_mentions_but_not_emails() {
+        assert_eq!(
+            extract_links(
+                "ask @alice_dev or mail bob@example.com, docs at https://example.com/guide. \
+                 Also www.test.org and [the repo](https://github.com/owner/repo)!"
+            ),
+            vec![
+                "https://example.com/guide",
+                "www.test.org",
+                "https://github.com/owner/repo",
+                "@alice_dev",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_links_and_mentions_missing_from_mixed_rewrite() {
+        let original = extract_links(
+            "@alice and @Bob_Smith: see https://Example.com/Docs/ and https://t.me/channel, \
+             cc @carol",
+        );
+        let rewritten = "Dear @bob_smith, kindly consult https://example.com/Docs \
+                         and let @carol know.";
+        assert_eq!(
+            dropped_links(&original, rewritten),
+            vec!["https://t.me/channel", "@alice"]
+        );
+    }
+
+    #[test]
+    fn url_paths_keep_their_case() {
+        let original = extract_links("https://example.com/AbC");
+        assert_eq!(
+            dropped_links(&original, "https://EXAMPLE.com/abc"),
+            vec!["https://example.com/AbC"]
+        );
+    }
+
+    #[test]
+    fn appended_links_go_on_a_last_line() {
+        let dropped = vec!["https://t.me/channel".to_owned(), "@alice".to_owned()];
+        assert_eq!(
+            append_links("Kindly consult the docs.\n", &dropped),
+            "Kindly consult the docs.\nhttps://t.me/channel @alice"
+        );
+    }
+}
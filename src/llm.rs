@@ -1,24 +1,410 @@
+mod anthropic;
+mod batch;
+mod chat_completions;
+mod ollama;
+mod rate_limit;
+mod response_cache;
+mod responses_stream;
+mod two_stage;
+
+pub use anthropic::AnthropicClient;
+pub use batch::{BatchRewrite, rewrite_batch};
+pub use ollama::OllamaClient;
+pub use rate_limit::{RateLimitedRewriter, RequestRateLimiter};
+pub use response_cache::{CachedRewriter, ResponseCache};
+pub use two_stage::TwoStageRewriter;
+
+use crate::config::{
+    ApiFlavor, ContextStyle, DEFAULT_OPENAI_API_BASE, NetworkConfig, OpenAiConfig, ProviderConfig,
+    ReasoningEffortLevel, RetryConfig,
+};
 use crate::context::ContextMessage;
-use anyhow::{Context, Result, bail};
-use async_openai::config::OpenAIConfig;
+use anyhow::{Context, Result, anyhow, bail};
 use async_openai::types::responses::{
     CreateResponse, EasyInputContent, EasyInputMessage, InputItem, InputParam, MessageType,
-    OutputItem, OutputMessageContent, Role,
-};
-use async_openai::{
-    Client,
-    types::responses::{Reasoning, ReasoningEffort},
+    OutputItem, OutputMessageContent, Reasoning, ReasoningEffort, Role,
 };
-use std::time::Duration;
-use tracing::debug;
+use chat_completions::build_chat_completion_request;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const ERROR_BODY_PREVIEW_CHARS: usize = 300;
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct OpenAiClient {
     model: String,
-    client: Client<OpenAIConfig>,
+    fallback_models: Vec<String>,
+    api_key: String,
+    api_base: String,
+    api_flavor: ApiFlavor,
+    stream_limit: Option<usize>,
+    structured_output: bool,
+    reasoning_effort: Option<ReasoningEffortLevel>,
+    context_style: ContextStyle,
+    stop: Vec<String>,
+    http_client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+pub type RewriteFuture<'a> = Pin<Box<dyn Future<Output = Result<Rewrite>> + Send + 'a>>;
+pub type HealthCheckFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// A backend that rewrites `input` given the system prompt and preceding chat context.
+///
+/// `chat_metadata` is an optional line describing the chat (see `rewrite.include_chat_metadata`)
+/// that backends send next to the system prompt, ahead of the context.
+pub trait LlmRewriter: Send + Sync {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a>;
+
+    /// Like [`LlmRewriter::rewrite`], but gives up once `deadline` has passed, retries and
+    /// rate limit waits included. `None` leaves only the configured request timeout.
+    fn rewrite_with_deadline<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+        deadline: Option<Duration>,
+    ) -> RewriteFuture<'a> {
+        let rewrite = self.rewrite(system_prompt, chat_metadata, context, input);
+        let Some(deadline) = deadline else {
+            return rewrite;
+        };
+        Box::pin(async move {
+            tokio::time::timeout(deadline, rewrite)
+                .await
+                .map_err(|_| anyhow!("rewrite did not finish within {deadline:?}"))?
+        })
+    }
+
+    /// Cheap request confirming the key and model work; backends without one report success.
+    fn health_check(&self) -> HealthCheckFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Answers every request with the same text; backs the test-only rewrite override.
+pub struct FixedRewriter {
+    text: String,
+}
+
+impl FixedRewriter {
+    pub const MODEL: &'static str = "rewrite_override";
+
+    pub fn new(text: String) -> Self {
+        Self { text }
+    }
+}
+
+impl LlmRewriter for FixedRewriter {
+    fn rewrite<'a>(
+        &'a self,
+        _system_prompt: &'a str,
+        _chat_metadata: Option<&'a str>,
+        _context: &'a [ContextMessage],
+        _input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(async move {
+            Ok(Rewrite {
+                text: self.text.clone(),
+                model: Self::MODEL.to_owned(),
+                usage: None,
+                refusal: None,
+            })
+        })
+    }
+}
+
+/// Rewriter state that outlives one client and is carried across hot reloads whose
+/// settings for it are unchanged.
+#[derive(Clone, Default)]
+pub struct SharedRewriterState {
+    pub rate_limiter: Option<Arc<RequestRateLimiter>>,
+    pub response_cache: Option<Arc<ResponseCache>>,
+}
+
+impl SharedRewriterState {
+    pub fn for_provider(provider: &ProviderConfig, previous: Option<&Self>) -> Self {
+        let ProviderConfig::OpenAi(openai) = provider else {
+            return Self::default();
+        };
+        let rate_limiter = openai.rate_limit.map(|limits| {
+            RequestRateLimiter::reuse_or_new(
+                previous.and_then(|previous| previous.rate_limiter.as_ref()),
+                limits,
+            )
+        });
+        let response_cache = (openai.cache_entries > 0).then(|| {
+            ResponseCache::reuse_or_new(
+                previous.and_then(|previous| previous.response_cache.as_ref()),
+                openai.cache_entries,
+                Duration::from_secs(openai.cache_ttl_seconds),
+            )
+        });
+        Self {
+            rate_limiter,
+            response_cache,
+        }
+    }
+}
+
+/// Builds the client for the selected provider; `network.openai_proxy` only applies to OpenAI.
+///
+/// `max_output_chars` is the longest rewrite that can be used; streaming clients stop there.
+/// Cache hits in `shared` skip the provider entirely; misses then wait on its rate limiter.
+/// With `two_stage`, every rewrite is a draft plus a review request (see [`TwoStageRewriter`]).
+pub fn build_rewriter(
+    provider: &ProviderConfig,
+    network: &NetworkConfig,
+    max_output_chars: usize,
+    two_stage: bool,
+    shared: &SharedRewriterState,
+) -> Result<Box<dyn LlmRewriter>> {
+    let rewriter: Box<dyn LlmRewriter> = match provider {
+        ProviderConfig::OpenAi(openai) => {
+            let client = OpenAiClient::new(
+                openai.api_key.expose().clone(),
+                openai.model.clone(),
+                &TransportOptions::from_config(openai, network)?,
+            )?
+            .with_api_base(openai.api_base.clone())
+            .with_api_flavor(openai.api_flavor)
+            .with_structured_output(openai.structured_output)
+            .with_reasoning_effort(openai.reasoning_effort)
+            .with_context_style(openai.context_style)
+            .with_stop(openai.stop.clone())
+            .with_fallback_models(openai.fallback_models.clone());
+            if openai.stream {
+                Box::new(client.with_streaming(max_output_chars))
+            } else {
+                Box::new(client)
+            }
+        }
+        ProviderConfig::Anthropic(anthropic) => Box::new(AnthropicClient::new(anthropic)?),
+        ProviderConfig::Ollama(ollama) => Box::new(OllamaClient::new(ollama)?),
+    };
+    let rewriter: Box<dyn LlmRewriter> = match &shared.rate_limiter {
+        Some(limiter) => Box::new(RateLimitedRewriter::new(rewriter, Arc::clone(limiter))),
+        None => rewriter,
+    };
+    // Each stage takes its own rate-limit slot; the cache stores only the final text.
+    let (rewriter, cache_model): (Box<dyn LlmRewriter>, String) = if two_stage {
+        (
+            Box::new(TwoStageRewriter::new(rewriter)),
+            format!("{} (two-stage)", provider.model()),
+        )
+    } else {
+        (rewriter, provider.model().to_owned())
+    };
+    Ok(match &shared.response_cache {
+        Some(cache) => Box::new(CachedRewriter::new(
+            rewriter,
+            Arc::clone(cache),
+            cache_model,
+        )),
+        None => rewriter,
+    })
+}
+
+/// A successful rewrite together with the model that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub text: String,
+    pub model: String,
+    /// Token counts reported by the provider, when it reports them.
+    pub usage: Option<TokenUsage>,
+    /// Refusal text the provider marked as such instead of (or besides) a rewrite.
+    pub refusal: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// Text of one successful API call together with its reported usage.
+struct Completion {
+    text: String,
+    usage: Option<TokenUsage>,
+    refusal: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportOptions {
+    /// Whole-request budget, from connecting to reading the last byte.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub proxy: Option<String>,
+    /// Sent with every request.
+    pub headers: HeaderMap,
+}
+
+impl TransportOptions {
+    pub fn from_config(openai: &OpenAiConfig, network: &NetworkConfig) -> Result<Self> {
+        Ok(Self {
+            timeout: Duration::from_secs(openai.request_timeout_seconds),
+            connect_timeout: Duration::from_secs(openai.connect_timeout_seconds),
+            retry: RetryPolicy::new(
+                &openai.retry,
+                Duration::from_secs(openai.request_timeout_seconds),
+            ),
+            proxy: network.openai_proxy.clone(),
+            headers: openai_headers(
+                openai.organization.as_deref(),
+                openai.project.as_deref(),
+                &openai.extra_headers,
+            )?,
+        })
+    }
+}
+
+/// Organization and project ids as OpenAI's own headers, plus `openai.extra_headers`.
+fn openai_headers(
+    organization: Option<&str>,
+    project: Option<&str>,
+    extra_headers: &BTreeMap<String, String>,
+) -> Result<HeaderMap> {
+    let ids = [
+        ("OpenAI-Organization", organization),
+        ("OpenAI-Project", project),
+    ];
+    let extra = extra_headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), Some(value.as_str())));
+    let mut headers = HeaderMap::new();
+    for (name, value) in ids.into_iter().chain(extra) {
+        let Some(value) = value else {
+            continue;
+        };
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid OpenAI header name {name:?}"))?;
+        let value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("invalid value for OpenAI header {name}"))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Upper bound on a server-requested `Retry-After` wait; the request timeout budget.
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig, timeout: Duration) -> Self {
+        Self {
+            max_attempts: config.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            max_retry_after: timeout,
+        }
+    }
+
+    fn backoff_before_retry(&self, failed_attempt: u32) -> Duration {
+        let multiplier = 2u32.saturating_pow(failed_attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(multiplier)
+            .min(self.max_backoff)
+    }
+
+    fn delay_before_retry(&self, failed_attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_retry_after),
+            None => jittered(self.backoff_before_retry(failed_attempt)),
+        }
+    }
+}
+
+/// Spreads concurrent retries over `[delay / 2, delay]` so bursts don't retry in lockstep.
+fn jittered(delay: Duration) -> Duration {
+    let random = RandomState::new().hash_one(Instant::now());
+    let half = delay / 2;
+    half + half.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+enum RequestError {
+    /// Timeouts, 429 and 5xx; `retry_after` is the server's `Retry-After` hint when present.
+    Retryable {
+        err: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
+    Permanent(anyhow::Error),
+}
+
+impl RequestError {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Self::Retryable { err, .. } | Self::Permanent(err) => err,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ResponsesApiResponse {
+    #[serde(default, deserialize_with = "deserialize_output_items")]
+    output: Vec<OutputItem>,
+    error: Option<ResponsesApiError>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+}
+
+/// Skips output items that don't parse instead of failing the whole response: reasoning
+/// models put reasoning items before the message, and only messages carry the rewrite.
+fn deserialize_output_items<'de, D>(deserializer: D) -> Result<Vec<OutputItem>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let items = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(items
+        .into_iter()
+        .filter_map(|item| match serde_json::from_value(item) {
+            Ok(item) => Some(item),
+            Err(err) => {
+                debug!(error = %err, "skipping unrecognized responses api output item");
+                None
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct ResponsesApiError {
+    code: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorEnvelope {
+    error: ApiErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    message: String,
 }
 
 impl OpenAiClient {
-    pub fn new(api_key: String, model: String, timeout: Duration) -> Result<Self> {
+    pub fn new(api_key: String, model: String, transport: &TransportOptions) -> Result<Self> {
         let api_key = api_key.trim().to_owned();
         if api_key.is_empty() {
             bail!("openai api key must not be empty");
@@ -29,82 +415,523 @@ impl OpenAiClient {
             bail!("openai model must not be empty");
         }
 
-        let config = OpenAIConfig::new().with_api_key(api_key);
-        let http_client = reqwest::Client::builder()
-            .timeout(timeout)
-            .build()
-            .context("failed to build HTTP client for OpenAI")?;
-        let client = Client::with_config(config).with_http_client(http_client);
+        let http_client = build_http_client(transport)?;
 
         debug!(
-            timeout_seconds = timeout.as_secs(),
+            request_timeout_seconds = transport.timeout.as_secs(),
+            connect_timeout_seconds = transport.connect_timeout.as_secs(),
+            max_attempts = transport.retry.max_attempts,
+            proxy_enabled = transport.proxy.is_some(),
             model = %model,
             "built openai HTTP client"
         );
 
-        Ok(Self { model, client })
+        Ok(Self {
+            model,
+            fallback_models: Vec::new(),
+            api_key,
+            api_base: DEFAULT_OPENAI_API_BASE.to_owned(),
+            api_flavor: ApiFlavor::default(),
+            stream_limit: None,
+            structured_output: false,
+            reasoning_effort: None,
+            context_style: ContextStyle::default(),
+            stop: Vec::new(),
+            http_client,
+            retry: transport.retry,
+        })
+    }
+
+    /// Sent as `stop` and also applied to the answer, for servers that ignore the parameter.
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Models tried in order, with the same request, when the primary model fails.
+    pub fn with_fallback_models(mut self, fallback_models: Vec<String>) -> Self {
+        self.fallback_models = fallback_models
+            .into_iter()
+            .map(|model| model.trim().to_owned())
+            .filter(|model| !model.is_empty())
+            .collect();
+        self
+    }
+
+    /// Base URL of the OpenAI or OpenAI-compatible API, e.g. `http://localhost:8080/v1`.
+    pub fn with_api_base(mut self, api_base: String) -> Self {
+        self.api_base = api_base.trim().trim_end_matches('/').to_owned();
+        self
+    }
+
+    pub fn with_api_flavor(mut self, api_flavor: ApiFlavor) -> Self {
+        self.api_flavor = api_flavor;
+        self
+    }
+
+    /// Asks for `{"rewritten": "..."}` JSON so commentary around the rewrite can be discarded.
+    pub fn with_structured_output(mut self, structured_output: bool) -> Self {
+        self.structured_output = structured_output;
+        self
+    }
+
+    /// Sets `reasoning.effort` on Responses API requests, for reasoning models.
+    pub fn with_reasoning_effort(mut self, reasoning_effort: Option<ReasoningEffortLevel>) -> Self {
+        self.reasoning_effort = reasoning_effort;
+        self
+    }
+
+    pub fn with_context_style(mut self, context_style: ContextStyle) -> Self {
+        self.context_style = context_style;
+        self
+    }
+
+    /// Streams Responses API output and aborts once it exceeds `max_output_chars` UTF-16 units.
+    pub fn with_streaming(mut self, max_output_chars: usize) -> Self {
+        self.stream_limit = Some(max_output_chars);
+        self
     }
 
     pub async fn rewrite(
         &self,
         system_prompt: &str,
+        chat_metadata: Option<&str>,
         context: &[ContextMessage],
         input: &str,
-    ) -> Result<String> {
-        let request = build_response_request(&self.model, system_prompt, context, input);
+    ) -> Result<Rewrite> {
+        let models = std::iter::once(&self.model).chain(&self.fallback_models);
+        let mut last_err = None;
+        for model in models {
+            match self
+                .rewrite_with_model(model, system_prompt, chat_metadata, context, input)
+                .await
+                .map(|mut completion| {
+                    completion.text = truncate_at_stop_sequences(&completion.text, &self.stop);
+                    completion
+                }) {
+                Ok(completion) if completion.text.is_empty() && completion.refusal.is_none() => {
+                    // A successful but empty answer is not an API failure; don't fall back.
+                    bail!("openai response missing assistant text content");
+                }
+                Ok(completion) => {
+                    if *model != self.model {
+                        info!(
+                            primary_model = %self.model,
+                            model = %model,
+                            "rewrite served by fallback model"
+                        );
+                    }
+                    return Ok(Rewrite {
+                        text: completion.text,
+                        model: model.clone(),
+                        usage: completion.usage,
+                        refusal: completion.refusal,
+                    });
+                }
+                Err(err) => {
+                    if !self.fallback_models.is_empty() {
+                        warn!(
+                            model = %model,
+                            error = %err,
+                            "openai model failed; trying next fallback model if any"
+                        );
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least the primary model is always attempted"))
+    }
 
-        debug!(
-            model = %self.model,
-            "sending rewrite request to openai responses api"
-        );
+    /// Retrieves the configured model, so a bad key or model name shows up before the first
+    /// rewrite. The API error message is kept, with the key redacted.
+    pub async fn health_check(&self) -> Result<()> {
+        let response = self
+            .http_client
+            .get(format!("{}/models/{}", self.api_base, self.model))
+            .bearer_auth(&self.api_key)
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await
+            .context("failed to reach OpenAI for health check")?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await.unwrap_or_default();
+        bail!(
+            "openai health check for model {} failed with status {status}: {}",
+            self.model,
+            api_error_message(&body).replace(&self.api_key, "[redacted]")
+        )
+    }
 
+    async fn rewrite_with_model(
+        &self,
+        model: &str,
+        system_prompt: &str,
+        chat_metadata: Option<&str>,
+        context: &[ContextMessage],
+        input: &str,
+    ) -> Result<Completion> {
+        match self.api_flavor {
+            ApiFlavor::Responses => {
+                let request = build_response_request(
+                    model,
+                    system_prompt,
+                    chat_metadata,
+                    context,
+                    input,
+                    ResponseOptions {
+                        reasoning_effort: self.reasoning_effort,
+                        context_style: self.context_style,
+                        stop: &self.stop,
+                    },
+                );
+                match self.stream_limit {
+                    Some(limit) => {
+                        send_with_retries(&self.retry, "openai", model, || {
+                            self.create_response_stream(&request, limit)
+                        })
+                        .await
+                    }
+                    None if self.structured_output => {
+                        let body = with_structured_output_format(&request)
+                            .context("failed to build structured output request")?;
+                        let mut completion =
+                            send_with_retries(&self.retry, "openai", model, || {
+                                self.create_response(&body)
+                            })
+                            .await?;
+                        if completion.refusal.is_none() {
+                            completion.text = extract_structured_rewrite(&completion.text);
+                        }
+                        Ok(completion)
+                    }
+                    None => {
+                        send_with_retries(&self.retry, "openai", model, || {
+                            self.create_response(&request)
+                        })
+                        .await
+                    }
+                }
+            }
+            ApiFlavor::ChatCompletions => {
+                let request = build_chat_completion_request(
+                    model,
+                    system_prompt,
+                    chat_metadata,
+                    context,
+                    input,
+                    self.context_style,
+                    &self.stop,
+                );
+                send_with_retries(&self.retry, "openai", model, || {
+                    self.create_chat_completion(&request)
+                })
+                .await
+            }
+        }
+    }
+
+    async fn create_response<R: Serialize + Sync>(
+        &self,
+        request: &R,
+    ) -> Result<Completion, RequestError> {
         let response = self
-            .client
-            .responses()
-            .create(request)
+            .http_client
+            .post(format!("{}/responses", self.api_base))
+            .bearer_auth(&self.api_key)
+            .json(request)
+            .send()
             .await
-            .context("failed to send request to OpenAI")?;
+            .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+
+        let status = response.status();
+        let retry_after = parse_retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+        if !status.is_success() {
+            return Err(status_error(
+                "openai responses api",
+                status,
+                retry_after,
+                &body,
+            ));
+        }
+
+        let response: ResponsesApiResponse = serde_json::from_str(&body)
+            .context("failed to parse OpenAI responses api body")
+            .map_err(RequestError::Permanent)?;
 
         if let Some(err) = response.error {
-            bail!(
+            return Err(RequestError::Permanent(anyhow!(
                 "openai responses api returned error {}: {}",
                 err.code,
                 err.message
-            );
+            )));
         }
 
-        let text = extract_response_text(&response.output);
-        if text.trim().is_empty() {
-            bail!("openai response missing assistant text content");
+        Ok(Completion {
+            text: extract_response_text(&response.output).trim().to_owned(),
+            usage: response.usage,
+            refusal: extract_response_refusal(&response.output),
+        })
+    }
+}
+
+impl LlmRewriter for OpenAiClient {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(OpenAiClient::rewrite(
+            self,
+            system_prompt,
+            chat_metadata,
+            context,
+            input,
+        ))
+    }
+
+    fn health_check(&self) -> HealthCheckFuture<'_> {
+        Box::pin(OpenAiClient::health_check(self))
+    }
+}
+
+/// Runs `send` until it succeeds, fails permanently, or the retry policy is exhausted.
+async fn send_with_retries<T, F, Fut>(
+    retry: &RetryPolicy,
+    provider: &str,
+    model: &str,
+    mut send: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 1;
+    loop {
+        debug!(provider, model = %model, attempt, "sending rewrite request");
+
+        match send().await {
+            Ok(output) => {
+                if attempt > 1 {
+                    info!(
+                        provider,
+                        model = %model,
+                        attempts = attempt,
+                        "llm request succeeded after retries"
+                    );
+                }
+                return Ok(output);
+            }
+            Err(RequestError::Retryable { err, retry_after }) if attempt < retry.max_attempts => {
+                let delay = retry.delay_before_retry(attempt, retry_after);
+                warn!(
+                    provider,
+                    model = %model,
+                    attempt,
+                    max_attempts = retry.max_attempts,
+                    delay_ms = delay.as_millis() as u64,
+                    retry_after_ms = retry_after.map(|retry_after| retry_after.as_millis() as u64),
+                    error = %err,
+                    "llm request failed; retrying after backoff"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                let err = err.into_error();
+                if attempt > 1 {
+                    warn!(
+                        provider,
+                        model = %model,
+                        attempts = attempt,
+                        error = %err,
+                        "llm request failed after retries"
+                    );
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+fn build_http_client(transport: &TransportOptions) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(transport.timeout)
+        .connect_timeout(transport.connect_timeout)
+        .default_headers(transport.headers.clone());
+    if let Some(proxy) = transport.proxy.as_deref() {
+        let proxy = reqwest::Proxy::all(proxy).context("invalid OpenAI proxy URL")?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .context("failed to build HTTP client for OpenAI")
+}
+
+fn classify_transport_error(err: reqwest::Error, api: &str) -> RequestError {
+    let retryable = err.is_timeout();
+    let err = anyhow::Error::new(err).context(format!("failed to send request to {api}"));
+    if retryable {
+        RequestError::Retryable {
+            err,
+            retry_after: None,
         }
+    } else {
+        RequestError::Permanent(err)
+    }
+}
+
+/// Auth and validation errors (other 4xx) fail immediately; 429 and 5xx are retried.
+fn status_error(
+    api: &str,
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: &str,
+) -> RequestError {
+    let err = anyhow!("{api} returned HTTP {status}: {}", api_error_message(body));
+    if is_retryable_status(status) {
+        RequestError::Retryable { err, retry_after }
+    } else {
+        RequestError::Permanent(err)
+    }
+}
+
+/// Parses a `Retry-After` header given in delay-seconds; HTTP-date values are ignored.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    (seconds.is_finite() && seconds >= 0.0).then(|| Duration::from_secs_f64(seconds))
+}
 
-        Ok(text.trim().to_owned())
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn api_error_message(body: &str) -> String {
+    if let Ok(envelope) = serde_json::from_str::<ApiErrorEnvelope>(body) {
+        return envelope.error.message;
     }
+    body.trim().chars().take(ERROR_BODY_PREVIEW_CHARS).collect()
+}
+
+/// A Responses API request plus `stop`, which the official schema lacks but several
+/// OpenAI-compatible servers accept.
+#[derive(Debug, Clone, Serialize)]
+struct ResponseRequest {
+    #[serde(flatten)]
+    body: CreateResponse,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+/// Client settings that shape every Responses API request.
+#[derive(Debug, Clone, Copy, Default)]
+struct ResponseOptions<'a> {
+    reasoning_effort: Option<ReasoningEffortLevel>,
+    context_style: ContextStyle,
+    stop: &'a [String],
 }
 
 fn build_response_request(
     model: &str,
     system_prompt: &str,
+    chat_metadata: Option<&str>,
     context: &[ContextMessage],
     input: &str,
-) -> CreateResponse {
-    let mut items = Vec::with_capacity(context.len() + 2);
+    options: ResponseOptions<'_>,
+) -> ResponseRequest {
+    let ResponseOptions {
+        reasoning_effort,
+        context_style,
+        stop,
+    } = options;
+    let mut items = Vec::with_capacity(context.len() + 3);
     items.push(input_item(Role::System, system_prompt.to_owned()));
-    items.extend(
-        context
-            .iter()
-            .map(|context_message| input_item(Role::User, context_message.as_llm_user_content())),
-    );
+    if let Some(chat_metadata) = chat_metadata {
+        items.push(input_item(Role::System, chat_metadata.to_owned()));
+    }
+    items.extend(context.iter().map(|context_message| {
+        if context_style == ContextStyle::Roles && context_message.is_own {
+            input_item(Role::Assistant, context_message.text.clone())
+        } else {
+            input_item(Role::User, context_message.as_llm_user_content())
+        }
+    }));
     items.push(input_item(Role::User, input.to_owned()));
 
-    CreateResponse {
+    let body = CreateResponse {
         model: Some(model.to_owned()),
         input: InputParam::Items(items),
-        reasoning: Some(Reasoning {
-            effort: Some(ReasoningEffort::High),
+        reasoning: reasoning_effort.map(|effort| Reasoning {
+            effort: Some(match effort {
+                ReasoningEffortLevel::Low => ReasoningEffort::Low,
+                ReasoningEffortLevel::Medium => ReasoningEffort::Medium,
+                ReasoningEffortLevel::High => ReasoningEffort::High,
+            }),
             ..Default::default()
         }),
         ..Default::default()
+    };
+    ResponseRequest {
+        body,
+        stop: stop.to_vec(),
+    }
+}
+
+/// Adds a strict JSON schema `text.format` so the model answers with `{"rewritten": "..."}`.
+fn with_structured_output_format(
+    request: &ResponseRequest,
+) -> serde_json::Result<serde_json::Value> {
+    let mut body = serde_json::to_value(request)?;
+    body["text"] = serde_json::json!({
+        "format": {
+            "type": "json_schema",
+            "name": "rewrite",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "rewritten": { "type": "string" }
+                },
+                "required": ["rewritten"],
+                "additionalProperties": false
+            }
+        }
+    });
+    Ok(body)
+}
+
+#[derive(Deserialize)]
+struct StructuredRewrite {
+    rewritten: String,
+}
+
+/// Returns the `rewritten` field of a structured answer, or the raw text if it isn't one.
+fn extract_structured_rewrite(text: &str) -> String {
+    match serde_json::from_str::<StructuredRewrite>(text) {
+        Ok(structured) => structured.rewritten.trim().to_owned(),
+        Err(err) => {
+            warn!(
+                error = %err,
+                "structured output was not valid rewrite JSON; using raw response text"
+            );
+            text.to_owned()
+        }
     }
 }
 
@@ -116,6 +943,93 @@ fn input_item(role: Role, text: String) -> InputItem {
     })
 }
 
+/// Quote pairs stripped when they wrap the whole rewrite.
+const WRAPPING_QUOTES: [(char, char); 5] =
+    [('"', '"'), ('\'', '\''), ('“', '”'), ('‘', '’'), ('«', '»')];
+
+/// Removes wrapping the model adds around an otherwise good rewrite: a code fence, a label
+/// such as `Rewritten:` from `strip_prefixes` (case-insensitive), and quotes around the whole
+/// text. Quotes are only stripped when neither quote character appears inside.
+pub fn sanitize_rewrite_output(text: &str, strip_prefixes: &[String]) -> String {
+    let mut current = text.trim();
+    loop {
+        let next = strip_wrapping_quotes(strip_label_prefix(
+            strip_code_fence(current),
+            strip_prefixes,
+        ));
+        if next == current {
+            return current.to_owned();
+        }
+        current = next;
+    }
+}
+
+/// Cuts `text` at the earliest stop sequence. Answers arrive trimmed, so a stop sequence whose
+/// trailing whitespace was trimmed away still matches at the very end.
+fn truncate_at_stop_sequences(text: &str, stop: &[String]) -> String {
+    let cut = stop
+        .iter()
+        .filter_map(|sequence| {
+            text.find(sequence.as_str()).or_else(|| {
+                let visible = sequence.trim_end();
+                (!visible.is_empty() && visible.len() < sequence.len() && text.ends_with(visible))
+                    .then(|| text.len() - visible.len())
+            })
+        })
+        .min();
+    match cut {
+        Some(cut) => text[..cut].trim_end().to_owned(),
+        None => text.to_owned(),
+    }
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    let Some(inner) = text
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    else {
+        return text;
+    };
+    if inner.contains("```") {
+        return text;
+    }
+    // The opening fence line may carry a language tag; a one-line fence has no tag.
+    match inner.split_once('\n') {
+        Some((tag, body)) if !tag.trim().contains(char::is_whitespace) => body.trim(),
+        _ => inner.trim(),
+    }
+}
+
+fn strip_label_prefix<'a>(text: &'a str, strip_prefixes: &[String]) -> &'a str {
+    strip_prefixes
+        .iter()
+        .map(|prefix| prefix.trim())
+        .filter(|prefix| !prefix.is_empty())
+        .filter_map(|prefix| {
+            let head = text.get(..prefix.len())?;
+            head.eq_ignore_ascii_case(prefix)
+                .then(|| text[prefix.len()..].trim_start())
+        })
+        .min_by_key(|rest| rest.len())
+        .unwrap_or(text)
+}
+
+fn strip_wrapping_quotes(text: &str) -> &str {
+    for (open, close) in WRAPPING_QUOTES {
+        let Some(inner) = text
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+        else {
+            continue;
+        };
+        if inner.contains(open) || inner.contains(close) {
+            return text;
+        }
+        return inner.trim();
+    }
+    text
+}
+
 fn extract_response_text(output: &[OutputItem]) -> String {
     output
         .iter()
@@ -147,40 +1061,846 @@ fn extract_response_text(output: &[OutputItem]) -> String {
         .join("\n")
 }
 
+/// Joins the refusal content parts the Responses API uses when the model declines.
+fn extract_response_refusal(output: &[OutputItem]) -> Option<String> {
+    let refusal = output
+        .iter()
+        .filter_map(|item| match item {
+            OutputItem::Message(message) => Some(&message.content),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|content| match content {
+            OutputMessageContent::Refusal(refusal) => Some(refusal.refusal.trim()),
+            _ => None,
+        })
+        .filter(|refusal| !refusal.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!refusal.is_empty()).then_some(refusal)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{build_response_request, extract_response_text};
+    use super::{
+        LlmRewriter, OpenAiClient, RequestError, ResponseOptions, RetryPolicy, Rewrite,
+        RewriteFuture, TokenUsage, TransportOptions, api_error_message, build_http_client,
+        build_response_request, extract_response_refusal, extract_response_text,
+        extract_structured_rewrite, is_retryable_status, jittered, openai_headers,
+        parse_retry_after, sanitize_rewrite_output, send_with_retries, truncate_at_stop_sequences,
+        with_structured_output_format,
+    };
+    use crate::config::{ApiFlavor, ContextStyle, ReasoningEffortLevel};
     use crate::context::ContextMessage;
+    use anyhow::anyhow;
     use async_openai::types::responses::{
         AssistantRole, EasyInputContent, InputItem, InputParam, MessageType, OutputItem,
         OutputMessage, OutputMessageContent, OutputStatus, OutputTextContent, Role,
     };
+    use reqwest::StatusCode;
+    use reqwest::header::{HeaderMap, RETRY_AFTER};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn retry_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            max_retry_after: Duration::from_secs(5),
+        }
+    }
+
+    fn test_client(server: &MockServer, max_attempts: u32) -> OpenAiClient {
+        OpenAiClient::new(
+            "sk-test".to_owned(),
+            "gpt-4.1-mini".to_owned(),
+            &TransportOptions {
+                timeout: Duration::from_secs(5),
+                connect_timeout: Duration::from_secs(5),
+                retry: retry_policy(max_attempts),
+                proxy: None,
+                headers: HeaderMap::new(),
+            },
+        )
+        .expect("client should build")
+        .with_api_base(server.uri())
+    }
+
+    fn response_body(text: &str) -> serde_json::Value {
+        serde_json::json!({
+            "output": [{
+                "type": "message",
+                "id": "msg-1",
+                "role": "assistant",
+                "status": "completed",
+                "content": [{
+                    "type": "output_text",
+                    "text": text,
+                    "annotations": []
+                }]
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn rewrite_retries_server_error_then_succeeds() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response_body("rewritten")))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 3);
+        let rewrite = client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("rewrite should succeed after retry");
+        assert_eq!(rewrite.text, "rewritten");
+        assert_eq!(rewrite.model, "gpt-4.1-mini");
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_does_not_retry_by_default() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 1);
+        client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("single attempt should surface the server error");
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_does_not_retry_auth_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "error": { "message": "Incorrect API key provided" }
+            })))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 3);
+        let err = client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("auth errors should fail immediately");
+        assert!(err.to_string().contains("Incorrect API key provided"));
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_falls_back_to_next_model_on_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .and(body_partial_json(
+                serde_json::json!({ "model": "gpt-4.1-mini" }),
+            ))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .and(body_partial_json(
+                serde_json::json!({ "model": "gpt-4o-mini" }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response_body("cheaper")))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 1).with_fallback_models(vec!["gpt-4o-mini".to_owned()]);
+        let rewrite = client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("fallback model should serve the rewrite");
+        assert_eq!(rewrite.text, "cheaper");
+        assert_eq!(rewrite.model, "gpt-4o-mini");
+    }
+
+    #[tokio::test]
+    async fn rewrite_does_not_fall_back_on_empty_successful_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response_body("   ")))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 1).with_fallback_models(vec!["gpt-4o-mini".to_owned()]);
+        client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("empty response should fail without fallback");
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_reports_last_error_when_all_models_fail() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 1).with_fallback_models(vec!["gpt-4o-mini".to_owned()]);
+        client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("all models failing should surface an error");
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn both_api_flavors_return_the_same_rewrite() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response_body("rewritten")))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": " rewritten " },
+                    "finish_reason": "stop"
+                }]
+            })))
+            .mount(&server)
+            .await;
+
+        let responses = test_client(&server, 1)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("responses flavor should succeed");
+        let chat_completions = test_client(&server, 1)
+            .with_api_flavor(ApiFlavor::ChatCompletions)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("chat completions flavor should succeed");
+        assert_eq!(responses.text, "rewritten");
+        assert_eq!(responses, chat_completions);
+    }
+
+    #[tokio::test]
+    async fn rewrite_reports_token_usage_for_both_api_flavors() {
+        let server = MockServer::start().await;
+        let mut body = response_body("rewritten");
+        body["usage"] = serde_json::json!({
+            "input_tokens": 40,
+            "output_tokens": 7,
+            "total_tokens": 47
+        });
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": "rewritten" } }],
+                "usage": { "prompt_tokens": 40, "completion_tokens": 7, "total_tokens": 47 }
+            })))
+            .mount(&server)
+            .await;
+
+        let expected = Some(TokenUsage {
+            input_tokens: 40,
+            output_tokens: 7,
+        });
+        let responses = test_client(&server, 1)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("responses flavor should succeed");
+        assert_eq!(responses.usage, expected);
+        let chat_completions = test_client(&server, 1)
+            .with_api_flavor(ApiFlavor::ChatCompletions)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("chat completions flavor should succeed");
+        assert_eq!(chat_completions.usage, expected);
+    }
+
+    #[tokio::test]
+    async fn structured_output_requests_schema_and_returns_rewritten_field() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .and(body_partial_json(serde_json::json!({
+                "text": { "format": { "type": "json_schema", "name": "rewrite", "strict": true } }
+            })))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(response_body(r#"{"rewritten": "Good day to you"}"#)),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 1)
+            .with_structured_output(true)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("structured rewrite should succeed");
+        assert_eq!(rewrite.text, "Good day to you");
+    }
+
+    #[tokio::test]
+    async fn structured_output_falls_back_to_raw_text_when_not_json() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(response_body("Good day to you")),
+            )
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 1)
+            .with_structured_output(true)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("raw text should still be used");
+        assert_eq!(rewrite.text, "Good day to you");
+    }
+
+    fn sanitize(text: &str) -> String {
+        let prefixes = ["Rewritten:".to_owned(), "Rewritten message:".to_owned()];
+        sanitize_rewrite_output(text, &prefixes)
+    }
+
+    #[test]
+    fn sanitize_strips_symmetric_wrapping_quotes() {
+        assert_eq!(sanitize("\"Good day\""), "Good day");
+        assert_eq!(sanitize("'Good day'"), "Good day");
+        assert_eq!(sanitize("“Good day”"), "Good day");
+        assert_eq!(sanitize("«Добрый день»"), "Добрый день");
+        assert_eq!(sanitize("  \" Good day \"  "), "Good day");
+    }
+
+    #[test]
+    fn sanitize_keeps_quotes_that_are_part_of_the_content() {
+        assert_eq!(sanitize("\"Hi\" and \"bye\""), "\"Hi\" and \"bye\"");
+        assert_eq!(sanitize("He said \"hi\""), "He said \"hi\"");
+        assert_eq!(sanitize("\"quoted\" tail"), "\"quoted\" tail");
+        assert_eq!(sanitize("'I don't know'"), "'I don't know'");
+        assert_eq!(sanitize("\""), "\"");
+    }
 
     #[test]
-    fn build_response_request_includes_context_in_expected_order() {
+    fn sanitize_leaves_asymmetric_quotes_alone() {
+        assert_eq!(sanitize("\"Good day'"), "\"Good day'");
+        assert_eq!(sanitize("”Good day“"), "”Good day“");
+        assert_eq!(sanitize("\"Good day"), "\"Good day");
+    }
+
+    #[test]
+    fn sanitize_unwraps_nested_quotes_and_fences() {
+        assert_eq!(sanitize("\"'Good day'\""), "Good day");
+        assert_eq!(sanitize("```\n\"Good day\"\n```"), "Good day");
+        assert_eq!(sanitize("```text\nGood day\n```"), "Good day");
+        assert_eq!(sanitize("```Good day```"), "Good day");
+        assert_eq!(sanitize("Rewritten: \"Good day\""), "Good day");
+        assert_eq!(sanitize("\"Rewritten: Good day\""), "Good day");
+    }
+
+    #[test]
+    fn sanitize_strips_configured_label_prefixes_case_insensitively() {
+        assert_eq!(sanitize("rewritten: Good day"), "Good day");
+        assert_eq!(sanitize("Rewritten message: Good day"), "Good day");
+        assert_eq!(sanitize("Rewrite: Good day"), "Rewrite: Good day");
+        assert_eq!(
+            sanitize("Good day. Rewritten: twice"),
+            "Good day. Rewritten: twice"
+        );
+        assert_eq!(
+            sanitize_rewrite_output("Rewritten: Good day", &[]),
+            "Rewritten: Good day"
+        );
+    }
+
+    #[test]
+    fn sanitize_keeps_code_that_is_not_a_single_wrapping_fence() {
+        assert_eq!(sanitize("```a``` and ```b```"), "```a``` and ```b```");
+        assert_eq!(
+            sanitize("Run ```cargo test``` first"),
+            "Run ```cargo test``` first"
+        );
+        assert_eq!(sanitize("```rust\nfn main() {}\n```"), "fn main() {}");
+    }
+
+    #[test]
+    fn extract_structured_rewrite_handles_valid_and_invalid_json() {
+        assert_eq!(
+            extract_structured_rewrite(r#"{"rewritten": "  hello \"there\"  "}"#),
+            r#"hello "there""#
+        );
+        assert_eq!(
+            extract_structured_rewrite(r#"{"rewritten": "a", "extra": 1}"#),
+            "a"
+        );
+        assert_eq!(
+            extract_structured_rewrite(r#"{"rewritten": 5}"#),
+            r#"{"rewritten": 5}"#
+        );
+        assert_eq!(
+            extract_structured_rewrite(r#"{"rewritten": "cut off"#),
+            r#"{"rewritten": "cut off"#
+        );
+        assert_eq!(
+            extract_structured_rewrite(r#"Here's your rewrite: "hi""#),
+            r#"Here's your rewrite: "hi""#
+        );
+    }
+
+    #[test]
+    fn structured_output_format_keeps_request_fields() {
+        let request = build_response_request(
+            "gpt-4.1-mini",
+            "Rewrite politely",
+            None,
+            &[],
+            "ok",
+            ResponseOptions::default(),
+        );
+        let body = with_structured_output_format(&request).expect("request should serialize");
+        assert_eq!(body["model"], "gpt-4.1-mini");
+        assert_eq!(body["text"]["format"]["type"], "json_schema");
+        assert_eq!(
+            body["text"]["format"]["schema"]["required"],
+            serde_json::json!(["rewritten"])
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_completions_flavor_retries_server_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": "rewritten" } }]
+            })))
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 2)
+            .with_api_flavor(ApiFlavor::ChatCompletions)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("chat completions should succeed after retry");
+        assert_eq!(rewrite.text, "rewritten");
+    }
+
+    #[test]
+    fn retryable_statuses_are_rate_limits_and_server_errors() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable_status(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable_status(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn http_client_accepts_configured_socks_proxy() {
+        build_http_client(&TransportOptions {
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            retry: retry_policy(1),
+            proxy: Some("socks5://127.0.0.1:1080".to_owned()),
+            headers: HeaderMap::new(),
+        })
+        .expect("socks5 proxy should be accepted");
+    }
+
+    #[tokio::test]
+    async fn rate_limit_response_carries_retry_after_hint() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "7"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = test_client(&server, 1);
+        let request = build_response_request(
+            "gpt-4.1-mini",
+            "Rewrite politely",
+            None,
+            &[],
+            "ok",
+            ResponseOptions::default(),
+        );
+        match client.create_response(&request).await {
+            Err(RequestError::Retryable { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(7)));
+            }
+            Err(RequestError::Permanent(err)) => panic!("429 should be retryable: {err:#}"),
+            Ok(completion) => panic!("429 should fail, got {}", completion.text),
+        }
+    }
+
+    #[tokio::test]
+    async fn health_check_retrieves_configured_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/models/gpt-4.1-mini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "gpt-4.1-mini",
+                "object": "model"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        test_client(&server, 1)
+            .health_check()
+            .await
+            .expect("health check should pass");
+    }
+
+    #[tokio::test]
+    async fn health_check_reports_api_error_without_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/models/gpt-4.1-mini"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "error": { "message": "Incorrect API key provided: sk-test." }
+            })))
+            .mount(&server)
+            .await;
+
+        let err = test_client(&server, 1)
+            .health_check()
+            .await
+            .expect_err("401 should fail the health check");
+        let rendered = err.to_string();
+        assert!(
+            rendered.contains("Incorrect API key provided: [redacted]."),
+            "{rendered}"
+        );
+        assert!(!rendered.contains("sk-test"), "{rendered}");
+    }
+
+    #[tokio::test]
+    async fn requests_carry_organization_project_and_extra_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .and(header("OpenAI-Organization", "org-123"))
+            .and(header("OpenAI-Project", "proj_abc"))
+            .and(header("X-Trace-Id", "rewriter"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response_body("rewritten")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let extra_headers = BTreeMap::from([("X-Trace-Id".to_owned(), "rewriter".to_owned())]);
+        let client = OpenAiClient::new(
+            "sk-test".to_owned(),
+            "gpt-4.1-mini".to_owned(),
+            &TransportOptions {
+                timeout: Duration::from_secs(5),
+                connect_timeout: Duration::from_secs(5),
+                retry: retry_policy(1),
+                proxy: None,
+                headers: openai_headers(Some("org-123"), Some("proj_abc"), &extra_headers)
+                    .expect("headers should build"),
+            },
+        )
+        .expect("client should build")
+        .with_api_base(server.uri());
+
+        let rewrite = client
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("rewrite should succeed with headers");
+        assert_eq!(rewrite.text, "rewritten");
+    }
+
+    #[tokio::test]
+    async fn rewrite_fails_immediately_on_auth_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "error": { "message": "invalid api key" }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let err = test_client(&server, 3)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("401 should fail");
+        assert!(format!("{err:#}").contains("invalid api key"), "{err:#}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_with_retries_sleeps_for_retry_after() {
+        let policy = RetryPolicy {
+            max_retry_after: Duration::from_secs(30),
+            ..retry_policy(2)
+        };
+        let mut calls = 0;
+        let started = tokio::time::Instant::now();
+        let text = send_with_retries(&policy, "openai", "gpt-4.1-mini", || {
+            calls += 1;
+            let first = calls == 1;
+            async move {
+                if first {
+                    Err(RequestError::Retryable {
+                        err: anyhow!("rate limited"),
+                        retry_after: Some(Duration::from_secs(12)),
+                    })
+                } else {
+                    Ok("rewritten".to_owned())
+                }
+            }
+        })
+        .await
+        .expect("retry should succeed");
+
+        assert_eq!(text, "rewritten");
+        assert_eq!(calls, 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(12));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_with_retries_caps_retry_after_at_timeout_budget() {
+        let policy = RetryPolicy {
+            max_retry_after: Duration::from_secs(5),
+            ..retry_policy(2)
+        };
+        let mut calls = 0;
+        let started = tokio::time::Instant::now();
+        let err = send_with_retries(&policy, "openai", "gpt-4.1-mini", || {
+            calls += 1;
+            async {
+                Err(RequestError::Retryable {
+                    err: anyhow!("rate limited"),
+                    retry_after: Some(Duration::from_secs(600)),
+                })
+            }
+        })
+        .await
+        .expect_err("retries should be exhausted");
+
+        assert!(err.to_string().contains("rate limited"));
+        assert_eq!(calls, 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    struct SlowRewriter;
+
+    impl LlmRewriter for SlowRewriter {
+        fn rewrite<'a>(
+            &'a self,
+            _system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            input: &'a str,
+        ) -> RewriteFuture<'a> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                Ok(Rewrite {
+                    text: input.to_owned(),
+                    model: "slow".to_owned(),
+                    usage: None,
+                    refusal: None,
+                })
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rewrite_with_deadline_gives_up_once_deadline_passes() {
+        let started = tokio::time::Instant::now();
+        let err = SlowRewriter
+            .rewrite_with_deadline("prompt", None, &[], "ok", Some(Duration::from_secs(5)))
+            .await
+            .expect_err("deadline should cut the rewrite short");
+        assert!(err.to_string().contains("did not finish within"), "{err}");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        let rewrite = SlowRewriter
+            .rewrite_with_deadline("prompt", None, &[], "ok", None)
+            .await
+            .expect("no deadline waits for the rewrite");
+        assert_eq!(rewrite.text, "ok");
+    }
+
+    #[test]
+    fn parse_retry_after_accepts_delay_seconds_only() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "3".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert(RETRY_AFTER, "1.5".parse().unwrap());
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(Duration::from_millis(1500))
+        );
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn jittered_backoff_stays_within_half_to_full_delay() {
+        let delay = Duration::from_millis(800);
+        for _ in 0..100 {
+            let jittered = jittered(delay);
+            assert!(jittered >= delay / 2 && jittered <= delay, "{jittered:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps_at_max() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            max_retry_after: Duration::from_secs(20),
+        };
+        assert_eq!(policy.backoff_before_retry(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff_before_retry(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff_before_retry(3), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn api_error_message_prefers_structured_error() {
+        assert_eq!(
+            api_error_message(r#"{"error":{"message":"quota exceeded","type":"x"}}"#),
+            "quota exceeded"
+        );
+        assert_eq!(api_error_message("  upstream down  "), "upstream down");
+    }
+
+    #[test]
+    fn build_response_request_sends_own_messages_as_assistant_turns() {
         let context = vec![
             ContextMessage {
                 sender_name: "Alice".to_owned(),
                 text: "Hey there".to_owned(),
+                is_own: false,
             },
             ContextMessage {
                 sender_name: "Me".to_owned(),
                 text: "Hi!".to_owned(),
+                is_own: true,
             },
         ];
 
-        let request = build_response_request("gpt-4.1-mini", "Rewrite politely", &context, "ok");
+        let items = |context_style| {
+            let request = build_response_request(
+                "gpt-4.1-mini",
+                "Rewrite politely",
+                None,
+                &context,
+                "ok",
+                ResponseOptions {
+                    context_style,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(request.body.model.as_deref(), Some("gpt-4.1-mini"));
+            match request.body.input {
+                InputParam::Items(items) => items,
+                InputParam::Text(_) => panic!("expected structured input items"),
+            }
+        };
+
+        let roles = items(ContextStyle::Roles);
+        assert_eq!(roles.len(), 4);
+        assert_message_text(&roles[0], Role::System, "Rewrite politely");
+        assert_message_text(&roles[1], Role::User, "Alice: Hey there");
+        assert_message_text(&roles[2], Role::Assistant, "Hi!");
+        assert_message_text(&roles[3], Role::User, "ok");
 
-        assert_eq!(request.model.as_deref(), Some("gpt-4.1-mini"));
-        let items = match request.input {
-            InputParam::Items(items) => items,
-            InputParam::Text(_) => panic!("expected structured input items"),
+        let flat = items(ContextStyle::Flat);
+        assert_eq!(flat.len(), 4);
+        assert_message_text(&flat[0], Role::System, "Rewrite politely");
+        assert_message_text(&flat[1], Role::User, "Alice: Hey there");
+        assert_message_text(&flat[2], Role::User, "Me: Hi!");
+        assert_message_text(&flat[3], Role::User, "ok");
+    }
+
+    #[test]
+    fn build_response_request_places_chat_metadata_before_context() {
+        let context = vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "Hey there".to_owned(),
+            is_own: false,
+        }];
+        let request = build_response_request(
+            "gpt-4.1-mini",
+            "Rewrite politely",
+            Some("Chat: Work – Infra team, Topic: Deploys"),
+            &context,
+            "ok",
+            ResponseOptions::default(),
+        );
+        let InputParam::Items(items) = request.body.input else {
+            panic!("expected structured input items");
         };
-        assert_eq!(items.len(), 4);
 
+        assert_eq!(items.len(), 4);
         assert_message_text(&items[0], Role::System, "Rewrite politely");
-        assert_message_text(&items[1], Role::User, "Alice: Hey there");
-        assert_message_text(&items[2], Role::User, "Me: Hi!");
+        assert_message_text(
+            &items[1],
+            Role::System,
+            "Chat: Work – Infra team, Topic: Deploys",
+        );
+        assert_message_text(&items[2], Role::User, "Alice: Hey there");
         assert_message_text(&items[3], Role::User, "ok");
     }
 
@@ -198,6 +1918,141 @@ mod tests {
         assert_eq!(text, expected_text);
     }
 
+    #[tokio::test]
+    async fn rewrite_surfaces_refusal_content_parts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "output": [{
+                    "type": "message",
+                    "id": "msg-1",
+                    "role": "assistant",
+                    "status": "completed",
+                    "content": [{ "type": "refusal", "refusal": "I can't help with that." }]
+                }]
+            })))
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 1)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("a refusal is a successful response");
+        assert_eq!(rewrite.text, "");
+        assert_eq!(rewrite.refusal.as_deref(), Some("I can't help with that."));
+    }
+
+    #[test]
+    fn extract_response_refusal_ignores_output_text() {
+        let response: super::ResponsesApiResponse =
+            serde_json::from_value(response_body("hi")).expect("response body should parse");
+        assert_eq!(extract_response_refusal(&response.output), None);
+    }
+
+    #[test]
+    fn build_response_request_sets_reasoning_effort_only_when_configured() {
+        let request = build_response_request(
+            "o4-mini",
+            "Rewrite politely",
+            None,
+            &[],
+            "ok",
+            ResponseOptions::default(),
+        );
+        assert!(request.body.reasoning.is_none());
+
+        let request = build_response_request(
+            "o4-mini",
+            "Rewrite politely",
+            None,
+            &[],
+            "ok",
+            ResponseOptions {
+                reasoning_effort: Some(ReasoningEffortLevel::Low),
+                ..Default::default()
+            },
+        );
+        let body = serde_json::to_value(&request).expect("request should serialize");
+        assert_eq!(body["reasoning"]["effort"], "low");
+    }
+
+    #[test]
+    fn build_response_request_sends_stop_only_when_configured() {
+        let request = |stop: &[String]| {
+            let request = build_response_request(
+                "local",
+                "Rewrite politely",
+                None,
+                &[],
+                "ok",
+                ResponseOptions {
+                    stop,
+                    ..Default::default()
+                },
+            );
+            serde_json::to_value(&request).expect("request should serialize")
+        };
+
+        assert!(request(&[]).get("stop").is_none());
+        let body = request(&["---".to_owned(), "Explanation:".to_owned()]);
+        assert_eq!(body["stop"], serde_json::json!(["---", "Explanation:"]));
+        assert_eq!(body["model"], "local");
+    }
+
+    #[test]
+    fn truncate_at_stop_sequences_cuts_at_earliest_match() {
+        let stop = vec!["---".to_owned(), "Explanation:".to_owned()];
+        assert_eq!(
+            truncate_at_stop_sequences("Good day.\nExplanation: formal\n---", &stop),
+            "Good day."
+        );
+        assert_eq!(
+            truncate_at_stop_sequences("Good day. --- note", &stop),
+            "Good day."
+        );
+        assert_eq!(truncate_at_stop_sequences("Good day.", &stop), "Good day.");
+        assert_eq!(truncate_at_stop_sequences("Good day.", &[]), "Good day.");
+    }
+
+    #[test]
+    fn truncate_at_stop_sequences_matches_stop_split_by_trimmed_whitespace() {
+        let stop = vec!["\n---\n".to_owned()];
+        // The trailing newline of the stop sequence was trimmed off with the answer.
+        assert_eq!(
+            truncate_at_stop_sequences("Good day.\n---", &stop),
+            "Good day."
+        );
+        assert_eq!(
+            truncate_at_stop_sequences("Good day.\n---\nnote", &stop),
+            "Good day."
+        );
+        // Only the very end counts as trimmed; a mid-text match needs the whole sequence.
+        assert_eq!(
+            truncate_at_stop_sequences("Good day.\n--- still here", &stop),
+            "Good day.\n--- still here"
+        );
+    }
+
+    #[test]
+    fn extract_response_text_skips_reasoning_items_before_message() {
+        let mut body = response_body("rewritten");
+        let message = body["output"][0].take();
+        body["output"] = serde_json::json!([
+            {
+                "type": "reasoning",
+                "id": "rs-1",
+                "summary": [{ "type": "summary_text", "text": "Thinking about tone." }]
+            },
+            { "type": "some_future_item", "id": "x-1" },
+            message
+        ]);
+
+        let response: super::ResponsesApiResponse =
+            serde_json::from_value(body).expect("response body should parse");
+        assert_eq!(extract_response_text(&response.output), "rewritten");
+    }
+
     #[test]
     fn extract_response_text_keeps_message_boundaries() {
         let output = vec![
@@ -0,0 +1,351 @@
+use super::{
+    LlmRewriter, RequestError, RetryPolicy, Rewrite, RewriteFuture, TransportOptions,
+    build_http_client, classify_transport_error, parse_retry_after, send_with_retries,
+    status_error,
+};
+use crate::config::AnthropicConfig;
+use crate::context::ContextMessage;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug;
+
+const ANTHROPIC_API_BASE: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Comfortably above a full 4096-character Telegram message.
+const MAX_OUTPUT_TOKENS: u32 = 4096;
+
+pub struct AnthropicClient {
+    model: String,
+    api_key: String,
+    api_base: String,
+    http_client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: String,
+    messages: Vec<Message>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Message {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl AnthropicClient {
+    pub fn new(config: &AnthropicConfig) -> Result<Self> {
+        let api_key = config.api_key.expose().trim().to_owned();
+        if api_key.is_empty() {
+            bail!("anthropic api key must not be empty");
+        }
+
+        let model = config.model.trim().to_owned();
+        if model.is_empty() {
+            bail!("anthropic model must not be empty");
+        }
+
+        let transport = TransportOptions {
+            timeout: Duration::from_secs(config.timeout_seconds),
+            connect_timeout: Duration::from_secs(config.timeout_seconds),
+            retry: RetryPolicy::new(&config.retry, Duration::from_secs(config.timeout_seconds)),
+            proxy: None,
+            headers: Default::default(),
+        };
+        let http_client = build_http_client(&transport)?;
+
+        debug!(
+            timeout_seconds = config.timeout_seconds,
+            max_attempts = transport.retry.max_attempts,
+            model = %model,
+            "built anthropic HTTP client"
+        );
+
+        Ok(Self {
+            model,
+            api_key,
+            api_base: ANTHROPIC_API_BASE.to_owned(),
+            http_client,
+            retry: transport.retry,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_api_base(mut self, api_base: String) -> Self {
+        self.api_base = api_base;
+        self
+    }
+
+    pub async fn rewrite(
+        &self,
+        system_prompt: &str,
+        chat_metadata: Option<&str>,
+        context: &[ContextMessage],
+        input: &str,
+    ) -> Result<Rewrite> {
+        let request =
+            build_messages_request(&self.model, system_prompt, chat_metadata, context, input);
+        let text = send_with_retries(&self.retry, "anthropic", &self.model, || {
+            self.create_message(&request)
+        })
+        .await?;
+        if text.is_empty() {
+            bail!("anthropic response missing assistant text content");
+        }
+        Ok(Rewrite {
+            text,
+            model: self.model.clone(),
+            usage: None,
+            refusal: None,
+        })
+    }
+
+    async fn create_message(&self, request: &MessagesRequest<'_>) -> Result<String, RequestError> {
+        let response = self
+            .http_client
+            .post(format!("{}/messages", self.api_base))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(request)
+            .send()
+            .await
+            .map_err(|err| classify_transport_error(err, "Anthropic"))?;
+
+        let status = response.status();
+        let retry_after = parse_retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .map_err(|err| classify_transport_error(err, "Anthropic"))?;
+        if !status.is_success() {
+            // 429 (rate limit) and 529 (overloaded) are retried like OpenAI's 429/5xx.
+            return Err(status_error(
+                "anthropic messages api",
+                status,
+                retry_after,
+                &body,
+            ));
+        }
+
+        let response: MessagesResponse = serde_json::from_str(&body)
+            .context("failed to parse Anthropic messages api body")
+            .map_err(RequestError::Permanent)?;
+        Ok(extract_message_text(&response.content))
+    }
+}
+
+impl LlmRewriter for AnthropicClient {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(AnthropicClient::rewrite(
+            self,
+            system_prompt,
+            chat_metadata,
+            context,
+            input,
+        ))
+    }
+}
+
+/// Anthropic takes a single system string, so the chat metadata line is appended to it.
+fn build_messages_request<'a>(
+    model: &'a str,
+    system_prompt: &str,
+    chat_metadata: Option<&str>,
+    context: &[ContextMessage],
+    input: &str,
+) -> MessagesRequest<'a> {
+    let mut messages = Vec::with_capacity(context.len() + 1);
+    messages.extend(context.iter().map(|context_message| Message {
+        role: "user",
+        content: context_message.as_llm_user_content(),
+    }));
+    messages.push(Message {
+        role: "user",
+        content: input.to_owned(),
+    });
+
+    MessagesRequest {
+        model,
+        max_tokens: MAX_OUTPUT_TOKENS,
+        system: match chat_metadata {
+            Some(chat_metadata) => format!("{system_prompt}\n\n{chat_metadata}"),
+            None => system_prompt.to_owned(),
+        },
+        messages,
+    }
+}
+
+fn extract_message_text(content: &[ContentBlock]) -> String {
+    content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.trim()),
+            ContentBlock::Other => None,
+        })
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnthropicClient, Message, build_messages_request};
+    use crate::config::{AnthropicConfig, RetryConfig};
+    use crate::context::ContextMessage;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_client(server: &MockServer, max_attempts: u32) -> AnthropicClient {
+        AnthropicClient::new(&AnthropicConfig {
+            api_key: "sk-ant-test".into(),
+            model: "claude-sonnet-4-5".to_owned(),
+            timeout_seconds: 5,
+            retry: RetryConfig {
+                max_attempts,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 5,
+            },
+        })
+        .expect("client should build")
+        .with_api_base(server.uri())
+    }
+
+    fn message_body(text: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [{ "type": "text", "text": text }],
+            "stop_reason": "end_turn"
+        })
+    }
+
+    #[test]
+    fn build_messages_request_maps_system_and_context() {
+        let context = vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "Hey there".to_owned(),
+            is_own: false,
+        }];
+        let request = build_messages_request(
+            "claude-sonnet-4-5",
+            "Rewrite politely",
+            None,
+            &context,
+            "ok",
+        );
+
+        assert_eq!(request.system, "Rewrite politely");
+        assert_eq!(
+            request.messages,
+            vec![
+                Message {
+                    role: "user",
+                    content: "Alice: Hey there".to_owned(),
+                },
+                Message {
+                    role: "user",
+                    content: "ok".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_sends_auth_headers_and_reads_text_blocks() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(header("x-api-key", "sk-ant-test"))
+            .and(header("anthropic-version", "2023-06-01"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(message_body("rewritten")))
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 1)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("rewrite should succeed");
+        assert_eq!(rewrite.text, "rewritten");
+        assert_eq!(rewrite.model, "claude-sonnet-4-5");
+    }
+
+    #[tokio::test]
+    async fn rewrite_retries_overloaded_responses() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(529).set_body_json(serde_json::json!({
+                "type": "error",
+                "error": { "type": "overloaded_error", "message": "Overloaded" }
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(message_body("rewritten")))
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server, 2)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("rewrite should succeed after retry");
+        assert_eq!(rewrite.text, "rewritten");
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_fails_immediately_on_invalid_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "type": "error",
+                "error": { "type": "invalid_request_error", "message": "bad model" }
+            })))
+            .mount(&server)
+            .await;
+
+        let err = test_client(&server, 3)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("invalid requests should not be retried");
+        assert!(err.to_string().contains("bad model"));
+        assert_eq!(
+            server.received_requests().await.unwrap_or_default().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use super::{LlmRewriter, TokenUsage, strip_code_fence};
+use crate::context::ContextMessage;
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::warn;
+
+const BATCH_INSTRUCTIONS: &str = "The user message is a JSON array of chat messages instead of \
+a single message. Rewrite each one separately following the instructions above. Reply with \
+only a JSON object of the form {\"rewrites\": [\"...\"]} holding exactly one rewrite per \
+message, in the same order.";
+
+/// One model answer covering several messages. `rewrites[i]` is `None` when the answer had
+/// no usable rewrite for `inputs[i]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchRewrite {
+    pub rewrites: Vec<Option<String>>,
+    pub model: String,
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchAnswer {
+    Object { rewrites: Vec<serde_json::Value> },
+    Array(Vec<serde_json::Value>),
+}
+
+/// Rewrites all `inputs` with a single request that asks for a JSON list of rewrites.
+pub async fn rewrite_batch(
+    llm: &dyn LlmRewriter,
+    system_prompt: &str,
+    chat_metadata: Option<&str>,
+    context: &[ContextMessage],
+    inputs: &[&str],
+    deadline: Option<Duration>,
+) -> Result<BatchRewrite> {
+    let system_prompt = format!("{}\n\n{BATCH_INSTRUCTIONS}", system_prompt.trim_end());
+    let input = serde_json::to_string(inputs)?;
+    let result = llm
+        .rewrite_with_deadline(&system_prompt, chat_metadata, context, &input, deadline)
+        .await?;
+    if let Some(refusal) = result.refusal {
+        return Err(anyhow!("model refused the batch rewrite: {refusal}"));
+    }
+    Ok(BatchRewrite {
+        rewrites: parse_batch_rewrites(&result.text, inputs.len()),
+        model: result.model,
+        usage: result.usage,
+    })
+}
+
+/// Accepts `{"rewrites": [...]}` or a bare array, optionally in a code fence. Entries that
+/// are missing, empty or not strings come back as `None`.
+fn parse_batch_rewrites(text: &str, expected: usize) -> Vec<Option<String>> {
+    let entries = match serde_json::from_str::<BatchAnswer>(strip_code_fence(text.trim())) {
+        Ok(BatchAnswer::Object { rewrites } | BatchAnswer::Array(rewrites)) => rewrites,
+        Err(err) => {
+            warn!(error = %err, "batch rewrite answer was not a JSON list of rewrites");
+            Vec::new()
+        }
+    };
+    if !entries.is_empty() && entries.len() != expected {
+        warn!(
+            expected,
+            received = entries.len(),
+            "batch rewrite answer has the wrong number of entries"
+        );
+    }
+    let mut entries = entries.into_iter();
+    (0..expected)
+        .map(|_| match entries.next()? {
+            serde_json::Value::String(text) if !text.trim().is_empty() => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchRewrite, parse_batch_rewrites, rewrite_batch};
+    use crate::context::ContextMessage;
+    use crate::llm::{LlmRewriter, Rewrite, RewriteFuture, TokenUsage};
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    struct JsonRewriter {
+        answer: String,
+        requests: Requests,
+    }
+
+    impl LlmRewriter for JsonRewriter {
+        fn rewrite<'a>(
+            &'a self,
+            system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            input: &'a str,
+        ) -> RewriteFuture<'a> {
+            self.requests
+                .lock()
+                .expect("requests mutex poisoned")
+                .push((system_prompt.to_owned(), input.to_owned()));
+            Box::pin(async move {
+                Ok(Rewrite {
+                    text: self.answer.clone(),
+                    model: "gpt-4.1-mini".to_owned(),
+                    usage: Some(TokenUsage {
+                        input_tokens: 90,
+                        output_tokens: 20,
+                    }),
+                    refusal: None,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn rewrite_batch_sends_inputs_as_json_and_maps_answers_in_order() {
+        let requests = Requests::default();
+        let llm = JsonRewriter {
+            answer: r#"{"rewrites": ["Hello there.", "See you soon."]}"#.to_owned(),
+            requests: Arc::clone(&requests),
+        };
+
+        let batch = rewrite_batch(&llm, "Rewrite politely", None, &[], &["hi", "cya"], None)
+            .await
+            .expect("batch should succeed");
+
+        assert_eq!(
+            batch,
+            BatchRewrite {
+                rewrites: vec![
+                    Some("Hello there.".to_owned()),
+                    Some("See you soon.".to_owned()),
+                ],
+                model: "gpt-4.1-mini".to_owned(),
+                usage: Some(TokenUsage {
+                    input_tokens: 90,
+                    output_tokens: 20,
+                }),
+            }
+        );
+        let requests = requests.lock().expect("requests mutex poisoned");
+        assert!(requests[0].0.starts_with("Rewrite politely\n\n"));
+        assert_eq!(requests[0].1, r#"["hi","cya"]"#);
+    }
+
+    #[test]
+    fn parse_batch_rewrites_accepts_bare_and_fenced_arrays() {
+        assert_eq!(
+            parse_batch_rewrites(r#"["a", "b"]"#, 2),
+            vec![Some("a".to_owned()), Some("b".to_owned())]
+        );
+        assert_eq!(
+            parse_batch_rewrites("```json\n{\"rewrites\": [\"a\"]}\n```", 1),
+            vec![Some("a".to_owned())]
+        );
+    }
+
+    #[test]
+    fn parse_batch_rewrites_marks_unusable_entries_as_failed() {
+        assert_eq!(
+            parse_batch_rewrites(r#"{"rewrites": ["a", 3, " "]}"#, 4),
+            vec![Some("a".to_owned()), None, None, None]
+        );
+        assert_eq!(
+            parse_batch_rewrites("Sure! Here you go", 2),
+            vec![None, None]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use super::{
+    Completion, OpenAiClient, RequestError, TokenUsage, classify_transport_error,
+    parse_retry_after, status_error,
+};
+use crate::config::ContextStyle;
+use crate::context::ContextMessage;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Request body for `/chat/completions`, the API flavor most OpenAI-compatible
+/// servers (llama.cpp server, vLLM) implement.
+#[derive(Debug, Serialize)]
+pub(super) struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    #[serde(default)]
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<ChatCompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChoiceMessage {
+    content: Option<String>,
+}
+
+impl OpenAiClient {
+    pub(super) async fn create_chat_completion(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<Completion, RequestError> {
+        let response = self
+            .http_client
+            .post(format!("{}/chat/completions", self.api_base))
+            .bearer_auth(&self.api_key)
+            .json(request)
+            .send()
+            .await
+            .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+
+        let status = response.status();
+        let retry_after = parse_retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+        if !status.is_success() {
+            return Err(status_error(
+                "openai chat completions api",
+                status,
+                retry_after,
+                &body,
+            ));
+        }
+
+        let response: ChatCompletionResponse = serde_json::from_str(&body)
+            .context("failed to parse OpenAI chat completions body")
+            .map_err(RequestError::Permanent)?;
+        let usage = response.usage.as_ref().map(|usage| TokenUsage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        });
+        Ok(Completion {
+            text: extract_chat_completion_text(response),
+            usage,
+            refusal: None,
+        })
+    }
+}
+
+pub(super) fn build_chat_completion_request(
+    model: &str,
+    system_prompt: &str,
+    chat_metadata: Option<&str>,
+    context: &[ContextMessage],
+    input: &str,
+    context_style: ContextStyle,
+    stop: &[String],
+) -> ChatCompletionRequest {
+    let mut messages = Vec::with_capacity(context.len() + 3);
+    messages.push(ChatMessage {
+        role: "system",
+        content: system_prompt.to_owned(),
+    });
+    if let Some(chat_metadata) = chat_metadata {
+        messages.push(ChatMessage {
+            role: "system",
+            content: chat_metadata.to_owned(),
+        });
+    }
+    messages.extend(context.iter().map(|context_message| {
+        if context_style == ContextStyle::Roles && context_message.is_own {
+            ChatMessage {
+                role: "assistant",
+                content: context_message.text.clone(),
+            }
+        } else {
+            ChatMessage {
+                role: "user",
+                content: context_message.as_llm_user_content(),
+            }
+        }
+    }));
+    messages.push(ChatMessage {
+        role: "user",
+        content: input.to_owned(),
+    });
+
+    ChatCompletionRequest {
+        model: model.to_owned(),
+        messages,
+        stop: stop.to_vec(),
+    }
+}
+
+fn extract_chat_completion_text(response: ChatCompletionResponse) -> String {
+    response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .map(|content| content.trim().to_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatMessage, build_chat_completion_request};
+    use crate::config::ContextStyle;
+    use crate::context::ContextMessage;
+
+    #[test]
+    fn build_chat_completion_request_includes_context_in_expected_order() {
+        let context = vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "Hey there".to_owned(),
+            is_own: false,
+        }];
+        let request = build_chat_completion_request(
+            "local",
+            "Rewrite politely",
+            None,
+            &context,
+            "ok",
+            ContextStyle::Roles,
+            &["---".to_owned()],
+        );
+
+        assert_eq!(request.model, "local");
+        assert_eq!(request.stop, vec!["---"]);
+        assert_eq!(
+            request.messages,
+            vec![
+                ChatMessage {
+                    role: "system",
+                    content: "Rewrite politely".to_owned(),
+                },
+                ChatMessage {
+                    role: "user",
+                    content: "Alice: Hey there".to_owned(),
+                },
+                ChatMessage {
+                    role: "user",
+                    content: "ok".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn build_chat_completion_request_flattens_own_messages_only_in_flat_style() {
+        let context = vec![ContextMessage {
+            sender_name: "Me".to_owned(),
+            text: "Hi!".to_owned(),
+            is_own: true,
+        }];
+        let own_turn = |context_style| {
+            build_chat_completion_request(
+                "local",
+                "Rewrite politely",
+                None,
+                &context,
+                "ok",
+                context_style,
+                &[],
+            )
+            .messages
+            .swap_remove(1)
+        };
+
+        assert_eq!(
+            own_turn(ContextStyle::Roles),
+            ChatMessage {
+                role: "assistant",
+                content: "Hi!".to_owned(),
+            }
+        );
+        assert_eq!(
+            own_turn(ContextStyle::Flat),
+            ChatMessage {
+                role: "user",
+                content: "Me: Hi!".to_owned(),
+            }
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use super::{LlmRewriter, Rewrite, RewriteFuture};
+use crate::config::OllamaConfig;
+use crate::context::ContextMessage;
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug;
+
+pub struct OllamaClient {
+    url: String,
+    model: String,
+    http_client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatError {
+    error: String,
+}
+
+impl OllamaClient {
+    pub fn new(config: &OllamaConfig) -> Result<Self> {
+        let model = config.model.trim().to_owned();
+        if model.is_empty() {
+            bail!("ollama model must not be empty");
+        }
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .context("failed to build HTTP client for Ollama")?;
+
+        debug!(
+            url = %config.url,
+            model = %model,
+            timeout_seconds = config.timeout_seconds,
+            "built ollama HTTP client"
+        );
+
+        Ok(Self {
+            url: config.url.trim().trim_end_matches('/').to_owned(),
+            model,
+            http_client,
+        })
+    }
+
+    pub async fn rewrite(
+        &self,
+        system_prompt: &str,
+        chat_metadata: Option<&str>,
+        context: &[ContextMessage],
+        input: &str,
+    ) -> Result<Rewrite> {
+        let request = build_chat_request(&self.model, system_prompt, chat_metadata, context, input);
+        debug!(model = %self.model, "sending rewrite request to ollama chat api");
+
+        let response = self
+            .http_client
+            .post(format!("{}/api/chat", self.url))
+            .json(&request)
+            .send()
+            .await
+            .context("failed to send request to Ollama")?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("failed to read Ollama response body")?;
+        if !status.is_success() {
+            let message = serde_json::from_str::<ChatError>(&body)
+                .map(|err| err.error)
+                .unwrap_or_else(|_| body.trim().to_owned());
+            return Err(anyhow!("ollama chat api returned HTTP {status}: {message}"));
+        }
+
+        let response: ChatResponse =
+            serde_json::from_str(&body).context("failed to parse Ollama chat api body")?;
+        let text = response.message.content.trim();
+        if text.is_empty() {
+            bail!("ollama response missing assistant text content");
+        }
+
+        Ok(Rewrite {
+            text: text.to_owned(),
+            model: self.model.clone(),
+            usage: None,
+            refusal: None,
+        })
+    }
+}
+
+impl LlmRewriter for OllamaClient {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(OllamaClient::rewrite(
+            self,
+            system_prompt,
+            chat_metadata,
+            context,
+            input,
+        ))
+    }
+}
+
+fn build_chat_request<'a>(
+    model: &'a str,
+    system_prompt: &str,
+    chat_metadata: Option<&str>,
+    context: &[ContextMessage],
+    input: &str,
+) -> ChatRequest<'a> {
+    let mut messages = Vec::with_capacity(context.len() + 3);
+    messages.push(chat_message("system", system_prompt.to_owned()));
+    if let Some(chat_metadata) = chat_metadata {
+        messages.push(chat_message("system", chat_metadata.to_owned()));
+    }
+    messages.extend(
+        context
+            .iter()
+            .map(|context_message| chat_message("user", context_message.as_llm_user_content())),
+    );
+    messages.push(chat_message("user", input.to_owned()));
+
+    ChatRequest {
+        model,
+        messages,
+        stream: false,
+    }
+}
+
+fn chat_message(role: &str, content: String) -> ChatMessage {
+    ChatMessage {
+        role: role.to_owned(),
+        content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OllamaClient, build_chat_request, chat_message};
+    use crate::config::OllamaConfig;
+    use crate::context::ContextMessage;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_client(server: &MockServer) -> OllamaClient {
+        OllamaClient::new(&OllamaConfig {
+            url: format!("{}/", server.uri()),
+            model: "llama3.1".to_owned(),
+            timeout_seconds: 5,
+        })
+        .expect("client should build")
+    }
+
+    #[test]
+    fn build_chat_request_includes_context_in_expected_order() {
+        let context = vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "Hey there".to_owned(),
+            is_own: false,
+        }];
+        let request = build_chat_request("llama3.1", "Rewrite politely", None, &context, "ok");
+
+        assert_eq!(request.model, "llama3.1");
+        assert!(!request.stream);
+        assert_eq!(
+            request.messages,
+            vec![
+                chat_message("system", "Rewrite politely".to_owned()),
+                chat_message("user", "Alice: Hey there".to_owned()),
+                chat_message("user", "ok".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_reads_chat_message_content() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(serde_json::json!({
+                "model": "llama3.1",
+                "stream": false
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "model": "llama3.1",
+                "message": { "role": "assistant", "content": "  rewritten  " },
+                "done": true
+            })))
+            .mount(&server)
+            .await;
+
+        let rewrite = test_client(&server)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("rewrite should succeed");
+        assert_eq!(rewrite.text, "rewritten");
+        assert_eq!(rewrite.model, "llama3.1");
+    }
+
+    #[tokio::test]
+    async fn rewrite_surfaces_ollama_error_message() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(serde_json::json!({
+                "error": "model \"llama3.1\" not found, try pulling it first"
+            })))
+            .mount(&server)
+            .await;
+
+        let err = test_client(&server)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("missing model should fail");
+        assert!(err.to_string().contains("not found, try pulling it first"));
+    }
+}
@@ -0,0 +1,243 @@
+use super::{HealthCheckFuture, LlmRewriter, RewriteFuture};
+use crate::config::RateLimitConfig;
+use crate::context::{ContextMessage, estimate_tokens};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// Global requests/tokens per minute budget; callers queue on the lock in arrival order.
+pub struct RequestRateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+    queued: AtomicUsize,
+}
+
+struct Buckets {
+    requests: Bucket,
+    tokens: Option<Bucket>,
+}
+
+/// Token bucket holding up to one minute of budget, refilled continuously.
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            capacity: f64::from(per_minute),
+            available: f64::from(per_minute),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// How long until `amount` is available; requests larger than the bucket wait for a full one.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let deficit = amount.min(self.capacity) - self.available;
+        if deficit <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(deficit * 60.0 / self.capacity)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+}
+
+impl RequestRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            buckets: Mutex::new(Buckets {
+                requests: Bucket::new(config.requests_per_minute, now),
+                tokens: config
+                    .tokens_per_minute
+                    .map(|per_minute| Bucket::new(per_minute, now)),
+            }),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Keeps the previous limiter, and so its spent budget, when a reload leaves the limits unchanged.
+    pub fn reuse_or_new(previous: Option<&Arc<Self>>, config: RateLimitConfig) -> Arc<Self> {
+        match previous {
+            Some(previous) if previous.config == config => Arc::clone(previous),
+            _ => Arc::new(Self::new(config)),
+        }
+    }
+
+    pub async fn acquire(&self, estimated_tokens: usize) {
+        let started = Instant::now();
+        let queue_depth = self.queued.fetch_add(1, Ordering::SeqCst);
+        let mut buckets = self.buckets.lock().await;
+
+        let tokens = estimated_tokens as f64;
+        loop {
+            let now = Instant::now();
+            buckets.requests.refill(now);
+            let mut wait = buckets.requests.wait_for(1.0);
+            if let Some(token_bucket) = buckets.tokens.as_mut() {
+                token_bucket.refill(now);
+                wait = wait.max(token_bucket.wait_for(tokens));
+            }
+            if wait.is_zero() {
+                break;
+            }
+            tokio::time::sleep(wait).await;
+        }
+        buckets.requests.take(1.0);
+        if let Some(token_bucket) = buckets.tokens.as_mut() {
+            token_bucket.take(tokens);
+        }
+        drop(buckets);
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        let waited = started.elapsed();
+        if waited >= Duration::from_millis(self.config.log_wait_threshold_ms) {
+            info!(
+                waited_ms = waited.as_millis() as u64,
+                queue_depth, "llm request waited for client-side rate limit"
+            );
+        } else {
+            debug!(
+                waited_ms = waited.as_millis() as u64,
+                "llm request passed rate limit"
+            );
+        }
+    }
+}
+
+/// Applies a shared [`RequestRateLimiter`] in front of another rewriter.
+pub struct RateLimitedRewriter {
+    inner: Box<dyn LlmRewriter>,
+    limiter: Arc<RequestRateLimiter>,
+}
+
+impl RateLimitedRewriter {
+    pub fn new(inner: Box<dyn LlmRewriter>, limiter: Arc<RequestRateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl LlmRewriter for RateLimitedRewriter {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(async move {
+            let estimated_tokens = estimate_tokens(system_prompt)
+                + chat_metadata.map_or(0, estimate_tokens)
+                + context
+                    .iter()
+                    .map(|message| estimate_tokens(&message.as_llm_user_content()))
+                    .sum::<usize>()
+                + estimate_tokens(input);
+            self.limiter.acquire(estimated_tokens).await;
+            self.inner
+                .rewrite(system_prompt, chat_metadata, context, input)
+                .await
+        })
+    }
+
+    fn health_check(&self) -> HealthCheckFuture<'_> {
+        self.inner.health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestRateLimiter;
+    use crate::config::RateLimitConfig;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    fn config(requests_per_minute: u32, tokens_per_minute: Option<u32>) -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_minute,
+            tokens_per_minute,
+            log_wait_threshold_ms: 1_000,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_beyond_budget_wait_for_refill() {
+        let limiter = RequestRateLimiter::new(config(2, None));
+        let started = Instant::now();
+
+        limiter.acquire(0).await;
+        limiter.acquire(0).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        limiter.acquire(0).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_budget_delays_large_requests() {
+        let limiter = RequestRateLimiter::new(config(100, Some(600)));
+        let started = Instant::now();
+
+        limiter.acquire(600).await;
+        limiter.acquire(300).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_queue_instead_of_failing() {
+        let limiter = Arc::new(RequestRateLimiter::new(config(1, None)));
+        let started = Instant::now();
+
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                tokio::spawn(async move {
+                    limiter.acquire(0).await;
+                    started.elapsed()
+                })
+            })
+            .collect();
+        let mut finished = Vec::new();
+        for task in tasks {
+            finished.push(task.await.expect("task should not panic"));
+        }
+        finished.sort();
+
+        assert_eq!(
+            finished,
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(60),
+                Duration::from_secs(120)
+            ]
+        );
+    }
+
+    #[test]
+    fn reuse_or_new_keeps_limiter_only_when_limits_match() {
+        let previous = Arc::new(RequestRateLimiter::new(config(60, None)));
+
+        let same = RequestRateLimiter::reuse_or_new(Some(&previous), config(60, None));
+        assert!(Arc::ptr_eq(&previous, &same));
+
+        let changed = RequestRateLimiter::reuse_or_new(Some(&previous), config(30, None));
+        assert!(!Arc::ptr_eq(&previous, &changed));
+    }
+}
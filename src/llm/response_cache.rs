@@ -0,0 +1,345 @@
+use super::{HealthCheckFuture, LlmRewriter, Rewrite, RewriteFuture};
+use crate::context::ContextMessage;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Bounded LRU of successful rewrites, so redelivered messages don't pay for a second call.
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    next_use: u64,
+}
+
+struct CacheEntry {
+    rewrite: Rewrite,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps the previous cache across reloads with the same size settings. Model and prompt
+    /// changes need no flush: both are part of every key.
+    pub fn reuse_or_new(previous: Option<&Arc<Self>>, capacity: usize, ttl: Duration) -> Arc<Self> {
+        match previous {
+            Some(previous) if previous.capacity == capacity && previous.ttl == ttl => {
+                Arc::clone(previous)
+            }
+            _ => Arc::new(Self::new(capacity, ttl)),
+        }
+    }
+
+    pub fn key(
+        model: &str,
+        system_prompt: &str,
+        chat_metadata: Option<&str>,
+        context: &[ContextMessage],
+        input: &str,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        system_prompt.hash(&mut hasher);
+        chat_metadata.hash(&mut hasher);
+        context.len().hash(&mut hasher);
+        for message in context {
+            message.sender_name.hash(&mut hasher);
+            message.text.hash(&mut hasher);
+            message.is_own.hash(&mut hasher);
+        }
+        input.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&self, key: u64, now: Instant) -> Option<Rewrite> {
+        let mut state = self.state.lock().expect("response cache mutex poisoned");
+        let expired = state
+            .entries
+            .get(&key)
+            .is_some_and(|entry| now.duration_since(entry.inserted_at) >= self.ttl);
+        if expired {
+            state.entries.remove(&key);
+            return None;
+        }
+        state.next_use += 1;
+        let next_use = state.next_use;
+        let entry = state.entries.get_mut(&key)?;
+        entry.last_used = next_use;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(entry.rewrite.clone())
+    }
+
+    pub fn insert(&self, key: u64, rewrite: Rewrite, now: Instant) {
+        let mut state = self.state.lock().expect("response cache mutex poisoned");
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            state
+                .entries
+                .retain(|_, entry| now.duration_since(entry.inserted_at) < ttl);
+            if state.entries.len() >= self.capacity
+                && let Some(&oldest) = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key)
+            {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.next_use += 1;
+        let last_used = state.next_use;
+        state.entries.insert(
+            key,
+            CacheEntry {
+                rewrite,
+                inserted_at: now,
+                last_used,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.state
+            .lock()
+            .expect("response cache mutex poisoned")
+            .entries
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves repeated requests for `model` from a shared [`ResponseCache`].
+pub struct CachedRewriter {
+    inner: Box<dyn LlmRewriter>,
+    cache: Arc<ResponseCache>,
+    model: String,
+}
+
+impl CachedRewriter {
+    pub fn new(inner: Box<dyn LlmRewriter>, cache: Arc<ResponseCache>, model: String) -> Self {
+        Self {
+            inner,
+            cache,
+            model,
+        }
+    }
+}
+
+impl LlmRewriter for CachedRewriter {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(async move {
+            let key = ResponseCache::key(&self.model, system_prompt, chat_metadata, context, input);
+            if let Some(rewrite) = self.cache.get(key, Instant::now()) {
+                info!(
+                    model = %rewrite.model,
+                    cache_hits = self.cache.hits(),
+                    "serving rewrite from response cache"
+                );
+                // Cached rewrites cost nothing, so they report no usage.
+                return Ok(Rewrite {
+                    usage: None,
+                    ..rewrite
+                });
+            }
+
+            let rewrite = self
+                .inner
+                .rewrite(system_prompt, chat_metadata, context, input)
+                .await?;
+            if rewrite.refusal.is_none() {
+                self.cache.insert(key, rewrite.clone(), Instant::now());
+            }
+            Ok(rewrite)
+        })
+    }
+
+    fn health_check(&self) -> HealthCheckFuture<'_> {
+        self.inner.health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedRewriter, ResponseCache};
+    use crate::context::ContextMessage;
+    use crate::llm::{LlmRewriter, Rewrite, RewriteFuture};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn rewrite(text: &str) -> Rewrite {
+        Rewrite {
+            text: text.to_owned(),
+            model: "gpt-4.1-mini".to_owned(),
+            usage: None,
+            refusal: None,
+        }
+    }
+
+    struct CountingRewriter {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl LlmRewriter for CountingRewriter {
+        fn rewrite<'a>(
+            &'a self,
+            _system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            _context: &'a [ContextMessage],
+            input: &'a str,
+        ) -> RewriteFuture<'a> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(rewrite(&input.to_uppercase())) })
+        }
+    }
+
+    #[test]
+    fn key_covers_model_prompt_metadata_context_and_input() {
+        let context = vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "hi".to_owned(),
+            is_own: false,
+        }];
+        let base = ResponseCache::key("m", "prompt", None, &context, "ok");
+        assert_eq!(
+            base,
+            ResponseCache::key("m", "prompt", None, &context, "ok")
+        );
+        assert_ne!(
+            base,
+            ResponseCache::key("other", "prompt", None, &context, "ok")
+        );
+        assert_ne!(base, ResponseCache::key("m", "other", None, &context, "ok"));
+        assert_ne!(
+            base,
+            ResponseCache::key("m", "prompt", Some("Chat: Work"), &context, "ok")
+        );
+        assert_ne!(base, ResponseCache::key("m", "prompt", None, &[], "ok"));
+        assert_ne!(
+            base,
+            ResponseCache::key("m", "prompt", None, &context, "other")
+        );
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used_entry_at_capacity() {
+        let cache = ResponseCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert(1, rewrite("one"), now);
+        cache.insert(2, rewrite("two"), now);
+        assert!(cache.get(1, now).is_some());
+
+        cache.insert(3, rewrite("three"), now);
+        assert_eq!(cache.len(), 2);
+        assert!(
+            cache.get(2, now).is_none(),
+            "least recently used entry should be evicted"
+        );
+        assert_eq!(
+            cache.get(1, now).map(|hit| hit.text),
+            Some("one".to_owned())
+        );
+        assert_eq!(
+            cache.get(3, now).map(|hit| hit.text),
+            Some("three".to_owned())
+        );
+    }
+
+    #[test]
+    fn insert_prefers_dropping_expired_entries() {
+        let cache = ResponseCache::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        cache.insert(1, rewrite("old"), start);
+        cache.insert(2, rewrite("fresh"), start + Duration::from_secs(50));
+        assert!(cache.get(1, start + Duration::from_secs(10)).is_some());
+
+        let later = start + Duration::from_secs(70);
+        cache.insert(3, rewrite("new"), later);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2, later).is_some());
+        assert!(cache.get(3, later).is_some());
+    }
+
+    #[test]
+    fn get_drops_expired_entries() {
+        let cache = ResponseCache::new(4, Duration::from_secs(60));
+        let start = Instant::now();
+        cache.insert(1, rewrite("one"), start);
+        assert!(cache.get(1, start + Duration::from_secs(59)).is_some());
+        assert!(cache.get(1, start + Duration::from_secs(60)).is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_rewriter_skips_inner_call_on_repeat_request() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = Arc::new(ResponseCache::new(8, Duration::from_secs(60)));
+        let rewriter = CachedRewriter::new(
+            Box::new(CountingRewriter {
+                calls: Arc::clone(&calls),
+            }),
+            Arc::clone(&cache),
+            "gpt-4.1-mini".to_owned(),
+        );
+
+        let first = rewriter
+            .rewrite("prompt", None, &[], "ok")
+            .await
+            .expect("rewrite");
+        let second = rewriter
+            .rewrite("prompt", None, &[], "ok")
+            .await
+            .expect("rewrite");
+        let other = rewriter
+            .rewrite("other prompt", None, &[], "ok")
+            .await
+            .expect("rewrite");
+
+        assert_eq!(first, second);
+        assert_eq!(other.text, "OK");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn reuse_or_new_keeps_cache_only_when_settings_match() {
+        let previous = Arc::new(ResponseCache::new(8, Duration::from_secs(60)));
+        let same = ResponseCache::reuse_or_new(Some(&previous), 8, Duration::from_secs(60));
+        assert!(Arc::ptr_eq(&previous, &same));
+        let resized = ResponseCache::reuse_or_new(Some(&previous), 4, Duration::from_secs(60));
+        assert!(!Arc::ptr_eq(&previous, &resized));
+    }
+}
@@ -0,0 +1,365 @@
+use super::{
+    Completion, OpenAiClient, RequestError, ResponseRequest, TokenUsage, classify_transport_error,
+    parse_retry_after, status_error,
+};
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+/// Subset of the Responses API streaming events the rewriter cares about.
+#[derive(Deserialize)]
+struct StreamEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    delta: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    response: Option<StreamEventResponse>,
+}
+
+#[derive(Deserialize)]
+struct StreamEventResponse {
+    #[serde(default)]
+    error: Option<StreamEventError>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamEventError {
+    message: String,
+}
+
+/// Accumulated output text plus its UTF-16 length, the unit Telegram limits are counted in.
+#[derive(Default)]
+struct StreamText {
+    text: String,
+    utf16_len: usize,
+}
+
+impl StreamText {
+    fn push(&mut self, delta: &str) {
+        self.text.push_str(delta);
+        self.utf16_len += delta.encode_utf16().count();
+    }
+
+    fn finish(self, usage: Option<TokenUsage>) -> Completion {
+        Completion {
+            text: self.text.trim().to_owned(),
+            usage,
+            refusal: None,
+        }
+    }
+
+    /// Falls back to the partial text on mid-stream errors; only an empty stream is an error.
+    fn finish_or(self, err: RequestError) -> Result<Completion, RequestError> {
+        if self.text.trim().is_empty() {
+            return Err(err);
+        }
+        let err = err.into_error();
+        warn!(
+            error = %err,
+            chars = self.text.trim().chars().count(),
+            "openai stream failed mid-response; using partial text"
+        );
+        Ok(self.finish(None))
+    }
+}
+
+/// Splits a server-sent event byte stream into the `data:` payloads of complete events.
+#[derive(Default)]
+struct SseDecoder {
+    pending: Vec<u8>,
+}
+
+impl SseDecoder {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut payloads = Vec::new();
+        while let Some(end) = find_event_end(&self.pending) {
+            let event: Vec<u8> = self.pending.drain(..end.consumed).collect();
+            let event = String::from_utf8_lossy(&event[..end.event_len]);
+            let data = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect::<Vec<_>>()
+                .join("\n");
+            if !data.is_empty() {
+                payloads.push(data);
+            }
+        }
+        payloads
+    }
+}
+
+struct EventEnd {
+    event_len: usize,
+    consumed: usize,
+}
+
+fn find_event_end(buffer: &[u8]) -> Option<EventEnd> {
+    let lf = buffer.windows(2).position(|window| window == b"\n\n");
+    let crlf = buffer.windows(4).position(|window| window == b"\r\n\r\n");
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) if crlf < lf => Some(EventEnd {
+            event_len: crlf,
+            consumed: crlf + 4,
+        }),
+        (Some(lf), _) => Some(EventEnd {
+            event_len: lf,
+            consumed: lf + 2,
+        }),
+        (None, Some(crlf)) => Some(EventEnd {
+            event_len: crlf,
+            consumed: crlf + 4,
+        }),
+        (None, None) => None,
+    }
+}
+
+impl OpenAiClient {
+    /// Streams a Responses API call, stopping once the text exceeds `max_output_chars` UTF-16 units.
+    pub(super) async fn create_response_stream(
+        &self,
+        request: &ResponseRequest,
+        max_output_chars: usize,
+    ) -> Result<Completion, RequestError> {
+        let mut request = request.clone();
+        request.body.stream = Some(true);
+
+        let mut response = self
+            .http_client
+            .post(format!("{}/responses", self.api_base))
+            .bearer_auth(&self.api_key)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = parse_retry_after(response.headers());
+            let body = response
+                .text()
+                .await
+                .map_err(|err| classify_transport_error(err, "OpenAI"))?;
+            return Err(status_error(
+                "openai responses api",
+                status,
+                retry_after,
+                &body,
+            ));
+        }
+
+        let mut decoder = SseDecoder::default();
+        let mut output = StreamText::default();
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(err) => return output.finish_or(classify_transport_error(err, "OpenAI")),
+            };
+
+            for payload in decoder.feed(&chunk) {
+                if payload == "[DONE]" {
+                    continue;
+                }
+                let event: StreamEvent = match serde_json::from_str(&payload) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        debug!(error = %err, "skipping unparseable openai stream event");
+                        continue;
+                    }
+                };
+
+                match event.kind.as_str() {
+                    "response.output_text.delta" => {
+                        output.push(event.delta.as_deref().unwrap_or_default());
+                        if output.utf16_len > max_output_chars {
+                            debug!(
+                                utf16_len = output.utf16_len,
+                                max_output_chars, "openai stream exceeded output limit; aborting"
+                            );
+                            return Ok(output.finish(None));
+                        }
+                    }
+                    "error" => {
+                        let message = event.message.unwrap_or_else(|| "unknown error".to_owned());
+                        return output.finish_or(RequestError::Permanent(anyhow!(
+                            "openai responses stream returned error: {message}"
+                        )));
+                    }
+                    "response.failed" => {
+                        let message = event
+                            .response
+                            .and_then(|response| response.error)
+                            .map_or_else(|| "unknown error".to_owned(), |error| error.message);
+                        return output.finish_or(RequestError::Permanent(anyhow!(
+                            "openai responses stream failed: {message}"
+                        )));
+                    }
+                    "response.completed" => {
+                        let usage = event.response.and_then(|response| response.usage);
+                        return Ok(output.finish(usage));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(output.finish(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SseDecoder;
+    use crate::llm::{OpenAiClient, RetryPolicy, TokenUsage, TransportOptions};
+    use std::time::Duration;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn streaming_client(server: &MockServer, max_output_chars: usize) -> OpenAiClient {
+        OpenAiClient::new(
+            "sk-test".to_owned(),
+            "gpt-4.1-mini".to_owned(),
+            &TransportOptions {
+                timeout: Duration::from_secs(5),
+                connect_timeout: Duration::from_secs(5),
+                retry: RetryPolicy {
+                    max_attempts: 1,
+                    initial_backoff: Duration::from_millis(1),
+                    max_backoff: Duration::from_millis(5),
+                    max_retry_after: Duration::from_secs(5),
+                },
+                proxy: None,
+                headers: Default::default(),
+            },
+        )
+        .expect("client should build")
+        .with_api_base(server.uri())
+        .with_streaming(max_output_chars)
+    }
+
+    fn sse_body(events: &[serde_json::Value]) -> String {
+        events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {event}\n\n",
+                    event["type"].as_str().unwrap()
+                )
+            })
+            .collect()
+    }
+
+    fn delta(text: &str) -> serde_json::Value {
+        serde_json::json!({ "type": "response.output_text.delta", "delta": text })
+    }
+
+    async fn mount_stream(server: &MockServer, body: String) {
+        Mock::given(method("POST"))
+            .and(path("/responses"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(server)
+            .await;
+    }
+
+    #[test]
+    fn sse_decoder_joins_events_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.feed(b"event: a\ndata: {\"x\"").is_empty());
+        assert_eq!(
+            decoder.feed(b":1}\n\ndata: 2\r\n\r\n"),
+            vec!["{\"x\":1}", "2"]
+        );
+        assert!(decoder.feed(b": keep-alive\n\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_accumulates_deltas_until_completed() {
+        let server = MockServer::start().await;
+        mount_stream(
+            &server,
+            sse_body(&[
+                serde_json::json!({ "type": "response.created" }),
+                delta(" Good "),
+                delta("day"),
+                serde_json::json!({
+                    "type": "response.completed",
+                    "response": { "usage": { "input_tokens": 12, "output_tokens": 3 } }
+                }),
+            ]),
+        )
+        .await;
+
+        let rewrite = streaming_client(&server, 4096)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("stream should succeed");
+        assert_eq!(rewrite.text, "Good day");
+        assert_eq!(
+            rewrite.usage,
+            Some(TokenUsage {
+                input_tokens: 12,
+                output_tokens: 3,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_stops_once_output_limit_is_exceeded() {
+        let server = MockServer::start().await;
+        let mut events: Vec<_> = (0..100).map(|_| delta("word ")).collect();
+        events.push(serde_json::json!({ "type": "response.completed" }));
+        mount_stream(&server, sse_body(&events)).await;
+
+        let rewrite = streaming_client(&server, 12)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("stream should succeed");
+        assert_eq!(rewrite.text, "word word word");
+    }
+
+    #[tokio::test]
+    async fn stream_error_after_partial_text_returns_partial_text() {
+        let server = MockServer::start().await;
+        mount_stream(
+            &server,
+            sse_body(&[
+                delta("partial"),
+                serde_json::json!({ "type": "error", "message": "server overloaded" }),
+            ]),
+        )
+        .await;
+
+        let rewrite = streaming_client(&server, 4096)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect("partial text should be returned");
+        assert_eq!(rewrite.text, "partial");
+    }
+
+    #[tokio::test]
+    async fn stream_error_without_text_returns_error() {
+        let server = MockServer::start().await;
+        mount_stream(
+            &server,
+            sse_body(&[serde_json::json!({
+                "type": "response.failed",
+                "response": { "error": { "code": "server_error", "message": "boom" } }
+            })]),
+        )
+        .await;
+
+        let err = streaming_client(&server, 4096)
+            .rewrite("Rewrite politely", None, &[], "ok")
+            .await
+            .expect_err("empty failed stream should error");
+        assert!(format!("{err:#}").contains("boom"), "{err:#}");
+    }
+}
@@ -0,0 +1,221 @@
+use super::{HealthCheckFuture, LlmRewriter, Rewrite, RewriteFuture, TokenUsage};
+use crate::context::ContextMessage;
+use tracing::{debug, warn};
+
+/// Drafts with the configured prompt, then asks the model to check the draft against the
+/// original and fix what it lost. A failed second stage keeps the draft.
+pub struct TwoStageRewriter {
+    inner: Box<dyn LlmRewriter>,
+}
+
+impl TwoStageRewriter {
+    pub fn new(inner: Box<dyn LlmRewriter>) -> Self {
+        Self { inner }
+    }
+}
+
+impl LlmRewriter for TwoStageRewriter {
+    fn rewrite<'a>(
+        &'a self,
+        system_prompt: &'a str,
+        chat_metadata: Option<&'a str>,
+        context: &'a [ContextMessage],
+        input: &'a str,
+    ) -> RewriteFuture<'a> {
+        Box::pin(async move {
+            let draft = self
+                .inner
+                .rewrite(system_prompt, chat_metadata, context, input)
+                .await?;
+            if draft.refusal.is_some() {
+                return Ok(draft);
+            }
+            debug!(model = %draft.model, draft = %draft.text, "first-stage rewrite draft");
+
+            let review_prompt = review_system_prompt(system_prompt);
+            let review_input = review_input(input, &draft.text);
+            match self
+                .inner
+                .rewrite(&review_prompt, chat_metadata, context, &review_input)
+                .await
+            {
+                Ok(fixed) if fixed.refusal.is_none() && !fixed.text.trim().is_empty() => {
+                    Ok(Rewrite {
+                        usage: combined_usage(draft.usage, fixed.usage),
+                        ..fixed
+                    })
+                }
+                Ok(_) => {
+                    warn!("second-stage rewrite returned no text; using first-stage draft");
+                    Ok(draft)
+                }
+                Err(err) => {
+                    warn!(
+                        error = %err,
+                        "second-stage rewrite failed; using first-stage draft"
+                    );
+                    Ok(draft)
+                }
+            }
+        })
+    }
+
+    fn health_check(&self) -> HealthCheckFuture<'_> {
+        self.inner.health_check()
+    }
+}
+
+fn review_system_prompt(system_prompt: &str) -> String {
+    format!(
+        "You are checking a draft rewrite of a chat message. The rewrite instructions were:\n\
+         {system_prompt}\n\n\
+         Compare the draft against the original message. Restore any details the draft \
+         dropped or changed, keep the requested style, and reply with only the corrected \
+         final version."
+    )
+}
+
+fn review_input(original: &str, draft: &str) -> String {
+    format!("Original message:\n{original}\n\nDraft rewrite:\n{draft}")
+}
+
+fn combined_usage(first: Option<TokenUsage>, second: Option<TokenUsage>) -> Option<TokenUsage> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(TokenUsage {
+            input_tokens: first.input_tokens + second.input_tokens,
+            output_tokens: first.output_tokens + second.output_tokens,
+        }),
+        (first, second) => first.or(second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TwoStageRewriter;
+    use crate::context::ContextMessage;
+    use crate::llm::{LlmRewriter, Rewrite, RewriteFuture, TokenUsage};
+    use anyhow::anyhow;
+    use std::sync::{Arc, Mutex};
+
+    type Calls = Arc<Mutex<Vec<(String, usize, String)>>>;
+
+    /// Answers each call with the next scripted reply and records what it was asked.
+    struct ScriptedRewriter {
+        replies: Mutex<Vec<anyhow::Result<Rewrite>>>,
+        calls: Calls,
+    }
+
+    fn scripted(mut replies: Vec<anyhow::Result<Rewrite>>) -> (TwoStageRewriter, Calls) {
+        replies.reverse();
+        let calls = Calls::default();
+        let inner = ScriptedRewriter {
+            replies: Mutex::new(replies),
+            calls: Arc::clone(&calls),
+        };
+        (TwoStageRewriter::new(Box::new(inner)), calls)
+    }
+
+    impl LlmRewriter for ScriptedRewriter {
+        fn rewrite<'a>(
+            &'a self,
+            system_prompt: &'a str,
+            _chat_metadata: Option<&'a str>,
+            context: &'a [ContextMessage],
+            input: &'a str,
+        ) -> RewriteFuture<'a> {
+            self.calls.lock().expect("calls mutex poisoned").push((
+                system_prompt.to_owned(),
+                context.len(),
+                input.to_owned(),
+            ));
+            let reply = self
+                .replies
+                .lock()
+                .expect("replies mutex poisoned")
+                .pop()
+                .expect("unexpected extra call");
+            Box::pin(async move { reply })
+        }
+    }
+
+    fn rewrite(text: &str, input_tokens: u64, output_tokens: u64) -> Rewrite {
+        Rewrite {
+            text: text.to_owned(),
+            model: "gpt-4.1-mini".to_owned(),
+            usage: Some(TokenUsage {
+                input_tokens,
+                output_tokens,
+            }),
+            refusal: None,
+        }
+    }
+
+    fn context() -> Vec<ContextMessage> {
+        vec![ContextMessage {
+            sender_name: "Alice".to_owned(),
+            text: "when is the deploy?".to_owned(),
+            is_own: false,
+        }]
+    }
+
+    #[tokio::test]
+    async fn second_stage_sees_draft_and_usage_covers_both_calls() {
+        let (rewriter, calls) = scripted(vec![
+            Ok(rewrite("deploy tmrw", 100, 10)),
+            Ok(rewrite("Deploy is tomorrow at 10.", 150, 12)),
+        ]);
+
+        let result = rewriter
+            .rewrite("Rewrite politely", None, &context(), "deploy tmrw 10am")
+            .await
+            .expect("rewrite should succeed");
+
+        assert_eq!(result.text, "Deploy is tomorrow at 10.");
+        assert_eq!(
+            result.usage,
+            Some(TokenUsage {
+                input_tokens: 250,
+                output_tokens: 22,
+            })
+        );
+        let calls = calls.lock().expect("calls mutex poisoned");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].0, "Rewrite politely");
+        assert!(calls[1].0.contains("Rewrite politely"));
+        assert_eq!(calls[1].1, 1, "both stages share the context");
+        assert_eq!(
+            calls[1].2,
+            "Original message:\ndeploy tmrw 10am\n\nDraft rewrite:\ndeploy tmrw"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_second_stage_falls_back_to_draft() {
+        let (rewriter, _calls) = scripted(vec![
+            Ok(rewrite("draft", 100, 10)),
+            Err(anyhow!("upstream unavailable")),
+        ]);
+
+        let result = rewriter
+            .rewrite("Rewrite politely", None, &[], "input")
+            .await
+            .expect("draft should be kept");
+        assert_eq!(result, rewrite("draft", 100, 10));
+    }
+
+    #[tokio::test]
+    async fn refused_draft_skips_second_stage() {
+        let refused = Rewrite {
+            refusal: Some("I can't help with that.".to_owned()),
+            ..rewrite("", 100, 5)
+        };
+        let (rewriter, calls) = scripted(vec![Ok(refused.clone())]);
+
+        let result = rewriter
+            .rewrite("Rewrite politely", None, &[], "input")
+            .await
+            .expect("refusal is passed through");
+        assert_eq!(result, refused);
+        assert_eq!(calls.lock().expect("calls mutex poisoned").len(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Appends to `path` and, once it would grow past `max_bytes`, renames it to `path.1`
+/// (shifting older files to `path.2`, ...) and starts a new one. At most `keep_files` rotated
+/// files are kept.
+///
+/// Rotation happens between writes, so a line written in one call is never split across
+/// files. Behind `tracing_appender::non_blocking` every write comes from its single worker
+/// thread, so nothing is written while the files are swapped.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    keep_files: usize,
+    file: File,
+    len: u64,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it and its parent directories as needed. Without
+    /// `max_bytes` the file is never rotated.
+    pub fn open(path: &Path, max_bytes: Option<u64>, keep_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_append(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_bytes,
+            keep_files,
+            file,
+            len,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let oldest = self.rotated_path(self.keep_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for index in (1..self.keep_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.keep_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_bytes) = self.max_bytes
+            && self.len > 0
+            && self.len + buf.len() as u64 > max_bytes
+        {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingFile;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn rotates_whole_lines_and_keeps_the_configured_number_of_files() {
+        let dir = std::env::temp_dir().join("brainrot_log_file_rotation");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("brainrot.log");
+
+        let mut file = RotatingFile::open(&path, Some(100), 2).expect("log file should open");
+        let lines = (0..30)
+            .map(|index| format!("line {index:02} of synthetic log output\n"))
+            .collect::<Vec<_>>();
+        for line in &lines {
+            file.write_all(line.as_bytes())
+                .expect("log line should be written");
+        }
+        file.flush().expect("log file should flush");
+
+        let current = fs::read_to_string(&path).expect("current log should exist");
+        let first = fs::read_to_string(dir.join("nested").join("brainrot.log.1"))
+            .expect("rotated log should exist");
+        let second = fs::read_to_string(dir.join("nested").join("brainrot.log.2"))
+            .expect("older rotated log should exist");
+        assert!(!dir.join("nested").join("brainrot.log.3").exists());
+        for contents in [&current, &first, &second] {
+            assert!(contents.len() <= 100, "{contents}");
+            assert!(contents.ends_with('\n'), "{contents}");
+        }
+        // Nothing written around a rotation is lost: the kept files end with the newest lines.
+        let kept = format!("{second}{first}{current}");
+        assert!(lines.concat().ends_with(&kept), "{kept}");
+
+        fs::remove_dir_all(&dir).expect("test directory should be removed");
+    }
+}
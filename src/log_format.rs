@@ -0,0 +1,159 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Fields logged as JSON text, written as the values they encode instead of as strings.
+const JSON_ENCODED_FIELDS: &[&str] = &["context"];
+
+/// One JSON object per line, with `timestamp`, `level`, `message`, the event's fields and the
+/// fields of every span it is in, all as top-level keys. Fields of inner spans win over outer
+/// ones, and the event's own over both. Spans must record their fields with `JsonFields`.
+pub struct FlatJson;
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut object = Map::new();
+        object.insert("timestamp".to_owned(), Value::String(timestamp));
+        object.insert(
+            "level".to_owned(),
+            Value::String(event.metadata().level().to_string()),
+        );
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                    continue;
+                };
+                if let Ok(Value::Object(fields)) = serde_json::from_str(fields.as_str()) {
+                    object.extend(fields);
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut object));
+
+        let line = serde_json::to_string(&object).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{line}")
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = JSON_ENCODED_FIELDS
+            .contains(&field.name())
+            .then(|| serde_json::from_str(value).ok())
+            .flatten()
+            .unwrap_or_else(|| Value::String(value.to_owned()));
+        self.insert(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlatJson;
+    use serde_json::{Value, json};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::fmt::format::JsonFields;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("buffer lock").extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn events_and_span_fields_are_flattened_into_one_json_line() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(captured.clone())
+                .fmt_fields(JsonFields::new())
+                .event_format(FlatJson),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let _chat = info_span!("message", chat_id = -100_i64, message_id = 7).entered();
+            info!(
+                attempt = 2,
+                context = %json!(["Alice: hi\nthere", "Bob: yo"]),
+                "prepared rewrite payload"
+            );
+        });
+
+        let output = String::from_utf8(captured.0.lock().expect("buffer lock").clone())
+            .expect("log output should be utf-8");
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1, "{output}");
+        let line: Value = serde_json::from_str(lines[0]).expect("log line should be json");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "prepared rewrite payload");
+        assert_eq!(line["chat_id"], -100);
+        assert_eq!(line["message_id"], 7);
+        assert_eq!(line["attempt"], 2);
+        assert_eq!(line["context"], json!(["Alice: hi\nthere", "Bob: yo"]));
+        assert!(line["timestamp"].is_string());
+    }
+}
@@ -1,17 +1,87 @@
-use anyhow::{Result, anyhow};
-use brainrot_tg_llm_rewrite::app::{init_tracing, run_rewrite_mode};
+use anyhow::{Context, Result, anyhow};
+use brainrot_tg_llm_rewrite::app::{
+    BackfillReport, SessionRevoked, fallback_tracing_subscriber, init_tracing, run_backfill_mode,
+    run_rewrite_mode,
+};
 use brainrot_tg_llm_rewrite::config::{Config, ConfigMode, load_config_for_mode};
-use brainrot_tg_llm_rewrite::telegram::TelegramBot;
-use clap::{ArgAction, Parser};
+use brainrot_tg_llm_rewrite::send_test::run_send_test_mode;
+use brainrot_tg_llm_rewrite::telegram::{
+    AccountInfo, ChatListItem, ForumTopicItem, HistoryEntry, LogoutOutcome, TelegramBot, prompt,
+};
+use clap::{ArgAction, ArgGroup, Parser, ValueEnum};
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
 const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const DEFAULT_SEND_TEST_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_EXPORT_HISTORY_LIMIT: usize = 100;
+/// Exit status when the Telegram session was revoked, so a service manager can alert on it.
+const EXIT_SESSION_REVOKED: u8 = 3;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum AppMode {
-    Rewrite,
-    ListChats { query: Option<String> },
+    /// Rewrites monitored chats until shut down; with `dry_run`, only logs the rewrites.
+    Rewrite {
+        dry_run: bool,
+    },
+    ListChats {
+        query: Option<String>,
+        format: ListFormat,
+        sort: ListSort,
+    },
+    ListTopics {
+        chat_id: i64,
+    },
+    SendTest {
+        chat_id: i64,
+        text: String,
+        timeout_seconds: u64,
+    },
+    /// Writes recent messages of a chat as JSON lines, to `out` or stdout.
+    ExportHistory {
+        chat_id: i64,
+        /// `None` for messages outside topics, including a forum's General topic.
+        topic_root_id: Option<i32>,
+        limit: usize,
+        out: Option<PathBuf>,
+    },
+    /// Rewrites our last `count` messages in a chat, or only prints the rewrites.
+    Backfill {
+        chat_id: i64,
+        count: usize,
+        dry_run: bool,
+    },
+    WhoAmI,
+    Logout {
+        keep_file: bool,
+    },
+}
+
+/// Output of `--list-chats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    /// `<id>\t<name>\t<kind>\t<@username>\t<members>\t<unread>` lines with `-` for unknown
+    /// values, or a message when nothing matched.
+    #[default]
+    Plain,
+    /// An array of objects with every `ChatListItem` field.
+    Json,
+    /// A header row, then one row per chat with tabs, newlines and backslashes in names
+    /// escaped as `\t`, `\n` and `\\`.
+    Tsv,
+}
+
+/// Order of `--list-chats` output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ListSort {
+    /// By name, then id.
+    #[default]
+    Name,
+    /// Users, groups, supergroups, then channels, each by name.
+    Kind,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,6 +93,7 @@ struct AppArgs {
 #[derive(Debug, Parser)]
 #[command(name = "brainrot_tg_llm_rewrite")]
 #[command(about = "Telegram userbot rewriter with optional chat listing mode")]
+#[command(group(ArgGroup::new("chat_modes").args(["send_test", "export_history", "backfill"])))]
 struct Cli {
     #[arg(long, value_name = "path", default_value = DEFAULT_CONFIG_PATH)]
     config: PathBuf,
@@ -30,43 +101,465 @@ struct Cli {
     list_chats: bool,
     #[arg(value_name = "query", requires = "list_chats")]
     query: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "format",
+        default_value_t,
+        requires = "list_chats"
+    )]
+    format: ListFormat,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "order",
+        default_value_t,
+        requires = "list_chats"
+    )]
+    sort: ListSort,
+    #[arg(
+        long,
+        value_name = "chat_id",
+        allow_negative_numbers = true,
+        conflicts_with = "list_chats"
+    )]
+    list_topics: Option<i64>,
+    /// Print the account signed in to the session, without logging in.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["list_chats", "list_topics", "send_test"]
+    )]
+    whoami: bool,
+    /// Log the session out on Telegram's side, then remove the session file.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["list_chats", "list_topics", "send_test", "whoami"]
+    )]
+    logout: bool,
+    /// Keep the session file after `--logout`.
+    #[arg(long, action = ArgAction::SetTrue, requires = "logout")]
+    keep_file: bool,
+    /// Send a message, wait for the rewriter to edit it and print both versions.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        requires_all = ["chat", "text"],
+        conflicts_with_all = ["list_chats", "list_topics"]
+    )]
+    send_test: bool,
+    /// Write recent messages of a chat as JSON lines, oldest first, as context sees them.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        requires = "chat",
+        conflicts_with_all = ["list_chats", "list_topics", "send_test", "whoami", "logout"]
+    )]
+    export_history: bool,
+    /// Rewrite your last `--count` messages in a chat, oldest first.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        requires_all = ["chat", "count"],
+        conflicts_with_all = [
+            "list_chats",
+            "list_topics",
+            "send_test",
+            "export_history",
+            "whoami",
+            "logout",
+        ]
+    )]
+    backfill: bool,
+    #[arg(long, value_name = "n", requires = "backfill")]
+    count: Option<usize>,
+    /// Log (or with `--backfill`, print) what would be rewritten without editing anything.
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = [
+            "list_chats",
+            "list_topics",
+            "send_test",
+            "export_history",
+            "whoami",
+            "logout",
+        ]
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        value_name = "chat_id",
+        allow_negative_numbers = true,
+        requires = "chat_modes"
+    )]
+    chat: Option<i64>,
+    /// Forum topic to export, by root id; 0 is the General topic.
+    #[arg(long, value_name = "root_id", requires = "export_history")]
+    topic: Option<i32>,
+    #[arg(
+        long,
+        value_name = "count",
+        default_value_t = DEFAULT_EXPORT_HISTORY_LIMIT,
+        requires = "export_history"
+    )]
+    limit: usize,
+    #[arg(long, value_name = "path", requires = "export_history")]
+    out: Option<PathBuf>,
+    #[arg(long, value_name = "text", requires = "send_test")]
+    text: Option<String>,
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = DEFAULT_SEND_TEST_TIMEOUT_SECONDS,
+        requires = "send_test"
+    )]
+    timeout: u64,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    init_tracing();
-
+async fn main() -> Result<ExitCode> {
     let args = parse_args()?;
     let config_mode = match args.mode {
-        AppMode::Rewrite => ConfigMode::Rewrite,
-        AppMode::ListChats { .. } => ConfigMode::ListChats,
+        AppMode::Rewrite { .. } | AppMode::SendTest { .. } | AppMode::Backfill { .. } => {
+            ConfigMode::Rewrite
+        }
+        AppMode::ListChats { .. }
+        | AppMode::ListTopics { .. }
+        | AppMode::ExportHistory { .. }
+        | AppMode::WhoAmI
+        | AppMode::Logout { .. } => ConfigMode::ListChats,
     };
-    let config = load_config_for_mode(&args.config_path, config_mode)?;
+    let config = {
+        let _fallback_tracing = tracing::subscriber::set_default(fallback_tracing_subscriber());
+        load_config_for_mode(&args.config_path, config_mode)?
+    };
+    let _logging_guard = init_tracing(&config.logging)?;
 
     match args.mode {
-        AppMode::ListChats { query } => run_list_mode(&config, query.as_deref()).await,
-        AppMode::Rewrite => run_rewrite_mode(&config, &args.config_path).await,
+        AppMode::ListChats {
+            query,
+            format,
+            sort,
+        } => run_list_mode(&config, query.as_deref(), format, sort).await,
+        AppMode::ListTopics { chat_id } => run_list_topics_mode(&config, chat_id).await,
+        AppMode::ExportHistory {
+            chat_id,
+            topic_root_id,
+            limit,
+            out,
+        } => run_export_history_mode(&config, chat_id, topic_root_id, limit, out.as_deref()).await,
+        AppMode::Backfill {
+            chat_id,
+            count,
+            dry_run,
+        } => {
+            let report = run_backfill_mode(&config, chat_id, count, dry_run).await?;
+            print!("{}", render_backfill_report(&report, dry_run));
+            Ok(())
+        }
+        AppMode::WhoAmI => run_whoami_mode(&config).await,
+        AppMode::Logout { keep_file } => run_logout_mode(&config, keep_file).await,
+        AppMode::SendTest {
+            chat_id,
+            text,
+            timeout_seconds,
+        } => {
+            let report = run_send_test_mode(
+                &config,
+                &args.config_path,
+                chat_id,
+                &text,
+                Duration::from_secs(timeout_seconds),
+            )
+            .await?;
+            println!("before: {}", report.before);
+            println!("after ({}): {}", report.model, report.after);
+            Ok(())
+        }
+        AppMode::Rewrite { dry_run } => {
+            match run_rewrite_mode(&config, &args.config_path, dry_run).await {
+                Err(err) if err.downcast_ref::<SessionRevoked>().is_some() => {
+                    eprintln!("Error: {err:#}");
+                    return Ok(ExitCode::from(EXIT_SESSION_REVOKED));
+                }
+                result => result,
+            }
+        }
+    }
+    .map(|()| ExitCode::SUCCESS)
+}
+
+async fn run_list_mode(
+    config: &Config,
+    query: Option<&str>,
+    format: ListFormat,
+    sort: ListSort,
+) -> Result<()> {
+    let mut bot = TelegramBot::connect_for_listing(
+        &config.telegram,
+        config.network.telegram_proxy.as_deref(),
+    )
+    .await?;
+    let chats = bot.list_chats(query).await;
+    bot.shutdown().await?;
+
+    let mut chats = chats?;
+    if sort == ListSort::Kind {
+        // Stable, so each kind stays sorted by name.
+        chats.sort_by_key(|chat| chat.kind);
     }
+    print!("{}", render_chat_list(&chats, query, format)?);
+    Ok(())
+}
+
+async fn run_list_topics_mode(config: &Config, chat_id: i64) -> Result<()> {
+    let mut bot = TelegramBot::connect_for_listing(
+        &config.telegram,
+        config.network.telegram_proxy.as_deref(),
+    )
+    .await?;
+    let topics = bot.list_forum_topics(chat_id).await;
+    bot.shutdown().await?;
+
+    print!("{}", render_topic_list(&topics?));
+    Ok(())
 }
 
-async fn run_list_mode(config: &Config, query: Option<&str>) -> Result<()> {
-    let mut bot = TelegramBot::connect_for_listing(&config.telegram).await?;
-    let chats = bot.list_chats(query).await?;
+async fn run_export_history_mode(
+    config: &Config,
+    chat_id: i64,
+    topic_root_id: Option<i32>,
+    limit: usize,
+    out: Option<&Path>,
+) -> Result<()> {
+    let mut bot = TelegramBot::connect_for_listing(
+        &config.telegram,
+        config.network.telegram_proxy.as_deref(),
+    )
+    .await?;
+    let history = bot.export_history(chat_id, topic_root_id, limit).await;
+    bot.shutdown().await?;
 
-    if chats.is_empty() {
-        if let Some(query) = query {
-            println!("No chats matched filter: {query}");
-        } else {
-            println!("No chats found.");
+    let output = render_history_jsonl(&history?)?;
+    match out {
+        Some(path) => fs::write(path, output)
+            .with_context(|| format!("failed to write history to {}", path.display())),
+        None => {
+            print!("{output}");
+            Ok(())
         }
+    }
+}
+
+/// The before/after pairs of a dry run, then a line with the counts.
+fn render_backfill_report(report: &BackfillReport, dry_run: bool) -> String {
+    let mut output = String::new();
+    for preview in &report.previews {
+        output.push_str(&format!(
+            "message {}\nbefore: {}\nafter: {}\n\n",
+            preview.message_id, preview.before, preview.after
+        ));
+    }
+    let rewritten = if dry_run {
+        "would rewrite"
     } else {
-        for chat in chats {
-            println!("{}\t{}", chat.id, chat.name);
+        "rewritten"
+    };
+    output.push_str(&format!(
+        "{rewritten}: {}, skipped: {}, failed: {}\n",
+        report.rewritten, report.skipped, report.failed
+    ));
+    output
+}
+
+async fn run_whoami_mode(config: &Config) -> Result<()> {
+    let info =
+        TelegramBot::whoami(&config.telegram, config.network.telegram_proxy.as_deref()).await?;
+    print!(
+        "{}",
+        render_whoami(&info, &config.telegram.session_file.display().to_string())
+    );
+    Ok(())
+}
+
+/// Logs the session out, then removes its file: right away once logged out, after asking
+/// when it wasn't logged in or the logout failed. A failed logout is still the result.
+async fn run_logout_mode(config: &Config, keep_file: bool) -> Result<()> {
+    let session_file = &config.telegram.session_file;
+    if !session_file.exists() {
+        return Err(anyhow!(
+            "session file {} does not exist",
+            session_file.display()
+        ));
+    }
+
+    let outcome =
+        TelegramBot::log_out(&config.telegram, config.network.telegram_proxy.as_deref()).await;
+    let remove_without_asking = match &outcome {
+        Ok(LogoutOutcome::LoggedOut) => {
+            println!("logged out; the session can no longer be used");
+            true
+        }
+        Ok(LogoutOutcome::NotLoggedIn) => {
+            println!("session was not logged in; nothing to log out");
+            false
         }
+        Err(err) => {
+            eprintln!("logout failed: {err:#}");
+            false
+        }
+    };
+
+    if keep_file {
+        println!("kept session file {}", session_file.display());
+    } else if remove_without_asking
+        || is_yes(&prompt(&format!(
+            "Remove session file {}? [y/N] ",
+            session_file.display()
+        ))?)
+    {
+        remove_session_file(session_file)?;
+        println!("removed session file {}", session_file.display());
     }
+    outcome.map(|_| ())
+}
 
-    bot.shutdown().await?;
-    Ok(())
+fn remove_session_file(path: &Path) -> Result<()> {
+    fs::remove_file(path)
+        .with_context(|| format!("failed to remove session file: {}", path.display()))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// `<field>: <value>` lines, with `-` for unknown values.
+fn render_whoami(info: &AccountInfo, session_file: &str) -> String {
+    let mut output = String::new();
+    output.push_str(&format!("id: {}\n", info.id));
+    output.push_str(&format!("name: {}\n", info.name));
+    output.push_str(&format!(
+        "username: {}\n",
+        info.username
+            .as_ref()
+            .map_or_else(|| "-".to_owned(), |username| format!("@{username}"))
+    ));
+    output.push_str(&format!(
+        "phone: {}\n",
+        info.phone.as_deref().unwrap_or("-")
+    ));
+    output.push_str(&format!(
+        "kind: {}\n",
+        if info.is_bot { "bot" } else { "user" }
+    ));
+    output.push_str(&format!("dc: {}\n", info.dc_id));
+    output.push_str(&format!(
+        "session: {session_file} (authorized, no login needed)\n"
+    ));
+    output
+}
+
+/// `<root id>\t<title>` lines, with the General topic under its config id 0 and marked.
+fn render_history_jsonl(history: &[HistoryEntry]) -> Result<String> {
+    let mut output = String::new();
+    for entry in history {
+        output.push_str(&serde_json::to_string(entry)?);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn render_topic_list(topics: &[ForumTopicItem]) -> String {
+    let mut output = String::new();
+    for topic in topics {
+        output.push_str(&format!("{}\t{}", topic.config_root_id(), topic.title));
+        if topic.is_general() {
+            output.push_str("\t(General)");
+        }
+        output.push('\n');
+    }
+    output
+}
+
+fn render_chat_list(
+    chats: &[ChatListItem],
+    query: Option<&str>,
+    format: ListFormat,
+) -> Result<String> {
+    let mut output = String::new();
+    match format {
+        ListFormat::Plain if chats.is_empty() => match query {
+            Some(query) => output.push_str(&format!("No chats matched filter: {query}\n")),
+            None => output.push_str("No chats found.\n"),
+        },
+        ListFormat::Plain => {
+            for chat in chats {
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    chat.id,
+                    chat.name,
+                    plain_kind(chat),
+                    chat.username
+                        .as_ref()
+                        .map_or_else(|| "-".to_owned(), |username| format!("@{username}")),
+                    optional_count(chat.member_count),
+                    optional_count(chat.unread_count),
+                ));
+            }
+        }
+        ListFormat::Json => {
+            output = serde_json::to_string_pretty(chats)?;
+            output.push('\n');
+        }
+        ListFormat::Tsv => {
+            output.push_str("id\tname\tkind\tusername\tis_forum\tmember_count\tunread_count\n");
+            for chat in chats {
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    chat.id,
+                    escape_tsv_field(&chat.name),
+                    chat.kind.as_str(),
+                    chat.username.as_deref().unwrap_or_default(),
+                    chat.is_forum,
+                    chat.member_count
+                        .map(|count| count.to_string())
+                        .unwrap_or_default(),
+                    chat.unread_count
+                        .map(|count| count.to_string())
+                        .unwrap_or_default(),
+                ));
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn plain_kind(chat: &ChatListItem) -> String {
+    if chat.is_forum {
+        format!("{} (forum)", chat.kind.as_str())
+    } else {
+        chat.kind.as_str().to_owned()
+    }
+}
+
+fn optional_count(count: Option<u32>) -> String {
+    count.map_or_else(|| "-".to_owned(), |count| count.to_string())
+}
+
+fn escape_tsv_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            other => escaped.push(other),
+        }
+    }
+    escaped
 }
 
 fn parse_args() -> Result<AppArgs> {
@@ -79,10 +572,43 @@ where
     S: Into<OsString> + Clone,
 {
     let cli = Cli::try_parse_from(args).map_err(|error| anyhow!(error.to_string()))?;
-    let mode = if cli.list_chats {
-        AppMode::ListChats { query: cli.query }
+    let mode = if cli.logout {
+        AppMode::Logout {
+            keep_file: cli.keep_file,
+        }
+    } else if cli.whoami {
+        AppMode::WhoAmI
+    } else if cli.send_test {
+        AppMode::SendTest {
+            chat_id: cli.chat.context("--send-test requires --chat")?,
+            text: cli.text.context("--send-test requires --text")?,
+            timeout_seconds: cli.timeout,
+        }
+    } else if cli.export_history {
+        AppMode::ExportHistory {
+            chat_id: cli.chat.context("--export-history requires --chat")?,
+            topic_root_id: cli.topic.filter(|&root_id| root_id != 0),
+            limit: cli.limit,
+            out: cli.out,
+        }
+    } else if cli.backfill {
+        AppMode::Backfill {
+            chat_id: cli.chat.context("--backfill requires --chat")?,
+            count: cli.count.context("--backfill requires --count")?,
+            dry_run: cli.dry_run,
+        }
+    } else if let Some(chat_id) = cli.list_topics {
+        AppMode::ListTopics { chat_id }
+    } else if cli.list_chats {
+        AppMode::ListChats {
+            query: cli.query,
+            format: cli.format,
+            sort: cli.sort,
+        }
     } else {
-        AppMode::Rewrite
+        AppMode::Rewrite {
+            dry_run: cli.dry_run,
+        }
     };
 
     Ok(AppArgs {
@@ -93,14 +619,29 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{AppMode, parse_args_from};
+    use super::{
+        AppMode, ListFormat, ListSort, is_yes, parse_args_from, render_backfill_report,
+        render_chat_list, render_history_jsonl, render_topic_list, render_whoami,
+    };
+    use brainrot_tg_llm_rewrite::app::{BackfillPreview, BackfillReport};
+    use brainrot_tg_llm_rewrite::context::ContextMessage;
+    use brainrot_tg_llm_rewrite::telegram::{
+        AccountInfo, ChatKind, ChatListItem, ForumTopicItem, HistoryEntry,
+    };
     use std::path::PathBuf;
 
     #[test]
     fn parse_list_chats_without_query() {
         let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--list-chats"])
             .expect("parsing should succeed");
-        assert_eq!(parsed.mode, AppMode::ListChats { query: None });
+        assert_eq!(
+            parsed.mode,
+            AppMode::ListChats {
+                query: None,
+                format: ListFormat::Plain,
+                sort: ListSort::Name,
+            }
+        );
     }
 
     #[test]
@@ -111,6 +652,8 @@ mod tests {
             parsed.mode,
             AppMode::ListChats {
                 query: Some("work".to_string()),
+                format: ListFormat::Plain,
+                sort: ListSort::Name,
             }
         );
     }
@@ -120,7 +663,7 @@ mod tests {
         let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--config", "custom.toml"])
             .expect("parsing should succeed");
         assert_eq!(parsed.config_path, PathBuf::from("custom.toml"));
-        assert_eq!(parsed.mode, AppMode::Rewrite);
+        assert_eq!(parsed.mode, AppMode::Rewrite { dry_run: false });
     }
 
     #[test]
@@ -152,6 +695,8 @@ mod tests {
             parsed.mode,
             AppMode::ListChats {
                 query: Some("team".to_string()),
+                format: ListFormat::Plain,
+                sort: ListSort::Name,
             }
         );
     }
@@ -166,7 +711,14 @@ mod tests {
         ])
         .expect("parsing should succeed");
         assert_eq!(parsed.config_path, PathBuf::from("x.toml"));
-        assert_eq!(parsed.mode, AppMode::ListChats { query: None });
+        assert_eq!(
+            parsed.mode,
+            AppMode::ListChats {
+                query: None,
+                format: ListFormat::Plain,
+                sort: ListSort::Name,
+            }
+        );
     }
 
     #[test]
@@ -175,4 +727,402 @@ mod tests {
             parse_args_from(["brainrot_tg_llm_rewrite", "work"]).expect_err("parsing should fail");
         assert!(err.to_string().contains("--list-chats"));
     }
+
+    #[test]
+    fn parse_list_chats_format() {
+        let parsed = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--list-chats",
+            "--format",
+            "json",
+        ])
+        .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::ListChats {
+                query: None,
+                format: ListFormat::Json,
+                sort: ListSort::Name,
+            }
+        );
+
+        let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--list-chats", "--sort", "kind"])
+            .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::ListChats {
+                query: None,
+                format: ListFormat::Plain,
+                sort: ListSort::Kind,
+            }
+        );
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--format", "tsv"])
+            .expect_err("format without list mode should fail");
+        assert!(err.to_string().contains("--list-chats"));
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--list-chats", "--format", "xml"])
+            .expect_err("unknown format should fail");
+        assert!(err.to_string().contains("xml"));
+    }
+
+    #[test]
+    fn parse_list_topics_with_negative_chat_id() {
+        let parsed =
+            parse_args_from(["brainrot_tg_llm_rewrite", "--list-topics", "-1001234567890"])
+                .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::ListTopics {
+                chat_id: -1001234567890
+            }
+        );
+
+        let err = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--list-chats",
+            "--list-topics",
+            "-100",
+        ])
+        .expect_err("list modes should conflict");
+        assert!(err.to_string().contains("--list-topics"));
+    }
+
+    #[test]
+    fn parse_send_test_with_chat_and_text() {
+        let parsed = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--send-test",
+            "--chat",
+            "-1001234567890",
+            "--text",
+            "hello",
+        ])
+        .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::SendTest {
+                chat_id: -1001234567890,
+                text: "hello".to_owned(),
+                timeout_seconds: 60,
+            }
+        );
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--send-test", "--chat", "1"])
+            .expect_err("send-test without text should fail");
+        assert!(err.to_string().contains("--text"));
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--chat", "1", "--text", "hi"])
+            .expect_err("chat without send-test should fail");
+        assert!(err.to_string().contains("--send-test"));
+    }
+
+    #[test]
+    fn parse_export_history_with_topic_and_output() {
+        let parsed = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--export-history",
+            "--chat",
+            "-1001234567890",
+            "--topic",
+            "4521",
+            "--limit",
+            "50",
+            "--out",
+            "history.jsonl",
+        ])
+        .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::ExportHistory {
+                chat_id: -1001234567890,
+                topic_root_id: Some(4521),
+                limit: 50,
+                out: Some(PathBuf::from("history.jsonl")),
+            }
+        );
+
+        let parsed = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--export-history",
+            "--chat",
+            "42",
+            "--topic",
+            "0",
+        ])
+        .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::ExportHistory {
+                chat_id: 42,
+                topic_root_id: None,
+                limit: 100,
+                out: None,
+            }
+        );
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--export-history"])
+            .expect_err("export-history without chat should fail");
+        assert!(err.to_string().contains("--chat"));
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--limit", "5"])
+            .expect_err("limit without export-history should fail");
+        assert!(err.to_string().contains("--export-history"));
+    }
+
+    #[test]
+    fn parse_backfill_with_dry_run() {
+        let parsed = parse_args_from([
+            "brainrot_tg_llm_rewrite",
+            "--backfill",
+            "--chat",
+            "-1001234567890",
+            "--count",
+            "20",
+            "--dry-run",
+        ])
+        .expect("parsing should succeed");
+        assert_eq!(
+            parsed.mode,
+            AppMode::Backfill {
+                chat_id: -1001234567890,
+                count: 20,
+                dry_run: true,
+            }
+        );
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--backfill", "--chat", "1"])
+            .expect_err("backfill without count should fail");
+        assert!(err.to_string().contains("--count"));
+    }
+
+    #[test]
+    fn parse_rewrite_with_dry_run() {
+        let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--dry-run"])
+            .expect("parsing should succeed");
+        assert_eq!(parsed.mode, AppMode::Rewrite { dry_run: true });
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--dry-run", "--list-chats"])
+            .expect_err("dry-run with list-chats should fail");
+        assert!(err.to_string().contains("--list-chats"), "{err}");
+    }
+
+    #[test]
+    fn backfill_report_lists_previews_before_the_counts() {
+        let report = BackfillReport {
+            rewritten: 1,
+            skipped: 2,
+            failed: 0,
+            previews: vec![BackfillPreview {
+                message_id: 7,
+                before: "hi".to_owned(),
+                after: "greetings".to_owned(),
+            }],
+        };
+        assert_eq!(
+            render_backfill_report(&report, true),
+            "message 7\nbefore: hi\nafter: greetings\n\n\
+             would rewrite: 1, skipped: 2, failed: 0\n"
+        );
+        assert_eq!(
+            render_backfill_report(
+                &BackfillReport {
+                    previews: Vec::new(),
+                    ..report
+                },
+                false
+            ),
+            "rewritten: 1, skipped: 2, failed: 0\n"
+        );
+    }
+
+    #[test]
+    fn history_is_written_as_one_json_object_per_line() {
+        let history = [
+            HistoryEntry {
+                message_id: 10,
+                timestamp: 1_700_000_000,
+                message: ContextMessage {
+                    sender_name: "Alice".to_owned(),
+                    text: "first\nline".to_owned(),
+                    is_own: false,
+                },
+            },
+            HistoryEntry {
+                message_id: 12,
+                timestamp: 1_700_000_060,
+                message: ContextMessage {
+                    sender_name: "Me".to_owned(),
+                    text: "second".to_owned(),
+                    is_own: true,
+                },
+            },
+        ];
+        assert_eq!(
+            render_history_jsonl(&history).expect("history should serialize"),
+            "{\"message_id\":10,\"timestamp\":1700000000,\"sender_name\":\"Alice\",\
+             \"text\":\"first\\nline\",\"is_own\":false}\n\
+             {\"message_id\":12,\"timestamp\":1700000060,\"sender_name\":\"Me\",\
+             \"text\":\"second\",\"is_own\":true}\n"
+        );
+    }
+
+    #[test]
+    fn parse_whoami_conflicts_with_list_modes() {
+        let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--whoami"])
+            .expect("parsing should succeed");
+        assert_eq!(parsed.mode, AppMode::WhoAmI);
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--whoami", "--list-chats"])
+            .expect_err("whoami and list-chats should conflict");
+        assert!(err.to_string().contains("--whoami") || err.to_string().contains("--list-chats"));
+    }
+
+    #[test]
+    fn parse_logout_with_keep_file() {
+        let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--logout"])
+            .expect("parsing should succeed");
+        assert_eq!(parsed.mode, AppMode::Logout { keep_file: false });
+
+        let parsed = parse_args_from(["brainrot_tg_llm_rewrite", "--logout", "--keep-file"])
+            .expect("parsing should succeed");
+        assert_eq!(parsed.mode, AppMode::Logout { keep_file: true });
+
+        let err = parse_args_from(["brainrot_tg_llm_rewrite", "--keep-file"])
+            .expect_err("keep-file without logout should fail");
+        assert!(err.to_string().contains("--logout"));
+    }
+
+    #[test]
+    fn only_yes_answers_confirm_removal() {
+        assert!(is_yes("y"));
+        assert!(is_yes(" YES\n"));
+        assert!(!is_yes(""));
+        assert!(!is_yes("no"));
+    }
+
+    #[test]
+    fn whoami_shows_dashes_for_missing_username_and_phone() {
+        let info = AccountInfo {
+            id: 123456789,
+            name: "Ada Lovelace".to_owned(),
+            username: None,
+            phone: Some("+447*******56".to_owned()),
+            dc_id: 2,
+            is_bot: false,
+        };
+        assert_eq!(
+            render_whoami(&info, "work.session"),
+            "id: 123456789\nname: Ada Lovelace\nusername: -\nphone: +447*******56\nkind: user\n\
+             dc: 2\nsession: work.session (authorized, no login needed)\n"
+        );
+    }
+
+    #[test]
+    fn topic_list_marks_general_with_config_id_zero() {
+        let topics = [
+            ForumTopicItem {
+                root_id: 1,
+                title: "General".to_owned(),
+            },
+            ForumTopicItem {
+                root_id: 4521,
+                title: "Releases 🚀".to_owned(),
+            },
+        ];
+        assert_eq!(
+            render_topic_list(&topics),
+            "0\tGeneral\t(General)\n4521\tReleases 🚀\n"
+        );
+    }
+
+    fn awkward_chats() -> Vec<ChatListItem> {
+        vec![
+            ChatListItem {
+                id: -1001234567890,
+                name: "Team\tChat\nOps 🚀".to_owned(),
+                kind: ChatKind::Supergroup,
+                username: Some("team_ops".to_owned()),
+                is_forum: true,
+                member_count: Some(1200),
+                unread_count: Some(3),
+            },
+            ChatListItem {
+                id: 42,
+                name: "C:\\Users 🙂".to_owned(),
+                kind: ChatKind::User,
+                username: None,
+                is_forum: false,
+                member_count: None,
+                unread_count: Some(0),
+            },
+        ]
+    }
+
+    #[test]
+    fn plain_chat_list_shows_metadata_columns() {
+        assert_eq!(
+            render_chat_list(&awkward_chats(), None, ListFormat::Plain).expect("should render"),
+            "-1001234567890\tTeam\tChat\nOps 🚀\tsupergroup (forum)\t@team_ops\t1200\t3\n\
+             42\tC:\\Users 🙂\tuser\t-\t-\t0\n"
+        );
+        assert_eq!(
+            render_chat_list(&[], Some("work"), ListFormat::Plain).expect("should render"),
+            "No chats matched filter: work\n"
+        );
+        assert_eq!(
+            render_chat_list(&[], None, ListFormat::Plain).expect("should render"),
+            "No chats found.\n"
+        );
+    }
+
+    #[test]
+    fn json_chat_list_escapes_names() {
+        let rendered =
+            render_chat_list(&awkward_chats(), None, ListFormat::Json).expect("should render");
+        assert!(
+            rendered.contains(r#""name": "Team\tChat\nOps 🚀""#),
+            "{rendered}"
+        );
+        let parsed: serde_json::Value =
+            serde_json::from_str(&rendered).expect("output should be valid JSON");
+        assert_eq!(
+            parsed,
+            serde_json::json!([
+                {
+                    "id": -1001234567890_i64,
+                    "name": "Team\tChat\nOps 🚀",
+                    "kind": "supergroup",
+                    "username": "team_ops",
+                    "is_forum": true,
+                    "member_count": 1200,
+                    "unread_count": 3,
+                },
+                {
+                    "id": 42,
+                    "name": "C:\\Users 🙂",
+                    "kind": "user",
+                    "username": null,
+                    "is_forum": false,
+                    "member_count": null,
+                    "unread_count": 0,
+                },
+            ])
+        );
+        assert_eq!(
+            render_chat_list(&[], Some("work"), ListFormat::Json).expect("should render"),
+            "[]\n"
+        );
+    }
+
+    #[test]
+    fn tsv_chat_list_escapes_tabs_newlines_and_backslashes() {
+        assert_eq!(
+            render_chat_list(&awkward_chats(), None, ListFormat::Tsv).expect("should render"),
+            "id\tname\tkind\tusername\tis_forum\tmember_count\tunread_count\n\
+             -1001234567890\tTeam\\tChat\\nOps 🚀\tsupergroup\tteam_ops\ttrue\t1200\t3\n\
+             42\tC:\\\\Users 🙂\tuser\t\tfalse\t\t0\n"
+        );
+        assert_eq!(
+            render_chat_list(&[], None, ListFormat::Tsv).expect("should render"),
+            "id\tname\tkind\tusername\tis_forum\tmember_count\tunread_count\n"
+        );
+    }
 }
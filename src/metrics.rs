@@ -0,0 +1,194 @@
+use anyhow::Result;
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Buckets of the latency histograms, in seconds. Edits take tens of milliseconds, model
+/// requests up to the provider timeout.
+const LATENCY_BUCKETS: &[f64] = &[0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Counters and histograms served on `[metrics] listen`, shared by every account.
+pub struct Metrics {
+    registry: Registry,
+    messages_seen: IntCounterVec,
+    rewrites_attempted: IntCounter,
+    rewrites_succeeded: IntCounter,
+    rewrites_failed: IntCounterVec,
+    llm_latency: Histogram,
+    edit_latency: Histogram,
+    context_fetches: IntCounterVec,
+    config_reloads: IntCounterVec,
+    dedupe_hits: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("brainrot".to_owned()), None)?;
+        let messages_seen = IntCounterVec::new(
+            Opts::new("messages_seen_total", "Messages handled in monitored chats"),
+            &["chat_id"],
+        )?;
+        let rewrites_attempted = IntCounter::new(
+            "rewrites_attempted_total",
+            "Rewrites requested from the provider",
+        )?;
+        let rewrites_succeeded =
+            IntCounter::new("rewrites_succeeded_total", "Rewrites applied to the chat")?;
+        let rewrites_failed = IntCounterVec::new(
+            Opts::new(
+                "rewrites_failed_total",
+                "Rewrites that failed, by the stage that failed: llm or edit",
+            ),
+            &["stage"],
+        )?;
+        let llm_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "llm_request_duration_seconds",
+                "Time a rewrite request took, retries included",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let edit_latency = Histogram::with_opts(
+            HistogramOpts::new("edit_duration_seconds", "Time a Telegram edit took")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let context_fetches = IntCounterVec::new(
+            Opts::new(
+                "context_fetches_total",
+                "Context fetches from Telegram, by result: ok or error",
+            ),
+            &["result"],
+        )?;
+        let config_reloads = IntCounterVec::new(
+            Opts::new(
+                "config_reloads_total",
+                "Config reloads, by result: ok or error",
+            ),
+            &["result"],
+        )?;
+        let dedupe_hits = IntCounter::new(
+            "dedupe_hits_total",
+            "Messages skipped because they were already rewritten",
+        )?;
+
+        registry.register(Box::new(messages_seen.clone()))?;
+        registry.register(Box::new(rewrites_attempted.clone()))?;
+        registry.register(Box::new(rewrites_succeeded.clone()))?;
+        registry.register(Box::new(rewrites_failed.clone()))?;
+        registry.register(Box::new(llm_latency.clone()))?;
+        registry.register(Box::new(edit_latency.clone()))?;
+        registry.register(Box::new(context_fetches.clone()))?;
+        registry.register(Box::new(config_reloads.clone()))?;
+        registry.register(Box::new(dedupe_hits.clone()))?;
+        Ok(Self {
+            registry,
+            messages_seen,
+            rewrites_attempted,
+            rewrites_succeeded,
+            rewrites_failed,
+            llm_latency,
+            edit_latency,
+            context_fetches,
+            config_reloads,
+            dedupe_hits,
+        })
+    }
+
+    pub fn message_seen(&self, chat_id: i64) {
+        self.messages_seen
+            .with_label_values(&[chat_id.to_string()])
+            .inc();
+    }
+
+    pub fn rewrite_attempted(&self) {
+        self.rewrites_attempted.inc();
+    }
+
+    pub fn rewrite_succeeded(&self) {
+        self.rewrites_succeeded.inc();
+    }
+
+    pub fn rewrite_failed(&self, stage: FailedStage) {
+        self.rewrites_failed
+            .with_label_values(&[stage.label()])
+            .inc();
+    }
+
+    pub fn observe_llm_latency(&self, elapsed: Duration) {
+        self.llm_latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_edit_latency(&self, elapsed: Duration) {
+        self.edit_latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn context_fetched(&self, ok: bool) {
+        self.context_fetches
+            .with_label_values(&[result_label(ok)])
+            .inc();
+    }
+
+    pub fn config_reloaded(&self, ok: bool) {
+        self.config_reloads
+            .with_label_values(&[result_label(ok)])
+            .inc();
+    }
+
+    pub fn dedupe_hit(&self) {
+        self.dedupe_hits.inc();
+    }
+
+    /// Everything registered, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|err| {
+                warn!(error = %err, "failed to encode metrics");
+                String::new()
+            })
+    }
+}
+
+/// Where a failed rewrite gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailedStage {
+    /// The provider failed or returned nothing.
+    Llm,
+    /// The rewrite couldn't be applied to the chat.
+    Edit,
+}
+
+impl FailedStage {
+    fn label(self) -> &'static str {
+        match self {
+            FailedStage::Llm => "llm",
+            FailedStage::Edit => "edit",
+        }
+    }
+}
+
+fn result_label(ok: bool) -> &'static str {
+    if ok { "ok" } else { "error" }
+}
+
+/// The `/metrics` route, to be served by a [`StatusServer`](crate::status_server::StatusServer).
+pub fn routes(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics)
+}
+
+async fn scrape(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
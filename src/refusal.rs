@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Heuristic fallback for refusals the provider doesn't mark as such.
+#[derive(Debug, Clone, Default)]
+pub struct RefusalDetector {
+    patterns: Vec<Regex>,
+}
+
+impl RefusalDetector {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("invalid refusal pattern `{pattern}`"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// True when `rewritten` matches a pattern that `original` does not, so a message that
+    /// itself reads like a refusal can still be rewritten.
+    pub fn is_refusal(&self, original: &str, rewritten: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.is_match(rewritten) && !pattern.is_match(original))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RefusalDetector;
+    use crate::config::RewriteConfig;
+
+    fn default_detector() -> RefusalDetector {
+        RefusalDetector::new(&RewriteConfig::default().refusal_patterns)
+            .expect("default patterns should compile")
+    }
+
+    #[test]
+    fn default_patterns_catch_common_refusals() {
+        let detector = default_detector();
+        for refusal in [
+            "I can't help rewrite that.",
+            "Sorry, but I cannot assist with that request.",
+            "I'm sorry, I can't do that.",
+            "I’m unable to help with this.",
+        ] {
+            assert!(detector.is_refusal("ok", refusal), "{refusal}");
+        }
+    }
+
+    #[test]
+    fn default_patterns_ignore_ordinary_rewrites() {
+        let detector = default_detector();
+        assert!(!detector.is_refusal("ok", "Very well, I shall attend."));
+        assert!(!detector.is_refusal("can't come", "Alas, I cannot attend."));
+    }
+
+    #[test]
+    fn refusal_like_original_is_not_flagged() {
+        let detector = default_detector();
+        assert!(!detector.is_refusal(
+            "sorry i can't help with that",
+            "I'm sorry, I can't help with that, dear friend."
+        ));
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let err = RefusalDetector::new(&["(unclosed".to_owned()]).expect_err("should fail");
+        assert!(err.to_string().contains("(unclosed"));
+    }
+}
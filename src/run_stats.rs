@@ -0,0 +1,228 @@
+use crate::usage::UsageTotals;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+/// Totals since the rewriter started, across accounts: logged and reported as
+/// [`RunSummary`] at shutdown and on SIGUSR1.
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    started_at: Instant,
+    messages_by_chat: BTreeMap<i64, u64>,
+    rewrites_applied: u64,
+    llm_failures: u64,
+    edit_failures: u64,
+    config_reloads: u64,
+    config_reload_failures: u64,
+}
+
+/// Snapshot of [`RunStats`], with the token totals of the usage tracker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub uptime: Duration,
+    /// Messages seen in each monitored chat, own and others'.
+    pub messages_by_chat: BTreeMap<i64, u64>,
+    pub rewrites_applied: u64,
+    /// Rewrites the provider failed or returned nothing for.
+    pub llm_failures: u64,
+    /// Rewrites that couldn't be applied to the chat.
+    pub edit_failures: u64,
+    pub tokens: UsageTotals,
+    /// Reloads applied, not counting failed ones.
+    pub config_reloads: u64,
+    pub config_reload_failures: u64,
+}
+
+impl RunStats {
+    pub fn new(started_at: Instant) -> Self {
+        Self {
+            started_at,
+            messages_by_chat: BTreeMap::new(),
+            rewrites_applied: 0,
+            llm_failures: 0,
+            edit_failures: 0,
+            config_reloads: 0,
+            config_reload_failures: 0,
+        }
+    }
+
+    pub fn message_seen(&mut self, chat_id: i64) {
+        *self.messages_by_chat.entry(chat_id).or_default() += 1;
+    }
+
+    pub fn rewrite_applied(&mut self) {
+        self.rewrites_applied += 1;
+    }
+
+    pub fn llm_failed(&mut self) {
+        self.llm_failures += 1;
+    }
+
+    pub fn edit_failed(&mut self) {
+        self.edit_failures += 1;
+    }
+
+    pub fn config_reloaded(&mut self, ok: bool) {
+        if ok {
+            self.config_reloads += 1;
+        } else {
+            self.config_reload_failures += 1;
+        }
+    }
+
+    pub fn summary(&self, now: Instant, tokens: UsageTotals) -> RunSummary {
+        RunSummary {
+            uptime: now.saturating_duration_since(self.started_at),
+            messages_by_chat: self.messages_by_chat.clone(),
+            rewrites_applied: self.rewrites_applied,
+            llm_failures: self.llm_failures,
+            edit_failures: self.edit_failures,
+            tokens,
+            config_reloads: self.config_reloads,
+            config_reload_failures: self.config_reload_failures,
+        }
+    }
+}
+
+impl Default for RunStats {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl RunSummary {
+    /// Indented lines for the log, one per total and one per chat.
+    pub fn render(&self) -> String {
+        let uptime = self.uptime.as_secs();
+        let mut rendered = format!(
+            "  uptime: {}h {:02}m {:02}s\n",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        );
+        let _ = writeln!(rendered, "  rewrites applied: {}", self.rewrites_applied);
+        let _ = writeln!(rendered, "  llm failures: {}", self.llm_failures);
+        let _ = writeln!(rendered, "  edit failures: {}", self.edit_failures);
+        if self.tokens.rewrites == 0 {
+            rendered.push_str("  tokens: none recorded\n");
+        } else {
+            let _ = writeln!(
+                rendered,
+                "  tokens: {} input, {} output over {} requests",
+                self.tokens.input_tokens, self.tokens.output_tokens, self.tokens.rewrites
+            );
+        }
+        let _ = writeln!(
+            rendered,
+            "  config reloads: {} ({} failed)",
+            self.config_reloads, self.config_reload_failures
+        );
+        if self.messages_by_chat.is_empty() {
+            rendered.push_str("  messages seen: none");
+        } else {
+            rendered.push_str("  messages seen:");
+            for (chat_id, seen) in &self.messages_by_chat {
+                let _ = write!(rendered, "\n    {chat_id}: {seen}");
+            }
+        }
+        rendered
+    }
+}
+
+/// SIGUSR1 on Unix, which asks for the summary without shutting down. Never fires elsewhere.
+pub struct SummarySignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl SummarySignal {
+    /// Must be called within a Tokio runtime.
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        let signal = {
+            use tokio::signal::unix::{SignalKind, signal};
+            signal(SignalKind::user_defined1())
+                .inspect_err(|err| tracing::warn!(error = %err, "failed to listen for SIGUSR1"))
+                .ok()
+        };
+        Self {
+            #[cfg(unix)]
+            signal,
+        }
+    }
+
+    /// Cancel-safe: a signal that arrives while nobody waits is kept for the next call.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut()
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+impl Default for SummarySignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunStats;
+    use crate::usage::UsageTotals;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn summary_totals_events_since_start() {
+        let start = Instant::now();
+        let mut stats = RunStats::new(start);
+        for chat_id in [-100, -100, 5] {
+            stats.message_seen(chat_id);
+        }
+        stats.rewrite_applied();
+        stats.llm_failed();
+        stats.edit_failed();
+        stats.edit_failed();
+        stats.config_reloaded(true);
+        stats.config_reloaded(false);
+        stats.config_reloaded(true);
+
+        let tokens = UsageTotals {
+            rewrites: 2,
+            input_tokens: 120,
+            output_tokens: 30,
+        };
+        let summary = stats.summary(start + Duration::from_secs(3_725), tokens);
+        assert_eq!(summary.uptime, Duration::from_secs(3_725));
+        assert_eq!(
+            summary.messages_by_chat.into_iter().collect::<Vec<_>>(),
+            vec![(-100, 2), (5, 1)]
+        );
+        assert_eq!(summary.rewrites_applied, 1);
+        assert_eq!(summary.llm_failures, 1);
+        assert_eq!(summary.edit_failures, 2);
+        assert_eq!(summary.tokens, tokens);
+        assert_eq!(summary.config_reloads, 2);
+        assert_eq!(summary.config_reload_failures, 1);
+    }
+
+    #[test]
+    fn summary_renders_one_line_per_total_and_chat() {
+        let start = Instant::now();
+        let mut stats = RunStats::new(start);
+        stats.message_seen(-100);
+        stats.rewrite_applied();
+
+        let rendered = stats
+            .summary(start + Duration::from_secs(3_725), UsageTotals::default())
+            .render();
+        assert_eq!(
+            rendered,
+            "  uptime: 1h 02m 05s\n  rewrites applied: 1\n  llm failures: 0\n  edit failures: 0\n  \
+             tokens: none recorded\n  config reloads: 0 (0 failed)\n  messages seen:\n    -100: 1"
+        );
+    }
+}
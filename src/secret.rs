@@ -0,0 +1,66 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// A credential that prints as `***` in `Debug` and `Display` output, so logging a config or
+/// adding it to an error context can't leak it. Call [`Secret::expose`] where the raw value
+/// is actually needed.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl From<String> for Secret<String> {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Secret;
+
+    #[test]
+    fn secret_hides_value_in_debug_and_display() {
+        let secret = Secret::from("sk-live-123");
+        assert_eq!(format!("{secret:?}"), "***");
+        assert_eq!(secret.to_string(), "***");
+        assert_eq!(secret.expose(), "sk-live-123");
+    }
+
+    #[test]
+    fn secret_deserializes_from_plain_string() {
+        #[derive(serde::Deserialize)]
+        struct Wrapper {
+            key: Secret<String>,
+        }
+        let wrapper: Wrapper = toml::from_str("key = \"abc\"").expect("secret should parse");
+        assert_eq!(wrapper.key.expose(), "abc");
+    }
+}
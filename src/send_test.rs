@@ -0,0 +1,427 @@
+use crate::app::{
+    RewriteEvent, RewriteHooks, RewriteRuntimeOptions, run_rewrite_mode_with_shutdown_and_hooks,
+};
+use crate::config::{Config, PRIMARY_ACCOUNT_NAME, SAVED_MESSAGES_CHAT_ID};
+use crate::telegram::ChatIdSpec;
+use anyhow::{Context, Result, anyhow, bail};
+use grammers_client::Client;
+use grammers_client::message::InputMessage;
+use grammers_session::types::PeerRef;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long `--send-test` waits for the account to connect before giving up.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The step of `--send-test` that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTestStage {
+    /// Connecting, resolving the chat or sending the test message.
+    Send,
+    /// The rewriter picking the message up and the model rewriting it.
+    Rewrite,
+    /// Applying the rewrite to the chat.
+    Edit,
+}
+
+impl fmt::Display for SendTestStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Send => "send",
+            Self::Rewrite => "rewrite",
+            Self::Edit => "edit",
+        })
+    }
+}
+
+/// The test message as sent and as the rewriter left it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendTestReport {
+    pub before: String,
+    pub after: String,
+    pub model: String,
+}
+
+/// Sends `text` to `chat_id` from the primary account while the rewriter runs in-process,
+/// and waits up to `timeout` for it to be rewritten. Errors name the stage that failed.
+pub async fn run_send_test_mode(
+    config: &Config,
+    config_path: &Path,
+    chat_id: i64,
+    text: &str,
+    timeout: Duration,
+) -> Result<SendTestReport> {
+    let chats = &config.rewrite_required()?.chats;
+    if !may_be_monitored(chats, chat_id) {
+        bail!(
+            "{} stage failed: chat {chat_id} is not in rewrite.chats, so the test message \
+             would never be rewritten",
+            SendTestStage::Send
+        );
+    }
+    if config.runtime.dry_run {
+        bail!(
+            "{} stage failed: runtime.dry_run is on, so the test message would never be edited",
+            SendTestStage::Edit
+        );
+    }
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<RewriteEvent>();
+    let (client_tx, client_rx) = oneshot::channel::<Client>();
+    let hooks = RewriteHooks::with_account_event_handler(move |account, event| {
+        if account.is_none_or(|account| account == PRIMARY_ACCOUNT_NAME) {
+            let _ = event_tx.send(event);
+        }
+    })
+    .with_client_channel(client_tx);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let runtime = run_rewrite_mode_with_shutdown_and_hooks(
+        config,
+        config_path,
+        Some(shutdown_rx),
+        hooks,
+        RewriteRuntimeOptions {
+            catch_up_enabled: false,
+            skip_historical_catch_up_messages: true,
+            rewrite_override: None,
+            dry_run: false,
+        },
+    );
+    let test = async {
+        let result = send_and_wait(client_rx, &mut event_rx, chat_id, text, timeout).await;
+        let _ = shutdown_tx.send(());
+        result
+    };
+    let (runtime_result, test_result) = tokio::join!(runtime, test);
+    match (test_result, runtime_result) {
+        (Ok(report), Ok(())) => Ok(report),
+        (Ok(report), Err(err)) => {
+            tracing::warn!(error = %err, "rewriter did not shut down cleanly after send-test");
+            Ok(report)
+        }
+        (Err(err), Ok(())) => Err(err),
+        (Err(err), Err(runtime_err)) => {
+            Err(err.context(format!("rewriter failed: {runtime_err:#}")))
+        }
+    }
+}
+
+async fn send_and_wait(
+    client_rx: oneshot::Receiver<Client>,
+    event_rx: &mut mpsc::UnboundedReceiver<RewriteEvent>,
+    chat_id: i64,
+    text: &str,
+    timeout: Duration,
+) -> Result<SendTestReport> {
+    let send_failed = |reason: &str| anyhow!("{} stage failed: {reason}", SendTestStage::Send);
+    let client = match tokio::time::timeout(STARTUP_TIMEOUT, client_rx).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(_)) => return Err(send_failed("the rewriter stopped before it connected")),
+        Err(_) => return Err(send_failed("timed out waiting for the rewriter to connect")),
+    };
+    let (chat_id, peer_ref) = dialog_peer_ref(&client, ChatIdSpec::parse(chat_id))
+        .await
+        .map_err(|err| send_failed(&format!("{err:#}")))?;
+    let sent = client
+        .send_message(peer_ref, InputMessage::new().text(text))
+        .await
+        .map_err(|err| send_failed(&format!("failed to send the test message: {err}")))?;
+    let message_id = sent.id();
+
+    let mut progress = SendTestProgress::new(chat_id, message_id);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let outcome = loop {
+        let event = match tokio::time::timeout_at(deadline, event_rx.recv()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                bail!(
+                    "{} stage failed: the rewriter stopped while waiting",
+                    progress.stage()
+                )
+            }
+            Err(_) => {
+                bail!(
+                    "{} stage failed: {}",
+                    progress.stage(),
+                    progress.timeout_reason(timeout)
+                )
+            }
+        };
+        match progress.observe(&event) {
+            Some(Ok(outcome)) => break outcome,
+            Some(Err((stage, reason))) => bail!("{stage} stage failed: {reason}"),
+            None => {}
+        }
+    };
+
+    let (after_id, model) = match outcome {
+        SendTestOutcome::Edited { model } => (message_id, model),
+        SendTestOutcome::Resent {
+            new_message_id,
+            model,
+        } => (new_message_id, model),
+    };
+    let after = client
+        .get_messages_by_id(peer_ref, &[after_id])
+        .await
+        .context("failed to fetch the rewritten test message")?
+        .pop()
+        .flatten()
+        .map(|message| message.text().to_owned())
+        .with_context(|| format!("rewritten test message {after_id} no longer exists"))?;
+    Ok(SendTestReport {
+        before: text.to_owned(),
+        after,
+        model,
+    })
+}
+
+/// The Bot API id and peer of the dialog `chat` names.
+async fn dialog_peer_ref(client: &Client, chat: ChatIdSpec) -> Result<(i64, PeerRef)> {
+    let mut dialogs = client.iter_dialogs();
+    while let Some(dialog) = dialogs
+        .next()
+        .await
+        .context("failed to list dialogs to find the test chat")?
+    {
+        let dialog_id = dialog.peer_id().bot_api_dialog_id();
+        if chat.matches(dialog_id) {
+            return Ok((dialog_id, dialog.peer_ref()));
+        }
+    }
+    bail!("chat {chat:?} is not one of this account's dialogs")
+}
+
+/// Whether the rewriter could monitor `chat_id`, given either id form on both sides. `"me"`
+/// is only resolved once connected, so any chat may be Saved Messages while it is listed.
+fn may_be_monitored(chats: &[i64], chat_id: i64) -> bool {
+    let candidates = ChatIdSpec::parse(chat_id).candidates();
+    chats.contains(&SAVED_MESSAGES_CHAT_ID)
+        || chats.iter().any(|&chat| {
+            ChatIdSpec::parse(chat)
+                .candidates()
+                .iter()
+                .any(|candidate| candidates.contains(candidate))
+        })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SendTestOutcome {
+    Edited { model: String },
+    Resent { new_message_id: i32, model: String },
+}
+
+/// Follows the rewriter's events for the test message.
+struct SendTestProgress {
+    chat_id: i64,
+    message_id: i32,
+    picked_up: bool,
+    rewritten: bool,
+}
+
+impl SendTestProgress {
+    fn new(chat_id: i64, message_id: i32) -> Self {
+        Self {
+            chat_id,
+            message_id,
+            picked_up: false,
+            rewritten: false,
+        }
+    }
+
+    /// The stage the test is waiting on.
+    fn stage(&self) -> SendTestStage {
+        if self.rewritten {
+            SendTestStage::Edit
+        } else {
+            SendTestStage::Rewrite
+        }
+    }
+
+    fn timeout_reason(&self, timeout: Duration) -> String {
+        let waiting_for = if self.rewritten {
+            "the rewrite to be applied"
+        } else if self.picked_up {
+            "the model to rewrite the message"
+        } else {
+            "the rewriter to pick up the message"
+        };
+        format!(
+            "timed out after {}s waiting for {waiting_for}",
+            timeout.as_secs()
+        )
+    }
+
+    /// Records `event`, returning how the test ended once it has.
+    fn observe(
+        &mut self,
+        event: &RewriteEvent,
+    ) -> Option<Result<SendTestOutcome, (SendTestStage, &'static str)>> {
+        match event {
+            RewriteEvent::MonitoredUpdate {
+                chat_id,
+                message_id,
+                ..
+            } if self.is_test_message(*chat_id, *message_id) => {
+                self.picked_up = true;
+                None
+            }
+            RewriteEvent::RewriteSucceeded {
+                chat_id,
+                message_id,
+                ..
+            } if self.is_test_message(*chat_id, *message_id) => {
+                self.rewritten = true;
+                None
+            }
+            RewriteEvent::RewriteRefused {
+                chat_id,
+                message_id,
+            } if self.is_test_message(*chat_id, *message_id) => Some(Err((
+                SendTestStage::Rewrite,
+                "the model refused to rewrite the message",
+            ))),
+            RewriteEvent::RateLimited {
+                chat_id,
+                message_id,
+            } if self.is_test_message(*chat_id, *message_id) => Some(Err((
+                SendTestStage::Rewrite,
+                "rewrite.max_per_minute was reached",
+            ))),
+            RewriteEvent::RewriteCancelled {
+                chat_id,
+                message_id,
+            } if self.is_test_message(*chat_id, *message_id) => Some(Err((
+                SendTestStage::Edit,
+                "the message was deleted before the rewrite was applied",
+            ))),
+            RewriteEvent::ResendInconsistent {
+                chat_id,
+                message_id,
+                ..
+            } if self.is_test_message(*chat_id, *message_id) => Some(Err((
+                SendTestStage::Edit,
+                "the rewrite was sent but the original could not be deleted",
+            ))),
+            RewriteEvent::MessageEdited {
+                chat_id,
+                message_id,
+                model,
+            } if self.is_test_message(*chat_id, *message_id) => Some(Ok(SendTestOutcome::Edited {
+                model: model.clone(),
+            })),
+            RewriteEvent::MessageResent {
+                chat_id,
+                message_id,
+                new_message_id,
+                model,
+            } if self.is_test_message(*chat_id, *message_id) => Some(Ok(SendTestOutcome::Resent {
+                new_message_id: *new_message_id,
+                model: model.clone(),
+            })),
+            _ => None,
+        }
+    }
+
+    fn is_test_message(&self, chat_id: i64, message_id: i32) -> bool {
+        chat_id == self.chat_id && message_id == self.message_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SendTestOutcome, SendTestProgress, SendTestStage, may_be_monitored};
+    use crate::app::{MonitoredUpdateKind, RewriteEvent};
+    use crate::config::SAVED_MESSAGES_CHAT_ID;
+    use std::time::Duration;
+
+    fn monitored_update(chat_id: i64, message_id: i32) -> RewriteEvent {
+        RewriteEvent::MonitoredUpdate {
+            chat_id,
+            topic_root_id: None,
+            topic: None,
+            message_id,
+            outgoing: true,
+            kind: MonitoredUpdateKind::NewMessage,
+        }
+    }
+
+    #[test]
+    fn progress_moves_through_the_stages_of_the_test_message_only() {
+        let mut progress = SendTestProgress::new(-100, 7);
+        assert_eq!(progress.stage(), SendTestStage::Rewrite);
+        assert!(
+            progress
+                .timeout_reason(Duration::from_secs(60))
+                .contains("pick up the message")
+        );
+
+        assert!(progress.observe(&monitored_update(-100, 8)).is_none());
+        assert!(progress.observe(&monitored_update(-100, 7)).is_none());
+        assert!(
+            progress
+                .timeout_reason(Duration::from_secs(60))
+                .contains("the model to rewrite")
+        );
+
+        let rewritten = RewriteEvent::RewriteSucceeded {
+            chat_id: -100,
+            message_id: 7,
+            input_tokens: None,
+            output_tokens: None,
+        };
+        assert!(progress.observe(&rewritten).is_none());
+        assert_eq!(progress.stage(), SendTestStage::Edit);
+
+        let other_chat = RewriteEvent::MessageEdited {
+            chat_id: -200,
+            message_id: 7,
+            model: "gpt".to_owned(),
+        };
+        assert!(progress.observe(&other_chat).is_none());
+        let edited = RewriteEvent::MessageEdited {
+            chat_id: -100,
+            message_id: 7,
+            model: "gpt".to_owned(),
+        };
+        assert_eq!(
+            progress.observe(&edited),
+            Some(Ok(SendTestOutcome::Edited {
+                model: "gpt".to_owned()
+            }))
+        );
+    }
+
+    #[test]
+    fn refusals_and_cancellations_fail_at_their_stage() {
+        let mut progress = SendTestProgress::new(-100, 7);
+        let refused = RewriteEvent::RewriteRefused {
+            chat_id: -100,
+            message_id: 7,
+        };
+        assert!(matches!(
+            progress.observe(&refused),
+            Some(Err((SendTestStage::Rewrite, _)))
+        ));
+        let cancelled = RewriteEvent::RewriteCancelled {
+            chat_id: -100,
+            message_id: 7,
+        };
+        assert!(matches!(
+            progress.observe(&cancelled),
+            Some(Err((SendTestStage::Edit, _)))
+        ));
+    }
+
+    #[test]
+    fn only_listed_chats_or_saved_messages_may_be_monitored() {
+        assert!(may_be_monitored(&[-100, -200], -200));
+        assert!(!may_be_monitored(&[-100], -200));
+        assert!(may_be_monitored(&[1234567890], -1001234567890));
+        assert!(may_be_monitored(&[-1001234567890], 1234567890));
+        assert!(may_be_monitored(&[SAVED_MESSAGES_CHAT_ID], 12345));
+    }
+}
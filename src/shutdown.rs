@@ -0,0 +1,149 @@
+use std::future::{Future, pending};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Where shutdown requests come from. The first one starts a graceful shutdown; another one
+/// while it is still running cuts it short.
+pub trait ShutdownSignal: Send {
+    /// Waits for the next request and names it for logs. Never resolves once the source has
+    /// nothing more to send.
+    fn recv(&mut self) -> impl Future<Output = &'static str> + Send;
+}
+
+/// Ctrl+C on every platform, plus SIGTERM (as sent by `systemctl stop` or `docker stop`) on
+/// Unix.
+pub struct OsShutdownSignal {
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+}
+
+impl OsShutdownSignal {
+    /// Starts listening right away, so a signal that arrives before the first `recv` still
+    /// counts. Must be called within a Tokio runtime.
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        let terminate = {
+            use tokio::signal::unix::{SignalKind, signal};
+            signal(SignalKind::terminate())
+                .inspect_err(|err| warn!(error = %err, "failed to listen for SIGTERM"))
+                .ok()
+        };
+        Self {
+            #[cfg(unix)]
+            terminate,
+        }
+    }
+}
+
+impl Default for OsShutdownSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownSignal for OsShutdownSignal {
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        let terminate = async {
+            match self.terminate.as_mut() {
+                Some(terminate) => terminate.recv().await,
+                None => pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = pending::<Option<()>>();
+        tokio::select! {
+            Ok(()) = tokio::signal::ctrl_c() => "Ctrl+C",
+            Some(()) = terminate => "SIGTERM",
+            else => pending().await,
+        }
+    }
+}
+
+/// A single request, sent (or dropped) by whoever runs the rewriter in-process.
+impl ShutdownSignal for Option<oneshot::Receiver<()>> {
+    async fn recv(&mut self) -> &'static str {
+        match self.take() {
+            Some(requested) => {
+                let _ = requested.await;
+                "shutdown requested"
+            }
+            None => pending().await,
+        }
+    }
+}
+
+/// Why a shutdown step was cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownCut {
+    TimedOut,
+    /// Another shutdown request arrived; carries its name.
+    Signal(&'static str),
+}
+
+/// Runs `work` unless `deadline` passes or another shutdown request arrives first.
+pub async fn within_shutdown_budget<T>(
+    work: impl Future<Output = T>,
+    deadline: Instant,
+    signal: &mut impl ShutdownSignal,
+) -> Result<T, ShutdownCut> {
+    tokio::select! {
+        result = tokio::time::timeout_at(deadline, work) => {
+            result.map_err(|_| ShutdownCut::TimedOut)
+        }
+        name = signal.recv() => Err(ShutdownCut::Signal(name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShutdownCut, ShutdownSignal, within_shutdown_budget};
+    use std::future::pending;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::Instant;
+
+    struct FakeSignal(mpsc::UnboundedReceiver<&'static str>);
+
+    impl ShutdownSignal for FakeSignal {
+        async fn recv(&mut self) -> &'static str {
+            match self.0.recv().await {
+                Some(name) => name,
+                None => pending().await,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn second_signal_or_deadline_cuts_a_shutdown_step_short() {
+        let (signal_tx, signal_rx) = mpsc::unbounded_channel();
+        let mut signal = FakeSignal(signal_rx);
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let finished = within_shutdown_budget(async { 7 }, deadline, &mut signal).await;
+        assert_eq!(finished, Ok(7));
+
+        let timed_out = within_shutdown_budget(pending::<()>(), deadline, &mut signal).await;
+        assert_eq!(timed_out, Err(ShutdownCut::TimedOut));
+
+        signal_tx.send("SIGTERM").expect("signal should be sent");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let interrupted = within_shutdown_budget(pending::<()>(), deadline, &mut signal).await;
+        assert_eq!(interrupted, Err(ShutdownCut::Signal("SIGTERM")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_process_request_fires_once() {
+        let (requested_tx, requested_rx) = oneshot::channel();
+        let mut signal = Some(requested_rx);
+        requested_tx.send(()).expect("request should be sent");
+        assert_eq!(signal.recv().await, "shutdown requested");
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let finished = within_shutdown_budget(async { "done" }, deadline, &mut signal).await;
+        assert_eq!(finished, Ok("done"));
+        let timed_out = within_shutdown_budget(pending::<()>(), deadline, &mut signal).await;
+        assert_eq!(timed_out, Err(ShutdownCut::TimedOut));
+    }
+}
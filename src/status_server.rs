@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use axum::Router;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// HTTP server for the `/metrics`, `/healthz` and `/readyz` routes, running until
+/// [`StatusServer::stop`] or until it is dropped.
+pub struct StatusServer {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+impl StatusServer {
+    /// Binds `listen` and serves `routes` from it. Binding happens before this returns, so a
+    /// port already in use fails startup.
+    pub async fn start(listen: SocketAddr, routes: Router) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to listen on {listen}"))?;
+        let local_addr = listener.local_addr()?;
+        let (shutdown, stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, routes)
+                .with_graceful_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        info!(listen = %local_addr, "status server started");
+        Ok(Self {
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting requests and waits for open ones to finish.
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(());
+        match self.task.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!(error = %err, "status server failed"),
+            Err(err) => warn!(error = %err, "status server stopped unexpectedly"),
+        }
+    }
+}
@@ -1,37 +1,232 @@
-use crate::config::TelegramConfig;
-use crate::context::{ContextEntry, ContextMessage, resolve_sender_name};
+use crate::config::{
+    ChatLink, LinkPreview, LoginMethod, ParseMode, TelegramConfig, resolve_saved_messages,
+};
+use crate::context::{
+    ContextEntry, ContextMessage, UNKNOWN_SENDER, chat_metadata_line, resolve_sender_name,
+};
+use crate::formatting::{entities_to_markdown, parse_html, parse_markdown, truncate_rendered};
 use anyhow::{Context, Result, bail};
 use grammers_client::client::{UpdateStream, UpdatesConfiguration};
-use grammers_client::message::Message as TelegramMessage;
-use grammers_client::update::{Message as UpdateMessage, Update};
+use grammers_client::message::{InputMessage, Message as TelegramMessage};
+use grammers_client::peer::Peer;
+use grammers_client::update::Update;
 use grammers_client::{Client, SignInError, tl};
-use grammers_mtsender::{SenderPool, SenderPoolFatHandle};
+use grammers_mtsender::{ConnectionParams, InvocationError, SenderPool, SenderPoolFatHandle};
 use grammers_session::storages::SqliteSession;
 use grammers_session::types::PeerRef;
 use grammers_session::updates::UpdatesLike;
-use std::collections::HashSet;
+use qrcode::QrCode;
+use qrcode::render::unicode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io::{self, BufRead, Write};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 const CONTEXT_SCAN_FACTOR: usize = 20;
 const CONTEXT_SCAN_MIN_MESSAGES: usize = 200;
 const UPDATE_QUEUE_LIMIT: usize = 10_000;
+const REPLY_TARGET_CACHE_LIMIT: usize = 256;
+const SENDER_NAME_CACHE_LIMIT: usize = 4_096;
+const TOPIC_NAMES_PER_CHAT_LIMIT: usize = 256;
+/// Telegram shows a chat action for about five seconds, so it is repeated a bit sooner.
+const TYPING_ACTION_INTERVAL: Duration = Duration::from_secs(4);
+/// How often the QR login checks whether the code was scanned and approved.
+const QR_LOGIN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long `--list-chats` listens for updates on a bot account, which can't list dialogs.
+const BOT_LIST_CHATS_WINDOW: Duration = Duration::from_secs(5);
+const FORUM_TOPICS_PAGE_SIZE: i32 = 100;
+/// Telegram's id for the General topic of every forum.
+const GENERAL_TOPIC_ID: i32 = 1;
+/// How `--list-chats` shows the account's own chat, so it is easy to find for `"me"`.
+const SAVED_MESSAGES_TITLE: &str = "Saved Messages (me)";
+/// Telegram's limit on message text, in UTF-16 code units of the text without markup.
+const MESSAGE_TEXT_MAX_UTF16: usize = 4096;
+/// Attempts per edit, including the first, while Telegram answers with transient errors.
+const TRANSIENT_EDIT_ATTEMPTS: u32 = 3;
+/// Wait before the second edit attempt after a transient error; doubled for each later one.
+const TRANSIENT_EDIT_BACKOFF: Duration = Duration::from_millis(500);
+/// RPC errors that say nothing about the edit itself and tend to pass on their own.
+const TRANSIENT_EDIT_RPC_ERRORS: &[&str] = &[
+    "CONNECTION_NOT_INITED",
+    "RPC_CALL_FAIL",
+    "RPC_MCGET_FAIL",
+    "MSG_WAIT_FAILED",
+    "MSG_WAIT_TIMEOUT",
+    "TIMEOUT",
+];
+/// RPC errors after which the message can never be edited by this account.
+const PERMANENT_EDIT_RPC_ERRORS: &[&str] = &["MESSAGE_ID_INVALID", "MESSAGE_AUTHOR_REQUIRED"];
+/// RPC errors saying the session's authorization is gone, for example after it was
+/// terminated from another device. Every later request fails the same way.
+const SESSION_REVOKED_RPC_ERRORS: &[&str] = &[
+    "AUTH_KEY_UNREGISTERED",
+    "AUTH_KEY_INVALID",
+    "SESSION_REVOKED",
+    "SESSION_EXPIRED",
+    "USER_DEACTIVATED",
+    "USER_DEACTIVATED_BAN",
+];
+/// Appended to the name of a session file whose authorization was revoked.
+const REVOKED_SESSION_SUFFIX: &str = "revoked";
+/// Appended to the session file name for the [`DialogCache`] next to it.
+const DIALOG_CACHE_SUFFIX: &str = "dialogs.json";
+/// Older dialog caches are ignored, so the list of administered chats stays current.
+const DIALOG_CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub struct TelegramBot {
-    client: Client,
+    requests: TelegramRequests,
     updates: Option<UpdateStream>,
     monitored_chats: HashSet<i64>,
+    /// Monitored chats that aren't dialogs of this session yet; ignored until they are.
+    unresolved_chats: HashSet<i64>,
+    /// Dialog titles by chat id, loaded at startup and when a reload adds unknown chats.
+    chat_titles: HashMap<i64, String>,
+    /// Dialog peers by chat id, for lookups that don't start from a received message.
+    dialog_peers: HashMap<i64, PeerRef>,
+    /// Slow mode intervals of monitored supergroups that have one, read at startup.
+    slow_modes: HashMap<i64, Duration>,
+    /// Chat ids that `rewrite.chats` links resolved to, so each link is joined only once.
+    linked_chats: HashMap<ChatLink, i64>,
+    /// Dialogs that are forums, whose topic names are loaded once they are monitored.
+    forum_chats: HashSet<i64>,
+    /// Supergroup ids of monitored basic groups that migrated, by old id. Kept so a reload
+    /// whose chat list still has the old id goes on monitoring the new one.
+    migrated_chats: HashMap<i64, i64>,
+    /// Signed in with `telegram.bot_token`; bots can't iterate dialogs.
+    is_bot: bool,
+    /// Login settings and proxy, kept to rebuild the connection in [`TelegramBot::reconnect`].
+    telegram_config: TelegramConfig,
+    proxy: Option<String>,
     pool_handle: SenderPoolFatHandle,
     pool_task: Option<JoinHandle<()>>,
 }
 
-#[derive(Debug, Clone)]
+/// The requests a rewrite makes off the update loop; clones share the lookup caches.
+#[derive(Clone)]
+pub struct TelegramRequests {
+    client: Client,
+    /// The signed-in user's id, which is also their Saved Messages chat. Only fetched for
+    /// rewriting, where `"me"` in the chat lists needs it.
+    self_chat_id: Option<i64>,
+    /// Channels and supergroups we administer, whose posts as that peer count as our own.
+    own_personas: Arc<HashSet<i64>>,
+    /// Forum topic names, from the forum topics API and topic service messages.
+    topic_names: Arc<Mutex<TopicNames>>,
+    /// Replied-to messages fetched by id, so replying to the same old message again is free.
+    reply_targets: Arc<Mutex<HashMap<(i64, i32), ContextMessage>>>,
+    /// Senders of replied-to messages by `(chat_id, message_id)`, for reply allow-lists.
+    reply_senders: Arc<Mutex<HashMap<(i64, i32), Option<i64>>>>,
+    /// Sender names by user id, for senders that arrive without one.
+    sender_names: Arc<Mutex<SenderNameCache>>,
+    /// Set once a request failed because the session's authorization was revoked.
+    session_revoked: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct ChatListItem {
     pub id: i64,
     pub name: String,
+    pub kind: ChatKind,
+    /// Public `@username`, without the `@`.
+    pub username: Option<String>,
+    pub is_forum: bool,
+    /// Approximate; Telegram doesn't always include it in dialog data.
+    pub member_count: Option<u32>,
+    pub unread_count: Option<u32>,
+}
+
+/// A message as `--export-history` writes it: the context line the model would see, with
+/// the message id and send time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    pub message_id: i32,
+    /// Unix seconds.
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub message: ContextMessage,
+}
+
+/// The signed-in account as `--whoami` prints it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub id: i64,
+    pub name: String,
+    /// Public `@username`, without the `@`.
+    pub username: Option<String>,
+    /// With the middle digits masked; see [`mask_phone`].
+    pub phone: Option<String>,
+    /// The data center the session is homed in.
+    pub dc_id: i32,
+    pub is_bot: bool,
+}
+
+/// What `--logout` found the session in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogoutOutcome {
+    /// Telegram invalidated the session's auth key.
+    LoggedOut,
+    /// The session wasn't logged in, so there was nothing to invalidate.
+    NotLoggedIn,
+}
+
+/// A forum topic as `--list-topics` prints it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForumTopicItem {
+    /// The topic's root message id, which messages in the topic reply to.
+    pub root_id: i32,
+    pub title: String,
+}
+
+impl ForumTopicItem {
+    pub fn is_general(&self) -> bool {
+        self.root_id == GENERAL_TOPIC_ID
+    }
+
+    /// The id to put in config files: the General topic has no root message to reply to,
+    /// so config marks it with 0.
+    pub fn config_root_id(&self) -> i32 {
+        if self.is_general() { 0 } else { self.root_id }
+    }
+}
+
+/// What kind of chat a list entry is. Ordered as `--sort kind` lists them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatKind {
+    User,
+    Group,
+    Supergroup,
+    Channel,
+}
+
+impl ChatKind {
+    /// Guesses the kind from the Bot API id ranges alone, which can't tell a supergroup
+    /// from a channel.
+    pub fn from_dialog_id(chat_id: i64) -> Self {
+        if chat_id > 0 {
+            Self::User
+        } else if is_channel_dialog_id(chat_id) {
+            Self::Channel
+        } else {
+            Self::Group
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Group => "group",
+            Self::Supergroup => "supergroup",
+            Self::Channel => "channel",
+        }
+    }
 }
 
 struct ConnectionParts {
@@ -42,9 +237,13 @@ struct ConnectionParts {
 }
 
 impl TelegramBot {
+    /// Connects an account for rewriting. Chats in `chat_links` are joined when the account
+    /// isn't in them yet and monitored along with `monitored_chats`.
     pub async fn connect_for_rewrite(
         config: &TelegramConfig,
-        monitored_chats: HashSet<i64>,
+        proxy: Option<&str>,
+        mut monitored_chats: HashSet<i64>,
+        chat_links: &[ChatLink],
         catch_up: bool,
     ) -> Result<Self> {
         let ConnectionParts {
@@ -52,8 +251,44 @@ impl TelegramBot {
             updates_rx,
             pool_handle,
             pool_task,
-        } = connect_and_auth(config).await?;
-        preflight_monitored_chats(&client, &monitored_chats).await?;
+        } = connect_and_auth(config, proxy, true).await?;
+        let is_bot = config.bot_token.is_some();
+        let self_chat_id = client
+            .get_me()
+            .await
+            .context("failed to fetch the signed-in Telegram user")?
+            .id()
+            .bot_api_dialog_id();
+        resolve_saved_messages(&mut monitored_chats, self_chat_id);
+        let mut linked_chats = HashMap::new();
+        if is_bot && !chat_links.is_empty() {
+            warn!("bot accounts can't join chats; links in rewrite.chats are ignored");
+        } else {
+            join_chat_links(&client, chat_links, &mut linked_chats).await;
+        }
+        monitored_chats.extend(linked_chats.values().copied());
+        let (
+            Dialogs {
+                titles: chat_titles,
+                peers: dialog_peers,
+                administered: own_personas,
+                forums: forum_chats,
+                ..
+            },
+            unresolved_chats,
+        ) = if is_bot {
+            warn!("bot accounts can't list dialogs; monitored chats are not checked at startup");
+            (Dialogs::default(), HashSet::new())
+        } else {
+            preflight_monitored_chats(
+                &client,
+                &mut monitored_chats,
+                config.strict_preflight,
+                &dialog_cache_path(&config.session_file),
+            )
+            .await?
+        };
+        let slow_modes = load_slow_modes(&client, &monitored_chats, &dialog_peers).await;
 
         let updates = client
             .stream_updates(
@@ -71,32 +306,118 @@ impl TelegramBot {
             "configured telegram update stream"
         );
 
-        Ok(Self {
-            client,
+        let bot = Self {
+            requests: TelegramRequests::new(client, Some(self_chat_id), own_personas, config),
             updates: Some(updates),
             monitored_chats,
+            unresolved_chats,
+            chat_titles,
+            dialog_peers,
+            slow_modes,
+            linked_chats,
+            forum_chats,
+            migrated_chats: HashMap::new(),
+            is_bot,
+            telegram_config: config.clone(),
+            proxy: proxy.map(str::to_owned),
             pool_handle,
             pool_task: Some(pool_task),
-        })
+        };
+        bot.backfill_topic_names().await;
+        Ok(bot)
     }
 
-    pub async fn connect_for_listing(config: &TelegramConfig) -> Result<Self> {
+    pub async fn connect_for_listing(config: &TelegramConfig, proxy: Option<&str>) -> Result<Self> {
         let ConnectionParts {
             client,
+            updates_rx,
             pool_handle,
             pool_task,
-            ..
-        } = connect_and_auth(config).await?;
+        } = connect_and_auth(config, proxy, true).await?;
+        let is_bot = config.bot_token.is_some();
+        // Bots find their chats through updates instead of dialogs; see `list_bot_chats`.
+        let updates = if is_bot {
+            let updates = client
+                .stream_updates(
+                    updates_rx,
+                    UpdatesConfiguration {
+                        catch_up: true,
+                        update_queue_limit: Some(UPDATE_QUEUE_LIMIT),
+                    },
+                )
+                .await;
+            Some(updates)
+        } else {
+            None
+        };
 
         Ok(Self {
-            client,
-            updates: None,
+            requests: TelegramRequests::new(client, None, HashSet::new(), config),
+            updates,
             monitored_chats: HashSet::new(),
+            unresolved_chats: HashSet::new(),
+            chat_titles: HashMap::new(),
+            dialog_peers: HashMap::new(),
+            slow_modes: HashMap::new(),
+            linked_chats: HashMap::new(),
+            forum_chats: HashSet::new(),
+            migrated_chats: HashMap::new(),
+            is_bot,
+            telegram_config: config.clone(),
+            proxy: proxy.map(str::to_owned),
             pool_handle,
             pool_task: Some(pool_task),
         })
     }
 
+    /// Looks up the account signed in to the session, without streaming updates or logging
+    /// in: an unauthorized session is an error.
+    pub async fn whoami(config: &TelegramConfig, proxy: Option<&str>) -> Result<AccountInfo> {
+        let ConnectionParts {
+            client,
+            updates_rx: _,
+            pool_handle,
+            pool_task,
+        } = connect_and_auth(config, proxy, false).await?;
+        let info = account_info(&client).await;
+        pool_handle.quit();
+        pool_task
+            .await
+            .context("failed waiting for Telegram sender pool task")?;
+        info
+    }
+
+    /// Invalidates the session's auth key on Telegram's side with `auth.logOut`. Never logs
+    /// in; the session file itself is left alone.
+    pub async fn log_out(config: &TelegramConfig, proxy: Option<&str>) -> Result<LogoutOutcome> {
+        let ConnectionParts {
+            client,
+            updates_rx: _,
+            pool_handle,
+            pool_task,
+        } = connect_session(config, proxy).await?;
+        let outcome = async {
+            if !client
+                .is_authorized()
+                .await
+                .context("failed to check Telegram authorization")?
+            {
+                return Ok(LogoutOutcome::NotLoggedIn);
+            }
+            client
+                .invoke(&tl::functions::auth::LogOut {})
+                .await
+                .context("auth.logOut failed")?;
+            Ok(LogoutOutcome::LoggedOut)
+        }
+        .await;
+        pool_handle.quit();
+        if let Err(err) = pool_task.await {
+            warn!(error = %err, "Telegram sender pool task failed during logout");
+        }
+        outcome
+    }
+
     pub async fn next_update(&mut self) -> Result<Update> {
         let updates = self
             .updates
@@ -105,267 +426,2510 @@ impl TelegramBot {
         updates
             .next()
             .await
+            .inspect_err(|err| note_session_revoked(&self.requests.session_revoked, err))
             .context("failed to fetch Telegram update")
     }
 
-    pub async fn list_chats(&self, query: Option<&str>) -> Result<Vec<ChatListItem>> {
-        let query = query.map(|value| value.to_lowercase());
-        let mut dialogs = self.client.iter_dialogs();
-        let mut chats: Vec<(String, ChatListItem)> = Vec::new();
+    /// Whether a request has failed because the session's authorization was revoked. It
+    /// stays set until [`Self::sign_in_again`] succeeds.
+    pub fn session_revoked(&self) -> bool {
+        self.requests.session_revoked.load(Ordering::Relaxed)
+    }
 
-        while let Some(dialog) = dialogs
-            .next()
-            .await
-            .context("failed while iterating Telegram dialogs")?
-        {
-            let peer = dialog.peer();
-            let name = peer.name().unwrap_or_default().trim().to_owned();
-            let name_lower = name.to_lowercase();
-            let matches = query.as_ref().is_none_or(|q| name_lower.contains(q));
-            if matches {
-                chats.push((
-                    name_lower,
-                    ChatListItem {
-                        id: peer.id().bot_api_dialog_id(),
-                        name,
-                    },
-                ));
-            }
-        }
+    /// Renames the revoked session file to `<file>.revoked`, replacing an older one, so
+    /// the next start logs in again instead of retrying the dead key. Returns the new path.
+    pub fn retire_session_file(&self) -> Result<PathBuf> {
+        let session_file = &self.telegram_config.session_file;
+        let mut retired = session_file.clone().into_os_string();
+        retired.push(".");
+        retired.push(REVOKED_SESSION_SUFFIX);
+        let retired = PathBuf::from(retired);
+        // The next login may be another account, with other dialogs.
+        let _ = std::fs::remove_file(dialog_cache_path(session_file));
+        std::fs::rename(session_file, &retired).with_context(|| {
+            format!(
+                "failed to move revoked session file {} to {}",
+                session_file.display(),
+                retired.display()
+            )
+        })?;
+        Ok(retired)
+    }
 
-        chats.sort_by(|left, right| left.0.cmp(&right.0).then(left.1.id.cmp(&right.1.id)));
-        Ok(chats.into_iter().map(|(_, item)| item).collect())
+    /// Logs in again interactively on a new session file after the old one was retired,
+    /// and resumes the update stream on it.
+    pub async fn sign_in_again(&mut self) -> Result<()> {
+        self.reconnect_with(true).await?;
+        self.requests
+            .session_revoked
+            .store(false, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn update_monitored_chats(&mut self, chats: HashSet<i64>) {
-        self.monitored_chats = chats;
+    /// Current Telegram server time, immune to local clock skew.
+    pub async fn server_unix_time(&self) -> Result<i64> {
+        let tl::enums::updates::State::State(state) = self
+            .client
+            .invoke(&tl::functions::updates::GetState {})
+            .await
+            .context("failed to fetch Telegram updates state")?;
+        Ok(i64::from(state.date))
     }
 
-    pub fn is_monitored_chat(&self, chat_id: i64) -> bool {
-        self.monitored_chats.contains(&chat_id)
+    pub async fn list_chats(&mut self, query: Option<&str>) -> Result<Vec<ChatListItem>> {
+        let known = if self.is_bot {
+            self.list_bot_chats().await?
+        } else {
+            self.list_dialog_chats().await?
+        };
+        Ok(filter_chat_list(known, query))
     }
 
-    pub(crate) fn client_clone(&self) -> Client {
-        self.client.clone()
+    /// Topics of the forum `chat_id`, by root id. Fails with a "not a forum" error for
+    /// other chats instead of returning an empty list.
+    pub async fn list_forum_topics(&self, chat_id: i64) -> Result<Vec<ForumTopicItem>> {
+        if self.is_bot {
+            bail!("bot accounts can't look up chats by id; --list-topics needs a user session");
+        }
+        let (chat, peer_ref) = self.find_dialog(chat_id).await?;
+        if !chat.is_forum {
+            bail!(
+                "chat {chat_id} ({}) is not a forum, so it has no topics",
+                chat.name
+            );
+        }
+        fetch_forum_topics(&self.requests.client, peer_ref).await
     }
 
-    pub async fn edit_message(&self, message: &UpdateMessage, new_text: &str) -> Result<()> {
-        let message_id = message.id();
-        let peer = message
-            .peer_ref()
-            .await
-            .context("failed to resolve peer for Telegram message edit")?;
+    /// The last `limit` messages of `chat_id`, oldest first, picked like context: only those
+    /// in the topic `topic_root_id`, without service messages or messages without text.
+    pub async fn export_history(
+        &self,
+        chat_id: i64,
+        topic_root_id: Option<i32>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        if self.is_bot {
+            bail!("bot accounts can't read chat history; --export-history needs a user session");
+        }
+        let (_, peer_ref) = self.find_dialog(chat_id).await?;
+        let (scanned_messages, _) = collect_context(
+            |offset_id| {
+                let mut iter = self
+                    .requests
+                    .client
+                    .iter_messages(peer_ref)
+                    .offset_id(offset_id);
+                async move || {
+                    let Some(msg) = iter
+                        .next()
+                        .await
+                        .context("failed while iterating messages for export")?
+                    else {
+                        return Ok(None);
+                    };
+                    Ok(Some(self.scanned_message(&msg)))
+                }
+            },
+            0,
+            limit,
+            topic_root_id,
+            false,
+        )
+        .await?;
 
-        self.client
-            .edit_message(peer, message_id, new_text)
-            .await
-            .context("failed to edit Telegram message")?;
-        Ok(())
+        let mut entries = Vec::with_capacity(scanned_messages.len());
+        for scanned_message in &scanned_messages {
+            let mut message = scanned_message.entry.message.clone();
+            message.sender_name = self.resolve_scanned_sender(peer_ref, scanned_message).await;
+            entries.push(HistoryEntry {
+                message_id: scanned_message.entry.message_id,
+                timestamp: scanned_message.unix,
+                message,
+            });
+        }
+        Ok(entries)
     }
 
-    pub async fn fetch_context(
+    /// Our last `count` messages with text in `chat_id`, oldest first. Looks as far back
+    /// as a context fetch for `count` messages would.
+    pub async fn recent_own_messages(
         &self,
-        message: &UpdateMessage,
+        chat_id: i64,
         count: usize,
-        target_topic_root_id: Option<i32>,
-    ) -> Result<Vec<ContextEntry>> {
-        if count == 0 {
-            return Ok(Vec::new());
+    ) -> Result<Vec<TelegramMessage>> {
+        if self.is_bot {
+            bail!("bot accounts can't read chat history; --backfill needs a user session");
         }
-
-        let peer_ref: PeerRef = message
-            .peer_ref()
-            .await
-            .context("failed to resolve peer for fetching context")?;
-
-        let message_id = message.id();
-        let mut iter = self.client.iter_messages(peer_ref);
+        let (_, peer_ref) = self.find_dialog(chat_id).await?;
+        let mut iter = self.requests.client.iter_messages(peer_ref);
         let mut messages = Vec::new();
-        let max_scan = context_scan_limit(count);
         let mut scanned = 0;
+        while messages.len() < count && scanned < context_scan_limit(count) {
+            let Some(message) = iter
+                .next()
+                .await
+                .context("failed while iterating messages for backfill")?
+            else {
+                break;
+            };
+            scanned += 1;
+            if self.is_own_message(&message)
+                && !message_is_service(&message)
+                && !message.text().trim().is_empty()
+            {
+                messages.push(message);
+            }
+        }
+        messages.reverse();
+        Ok(messages)
+    }
 
-        while let Some(msg) = iter
+    async fn find_dialog(&self, chat_id: i64) -> Result<(ChatListItem, PeerRef)> {
+        let mut dialogs = self.requests.client.iter_dialogs();
+        while let Some(dialog) = dialogs
             .next()
             .await
-            .context("failed while iterating messages for context")?
+            .context("failed while iterating Telegram dialogs")?
         {
-            scanned += 1;
-            if scanned > max_scan {
-                break;
-            }
-
-            if msg.id() == message_id {
-                continue;
-            }
-            if message_topic_root_id(&msg) != target_topic_root_id {
-                continue;
-            }
-
-            let text = msg.text().trim().to_owned();
-            if text.is_empty() {
+            if dialog.peer_id().bot_api_dialog_id() != chat_id {
                 continue;
             }
+            let peer_ref = dialog
+                .peer()
+                .to_ref()
+                .with_context(|| format!("chat {chat_id} has no usable access hash"))?;
+            return Ok((chat_list_item(dialog.peer()), peer_ref));
+        }
+        bail!("chat {chat_id} is not one of this session's dialogs")
+    }
 
-            let msg_id = msg.id();
-            let peer_name = msg.sender().and_then(|p| p.name().map(str::to_owned));
-            let sender_name = resolve_sender_name(msg.outgoing(), peer_name.as_deref());
-            messages.push(ContextEntry {
-                message_id: msg_id,
-                message: ContextMessage { sender_name, text },
-            });
-
-            if messages.len() >= count {
-                break;
+    async fn list_dialog_chats(&self) -> Result<Vec<ChatListItem>> {
+        let mut dialogs = self.requests.client.iter_dialogs();
+        let mut chats = Vec::new();
+        while let Some(dialog) = dialogs
+            .next()
+            .await
+            .context("failed while iterating Telegram dialogs")?
+        {
+            let mut chat = chat_list_item(dialog.peer());
+            if let tl::enums::Dialog::Dialog(raw) = &dialog.raw {
+                chat.unread_count = u32::try_from(raw.unread_count).ok();
             }
+            chats.push(chat);
         }
+        Ok(chats)
+    }
 
-        if scanned >= max_scan && messages.len() < count {
-            info!(
-                message_id,
-                target_topic_root_id = ?target_topic_root_id,
-                requested_context_messages = count,
-                scanned_messages = scanned,
-                scan_limit = max_scan,
-                fetched_context_messages = messages.len(),
-                "stopped context fetch after scan limit"
-            );
+    /// Bots can't iterate dialogs, so this collects the chats of updates received within
+    /// `BOT_LIST_CHATS_WINDOW`, including those queued while the bot was offline.
+    async fn list_bot_chats(&mut self) -> Result<Vec<ChatListItem>> {
+        info!(
+            window_seconds = BOT_LIST_CHATS_WINDOW.as_secs(),
+            "bot accounts can't list dialogs; collecting chats from incoming updates"
+        );
+        let mut chats: HashMap<i64, ChatListItem> = HashMap::new();
+        let deadline = tokio::time::Instant::now() + BOT_LIST_CHATS_WINDOW;
+        while let Ok(update) = tokio::time::timeout_at(deadline, self.next_update()).await {
+            let (Update::NewMessage(message) | Update::MessageEdited(message)) = update? else {
+                continue;
+            };
+            let id = message.peer_id().bot_api_dialog_id();
+            let chat = message
+                .peer()
+                .map(|peer| chat_list_item(&peer))
+                .unwrap_or_else(|| ChatListItem {
+                    id,
+                    name: String::new(),
+                    kind: ChatKind::from_dialog_id(id),
+                    username: None,
+                    is_forum: false,
+                    member_count: None,
+                    unread_count: None,
+                });
+            chats.insert(id, chat);
         }
+        Ok(chats.into_values().collect())
+    }
 
-        messages.reverse();
-        Ok(messages)
+    /// Replaces the monitored set with `chats` and the chats of `chat_links`, joining those
+    /// not joined yet, plus the supergroups that listed basic groups migrated to. Reloads dialogs if a chat has no cached title yet or some chats are
+    /// still unresolved, and loads topic names of newly monitored forums. Returns the chats
+    /// that became resolvable.
+    pub async fn update_monitored_chats(
+        &mut self,
+        mut chats: HashSet<i64>,
+        chat_links: &[ChatLink],
+    ) -> Vec<i64> {
+        if !self.is_bot {
+            join_chat_links(&self.requests.client, chat_links, &mut self.linked_chats).await;
+        }
+        self.linked_chats
+            .retain(|link, _| chat_links.contains(link));
+        chats.extend(self.linked_chats.values().copied());
+        for (old_id, new_id) in &self.migrated_chats {
+            if chats.contains(old_id) {
+                chats.insert(*new_id);
+            }
+        }
+        let resolved = self.replace_monitored_chats(chats).await;
+        self.backfill_topic_names().await;
+        resolved
     }
 
-    pub async fn shutdown(&mut self) -> Result<()> {
-        if let Some(updates) = self.updates.as_ref() {
-            updates.sync_update_state().await;
+    async fn replace_monitored_chats(&mut self, chats: HashSet<i64>) -> Vec<i64> {
+        let has_new_chats = chats
+            .iter()
+            .any(|chat_id| !self.chat_titles.contains_key(chat_id));
+        self.monitored_chats = chats;
+        let monitored_chats = &self.monitored_chats;
+        self.unresolved_chats
+            .retain(|chat_id| monitored_chats.contains(chat_id));
+        if (!has_new_chats && self.unresolved_chats.is_empty()) || self.is_bot {
+            return Vec::new();
         }
-        self.pool_handle.quit();
-        if let Some(pool_task) = self.pool_task.take() {
-            pool_task
-                .await
-                .context("failed waiting for Telegram sender pool task")?;
+        let previously_unresolved = self.unresolved_chats.clone();
+        // Only the new chats are looked for; the scan stops once they all turned up.
+        let wanted: HashSet<i64> = self
+            .monitored_chats
+            .iter()
+            .filter(|chat_id| !self.chat_titles.contains_key(chat_id))
+            .copied()
+            .collect();
+        let resolved = match scan_dialogs(&self.requests.client, Some(&wanted)).await {
+            Ok(dialogs) => self.apply_dialogs(dialogs),
+            Err(err) => {
+                warn!(error = %err, "failed to refresh chat titles after reload");
+                return Vec::new();
+            }
+        };
+        let mut newly_unresolved: Vec<i64> = self
+            .unresolved_chats
+            .difference(&previously_unresolved)
+            .copied()
+            .collect();
+        if !newly_unresolved.is_empty() {
+            newly_unresolved.sort_unstable();
+            warn!(
+                unresolved_chat_ids = ?newly_unresolved,
+                "reloaded chat ids are not present in Telegram dialogs for this session; retrying them periodically"
+            );
         }
-        Ok(())
+        resolved
     }
-}
 
-async fn preflight_monitored_chats(client: &Client, monitored_chats: &HashSet<i64>) -> Result<()> {
-    let known_chat_ids = prime_dialog_chat_ids(client).await?;
-    let unresolved_chat_ids = unresolved_monitored_chats(monitored_chats, &known_chat_ids);
-    if !unresolved_chat_ids.is_empty() {
-        bail!(
-            "monitored chat ids are not present in Telegram dialogs for this session: {:?}",
-            unresolved_chat_ids
-        );
+    pub fn own_personas(&self) -> &HashSet<i64> {
+        &self.requests.own_personas
     }
 
-    info!(
-        monitored_chat_count = monitored_chats.len(),
-        known_dialog_chat_count = known_chat_ids.len(),
-        "primed telegram peer cache for monitored chats"
-    );
+    /// Whether we wrote the message, as ourselves or posting as a peer we administer.
+    pub fn is_own_message(&self, message: &TelegramMessage) -> bool {
+        self.requests.is_own_message(message)
+    }
 
-    Ok(())
-}
+    /// The signed-in user's own chat id, known for accounts connected for rewriting.
+    pub fn self_chat_id(&self) -> Option<i64> {
+        self.requests.self_chat_id
+    }
 
-async fn prime_dialog_chat_ids(client: &Client) -> Result<HashSet<i64>> {
-    let mut dialogs = client.iter_dialogs();
-    let mut chat_ids = HashSet::new();
-    while let Some(dialog) = dialogs
-        .next()
-        .await
-        .context("failed while iterating dialogs for monitored chat preflight")?
-    {
-        chat_ids.insert(dialog.peer_id().bot_api_dialog_id());
+    /// The requests a rewrite makes with this account, for running them off the update loop.
+    pub fn requests(&self) -> &TelegramRequests {
+        &self.requests
     }
-    Ok(chat_ids)
-}
 
-fn unresolved_monitored_chats(
-    monitored_chats: &HashSet<i64>,
-    known_chat_ids: &HashSet<i64>,
-) -> Vec<i64> {
-    let mut unresolved: Vec<i64> = monitored_chats
-        .iter()
-        .filter(|chat_id| !known_chat_ids.contains(chat_id))
-        .copied()
-        .collect();
-    unresolved.sort_unstable();
-    unresolved
-}
+    pub fn has_unresolved_chats(&self) -> bool {
+        !self.unresolved_chats.is_empty()
+    }
 
-pub fn message_topic_root_id(message: &TelegramMessage) -> Option<i32> {
-    if let Some(reply_header) = message_reply_header(message) {
-        if let Some(top_id) = reply_header.reply_to_top_id {
-            return Some(top_id);
+    /// Reloads dialogs to look for unresolved monitored chats again. Returns the chats that
+    /// became resolvable, which are monitored from now on.
+    pub async fn resolve_pending_chats(&mut self) -> Vec<i64> {
+        if self.unresolved_chats.is_empty() || self.is_bot {
+            return Vec::new();
         }
-        if reply_header.forum_topic {
-            // Some forum-topic replies may not include reply_to_top_id.
-            if let Some(reply_to_id) = reply_header.reply_to_msg_id {
-                return Some(reply_to_id);
+        let resolved = match scan_dialogs(&self.requests.client, Some(&self.unresolved_chats)).await
+        {
+            Ok(dialogs) => self.apply_dialogs(dialogs),
+            Err(err) => {
+                warn!(error = %err, "failed to reload dialogs for unresolved chats");
+                return Vec::new();
             }
+        };
+        self.backfill_topic_names().await;
+        resolved
+    }
+
+    /// Caches fresh dialogs and recomputes the unresolved chats, returning the ones that
+    /// were unresolved before and are dialogs now. A complete scan replaces the cached
+    /// dialogs and the [`DialogCache`]; a partial one is added to them.
+    fn apply_dialogs(&mut self, dialogs: Dialogs) -> Vec<i64> {
+        if dialogs.complete {
+            save_dialog_cache(
+                &dialog_cache_path(&self.telegram_config.session_file),
+                &dialogs,
+            );
+            self.chat_titles = dialogs.titles;
+            self.dialog_peers = dialogs.peers;
+            self.requests.own_personas = Arc::new(dialogs.administered);
+            self.forum_chats = dialogs.forums;
+        } else {
+            self.chat_titles.extend(dialogs.titles);
+            self.dialog_peers.extend(dialogs.peers);
+            Arc::make_mut(&mut self.requests.own_personas).extend(dialogs.administered);
+            self.forum_chats.extend(dialogs.forums);
         }
+        let known_chat_ids: HashSet<i64> = self.chat_titles.keys().copied().collect();
+        self.monitored_chats = normalize_chat_ids(&self.monitored_chats, &known_chat_ids);
+        let resolved = newly_resolved_chats(&self.unresolved_chats, &known_chat_ids);
+        self.unresolved_chats = unresolved_monitored_chats(&self.monitored_chats, &known_chat_ids)
+            .into_iter()
+            .collect();
+        resolved
     }
 
-    if matches!(
-        message.action(),
-        Some(tl::enums::MessageAction::TopicCreate(_))
-    ) {
-        return Some(message.id());
+    /// Caches the topic name carried by a topic creation or rename service message.
+    pub fn remember_topic_name(&self, chat_id: i64, message: &TelegramMessage) {
+        self.requests.remember_topic_name(chat_id, message);
     }
 
-    None
-}
+    /// The name of the forum topic rooted at `topic_root_id`, if it is known.
+    pub fn topic_name(&self, chat_id: i64, topic_root_id: i32) -> Option<String> {
+        self.requests.topic_name(chat_id, topic_root_id)
+    }
 
-fn message_reply_header(message: &TelegramMessage) -> Option<&tl::types::MessageReplyHeader> {
-    let reply_to = match &message.raw {
-        tl::enums::Message::Message(raw) => raw.reply_to.as_ref(),
-        tl::enums::Message::Service(raw) => raw.reply_to.as_ref(),
-        tl::enums::Message::Empty(_) => None,
-    }?;
+    /// Loads the topic names of monitored forums that haven't been loaded yet. A forum whose
+    /// topics fail to load is tried again on the next reload.
+    async fn backfill_topic_names(&self) {
+        let pending: Vec<(i64, PeerRef)> = {
+            let topic_names = self
+                .requests
+                .topic_names
+                .lock()
+                .expect("topic names mutex poisoned");
+            self.monitored_chats
+                .iter()
+                .filter(|&&chat_id| {
+                    self.forum_chats.contains(&chat_id) && !topic_names.is_backfilled(chat_id)
+                })
+                .filter_map(|&chat_id| Some((chat_id, *self.dialog_peers.get(&chat_id)?)))
+                .collect()
+        };
+        for (chat_id, peer_ref) in pending {
+            match fetch_forum_topics(&self.requests.client, peer_ref).await {
+                Ok(topics) => {
+                    debug!(chat_id, topics = topics.len(), "loaded forum topic names");
+                    self.requests
+                        .topic_names
+                        .lock()
+                        .expect("topic names mutex poisoned")
+                        .backfill(
+                            chat_id,
+                            topics.into_iter().map(|topic| (topic.root_id, topic.title)),
+                        );
+                }
+                Err(err) => warn!(chat_id, error = %err, "failed to load forum topic names"),
+            }
+        }
+    }
 
-    match reply_to {
-        tl::enums::MessageReplyHeader::Header(header) => Some(header),
-        tl::enums::MessageReplyHeader::MessageReplyStoryHeader(_) => None,
+    /// `Chat: <title>, Topic: <name>` for the scope, leaving out names that aren't known.
+    pub fn chat_metadata(&self, chat_id: i64, topic_root_id: Option<i32>) -> Option<String> {
+        let topic_name =
+            topic_root_id.and_then(|topic_root_id| self.topic_name(chat_id, topic_root_id));
+        chat_metadata_line(
+            self.chat_titles.get(&chat_id).map(String::as_str),
+            topic_name.as_deref(),
+        )
     }
-}
 
-fn context_scan_limit(count: usize) -> usize {
-    count
-        .saturating_mul(CONTEXT_SCAN_FACTOR)
-        .max(CONTEXT_SCAN_MIN_MESSAGES)
-}
+    /// The slow mode interval of `chat_id` as read at startup; zero when it has none.
+    pub fn slow_mode_window(&self, chat_id: i64) -> Duration {
+        self.slow_modes
+            .get(&chat_id)
+            .copied()
+            .unwrap_or(Duration::ZERO)
+    }
 
-async fn connect_and_auth(config: &TelegramConfig) -> Result<ConnectionParts> {
-    let session = Arc::new(
-        SqliteSession::open(&config.session_file)
-            .await
-            .with_context(|| {
-                format!(
-                    "failed to open session db: {}",
-                    config.session_file.display()
-                )
-            })?,
-    );
+    /// The monitored chats, in Bot API form once the dialogs have been loaded.
+    pub fn monitored_chats(&self) -> &HashSet<i64> {
+        &self.monitored_chats
+    }
 
-    let pool = SenderPool::new(Arc::clone(&session), config.api_id);
-    let client = Client::new(pool.handle.clone());
-    let SenderPool {
-        runner,
-        updates,
-        handle,
-    } = pool;
-    let pool_task = tokio::spawn(runner.run());
+    pub fn is_monitored_chat(&self, chat_id: i64) -> bool {
+        self.monitored_chats.contains(&chat_id) && !self.unresolved_chats.contains(&chat_id)
+    }
 
-    if !client
-        .is_authorized()
-        .await
-        .context("failed to check Telegram authorization")?
-    {
-        info!("session not authorized; starting interactive Telegram login");
-        sign_in_interactively(&client, &config.api_hash).await?;
+    /// Monitors `new_id` in place of the monitored basic group `old_id` that migrated to it,
+    /// also across reloads that still list `old_id`. Returns whether anything changed.
+    pub fn migrate_chat(&mut self, old_id: i64, new_id: i64) -> bool {
+        if !self.monitored_chats.contains(&old_id) || self.monitored_chats.contains(&new_id) {
+            return false;
+        }
+        self.monitored_chats.insert(new_id);
+        self.migrated_chats.insert(old_id, new_id);
+        true
     }
 
+    pub(crate) fn client_clone(&self) -> Client {
+        self.requests.client.clone()
+    }
+
+    /// Shows "typing" in the chat, or in the forum topic when `topic_root_id` is set.
+    pub async fn set_typing(&self, peer: PeerRef, topic_root_id: Option<i32>) -> Result<()> {
+        send_typing(&self.requests.client, peer, topic_root_id).await
+    }
+
+    /// Fetches one message of a dialog by id; `None` when it doesn't exist.
+    pub async fn get_message(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+    ) -> Result<Option<TelegramMessage>> {
+        self.requests
+            .get_message(self.dialog_peer(chat_id)?, message_id)
+            .await
+    }
+
+    /// The peer of one of this session's dialogs, for the [`TelegramRequests`] that take one.
+    pub fn dialog_peer(&self, chat_id: i64) -> Result<PeerRef> {
+        self.dialog_peers
+            .get(&chat_id)
+            .copied()
+            .with_context(|| format!("chat {chat_id} is not one of this session's dialogs"))
+    }
+
+    /// Tears down the sender pool and update stream and connects again, catching up on the
+    /// updates missed in between. Monitored chats and dialog data are kept.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        self.reconnect_with(false).await
+    }
+
+    /// Rebuilds the connection, logging in first when `sign_in` is set and the session
+    /// isn't authorized.
+    async fn reconnect_with(&mut self, sign_in: bool) -> Result<()> {
+        if let Some(updates) = self.updates.take() {
+            updates.sync_update_state().await;
+        }
+        self.pool_handle.quit();
+        if let Some(pool_task) = self.pool_task.take()
+            && let Err(err) = pool_task.await
+        {
+            warn!(error = %err, "previous Telegram sender pool task failed");
+        }
+
+        let ConnectionParts {
+            client,
+            updates_rx,
+            pool_handle,
+            pool_task,
+        } = connect_and_auth(&self.telegram_config, self.proxy.as_deref(), sign_in).await?;
+        let updates = client
+            .stream_updates(
+                updates_rx,
+                UpdatesConfiguration {
+                    catch_up: true,
+                    update_queue_limit: Some(UPDATE_QUEUE_LIMIT),
+                },
+            )
+            .await;
+        self.requests.client = client;
+        self.updates = Some(updates);
+        self.pool_handle = pool_handle;
+        self.pool_task = Some(pool_task);
+        info!("reconnected telegram update stream with catch-up");
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if let Some(updates) = self.updates.as_ref() {
+            updates.sync_update_state().await;
+        }
+        self.pool_handle.quit();
+        if let Some(pool_task) = self.pool_task.as_mut() {
+            pool_task
+                .await
+                .context("failed waiting for Telegram sender pool task")?;
+            self.pool_task = None;
+        }
+        Ok(())
+    }
+
+    /// Aborts the sender pool task when [`TelegramBot::shutdown`] didn't finish it. Returns
+    /// whether one was still running.
+    pub fn abort(&mut self) -> bool {
+        self.pool_handle.quit();
+        let Some(pool_task) = self.pool_task.take() else {
+            return false;
+        };
+        let running = !pool_task.is_finished();
+        pool_task.abort();
+        running
+    }
+}
+
+impl TelegramRequests {
+    fn new(
+        client: Client,
+        self_chat_id: Option<i64>,
+        own_personas: HashSet<i64>,
+        config: &TelegramConfig,
+    ) -> Self {
+        Self {
+            client,
+            self_chat_id,
+            own_personas: Arc::new(own_personas),
+            topic_names: Arc::default(),
+            reply_targets: Arc::default(),
+            reply_senders: Arc::default(),
+            sender_names: Arc::new(Mutex::new(SenderNameCache::new(Duration::from_secs(
+                config.sender_name_ttl_seconds,
+            )))),
+            session_revoked: Arc::default(),
+        }
+    }
+
+    /// Whether we wrote the message, as ourselves or posting as a peer we administer.
+    pub fn is_own_message(&self, message: &TelegramMessage) -> bool {
+        message_is_own(message, &self.own_personas)
+    }
+
+    /// Caches the topic name carried by a topic creation or rename service message.
+    pub fn remember_topic_name(&self, chat_id: i64, message: &TelegramMessage) {
+        let Some((topic_root_id, name)) = topic_name_update(message) else {
+            return;
+        };
+        self.topic_names
+            .lock()
+            .expect("topic names mutex poisoned")
+            .insert(chat_id, topic_root_id, name);
+    }
+
+    /// The name of the forum topic rooted at `topic_root_id`, if it is known.
+    pub fn topic_name(&self, chat_id: i64, topic_root_id: i32) -> Option<String> {
+        self.topic_names
+            .lock()
+            .expect("topic names mutex poisoned")
+            .get(chat_id, topic_root_id)
+            .map(str::to_owned)
+    }
+
+    /// Keeps "typing" showing in the message's chat and topic until the returned guard is
+    /// dropped. Failures to send the action are only logged at debug level.
+    pub async fn start_typing(
+        &self,
+        message: &TelegramMessage,
+        topic_root_id: Option<i32>,
+    ) -> Result<TypingIndicator> {
+        let peer = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for typing action")?;
+        let client = self.client.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TYPING_ACTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = send_typing(&client, peer, topic_root_id).await {
+                    debug!(error = %err, "failed to send typing action");
+                }
+            }
+        });
+        Ok(TypingIndicator { task })
+    }
+
+    /// Edits the message to `new_text` parsed under `parse_mode`; returns it without markup.
+    pub async fn edit_message(
+        &self,
+        message: &TelegramMessage,
+        new_text: &str,
+        parse_mode: ParseMode,
+        link_preview: LinkPreview,
+    ) -> Result<String> {
+        let (input, text) = formatted_input(message.id(), new_text, parse_mode);
+        let input = input.link_preview(link_preview_enabled(
+            link_preview,
+            message_has_link_preview(message),
+        ));
+        self.edit_with(message, input).await?;
+        Ok(text)
+    }
+
+    /// Edits the message, retrying transient errors a few times with a short backoff. An edit
+    /// to the text the message already has (`MESSAGE_NOT_MODIFIED`) counts as applied.
+    /// Failures carry a [`FloodWait`] or an [`EditFailure`] the caller can downcast to.
+    async fn edit_with(&self, message: &TelegramMessage, input: InputMessage) -> Result<()> {
+        let message_id = message.id();
+        let peer = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for Telegram message edit")?;
+
+        let mut attempt = 1;
+        loop {
+            let err = match self
+                .client
+                .edit_message(peer, message_id, input.clone())
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if is_message_not_modified(&err) {
+                debug!(
+                    message_id,
+                    "telegram message already has the rewritten text; treating edit as applied"
+                );
+                return Ok(());
+            }
+            if let Some(seconds) = flood_wait_seconds(&err) {
+                return Err(FloodWait { seconds }.into());
+            }
+            if let Some(seconds) = slow_mode_wait_seconds(&err) {
+                return Err(SlowModeWait { seconds }.into());
+            }
+            note_session_revoked(&self.session_revoked, &err);
+            let failure = classify_edit_error(&err);
+            if let Some(backoff) = transient_edit_backoff(failure, attempt) {
+                debug!(
+                    message_id,
+                    attempt,
+                    backoff_ms = backoff.as_millis() as u64,
+                    error = %err,
+                    "transient telegram error while editing; retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                continue;
+            }
+            return Err(anyhow::Error::new(err).context(failure));
+        }
+    }
+
+    /// Sends `text` to the message's chat, replying to what it replied to or, in forum
+    /// topics, to the topic root so it lands in the same topic.
+    pub async fn send_in_scope(
+        &self,
+        message: &TelegramMessage,
+        text: &str,
+        parse_mode: ParseMode,
+        link_preview: LinkPreview,
+    ) -> Result<SentMessage> {
+        let (input, text) = formatted_input(message.id(), text, parse_mode);
+        let input = input.link_preview(link_preview_enabled(
+            link_preview,
+            message_has_link_preview(message),
+        ));
+        let reply_to =
+            message_reply_to_message_id(message).or_else(|| message_topic_root_id(message));
+        let peer = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for Telegram message send")?;
+
+        let sent = match self
+            .client
+            .send_message(peer, input.reply_to(reply_to))
+            .await
+        {
+            Ok(sent) => sent,
+            Err(err) => {
+                if let Some(seconds) = slow_mode_wait_seconds(&err) {
+                    return Err(SlowModeWait { seconds }.into());
+                }
+                return Err(anyhow::Error::new(err).context("failed to send Telegram message"));
+            }
+        };
+        Ok(SentMessage {
+            id: sent.id(),
+            text,
+        })
+    }
+
+    /// Replaces the text of a scheduled message before it is sent; returns it without markup.
+    pub async fn edit_scheduled_message(
+        &self,
+        peer: PeerRef,
+        scheduled: &ScheduledMessage,
+        text: &str,
+        parse_mode: ParseMode,
+    ) -> Result<String> {
+        let (text, entities) = parse_formatted(scheduled.message_id, text, parse_mode);
+        let entities = (!entities.is_empty()).then_some(entities);
+        self.client
+            .invoke(&tl::functions::messages::EditMessage {
+                no_webpage: false,
+                invert_media: false,
+                peer: peer.into(),
+                id: scheduled.message_id,
+                message: Some(text.clone()),
+                media: None,
+                reply_markup: None,
+                entities,
+                schedule_date: Some(scheduled.schedule_unix),
+                quick_reply_shortcut_id: None,
+            })
+            .await
+            .context("failed to edit scheduled Telegram message")?;
+        Ok(text)
+    }
+
+    /// Fetches one message of the dialog `peer` by id; `None` when it doesn't exist.
+    pub async fn get_message(
+        &self,
+        peer: PeerRef,
+        message_id: i32,
+    ) -> Result<Option<TelegramMessage>> {
+        let mut messages = self
+            .client
+            .get_messages_by_id(peer, &[message_id])
+            .await
+            .context("failed to fetch Telegram message by id")?;
+        Ok(messages.pop().flatten())
+    }
+
+    /// Removes our reactions from a message of the dialog `peer`.
+    pub async fn clear_reaction(&self, peer: PeerRef, message_id: i32) -> Result<()> {
+        self.client
+            .invoke(&tl::functions::messages::SendReaction {
+                big: false,
+                add_to_recent: false,
+                peer: peer.into(),
+                msg_id: message_id,
+                reaction: Some(Vec::new()),
+            })
+            .await
+            .context("failed to remove Telegram reaction")?;
+        Ok(())
+    }
+
+    pub async fn delete_message(&self, message: &TelegramMessage) -> Result<()> {
+        let peer = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for Telegram message delete")?;
+
+        let deleted = self
+            .client
+            .delete_messages(peer, &[message.id()])
+            .await
+            .context("failed to delete Telegram message")?;
+        if deleted == 0 {
+            bail!("Telegram reported no message deleted");
+        }
+        Ok(())
+    }
+
+    /// The message `reply_to_id` in the chat of `message`, fetched by id and cached. `None`
+    /// when it no longer exists or has no text.
+    pub async fn fetch_reply_target(
+        &self,
+        message: &TelegramMessage,
+        reply_to_id: i32,
+    ) -> Result<Option<ContextMessage>> {
+        let chat_id = message.peer_id().bot_api_dialog_id();
+        if let Some(cached) = self
+            .reply_targets
+            .lock()
+            .expect("reply targets mutex poisoned")
+            .get(&(chat_id, reply_to_id))
+        {
+            return Ok(Some(cached.clone()));
+        }
+
+        let peer_ref: PeerRef = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for fetching the replied-to message")?;
+        let fetched = self
+            .client
+            .get_messages_by_id(peer_ref, &[reply_to_id])
+            .await
+            .context("failed to fetch the replied-to message")?;
+        let Some(target) = fetched.into_iter().flatten().next() else {
+            return Ok(None);
+        };
+        let text = target.text().trim().to_owned();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let scanned = self.scanned_message(&target);
+        let reply_target = ContextMessage {
+            sender_name: self.resolve_scanned_sender(peer_ref, &scanned).await,
+            text,
+            is_own: scanned.entry.message.is_own,
+        };
+
+        let mut reply_targets = self
+            .reply_targets
+            .lock()
+            .expect("reply targets mutex poisoned");
+        if reply_targets.len() >= REPLY_TARGET_CACHE_LIMIT {
+            reply_targets.clear();
+        }
+        reply_targets.insert((chat_id, reply_to_id), reply_target.clone());
+        Ok(Some(reply_target))
+    }
+
+    /// What [`Self::reply_sender_id`] answers from its cache alone.
+    pub fn cached_reply_sender_id(&self, chat_id: i64, reply_to_id: i32) -> Option<Option<i64>> {
+        self.reply_senders
+            .lock()
+            .expect("reply senders mutex poisoned")
+            .get(&(chat_id, reply_to_id))
+            .copied()
+    }
+
+    /// The user who sent message `reply_to_id` in the chat of `message`, fetched by id and
+    /// cached. `None` when it no longer exists or wasn't sent by a user.
+    pub async fn reply_sender_id(
+        &self,
+        message: &TelegramMessage,
+        reply_to_id: i32,
+    ) -> Result<Option<i64>> {
+        let chat_id = message.peer_id().bot_api_dialog_id();
+        if let Some(cached) = self.cached_reply_sender_id(chat_id, reply_to_id) {
+            return Ok(cached);
+        }
+
+        let peer_ref: PeerRef = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for fetching the replied-to message")?;
+        let fetched = self
+            .client
+            .get_messages_by_id(peer_ref, &[reply_to_id])
+            .await
+            .context("failed to fetch the replied-to message")?;
+        let sender = fetched.into_iter().flatten().next().and_then(|target| {
+            if self.is_own_message(&target) {
+                self.self_chat_id
+            } else {
+                message_sender_user_id(&target)
+            }
+        });
+
+        let mut reply_senders = self
+            .reply_senders
+            .lock()
+            .expect("reply senders mutex poisoned");
+        if reply_senders.len() >= REPLY_TARGET_CACHE_LIMIT {
+            reply_senders.clear();
+        }
+        reply_senders.insert((chat_id, reply_to_id), sender);
+        Ok(sender)
+    }
+
+    /// Up to `count` messages before `message` in its topic, oldest first. Service messages
+    /// are left out unless `include_service_messages` is set.
+    pub async fn fetch_context(
+        &self,
+        message: &TelegramMessage,
+        count: usize,
+        target_topic_root_id: Option<i32>,
+        include_service_messages: bool,
+    ) -> Result<Vec<ContextEntry>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let peer_ref: PeerRef = message
+            .peer_ref()
+            .await
+            .context("failed to resolve peer for fetching context")?;
+
+        let chat_id = message.peer_id().bot_api_dialog_id();
+        let message_id = message.id();
+        let max_scan = context_scan_limit(count);
+        let (scanned_messages, scanned) = collect_context(
+            |offset_id| {
+                let mut iter = self.client.iter_messages(peer_ref).offset_id(offset_id);
+                async move || {
+                    let Some(msg) = iter
+                        .next()
+                        .await
+                        .inspect_err(|err| note_session_revoked(&self.session_revoked, err))
+                        .context("failed while iterating messages for context")?
+                    else {
+                        return Ok(None);
+                    };
+                    self.remember_topic_name(chat_id, &msg);
+                    Ok(Some(self.scanned_message(&msg)))
+                }
+            },
+            message_id,
+            count,
+            target_topic_root_id,
+            include_service_messages,
+        )
+        .await?;
+
+        let mut messages = Vec::with_capacity(scanned_messages.len());
+        for scanned_message in &scanned_messages {
+            let mut entry = scanned_message.entry.clone();
+            entry.message.sender_name =
+                self.resolve_scanned_sender(peer_ref, scanned_message).await;
+            messages.push(entry);
+        }
+
+        if scanned >= max_scan && messages.len() < count {
+            info!(
+                message_id,
+                target_topic_root_id = ?target_topic_root_id,
+                requested_context_messages = count,
+                scanned_messages = scanned,
+                scan_limit = max_scan,
+                fetched_context_messages = messages.len(),
+                "stopped context fetch after scan limit"
+            );
+        }
+
+        Ok(messages)
+    }
+
+    /// Display name of `message`'s sender for context lines, looking it up when Telegram
+    /// sent the message without one. Falls back to "Unknown".
+    pub async fn sender_name(&self, message: &TelegramMessage) -> String {
+        let scanned = self.scanned_message(message);
+        if scanned.sender_user_id.is_none() || scanned.entry.message.sender_name != UNKNOWN_SENDER {
+            return scanned.entry.message.sender_name;
+        }
+        match message.peer_ref().await {
+            Ok(chat) => self.resolve_scanned_sender(chat, &scanned).await,
+            Err(err) => {
+                debug!(error = %err, "failed to resolve chat for sender lookup");
+                scanned.entry.message.sender_name
+            }
+        }
+    }
+
+    /// What [`Self::sender_name`] answers without a request.
+    pub fn known_sender_name(&self, message: &TelegramMessage) -> Option<String> {
+        let scanned = self.scanned_message(message);
+        let Some(user_id) = scanned.sender_user_id else {
+            return Some(scanned.entry.message.sender_name);
+        };
+        if scanned.entry.message.sender_name != UNKNOWN_SENDER {
+            return Some(scanned.entry.message.sender_name);
+        }
+        let cached = self
+            .sender_names
+            .lock()
+            .expect("sender names mutex poisoned")
+            .get(user_id, Instant::now())?;
+        Some(resolve_sender_name(false, cached.as_deref()))
+    }
+
+    /// Reduces `message` for context selection, remembering its sender's name when it
+    /// came with one.
+    fn scanned_message(&self, message: &TelegramMessage) -> ScannedMessage {
+        let scanned = scanned_message(message, self.is_own_message(message));
+        if let Some(user_id) = scanned.sender_user_id
+            && !scanned.entry.message.is_own
+            && scanned.entry.message.sender_name != UNKNOWN_SENDER
+        {
+            self.sender_names
+                .lock()
+                .expect("sender names mutex poisoned")
+                .insert(
+                    user_id,
+                    Some(scanned.entry.message.sender_name.clone()),
+                    Instant::now(),
+                );
+        }
+        scanned
+    }
+
+    /// The scanned sender's name, looked up by user id when it is "Unknown".
+    async fn resolve_scanned_sender(&self, chat: PeerRef, scanned: &ScannedMessage) -> String {
+        let name = &scanned.entry.message.sender_name;
+        match scanned.sender_user_id {
+            Some(user_id) if name == UNKNOWN_SENDER => {
+                let looked_up = self
+                    .lookup_sender_name(chat, scanned.entry.message_id, user_id)
+                    .await;
+                resolve_sender_name(false, looked_up.as_deref())
+            }
+            _ => name.clone(),
+        }
+    }
+
+    /// Fetches the name of `user_id`, who sent `message_id` in `chat`. The message
+    /// reference stands in for the access hash, which senders without a name lack too.
+    async fn lookup_sender_name(
+        &self,
+        chat: PeerRef,
+        message_id: i32,
+        user_id: i64,
+    ) -> Option<String> {
+        if let Some(cached) = self
+            .sender_names
+            .lock()
+            .expect("sender names mutex poisoned")
+            .get(user_id, Instant::now())
+        {
+            return cached;
+        }
+        let users = match self
+            .client
+            .invoke(&tl::functions::users::GetUsers {
+                id: vec![
+                    tl::types::InputUserFromMessage {
+                        peer: chat.into(),
+                        msg_id: message_id,
+                        user_id,
+                    }
+                    .into(),
+                ],
+            })
+            .await
+        {
+            Ok(users) => users,
+            Err(err) => {
+                debug!(user_id, error = %err, "failed to look up message sender");
+                return None;
+            }
+        };
+        let name = users.iter().find_map(user_display_name);
+        self.sender_names
+            .lock()
+            .expect("sender names mutex poisoned")
+            .insert(user_id, name.clone(), Instant::now());
+        name
+    }
+}
+
+/// Titles and peers of this session's dialogs, keyed by chat id.
+#[derive(Default)]
+struct Dialogs {
+    titles: HashMap<i64, String>,
+    peers: HashMap<i64, PeerRef>,
+    /// Channels and supergroups where we are the creator or an admin.
+    administered: HashSet<i64>,
+    forums: HashSet<i64>,
+    /// Every dialog was scanned, rather than only those up to the last wanted chat.
+    complete: bool,
+}
+
+/// The dialog ids and administered chats found by the last complete dialog scan, saved
+/// next to the session file. When it lists every monitored chat, startup only scans
+/// dialogs as far as the monitored chats instead of all of them.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct DialogCache {
+    scanned_at: i64,
+    chat_ids: HashSet<i64>,
+    administered: HashSet<i64>,
+}
+
+impl DialogCache {
+    /// Whether every chat in `chats`, bare channel ids included, is a cached dialog.
+    fn covers(&self, chats: &HashSet<i64>) -> bool {
+        chats.iter().all(|&chat_id| {
+            ChatIdSpec::parse(chat_id)
+                .candidates()
+                .iter()
+                .any(|candidate| self.chat_ids.contains(candidate))
+        })
+    }
+}
+
+fn dialog_cache_path(session_file: &Path) -> PathBuf {
+    let mut path = session_file.as_os_str().to_owned();
+    path.push(".");
+    path.push(DIALOG_CACHE_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// The cache at `path`, unless it is missing, unreadable or older than
+/// [`DIALOG_CACHE_MAX_AGE`] at `now_unix`.
+fn load_dialog_cache(path: &Path, now_unix: i64) -> Option<DialogCache> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let cache: DialogCache = match serde_json::from_str(&contents) {
+        Ok(cache) => cache,
+        Err(err) => {
+            warn!(path = %path.display(), error = %err, "ignoring unreadable dialog cache");
+            return None;
+        }
+    };
+    let age = now_unix.saturating_sub(cache.scanned_at);
+    (age >= 0 && age.unsigned_abs() <= DIALOG_CACHE_MAX_AGE.as_secs()).then_some(cache)
+}
+
+fn save_dialog_cache(path: &Path, dialogs: &Dialogs) {
+    let cache = DialogCache {
+        scanned_at: unix_now(),
+        chat_ids: dialogs.titles.keys().copied().collect(),
+        administered: dialogs.administered.clone(),
+    };
+    let written = serde_json::to_string(&cache)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| std::fs::write(path, json).map_err(anyhow::Error::from));
+    if let Err(err) = written {
+        warn!(path = %path.display(), error = %err, "failed to save dialog cache");
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Checks which monitored chats are dialogs of this session and returns the dialogs with
+/// the chats that aren't. Those fail startup with `strict`, and are otherwise skipped with a
+/// warning until a later dialog reload finds them. Bare channel ids in `monitored_chats` are
+/// rewritten to their Bot API form first. With a [`DialogCache`] at `cache_path` listing
+/// every monitored chat, dialogs are only scanned until those turn up.
+async fn preflight_monitored_chats(
+    client: &Client,
+    monitored_chats: &mut HashSet<i64>,
+    strict: bool,
+    cache_path: &Path,
+) -> Result<(Dialogs, HashSet<i64>)> {
+    let dialogs = match load_dialog_cache(cache_path, unix_now()) {
+        Some(cache) if cache.covers(monitored_chats) => {
+            let mut dialogs = scan_dialogs(client, Some(&*monitored_chats)).await?;
+            if dialogs.complete {
+                save_dialog_cache(cache_path, &dialogs);
+            } else {
+                // Chats we administer past the last monitored one are only in the cache.
+                dialogs.administered.extend(cache.administered);
+            }
+            dialogs
+        }
+        _ => {
+            let dialogs = scan_dialogs(client, None).await?;
+            save_dialog_cache(cache_path, &dialogs);
+            dialogs
+        }
+    };
+    let known_chat_ids: HashSet<i64> = dialogs.titles.keys().copied().collect();
+    *monitored_chats = normalize_chat_ids(monitored_chats, &known_chat_ids);
+    let unresolved_chat_ids = unresolved_monitored_chats(monitored_chats, &known_chat_ids);
+    if !unresolved_chat_ids.is_empty() {
+        if strict {
+            bail!(
+                "monitored chat ids are not present in Telegram dialogs for this session: {:?}",
+                unresolved_chat_ids
+            );
+        }
+        warn!(
+            unresolved_chat_ids = ?unresolved_chat_ids,
+            "monitored chat ids are not present in Telegram dialogs for this session; rewriting the other chats and retrying these periodically"
+        );
+    }
+
+    info!(
+        monitored_chat_count = monitored_chats.len(),
+        unresolved_chat_count = unresolved_chat_ids.len(),
+        known_dialog_chat_count = known_chat_ids.len(),
+        "primed telegram peer cache for monitored chats"
+    );
+
+    Ok((dialogs, unresolved_chat_ids.into_iter().collect()))
+}
+
+/// Reads the slow mode interval of the monitored supergroups whose full info can be read.
+async fn load_slow_modes(
+    client: &Client,
+    monitored_chats: &HashSet<i64>,
+    dialog_peers: &HashMap<i64, PeerRef>,
+) -> HashMap<i64, Duration> {
+    let mut slow_modes = HashMap::new();
+    for &chat_id in monitored_chats {
+        let Some(&peer_ref) = dialog_peers.get(&chat_id) else {
+            continue;
+        };
+        let input_peer: tl::enums::InputPeer = peer_ref.into();
+        let tl::enums::InputPeer::Channel(channel) = input_peer else {
+            continue;
+        };
+        let full = client
+            .invoke(&tl::functions::channels::GetFullChannel {
+                channel: tl::types::InputChannel {
+                    channel_id: channel.channel_id,
+                    access_hash: channel.access_hash,
+                }
+                .into(),
+            })
+            .await;
+        match full {
+            Ok(tl::enums::messages::ChatFull::Full(full)) => {
+                if let tl::enums::ChatFull::ChannelFull(channel_full) = full.full_chat
+                    && let Some(seconds) =
+                        channel_full.slowmode_seconds.filter(|&seconds| seconds > 0)
+                {
+                    info!(
+                        chat_id,
+                        slow_mode_seconds = seconds,
+                        "monitored chat has slow mode"
+                    );
+                    slow_modes.insert(chat_id, Duration::from_secs(seconds as u64));
+                }
+            }
+            Err(err) => debug!(chat_id, error = %err, "failed to read slow mode of monitored chat"),
+        }
+    }
+    slow_modes
+}
+
+/// Iterates this session's dialogs, stopping once every chat in `wanted` has been seen.
+/// Without `wanted`, or when some of it isn't a dialog, every dialog is scanned.
+async fn scan_dialogs(client: &Client, wanted: Option<&HashSet<i64>>) -> Result<Dialogs> {
+    let started = Instant::now();
+    let mut iter = client.iter_dialogs();
+    let mut dialogs = Dialogs::default();
+    let all_wanted_seen = |dialogs: &Dialogs| {
+        wanted.is_some_and(|wanted| {
+            wanted.iter().all(|&chat_id| {
+                ChatIdSpec::parse(chat_id)
+                    .candidates()
+                    .iter()
+                    .any(|candidate| dialogs.titles.contains_key(candidate))
+            })
+        })
+    };
+    let mut scanned = 0usize;
+    let mut complete = false;
+    while !all_wanted_seen(&dialogs) {
+        let Some(dialog) = iter
+            .next()
+            .await
+            .context("failed while iterating dialogs for monitored chat preflight")?
+        else {
+            complete = true;
+            break;
+        };
+        scanned += 1;
+        let chat_id = dialog.peer_id().bot_api_dialog_id();
+        let title = dialog.peer().name().unwrap_or_default().trim().to_owned();
+        dialogs.titles.insert(chat_id, title);
+        if is_administered(dialog.peer()) {
+            dialogs.administered.insert(chat_id);
+        }
+        if is_forum(dialog.peer()) {
+            dialogs.forums.insert(chat_id);
+        }
+        if let Some(peer_ref) = dialog.peer().to_ref() {
+            dialogs.peers.insert(chat_id, peer_ref);
+        }
+    }
+    dialogs.complete = complete;
+    info!(
+        dialogs_scanned = scanned,
+        complete,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "scanned telegram dialogs"
+    );
+    Ok(dialogs)
+}
+
+/// Whether we can post as the chat: a channel or supergroup we created or administer.
+fn is_administered(peer: &Peer) -> bool {
+    match peer {
+        Peer::Group(group) => matches!(
+            &group.raw,
+            tl::enums::Chat::Channel(channel) if channel.creator || channel.admin_rights.is_some()
+        ),
+        Peer::Channel(channel) => channel.raw.creator || channel.raw.admin_rights.is_some(),
+        Peer::User(_) => false,
+    }
+}
+
+/// Resolves each of `links` missing from `resolved`, joining its chat first when needed.
+/// A link that fails is reported and tried again on the next call.
+async fn join_chat_links(
+    client: &Client,
+    links: &[ChatLink],
+    resolved: &mut HashMap<ChatLink, i64>,
+) {
+    for link in links {
+        if resolved.contains_key(link) {
+            continue;
+        }
+        match join_chat_link(client, link).await {
+            Ok(chat_id) => {
+                info!(
+                    link = %link,
+                    chat_id,
+                    "resolved chat link; it can be replaced with the chat id in rewrite.chats"
+                );
+                resolved.insert(link.clone(), chat_id);
+            }
+            Err(err) => {
+                warn!(link = %link, error = %err, "failed to join chat from link; skipping it")
+            }
+        }
+    }
+}
+
+/// The Bot API id of the chat behind `link`, joining it unless we are a member already.
+async fn join_chat_link(client: &Client, link: &ChatLink) -> Result<i64> {
+    match link {
+        ChatLink::Invite(hash) => {
+            let invite = client
+                .invoke(&tl::functions::messages::CheckChatInvite { hash: hash.clone() })
+                .await
+                .context("failed to check the invite link")?;
+            if let tl::enums::ChatInvite::Already(already) = invite {
+                return Ok(tl_chat_dialog_id(&already.chat));
+            }
+            let updates = client
+                .invoke(&tl::functions::messages::ImportChatInvite { hash: hash.clone() })
+                .await
+                .map_err(join_error)?;
+            joined_chat_id(&updates).context("joining returned no chat")
+        }
+        ChatLink::Username(username) => {
+            let peer = client
+                .resolve_username(username)
+                .await
+                .context("failed to resolve the username")?
+                .with_context(|| format!("no chat is named @{username}"))?;
+            let peer_ref = peer
+                .to_ref()
+                .with_context(|| format!("@{username} has no usable access hash"))?;
+            let tl::enums::InputPeer::Channel(channel) = peer_ref.into() else {
+                bail!("@{username} is not a group or channel");
+            };
+            let joined = client
+                .invoke(&tl::functions::channels::JoinChannel {
+                    channel: tl::types::InputChannel {
+                        channel_id: channel.channel_id,
+                        access_hash: channel.access_hash,
+                    }
+                    .into(),
+                })
+                .await;
+            match joined {
+                Ok(_) => {}
+                Err(InvocationError::Rpc(rpc)) if rpc.name == "USER_ALREADY_PARTICIPANT" => {}
+                Err(err) => return Err(join_error(err)),
+            }
+            Ok(peer.id().bot_api_dialog_id())
+        }
+    }
+}
+
+fn join_error(err: InvocationError) -> anyhow::Error {
+    match &err {
+        InvocationError::Rpc(rpc) if rpc.name == "INVITE_REQUEST_SENT" => anyhow::anyhow!(
+            "a join request was sent; the chat is monitored once an admin approves it and the config is reloaded"
+        ),
+        InvocationError::Rpc(rpc) if rpc.name == "INVITE_HASH_EXPIRED" => {
+            anyhow::anyhow!("the invite link has expired")
+        }
+        _ => anyhow::Error::new(err).context("failed to join the chat"),
+    }
+}
+
+/// The chat a join's updates are about.
+fn joined_chat_id(updates: &tl::enums::Updates) -> Option<i64> {
+    let chats = match updates {
+        tl::enums::Updates::Updates(updates) => &updates.chats,
+        tl::enums::Updates::Combined(updates) => &updates.chats,
+        _ => return None,
+    };
+    chats.first().map(tl_chat_dialog_id)
+}
+
+fn tl_chat_dialog_id(chat: &tl::enums::Chat) -> i64 {
+    match chat {
+        tl::enums::Chat::Empty(chat) => -chat.id,
+        tl::enums::Chat::Chat(chat) => -chat.id,
+        tl::enums::Chat::Forbidden(chat) => -chat.id,
+        tl::enums::Chat::Channel(channel) => channel_dialog_id(channel.id),
+        tl::enums::Chat::ChannelForbidden(channel) => channel_dialog_id(channel.id),
+    }
+}
+
+fn is_forum(peer: &Peer) -> bool {
+    matches!(
+        peer,
+        Peer::Group(group)
+            if matches!(&group.raw, tl::enums::Chat::Channel(channel) if channel.forum)
+    )
+}
+
+/// Topics of the forum behind `peer_ref`, by root id.
+async fn fetch_forum_topics(client: &Client, peer_ref: PeerRef) -> Result<Vec<ForumTopicItem>> {
+    let mut topics = Vec::new();
+    let (mut offset_date, mut offset_id, mut offset_topic) = (0, 0, 0);
+    loop {
+        let tl::enums::messages::ForumTopics::Topics(page) = client
+            .invoke(&tl::functions::channels::GetForumTopics {
+                channel: peer_ref.into(),
+                q: None,
+                offset_date,
+                offset_id,
+                offset_topic,
+                limit: FORUM_TOPICS_PAGE_SIZE,
+            })
+            .await
+            .context("failed to fetch Telegram forum topics")?;
+        let page_len = page.topics.len();
+        for topic in page.topics {
+            match topic {
+                tl::enums::ForumTopic::Topic(topic) => {
+                    (offset_date, offset_id, offset_topic) =
+                        (topic.date, topic.top_message, topic.id);
+                    topics.push(ForumTopicItem {
+                        root_id: topic.id,
+                        title: topic.title,
+                    });
+                }
+                tl::enums::ForumTopic::Deleted(deleted) => offset_topic = deleted.id,
+            }
+        }
+        if page_len < FORUM_TOPICS_PAGE_SIZE as usize
+            || topics.len() >= usize::try_from(page.count).unwrap_or_default()
+        {
+            break;
+        }
+    }
+    topics.sort_by_key(|topic| topic.root_id);
+    Ok(topics)
+}
+
+/// Sorted chats of `unresolved` that are in `known_chat_ids` now.
+fn newly_resolved_chats(unresolved: &HashSet<i64>, known_chat_ids: &HashSet<i64>) -> Vec<i64> {
+    let mut resolved: Vec<i64> = unresolved.intersection(known_chat_ids).copied().collect();
+    resolved.sort_unstable();
+    resolved
+}
+
+fn unresolved_monitored_chats(
+    monitored_chats: &HashSet<i64>,
+    known_chat_ids: &HashSet<i64>,
+) -> Vec<i64> {
+    let mut unresolved: Vec<i64> = monitored_chats
+        .iter()
+        .filter(|chat_id| !known_chat_ids.contains(chat_id))
+        .copied()
+        .collect();
+    unresolved.sort_unstable();
+    unresolved
+}
+
+pub fn message_topic_root_id(message: &TelegramMessage) -> Option<i32> {
+    if let Some(reply_header) = message_reply_header(message) {
+        if let Some(top_id) = reply_header.reply_to_top_id {
+            return Some(top_id);
+        }
+        if reply_header.forum_topic {
+            // Some forum-topic replies may not include reply_to_top_id.
+            if let Some(reply_to_id) = reply_header.reply_to_msg_id {
+                return Some(reply_to_id);
+            }
+        }
+    }
+
+    if matches!(
+        message.action(),
+        Some(tl::enums::MessageAction::TopicCreate(_))
+    ) {
+        return Some(message.id());
+    }
+
+    None
+}
+
+/// `(topic_root_id, name)` from a forum topic creation or rename service message.
+fn topic_name_update(message: &TelegramMessage) -> Option<(i32, String)> {
+    match message.action()? {
+        tl::enums::MessageAction::TopicCreate(create) => Some((message.id(), create.title.clone())),
+        tl::enums::MessageAction::TopicEdit(edit) => {
+            Some((message_topic_root_id(message)?, edit.title.clone()?))
+        }
+        _ => None,
+    }
+}
+
+/// Id of the message this one explicitly replies to, ignoring the implicit reply to a
+/// forum topic root that every topic message carries.
+pub fn message_reply_to_message_id(message: &TelegramMessage) -> Option<i32> {
+    let header = message_reply_header(message)?;
+    specific_reply_target(
+        header.reply_to_msg_id,
+        header.reply_to_top_id,
+        header.forum_topic,
+    )
+}
+
+/// Bot API dialog ids of channels and supergroups are `-100` followed by the channel id.
+const CHANNEL_DIALOG_ID_OFFSET: i64 = -1_000_000_000_000;
+
+/// A list entry for `peer`, without the unread count that only dialogs carry.
+fn chat_list_item(peer: &Peer) -> ChatListItem {
+    let id = peer.id().bot_api_dialog_id();
+    let (kind, is_forum, member_count) = match peer {
+        Peer::User(_) => (ChatKind::User, false, None),
+        Peer::Group(group) => match &group.raw {
+            tl::enums::Chat::Chat(chat) => (ChatKind::Group, false, Some(chat.participants_count)),
+            tl::enums::Chat::Channel(channel) => (
+                ChatKind::Supergroup,
+                channel.forum,
+                channel.participants_count,
+            ),
+            _ => (ChatKind::from_dialog_id(id), false, None),
+        },
+        Peer::Channel(channel) => (
+            if channel.raw.megagroup {
+                ChatKind::Supergroup
+            } else {
+                ChatKind::Channel
+            },
+            channel.raw.forum,
+            channel.raw.participants_count,
+        ),
+    };
+    let name = match peer {
+        Peer::User(user) if user.is_self() => SAVED_MESSAGES_TITLE.to_owned(),
+        _ => peer.name().unwrap_or_default().trim().to_owned(),
+    };
+    ChatListItem {
+        id,
+        name,
+        kind,
+        username: peer.username().map(str::to_owned),
+        is_forum,
+        member_count: member_count.and_then(|count| u32::try_from(count).ok()),
+        unread_count: None,
+    }
+}
+
+/// Keeps chats whose name or username contains `query` (case-insensitive, a leading `@`
+/// is ignored), sorted by name and then id.
+fn filter_chat_list(chats: Vec<ChatListItem>, query: Option<&str>) -> Vec<ChatListItem> {
+    let query = query.map(|value| value.trim_start_matches('@').to_lowercase());
+    let mut chats: Vec<(String, ChatListItem)> = chats
+        .into_iter()
+        .filter_map(|chat| {
+            let name_lower = chat.name.to_lowercase();
+            let matches = query.as_ref().is_none_or(|q| {
+                name_lower.contains(q)
+                    || chat
+                        .username
+                        .as_ref()
+                        .is_some_and(|username| username.to_lowercase().contains(q))
+            });
+            matches.then_some((name_lower, chat))
+        })
+        .collect();
+
+    chats.sort_by(|left, right| left.0.cmp(&right.0).then(left.1.id.cmp(&right.1.id)));
+    chats.into_iter().map(|(_, chat)| chat).collect()
+}
+
+pub fn channel_dialog_id(channel_id: i64) -> i64 {
+    CHANNEL_DIALOG_ID_OFFSET - channel_id
+}
+
+pub fn is_channel_dialog_id(chat_id: i64) -> bool {
+    chat_id < CHANNEL_DIALOG_ID_OFFSET
+}
+
+/// The bare channel id of a `-100…` Bot API id, as grammers-native tools show it.
+pub fn bare_channel_id(chat_id: i64) -> Option<i64> {
+    is_channel_dialog_id(chat_id).then(|| CHANNEL_DIALOG_ID_OFFSET - chat_id)
+}
+
+/// A chat id as written in config or on the command line, in Bot API form or as a bare
+/// channel id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatIdSpec {
+    /// Negative: a `-100…` channel or supergroup, or a basic group.
+    Canonical(i64),
+    /// Positive: a user, or a channel written without its `-100` prefix. The dialog list
+    /// tells them apart.
+    Ambiguous(i64),
+}
+
+impl ChatIdSpec {
+    pub fn parse(chat_id: i64) -> Self {
+        if chat_id > 0 {
+            Self::Ambiguous(chat_id)
+        } else {
+            Self::Canonical(chat_id)
+        }
+    }
+
+    /// The Bot API ids this may stand for, most likely first.
+    pub fn candidates(self) -> Vec<i64> {
+        match self {
+            Self::Canonical(chat_id) => vec![chat_id],
+            Self::Ambiguous(chat_id) => vec![chat_id, channel_dialog_id(chat_id)],
+        }
+    }
+
+    /// The Bot API id among `known_chat_ids`, or the first candidate when none is known.
+    pub fn normalize(self, known_chat_ids: &HashSet<i64>) -> i64 {
+        let candidates = self.candidates();
+        candidates
+            .iter()
+            .copied()
+            .find(|candidate| known_chat_ids.contains(candidate))
+            .unwrap_or(candidates[0])
+    }
+
+    /// Whether this may be the chat with Bot API id `chat_id`.
+    pub fn matches(self, chat_id: i64) -> bool {
+        self.candidates().contains(&chat_id)
+    }
+}
+
+/// `chats` with every bare channel id that names a known dialog in Bot API form.
+fn normalize_chat_ids(chats: &HashSet<i64>, known_chat_ids: &HashSet<i64>) -> HashSet<i64> {
+    chats
+        .iter()
+        .map(|&chat_id| {
+            let normalized = ChatIdSpec::parse(chat_id).normalize(known_chat_ids);
+            if normalized != chat_id {
+                info!(
+                    configured_chat_id = chat_id,
+                    chat_id = normalized,
+                    "bare channel id matched a dialog; using its Bot API id"
+                );
+            }
+            normalized
+        })
+        .collect()
+}
+
+fn peer_dialog_id(peer: &tl::enums::Peer) -> i64 {
+    match peer {
+        tl::enums::Peer::User(user) => user.user_id,
+        tl::enums::Peer::Chat(chat) => -chat.chat_id,
+        tl::enums::Peer::Channel(channel) => channel_dialog_id(channel.channel_id),
+    }
+}
+
+/// `(chat_id, message_id)` when the update shows our own `trigger` emoji reaction on a
+/// message. Reactions only chosen by other users are ignored.
+pub fn reaction_trigger_target(update: &Update, trigger: &str) -> Option<(i64, i32)> {
+    let Update::Raw(raw) = update else {
+        return None;
+    };
+    let tl_update: &tl::enums::Update = raw;
+    let tl::enums::Update::MessageReactions(reactions) = tl_update else {
+        return None;
+    };
+    let tl::enums::MessageReactions::Reactions(counts) = &reactions.reactions;
+    let chosen_by_us = counts.results.iter().any(|result| {
+        let tl::enums::ReactionCount::Count(count) = result;
+        count.chosen_order.is_some()
+            && matches!(
+                &count.reaction,
+                tl::enums::Reaction::Emoji(emoji) if emoji.emoticon == trigger
+            )
+    });
+    chosen_by_us.then(|| (peer_dialog_id(&reactions.peer), reactions.msg_id))
+}
+
+/// One of our messages waiting to be sent, from `UpdateNewScheduledMessage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledMessage {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub text: String,
+    /// The text with its formatting entities rendered as Markdown.
+    pub markdown: String,
+    /// When the message is scheduled to be sent.
+    pub schedule_unix: i32,
+}
+
+/// The scheduled message when the update announces one of ours being scheduled.
+pub fn scheduled_message(update: &Update) -> Option<ScheduledMessage> {
+    let Update::Raw(raw) = update else {
+        return None;
+    };
+    let tl_update: &tl::enums::Update = raw;
+    let tl::enums::Update::NewScheduledMessage(scheduled) = tl_update else {
+        return None;
+    };
+    let tl::enums::Message::Message(message) = &scheduled.message else {
+        return None;
+    };
+    message.out.then(|| ScheduledMessage {
+        chat_id: peer_dialog_id(&message.peer_id),
+        message_id: message.id,
+        text: message.message.clone(),
+        markdown: entities_to_markdown(
+            &message.message,
+            message.entities.as_deref().unwrap_or_default(),
+        ),
+        schedule_unix: message.date,
+    })
+}
+
+/// Stops the typing action task from [`TelegramBot::start_typing`] when dropped.
+pub struct TypingIndicator {
+    task: JoinHandle<()>,
+}
+
+impl Drop for TypingIndicator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn send_typing(client: &Client, peer: PeerRef, topic_root_id: Option<i32>) -> Result<()> {
+    client
+        .invoke(&tl::functions::messages::SetTyping {
+            peer: peer.into(),
+            top_msg_id: topic_root_id,
+            action: tl::types::SendMessageTypingAction {}.into(),
+        })
+        .await
+        .context("failed to send Telegram typing action")?;
+    Ok(())
+}
+
+/// A message sent by [`TelegramBot::send_in_scope`], with its text as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub id: i32,
+    pub text: String,
+}
+
+/// Telegram rejected an edit with `FLOOD_WAIT_X`; the edit may be retried after `seconds`.
+/// Returned inside the `anyhow::Error` from the edit methods, so callers can downcast to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloodWait {
+    pub seconds: u32,
+}
+
+impl fmt::Display for FloodWait {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "telegram asked to wait {}s before editing (FLOOD_WAIT)",
+            self.seconds
+        )
+    }
+}
+
+impl std::error::Error for FloodWait {}
+
+/// A `SLOWMODE_WAIT_X` refusal, inside the `anyhow::Error` of the send and edit methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowModeWait {
+    pub seconds: u32,
+}
+
+impl fmt::Display for SlowModeWait {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chat slow mode allows the next message in {}s (SLOWMODE_WAIT)",
+            self.seconds
+        )
+    }
+}
+
+impl std::error::Error for SlowModeWait {}
+
+/// Rewrites waiting out chat slow mode, one per chat; a newer one replaces the queued one.
+pub struct SlowModeQueue<T> {
+    chats: HashMap<i64, SlowModeSlot<T>>,
+}
+
+struct SlowModeSlot<T> {
+    next_send_at: tokio::time::Instant,
+    pending: Option<T>,
+}
+
+/// What [`SlowModeQueue::admit`] decided.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlowModeAdmission<T> {
+    /// Send now; the chat's next slow mode window is booked.
+    Now(T),
+    /// Held until `ready_at`, replacing the rewrite that was waiting there, if any.
+    Queued {
+        ready_at: tokio::time::Instant,
+        superseded: Option<T>,
+    },
+}
+
+impl<T> SlowModeQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            chats: HashMap::new(),
+        }
+    }
+
+    /// Lets `item` be sent now when the chat's window is open, or queues it until it opens.
+    pub fn admit(
+        &mut self,
+        chat_id: i64,
+        window: Duration,
+        now: tokio::time::Instant,
+        item: T,
+    ) -> SlowModeAdmission<T> {
+        if let Some(slot) = self.chats.get_mut(&chat_id)
+            && slot.next_send_at > now
+        {
+            let superseded = slot.pending.replace(item);
+            return SlowModeAdmission::Queued {
+                ready_at: slot.next_send_at,
+                superseded,
+            };
+        }
+        if window.is_zero() {
+            self.chats.remove(&chat_id);
+        } else {
+            self.chats.insert(
+                chat_id,
+                SlowModeSlot {
+                    next_send_at: now + window,
+                    pending: None,
+                },
+            );
+        }
+        SlowModeAdmission::Now(item)
+    }
+
+    /// Records a `SLOWMODE_WAIT` from Telegram: nothing more goes to `chat_id` for `wait`.
+    pub fn record_wait(&mut self, chat_id: i64, wait: Duration, now: tokio::time::Instant) {
+        let next_send_at = now + wait;
+        self.chats
+            .entry(chat_id)
+            .and_modify(|slot| slot.next_send_at = slot.next_send_at.max(next_send_at))
+            .or_insert(SlowModeSlot {
+                next_send_at,
+                pending: None,
+            });
+    }
+
+    /// The rewrite waiting for `chat_id`'s next window.
+    pub fn queued(&self, chat_id: i64) -> Option<&T> {
+        self.chats.get(&chat_id)?.pending.as_ref()
+    }
+
+    pub fn next_ready_at(&self) -> Option<tokio::time::Instant> {
+        self.chats
+            .values()
+            .filter(|slot| slot.pending.is_some())
+            .map(|slot| slot.next_send_at)
+            .min()
+    }
+
+    /// Removes and returns the queued rewrites whose window has opened at `now`.
+    pub fn take_ready(&mut self, now: tokio::time::Instant) -> Vec<T> {
+        self.chats
+            .values_mut()
+            .filter(|slot| slot.next_send_at <= now)
+            .filter_map(|slot| slot.pending.take())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chats
+            .values()
+            .filter(|slot| slot.pending.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for SlowModeQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How an edit failure other than [`FloodWait`] should be treated. Attached as context to
+/// the `anyhow::Error` from the edit methods, so callers can downcast to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditFailure {
+    /// Reconnects, internal server errors and timeouts; the edit may work if tried again.
+    Transient,
+    /// The message can no longer be edited by this account, e.g. it was deleted.
+    Permanent,
+    /// Anything else; trying again isn't expected to help.
+    Other,
+}
+
+impl fmt::Display for EditFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Transient => "transient",
+            Self::Permanent => "permanent",
+            Self::Other => "unexpected",
+        };
+        write!(f, "failed to edit Telegram message ({kind} error)")
+    }
+}
+
+fn classify_edit_error(err: &InvocationError) -> EditFailure {
+    match err {
+        InvocationError::Rpc(rpc) => classify_edit_rpc_error(rpc.code, &rpc.name),
+        InvocationError::Io(_) | InvocationError::Dropped => EditFailure::Transient,
+        _ => EditFailure::Other,
+    }
+}
+
+/// Classifies an RPC error by its code and name, as grammers reports them (`FLOOD_WAIT_X`
+/// arrives as name `FLOOD_WAIT`; it is handled separately as [`FloodWait`]).
+fn classify_edit_rpc_error(code: i32, name: &str) -> EditFailure {
+    if PERMANENT_EDIT_RPC_ERRORS.contains(&name) {
+        EditFailure::Permanent
+    } else if code == 500 || code == -503 || TRANSIENT_EDIT_RPC_ERRORS.contains(&name) {
+        EditFailure::Transient
+    } else {
+        EditFailure::Other
+    }
+}
+
+/// Backoff before the next attempt after `attempt` failed with `failure`, or `None` when the
+/// edit shouldn't be tried again right away.
+fn transient_edit_backoff(failure: EditFailure, attempt: u32) -> Option<Duration> {
+    (failure == EditFailure::Transient && attempt < TRANSIENT_EDIT_ATTEMPTS)
+        .then(|| TRANSIENT_EDIT_BACKOFF * 2u32.pow(attempt - 1))
+}
+
+/// Whether an update stream error means the connection to Telegram is gone, rather than
+/// one bad update.
+pub fn is_connection_lost(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<InvocationError>(),
+        Some(InvocationError::Io(_) | InvocationError::Dropped)
+    )
+}
+
+fn is_session_revoked_rpc_error(name: &str) -> bool {
+    SESSION_REVOKED_RPC_ERRORS.contains(&name)
+}
+
+fn note_session_revoked(session_revoked: &AtomicBool, err: &InvocationError) {
+    if matches!(err, InvocationError::Rpc(rpc) if is_session_revoked_rpc_error(&rpc.name)) {
+        session_revoked.store(true, Ordering::Relaxed);
+    }
+}
+
+fn is_message_not_modified(err: &InvocationError) -> bool {
+    matches!(err, InvocationError::Rpc(rpc) if is_message_not_modified_rpc_error(&rpc.name))
+}
+
+fn is_message_not_modified_rpc_error(name: &str) -> bool {
+    name == "MESSAGE_NOT_MODIFIED"
+}
+
+fn slow_mode_wait_seconds(err: &InvocationError) -> Option<u32> {
+    match err {
+        InvocationError::Rpc(rpc) if rpc.name == "SLOWMODE_WAIT" => rpc.value,
+        _ => None,
+    }
+}
+
+fn flood_wait_seconds(err: &InvocationError) -> Option<u32> {
+    match err {
+        InvocationError::Rpc(rpc) if rpc.name == "FLOOD_WAIT" => rpc.value,
+        _ => None,
+    }
+}
+
+/// Parses the rewrite's markup into text and entities cut to Telegram's limit, or plain text.
+fn parse_formatted(
+    message_id: i32,
+    text: &str,
+    parse_mode: ParseMode,
+) -> (String, Vec<tl::enums::MessageEntity>) {
+    let parsed = match parse_mode {
+        ParseMode::Plain => return (text.to_owned(), Vec::new()),
+        ParseMode::Markdown => parse_markdown(text),
+        ParseMode::Html => parse_html(text),
+    };
+    let Some((parsed_text, entities)) = parsed else {
+        warn!(
+            message_id,
+            ?parse_mode,
+            "rewrite markup does not parse; sending it as plain text"
+        );
+        let (text, _) = truncate_rendered(text.to_owned(), Vec::new(), MESSAGE_TEXT_MAX_UTF16);
+        return (text, Vec::new());
+    };
+    truncate_rendered(parsed_text, entities, MESSAGE_TEXT_MAX_UTF16)
+}
+
+/// `text` without its `parse_mode` markup, as [`TelegramRequests::edit_message`] returns it.
+pub fn plain_text(text: &str, parse_mode: ParseMode) -> String {
+    let parsed = match parse_mode {
+        ParseMode::Plain => return text.to_owned(),
+        ParseMode::Markdown => parse_markdown(text),
+        ParseMode::Html => parse_html(text),
+    };
+    let (text, entities) = parsed.unwrap_or_else(|| (text.to_owned(), Vec::new()));
+    truncate_rendered(text, entities, MESSAGE_TEXT_MAX_UTF16).0
+}
+
+fn formatted_input(message_id: i32, text: &str, parse_mode: ParseMode) -> (InputMessage, String) {
+    let (text, entities) = parse_formatted(message_id, text, parse_mode);
+    (InputMessage::new().text(&text).fmt_entities(entities), text)
+}
+
+/// The message text with its formatting entities rendered as Markdown.
+pub fn message_markdown(message: &TelegramMessage) -> String {
+    let entities = match &message.raw {
+        tl::enums::Message::Message(raw) => raw.entities.as_deref().unwrap_or_default(),
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => &[],
+    };
+    entities_to_markdown(message.text(), entities)
+}
+
+/// Whether we wrote the message: Telegram marks it outgoing, or it was posted as one of
+/// `own_personas`, as channel posts and anonymous admin messages are. Posts other admins
+/// make as the same peer can't be told apart and count as ours too.
+pub fn message_is_own(message: &TelegramMessage, own_personas: &HashSet<i64>) -> bool {
+    message.outgoing()
+        || message_persona_chat_id(message).is_some_and(|chat_id| own_personas.contains(&chat_id))
+}
+
+/// The channel or group a message was posted as: a channel `from_id`, or the chat itself
+/// for channel posts that carry no sender.
+fn message_persona_chat_id(message: &TelegramMessage) -> Option<i64> {
+    let tl::enums::Message::Message(raw) = &message.raw else {
+        return None;
+    };
+    persona_chat_id(raw.from_id.as_ref(), &raw.peer_id, raw.post)
+}
+
+fn persona_chat_id(
+    from_id: Option<&tl::enums::Peer>,
+    peer_id: &tl::enums::Peer,
+    post: bool,
+) -> Option<i64> {
+    match from_id {
+        Some(tl::enums::Peer::Channel(channel)) => Some(channel_dialog_id(channel.channel_id)),
+        Some(_) => None,
+        None => post.then(|| peer_dialog_id(peer_id)),
+    }
+}
+
+/// Whether the message is a post in a broadcast channel.
+pub fn message_is_channel_post(message: &TelegramMessage) -> bool {
+    match &message.raw {
+        tl::enums::Message::Message(raw) => raw.post,
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => false,
+    }
+}
+
+/// Whether Telegram sent the message from the chat's scheduled messages.
+pub fn message_is_from_scheduled(message: &TelegramMessage) -> bool {
+    match &message.raw {
+        tl::enums::Message::Message(raw) => raw.from_scheduled,
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => false,
+    }
+}
+
+/// Whether the message is a service message: a pin, a join, a topic change and the like.
+pub fn message_is_service(message: &TelegramMessage) -> bool {
+    matches!(message.raw, tl::enums::Message::Service(_))
+}
+
+/// `(old_id, new_id)` when the message announces a basic group migrating to a supergroup.
+/// Both halves arrive: `ChatMigrateTo` in the old group and `ChannelMigrateFrom` in the new.
+pub fn chat_migration(message: &TelegramMessage) -> Option<(i64, i64)> {
+    let chat_id = message.peer_id().bot_api_dialog_id();
+    match message.action()? {
+        tl::enums::MessageAction::ChatMigrateTo(action) => {
+            Some((chat_id, channel_dialog_id(action.channel_id)))
+        }
+        tl::enums::MessageAction::ChannelMigrateFrom(action) => Some((-action.chat_id, chat_id)),
+        _ => None,
+    }
+}
+
+/// Whether Telegram shows a link preview under the message.
+fn message_has_link_preview(message: &TelegramMessage) -> bool {
+    match &message.raw {
+        tl::enums::Message::Message(raw) => {
+            matches!(raw.media, Some(tl::enums::MessageMedia::WebPage(_)))
+        }
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => false,
+    }
+}
+
+/// Whether the rewrite of a message is sent with a link preview, given `rewrite.link_preview`
+/// and whether the original had one.
+fn link_preview_enabled(setting: LinkPreview, original_has_preview: bool) -> bool {
+    match setting {
+        LinkPreview::Keep => original_has_preview,
+        LinkPreview::Disable => false,
+        LinkPreview::Enable => true,
+    }
+}
+
+/// URLs of the message's text links, which its text alone doesn't show.
+pub fn message_hidden_links(message: &TelegramMessage) -> Vec<String> {
+    let entities = match &message.raw {
+        tl::enums::Message::Message(raw) => raw.entities.as_deref().unwrap_or_default(),
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => &[],
+    };
+    entities
+        .iter()
+        .filter_map(|entity| match entity {
+            tl::enums::MessageEntity::TextUrl(link) => Some(link.url.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn message_is_forwarded(message: &TelegramMessage) -> bool {
+    match &message.raw {
+        tl::enums::Message::Message(raw) => raw.fwd_from.is_some(),
+        tl::enums::Message::Service(_) | tl::enums::Message::Empty(_) => false,
+    }
+}
+
+fn specific_reply_target(
+    reply_to_msg_id: Option<i32>,
+    reply_to_top_id: Option<i32>,
+    forum_topic: bool,
+) -> Option<i32> {
+    let reply_to_msg_id = reply_to_msg_id?;
+    if forum_topic && reply_to_top_id.is_none_or(|top_id| top_id == reply_to_msg_id) {
+        return None;
+    }
+    Some(reply_to_msg_id)
+}
+
+fn message_reply_header(message: &TelegramMessage) -> Option<&tl::types::MessageReplyHeader> {
+    let reply_to = match &message.raw {
+        tl::enums::Message::Message(raw) => raw.reply_to.as_ref(),
+        tl::enums::Message::Service(raw) => raw.reply_to.as_ref(),
+        tl::enums::Message::Empty(_) => None,
+    }?;
+
+    match reply_to {
+        tl::enums::MessageReplyHeader::Header(header) => Some(header),
+        tl::enums::MessageReplyHeader::MessageReplyStoryHeader(_) => None,
+    }
+}
+
+/// Marks album captions in context, so the model knows media came with them.
+const ALBUM_CONTEXT_PREFIX: &str = "[album]";
+
+/// Media albums arrive as one message per item sharing this id.
+pub fn message_grouped_id(message: &TelegramMessage) -> Option<i64> {
+    match &message.raw {
+        tl::enums::Message::Message(raw) => raw.grouped_id,
+        _ => None,
+    }
+}
+
+/// `text` as a context line for `message`: trimmed, and marked when it is an album caption.
+pub fn context_text(message: &TelegramMessage, text: &str) -> String {
+    mark_album_caption(text, message_grouped_id(message).is_some())
+}
+
+fn mark_album_caption(text: &str, is_album: bool) -> String {
+    let text = text.trim();
+    if is_album && !text.is_empty() {
+        format!("{ALBUM_CONTEXT_PREFIX} {text}")
+    } else {
+        text.to_owned()
+    }
+}
+
+/// A history message reduced to what context selection looks at.
+#[derive(Debug, Clone)]
+struct ScannedMessage {
+    topic_root_id: Option<i32>,
+    is_service: bool,
+    /// When the message was sent, in unix seconds.
+    unix: i64,
+    /// Set for messages sent by a user, so an "Unknown" sender can be looked up.
+    sender_user_id: Option<i64>,
+    entry: ContextEntry,
+}
+
+fn scanned_message(message: &TelegramMessage, is_own: bool) -> ScannedMessage {
+    let peer_name = message.sender().and_then(|p| p.name().map(str::to_owned));
+    ScannedMessage {
+        topic_root_id: message_topic_root_id(message),
+        is_service: message_is_service(message),
+        unix: message.date().timestamp(),
+        sender_user_id: message_sender_user_id(message),
+        entry: ContextEntry {
+            message_id: message.id(),
+            message: ContextMessage {
+                sender_name: resolve_sender_name(is_own, peer_name.as_deref()),
+                text: context_text(message, message.text()),
+                is_own,
+            },
+        },
+    }
+}
+
+/// The user who sent `message`: `from_id`, or the chat itself in a private chat.
+fn message_sender_user_id(message: &TelegramMessage) -> Option<i64> {
+    let tl::enums::Message::Message(raw) = &message.raw else {
+        return None;
+    };
+    match raw.from_id.as_ref().unwrap_or(&raw.peer_id) {
+        tl::enums::Peer::User(user) => Some(user.user_id),
+        _ => None,
+    }
+}
+
+fn user_display_name(user: &tl::enums::User) -> Option<String> {
+    let tl::enums::User::User(user) = user else {
+        return None;
+    };
+    let name = [user.first_name.as_deref(), user.last_name.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Looked-up sender names by user id, `None` for users without one (deleted accounts).
+/// Entries expire after `ttl` so renames propagate; when full, expired entries are dropped
+/// first and everything else if that isn't enough.
+struct SenderNameCache {
+    ttl: Duration,
+    entries: HashMap<i64, (Option<String>, Instant)>,
+}
+
+impl SenderNameCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&self, user_id: i64, now: Instant) -> Option<Option<String>> {
+        let (name, stored_at) = self.entries.get(&user_id)?;
+        (now.saturating_duration_since(*stored_at) < self.ttl).then(|| name.clone())
+    }
+
+    fn insert(&mut self, user_id: i64, name: Option<String>, now: Instant) {
+        if self.entries.len() >= SENDER_NAME_CACHE_LIMIT && !self.entries.contains_key(&user_id) {
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, (_, stored_at)| now.saturating_duration_since(*stored_at) < ttl);
+            if self.entries.len() >= SENDER_NAME_CACHE_LIMIT {
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(user_id, (name, now));
+    }
+}
+
+/// Forum topic names by chat. Each chat keeps its `TOPIC_NAMES_PER_CHAT_LIMIT` most recently
+/// named topics; forums already loaded from the forum topics API are remembered as such.
+#[derive(Default)]
+struct TopicNames {
+    chats: HashMap<i64, VecDeque<(i32, String)>>,
+    backfilled: HashSet<i64>,
+}
+
+impl TopicNames {
+    fn get(&self, chat_id: i64, topic_root_id: i32) -> Option<&str> {
+        self.chats
+            .get(&chat_id)?
+            .iter()
+            .find(|(root_id, _)| *root_id == topic_root_id)
+            .map(|(_, name)| name.as_str())
+    }
+
+    fn insert(&mut self, chat_id: i64, topic_root_id: i32, name: String) {
+        let topics = self.chats.entry(chat_id).or_default();
+        topics.retain(|(root_id, _)| *root_id != topic_root_id);
+        if topics.len() >= TOPIC_NAMES_PER_CHAT_LIMIT {
+            topics.pop_front();
+        }
+        topics.push_back((topic_root_id, name));
+    }
+
+    fn is_backfilled(&self, chat_id: i64) -> bool {
+        self.backfilled.contains(&chat_id)
+    }
+
+    fn backfill(&mut self, chat_id: i64, topics: impl IntoIterator<Item = (i32, String)>) {
+        for (topic_root_id, name) in topics {
+            self.insert(chat_id, topic_root_id, name);
+        }
+        self.backfilled.insert(chat_id);
+    }
+}
+
+/// Picks up to `count` context entries in `target_topic_root_id`, oldest first, from the
+/// history `open_history` returns for an offset id: messages strictly older than that id,
+/// newest first. Starting at `message_id` keeps messages sent after the one being
+/// rewritten (during catch-up) out of its context. Service messages are skipped unless
+/// `include_service_messages` is set. Also returns how many were scanned.
+async fn collect_context<H>(
+    open_history: impl FnOnce(i32) -> H,
+    message_id: i32,
+    count: usize,
+    target_topic_root_id: Option<i32>,
+    include_service_messages: bool,
+) -> Result<(Vec<ScannedMessage>, usize)>
+where
+    H: AsyncFnMut() -> Result<Option<ScannedMessage>>,
+{
+    let mut history = open_history(message_id);
+    let max_scan = context_scan_limit(count);
+    let mut entries = Vec::new();
+    let mut scanned = 0;
+    while let Some(scanned_message) = history().await? {
+        scanned += 1;
+        if scanned > max_scan {
+            break;
+        }
+        if scanned_message.topic_root_id != target_topic_root_id
+            || scanned_message.entry.message.text.is_empty()
+            || (scanned_message.is_service && !include_service_messages)
+        {
+            continue;
+        }
+        entries.push(scanned_message);
+        if entries.len() >= count {
+            break;
+        }
+    }
+    entries.reverse();
+    Ok((entries, scanned))
+}
+
+fn context_scan_limit(count: usize) -> usize {
+    count
+        .saturating_mul(CONTEXT_SCAN_FACTOR)
+        .max(CONTEXT_SCAN_MIN_MESSAGES)
+}
+
+async fn account_info(client: &Client) -> Result<AccountInfo> {
+    let me = client
+        .get_me()
+        .await
+        .context("failed to fetch the signed-in Telegram user")?;
+    let tl::enums::NearestDc::Dc(nearest) = client
+        .invoke(&tl::functions::help::GetNearestDc {})
+        .await
+        .context("failed to fetch the Telegram data center")?;
+    Ok(AccountInfo {
+        id: me.id().bare_id(),
+        name: me.full_name(),
+        username: me.username().map(str::to_owned),
+        phone: me.phone().map(mask_phone),
+        dc_id: nearest.this_dc,
+        is_bot: me.is_bot(),
+    })
+}
+
+/// `+` and the number with all but the first three and last two digits replaced by `*`;
+/// short numbers are masked entirely.
+pub fn mask_phone(phone: &str) -> String {
+    let digits: Vec<char> = phone.chars().filter(char::is_ascii_digit).collect();
+    let masked: String = if digits.len() <= 5 {
+        "*".repeat(digits.len())
+    } else {
+        digits
+            .iter()
+            .enumerate()
+            .map(|(index, digit)| {
+                if index < 3 || index >= digits.len() - 2 {
+                    *digit
+                } else {
+                    '*'
+                }
+            })
+            .collect()
+    };
+    format!("+{masked}")
+}
+
+/// Opens the session and connects. An unauthorized session is logged in when `sign_in` is
+/// set, and is an error otherwise.
+async fn connect_and_auth(
+    config: &TelegramConfig,
+    proxy: Option<&str>,
+    sign_in: bool,
+) -> Result<ConnectionParts> {
+    let ConnectionParts {
+        client,
+        updates_rx,
+        pool_handle,
+        pool_task,
+    } = connect_session(config, proxy).await?;
+
+    if !client
+        .is_authorized()
+        .await
+        .context("failed to check Telegram authorization")?
+    {
+        if !sign_in {
+            pool_handle.quit();
+            let _ = pool_task.await;
+            bail!(
+                "session {} is not logged in; run the rewriter or --list-chats once to log in",
+                config.session_file.display()
+            );
+        }
+        match config.bot_token.as_ref() {
+            Some(bot_token) => {
+                info!("session not authorized; signing in with telegram.bot_token");
+                sign_in_bot(&client, bot_token.expose(), config.api_hash.expose()).await?;
+            }
+            None if config.login.method == LoginMethod::Qr => {
+                info!("session not authorized; starting Telegram QR login");
+                sign_in_with_qr(&client, config.api_id, config.api_hash.expose()).await?;
+            }
+            None => {
+                info!("session not authorized; starting interactive Telegram login");
+                sign_in_interactively(&client, config.api_hash.expose()).await?;
+            }
+        }
+    }
+
+    Ok(ConnectionParts {
+        client,
+        updates_rx,
+        pool_handle,
+        pool_task,
+    })
+}
+
+/// Opens the session and starts its sender pool, whether or not it is logged in.
+async fn connect_session(config: &TelegramConfig, proxy: Option<&str>) -> Result<ConnectionParts> {
+    let session = Arc::new(
+        SqliteSession::open(&config.session_file)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to open session db: {}",
+                    config.session_file.display()
+                )
+            })?,
+    );
+
+    let pool = SenderPool::with_configuration(
+        Arc::clone(&session),
+        config.api_id,
+        ConnectionParams {
+            proxy_url: proxy.map(str::to_owned),
+            ..Default::default()
+        },
+    );
+    if proxy.is_some() {
+        info!("connecting to Telegram through configured proxy");
+    }
+    let client = Client::new(pool.handle.clone());
+    let SenderPool {
+        runner,
+        updates,
+        handle,
+    } = pool;
+    let pool_task = tokio::spawn(runner.run());
+
     Ok(ConnectionParts {
         client,
         updates_rx: updates,
@@ -374,6 +2938,18 @@ async fn connect_and_auth(config: &TelegramConfig) -> Result<ConnectionParts> {
     })
 }
 
+async fn sign_in_bot(client: &Client, bot_token: &str, api_hash: &str) -> Result<()> {
+    let user = client
+        .bot_sign_in(bot_token, api_hash)
+        .await
+        .context("failed to sign in with telegram.bot_token")?;
+    info!(
+        user_id = user.id().bare_id(),
+        "Telegram bot sign-in successful"
+    );
+    Ok(())
+}
+
 async fn sign_in_interactively(client: &Client, api_hash: &str) -> Result<()> {
     let phone = prompt("Telegram phone number (with country code): ")?;
     let login_token = client
@@ -402,7 +2978,112 @@ async fn sign_in_interactively(client: &Client, api_hash: &str) -> Result<()> {
     }
 }
 
-fn prompt(prompt: &str) -> Result<String> {
+/// Shows a `tg://login` QR code to approve from Settings > Devices on a logged-in device.
+/// The login token is re-exported on every poll, which also replaces an expired one; the
+/// code is redrawn whenever the token changes.
+async fn sign_in_with_qr(client: &Client, api_id: i32, api_hash: &str) -> Result<()> {
+    let export = tl::functions::auth::ExportLoginToken {
+        api_id,
+        api_hash: api_hash.to_owned(),
+        except_ids: Vec::new(),
+    };
+    let mut shown_token: Option<Vec<u8>> = None;
+    loop {
+        let login_token = match client.invoke(&export).await {
+            Ok(login_token) => login_token,
+            Err(err) if is_password_needed(&err) => return sign_in_with_password(client).await,
+            Err(err) => return Err(err).context("failed to export Telegram login token"),
+        };
+        let login_token = match login_token {
+            tl::enums::auth::LoginToken::MigrateTo(migrate) => {
+                let import = tl::functions::auth::ImportLoginToken {
+                    token: migrate.token,
+                };
+                match client.invoke_in_dc(migrate.dc_id, &import).await {
+                    Ok(login_token) => login_token,
+                    Err(err) if is_password_needed(&err) => {
+                        return sign_in_with_password(client).await;
+                    }
+                    Err(err) => {
+                        return Err(err).context("failed to import Telegram login token");
+                    }
+                }
+            }
+            login_token => login_token,
+        };
+
+        match login_token {
+            tl::enums::auth::LoginToken::Token(token) => {
+                if shown_token.as_ref() != Some(&token.token) {
+                    print_login_qr(&token.token)?;
+                    shown_token = Some(token.token);
+                }
+                tokio::time::sleep(QR_LOGIN_POLL_INTERVAL).await;
+            }
+            tl::enums::auth::LoginToken::Success(_) => {
+                info!("Telegram QR sign-in successful");
+                return Ok(());
+            }
+            tl::enums::auth::LoginToken::MigrateTo(_) => {
+                bail!("Telegram redirected the QR login more than once")
+            }
+        }
+    }
+}
+
+/// The 2FA step of the QR login, prompting like the code login does.
+async fn sign_in_with_password(client: &Client) -> Result<()> {
+    let password_token = client
+        .get_password_information()
+        .await
+        .context("failed to fetch Telegram 2FA password information")?;
+    let password = prompt("Telegram 2FA password: ")?;
+    client
+        .check_password(password_token, password.trim())
+        .await
+        .context("failed to validate Telegram 2FA password")?;
+    Ok(())
+}
+
+fn is_password_needed(err: &InvocationError) -> bool {
+    matches!(err, InvocationError::Rpc(rpc) if rpc.name == "SESSION_PASSWORD_NEEDED")
+}
+
+fn print_login_qr(token: &[u8]) -> Result<()> {
+    let url = login_token_url(token);
+    let code = QrCode::new(url.as_bytes()).context("failed to encode Telegram login QR code")?;
+    let rendered = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    let mut stdout = io::stdout();
+    writeln!(
+        stdout,
+        "Scan with Telegram on a logged-in device (Settings > Devices > Link Desktop Device):\n{rendered}\n{url}"
+    )?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// `tg://login?token=` with the token in unpadded URL-safe base64.
+fn login_token_url(token: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut url = String::from("tg://login?token=");
+    for chunk in token.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (u32::from(*byte) << (16 - 8 * i))
+        });
+        for i in 0..=chunk.len() {
+            url.push(char::from(
+                ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize],
+            ));
+        }
+    }
+    url
+}
+
+pub fn prompt(prompt: &str) -> Result<String> {
     {
         let mut out = io::stdout().lock();
         out.write_all(prompt.as_bytes())
@@ -420,8 +3101,323 @@ fn prompt(prompt: &str) -> Result<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{context_scan_limit, unresolved_monitored_chats};
+    use super::{
+        ChatIdSpec, ChatKind, ChatListItem, DialogCache, EditFailure, ForumTopicItem,
+        SENDER_NAME_CACHE_LIMIT, ScannedMessage, SenderNameCache, SlowModeAdmission, SlowModeQueue,
+        TOPIC_NAMES_PER_CHAT_LIMIT, TRANSIENT_EDIT_ATTEMPTS, TopicNames, bare_channel_id,
+        channel_dialog_id, classify_edit_rpc_error, collect_context, context_scan_limit,
+        dialog_cache_path, filter_chat_list, is_connection_lost, is_message_not_modified_rpc_error,
+        is_session_revoked_rpc_error, link_preview_enabled, load_dialog_cache, login_token_url,
+        mark_album_caption, mask_phone, newly_resolved_chats, normalize_chat_ids, persona_chat_id,
+        reaction_trigger_target, specific_reply_target, transient_edit_backoff,
+        unresolved_monitored_chats,
+    };
+    use crate::config::LinkPreview;
+    use crate::context::{ContextEntry, ContextMessage};
+    use grammers_client::tl;
+    use grammers_client::update::{Raw, Update};
+    use grammers_mtsender::InvocationError;
     use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn chat_kind_follows_dialog_id_ranges() {
+        assert_eq!(ChatKind::from_dialog_id(42), ChatKind::User);
+        assert_eq!(ChatKind::from_dialog_id(-4242), ChatKind::Group);
+        assert_eq!(
+            ChatKind::from_dialog_id(channel_dialog_id(1234567890)),
+            ChatKind::Channel
+        );
+    }
+
+    fn listed_chat(id: i64, name: &str, username: Option<&str>) -> ChatListItem {
+        ChatListItem {
+            id,
+            name: name.to_owned(),
+            kind: ChatKind::from_dialog_id(id),
+            username: username.map(str::to_owned),
+            is_forum: false,
+            member_count: None,
+            unread_count: None,
+        }
+    }
+
+    #[test]
+    fn chat_list_query_matches_names_and_usernames() {
+        let chats = vec![
+            listed_chat(-1003, "Work Team", None),
+            listed_chat(-1002, "Announcements", Some("acme_news")),
+            listed_chat(42, "alice", Some("alice_w")),
+            listed_chat(-1001, "work team", None),
+        ];
+        let ids = |query| -> Vec<i64> {
+            filter_chat_list(chats.clone(), query)
+                .iter()
+                .map(|chat| chat.id)
+                .collect()
+        };
+
+        assert_eq!(ids(None), vec![42, -1002, -1003, -1001]);
+        assert_eq!(ids(Some("WORK")), vec![-1003, -1001]);
+        assert_eq!(ids(Some("acme")), vec![-1002]);
+        assert_eq!(ids(Some("@Alice_W")), vec![42]);
+        assert!(ids(Some("nobody")).is_empty());
+    }
+
+    #[test]
+    fn general_topic_uses_the_config_zero_marker() {
+        let general = ForumTopicItem {
+            root_id: 1,
+            title: "General".to_owned(),
+        };
+        let topic = ForumTopicItem {
+            root_id: 77,
+            title: "Releases".to_owned(),
+        };
+        assert!(general.is_general());
+        assert_eq!(general.config_root_id(), 0);
+        assert!(!topic.is_general());
+        assert_eq!(topic.config_root_id(), 77);
+    }
+
+    #[test]
+    fn login_token_url_uses_unpadded_url_safe_base64() {
+        assert_eq!(login_token_url(b"Man"), "tg://login?token=TWFu");
+        assert_eq!(login_token_url(&[0xfb, 0xff]), "tg://login?token=-_8");
+        assert_eq!(login_token_url(&[0xff]), "tg://login?token=_w");
+    }
+
+    fn reaction_update(emoticon: &str, chosen_by_us: bool) -> Update {
+        let reactions = tl::types::UpdateMessageReactions {
+            peer: tl::types::PeerChannel {
+                channel_id: 1234567890,
+            }
+            .into(),
+            msg_id: 55,
+            top_msg_id: None,
+            saved_peer_id: None,
+            reactions: tl::types::MessageReactions {
+                min: false,
+                can_see_list: false,
+                reactions_as_tags: false,
+                results: vec![
+                    tl::types::ReactionCount {
+                        chosen_order: chosen_by_us.then_some(0),
+                        reaction: tl::types::ReactionEmoji {
+                            emoticon: emoticon.to_owned(),
+                        }
+                        .into(),
+                        count: 1,
+                    }
+                    .into(),
+                ],
+                recent_reactions: None,
+                top_reactors: None,
+            }
+            .into(),
+        };
+        Update::Raw(Raw {
+            raw: reactions.into(),
+            state: grammers_session::updates::State {
+                date: 0,
+                seq: 0,
+                message_box: None,
+            },
+        })
+    }
+
+    #[test]
+    fn reaction_trigger_target_needs_our_own_matching_emoji() {
+        assert_eq!(
+            reaction_trigger_target(&reaction_update("🤖", true), "🤖"),
+            Some((channel_dialog_id(1234567890), 55))
+        );
+        assert_eq!(
+            reaction_trigger_target(&reaction_update("🤖", false), "🤖"),
+            None,
+            "someone else's reaction must not trigger a rewrite"
+        );
+        assert_eq!(
+            reaction_trigger_target(&reaction_update("👍", true), "🤖"),
+            None
+        );
+    }
+
+    fn scanned(id: i32, topic_root_id: Option<i32>, text: &str) -> ScannedMessage {
+        ScannedMessage {
+            topic_root_id,
+            is_service: false,
+            unix: 0,
+            sender_user_id: Some(7),
+            entry: ContextEntry {
+                message_id: id,
+                message: ContextMessage {
+                    sender_name: "Alice".to_owned(),
+                    text: text.to_owned(),
+                    is_own: false,
+                },
+            },
+        }
+    }
+
+    /// Serves `chat` like Telegram serves history for an `offset_id`.
+    async fn context_ids(
+        chat: &[ScannedMessage],
+        message_id: i32,
+        count: usize,
+        topic_root_id: Option<i32>,
+    ) -> Vec<i32> {
+        context_ids_with_service(chat, message_id, count, topic_root_id, false).await
+    }
+
+    async fn context_ids_with_service(
+        chat: &[ScannedMessage],
+        message_id: i32,
+        count: usize,
+        topic_root_id: Option<i32>,
+        include_service_messages: bool,
+    ) -> Vec<i32> {
+        let (entries, _) = collect_context(
+            |offset_id| {
+                let mut older = chat
+                    .iter()
+                    .rev()
+                    .filter(move |message| message.entry.message_id < offset_id)
+                    .cloned();
+                async move || Ok(older.next())
+            },
+            message_id,
+            count,
+            topic_root_id,
+            include_service_messages,
+        )
+        .await
+        .expect("context should be collected");
+        entries
+            .iter()
+            .map(|scanned| scanned.entry.message_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn catch_up_context_never_includes_later_messages() {
+        let chat: Vec<ScannedMessage> = (1..=9)
+            .map(|id| scanned(id, None, &format!("message {id}")))
+            .collect();
+
+        assert_eq!(context_ids(&chat, 5, 3, None).await, vec![2, 3, 4]);
+        assert_eq!(context_ids(&chat, 2, 3, None).await, vec![1]);
+        assert_eq!(context_ids(&chat, 9, 2, None).await, vec![7, 8]);
+    }
+
+    #[tokio::test]
+    async fn context_skips_other_topics_and_empty_messages() {
+        let chat = vec![
+            scanned(1, Some(100), "topic start"),
+            scanned(2, Some(200), "other topic"),
+            scanned(3, Some(100), ""),
+            scanned(4, Some(100), "reply"),
+            scanned(5, Some(100), "target"),
+        ];
+
+        assert_eq!(context_ids(&chat, 5, 10, Some(100)).await, vec![1, 4]);
+    }
+
+    #[tokio::test]
+    async fn context_skips_service_messages_unless_included() {
+        let pinned = ScannedMessage {
+            is_service: true,
+            ..scanned(2, None, "Alice pinned a message")
+        };
+        let chat = vec![
+            scanned(1, None, "hello"),
+            pinned,
+            scanned(3, None, "anyone here?"),
+            scanned(4, None, "target"),
+        ];
+
+        assert_eq!(context_ids(&chat, 4, 10, None).await, vec![1, 3]);
+        assert_eq!(
+            context_ids_with_service(&chat, 4, 10, None, true).await,
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn album_captions_are_marked_in_context() {
+        assert_eq!(
+            mark_album_caption(" trip photos ", true),
+            "[album] trip photos"
+        );
+        assert_eq!(mark_album_caption("", true), "");
+        assert_eq!(mark_album_caption(" plain ", false), "plain");
+    }
+
+    #[test]
+    fn sender_names_expire_after_ttl() {
+        let start = Instant::now();
+        let mut cache = SenderNameCache::new(Duration::from_secs(60));
+        cache.insert(7, Some("Alice".to_owned()), start);
+        cache.insert(8, None, start);
+
+        assert_eq!(cache.get(7, start), Some(Some("Alice".to_owned())));
+        assert_eq!(cache.get(8, start + Duration::from_secs(59)), Some(None));
+        assert_eq!(cache.get(7, start + Duration::from_secs(60)), None);
+        assert_eq!(cache.get(9, start), None);
+
+        cache.insert(
+            7,
+            Some("Alice Renamed".to_owned()),
+            start + Duration::from_secs(61),
+        );
+        assert_eq!(
+            cache.get(7, start + Duration::from_secs(62)),
+            Some(Some("Alice Renamed".to_owned()))
+        );
+    }
+
+    #[test]
+    fn sender_name_cache_stays_bounded() {
+        let start = Instant::now();
+        let fill = |cache: &mut SenderNameCache| {
+            for user_id in 0..SENDER_NAME_CACHE_LIMIT as i64 {
+                cache.insert(user_id, Some(format!("user {user_id}")), start);
+            }
+        };
+
+        let mut cache = SenderNameCache::new(Duration::from_secs(60));
+        fill(&mut cache);
+        cache.insert(7, Some("renamed".to_owned()), start);
+        assert_eq!(cache.entries.len(), SENDER_NAME_CACHE_LIMIT);
+        cache.insert(-1, Some("late".to_owned()), start + Duration::from_secs(61));
+        assert_eq!(cache.entries.len(), 1);
+
+        let mut cache = SenderNameCache::new(Duration::from_secs(60));
+        fill(&mut cache);
+        cache.insert(-1, Some("new".to_owned()), start);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get(-1, start), Some(Some("new".to_owned())));
+    }
+
+    #[test]
+    fn topic_names_stay_bounded_per_chat() {
+        let mut names = TopicNames::default();
+        names.backfill(
+            -100,
+            (1..=TOPIC_NAMES_PER_CHAT_LIMIT as i32).map(|root_id| (root_id, format!("t{root_id}"))),
+        );
+        assert!(names.is_backfilled(-100));
+        names.insert(-100, 1, "Design".to_owned());
+        names.insert(-100, 9_000, "Release".to_owned());
+        names.insert(-200, 5, "Other chat".to_owned());
+
+        assert_eq!(names.chats[&-100].len(), TOPIC_NAMES_PER_CHAT_LIMIT);
+        assert_eq!(names.get(-100, 1), Some("Design"));
+        assert_eq!(names.get(-100, 2), None);
+        assert_eq!(names.get(-100, 9_000), Some("Release"));
+        assert_eq!(names.get(-200, 5), Some("Other chat"));
+        assert!(!names.is_backfilled(-200));
+    }
 
     #[test]
     fn context_scan_limit_uses_minimum_window() {
@@ -433,6 +3429,283 @@ mod tests {
         assert_eq!(context_scan_limit(20), 400);
     }
 
+    #[test]
+    fn link_preview_follows_the_original_only_when_kept() {
+        for original_has_preview in [false, true] {
+            assert_eq!(
+                link_preview_enabled(LinkPreview::Keep, original_has_preview),
+                original_has_preview
+            );
+            assert!(!link_preview_enabled(
+                LinkPreview::Disable,
+                original_has_preview
+            ));
+            assert!(link_preview_enabled(
+                LinkPreview::Enable,
+                original_has_preview
+            ));
+        }
+    }
+
+    #[test]
+    fn specific_reply_target_ignores_forum_topic_root_marker() {
+        assert_eq!(specific_reply_target(Some(100), None, true), None);
+        assert_eq!(specific_reply_target(Some(100), Some(100), true), None);
+    }
+
+    #[test]
+    fn specific_reply_target_detects_replies_inside_topics_and_plain_chats() {
+        assert_eq!(specific_reply_target(Some(150), Some(100), true), Some(150));
+        assert_eq!(specific_reply_target(Some(42), None, false), Some(42));
+        assert_eq!(specific_reply_target(None, Some(100), true), None);
+    }
+
+    #[test]
+    fn persona_is_the_channel_sender_or_the_posting_channel() {
+        let channel = |channel_id| tl::enums::Peer::Channel(tl::types::PeerChannel { channel_id });
+        let user = tl::enums::Peer::User(tl::types::PeerUser { user_id: 42 });
+        let group = channel(555);
+
+        assert_eq!(
+            persona_chat_id(Some(&channel(777)), &group, false),
+            Some(channel_dialog_id(777))
+        );
+        assert_eq!(
+            persona_chat_id(Some(&group), &group, false),
+            Some(channel_dialog_id(555))
+        );
+        assert_eq!(
+            persona_chat_id(None, &channel(777), true),
+            Some(channel_dialog_id(777))
+        );
+        assert_eq!(persona_chat_id(Some(&user), &group, false), None);
+        assert_eq!(persona_chat_id(None, &group, false), None);
+    }
+
+    #[test]
+    fn edit_rpc_errors_are_classified_by_code_and_name() {
+        assert_eq!(
+            classify_edit_rpc_error(400, "MESSAGE_ID_INVALID"),
+            EditFailure::Permanent
+        );
+        assert_eq!(
+            classify_edit_rpc_error(403, "MESSAGE_AUTHOR_REQUIRED"),
+            EditFailure::Permanent
+        );
+        assert_eq!(
+            classify_edit_rpc_error(400, "CONNECTION_NOT_INITED"),
+            EditFailure::Transient
+        );
+        assert_eq!(
+            classify_edit_rpc_error(500, "INTERNAL"),
+            EditFailure::Transient
+        );
+        assert_eq!(
+            classify_edit_rpc_error(-503, "TIMEOUT"),
+            EditFailure::Transient
+        );
+        assert_eq!(
+            classify_edit_rpc_error(400, "MESSAGE_TOO_LONG"),
+            EditFailure::Other
+        );
+    }
+
+    #[test]
+    fn phone_numbers_keep_only_their_first_and_last_digits() {
+        assert_eq!(mask_phone("447911123456"), "+447*******56");
+        assert_eq!(mask_phone("+1 555 0100"), "+155***00");
+        assert_eq!(mask_phone("42777"), "+*****");
+    }
+
+    #[test]
+    fn dropped_connections_are_told_apart_from_other_stream_errors() {
+        let dropped = anyhow::Error::from(InvocationError::Dropped)
+            .context("failed to fetch Telegram update");
+        assert!(is_connection_lost(&dropped));
+        assert!(!is_connection_lost(&anyhow::anyhow!(
+            "telegram bot is not connected for update streaming"
+        )));
+    }
+
+    #[test]
+    fn dialog_cache_covers_bare_channel_ids_and_expires_after_a_day() {
+        let cache = DialogCache {
+            scanned_at: 1_700_000_000,
+            chat_ids: HashSet::from([-1001234567890, 42]),
+            administered: HashSet::new(),
+        };
+        assert!(cache.covers(&HashSet::from([1234567890, 42])));
+        assert!(!cache.covers(&HashSet::from([-1001234567890, 43])));
+
+        let session_file = std::env::temp_dir().join(format!(
+            "brainrot-dialog-cache-{}.session",
+            std::process::id()
+        ));
+        let path = dialog_cache_path(&session_file);
+        assert!(path.to_string_lossy().ends_with(".session.dialogs.json"));
+        std::fs::write(
+            &path,
+            serde_json::to_string(&cache).expect("cache should serialize"),
+        )
+        .expect("cache should be written");
+        assert_eq!(load_dialog_cache(&path, 1_700_000_000 + 60), Some(cache));
+        assert_eq!(
+            load_dialog_cache(&path, 1_700_000_000 + 2 * 24 * 60 * 60),
+            None
+        );
+        std::fs::write(&path, "not json").expect("cache should be written");
+        assert_eq!(load_dialog_cache(&path, 1_700_000_000), None);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn revoked_authorization_is_recognized_by_name() {
+        assert!(is_session_revoked_rpc_error("AUTH_KEY_UNREGISTERED"));
+        assert!(is_session_revoked_rpc_error("SESSION_REVOKED"));
+        assert!(is_session_revoked_rpc_error("USER_DEACTIVATED_BAN"));
+        assert!(!is_session_revoked_rpc_error("FLOOD_WAIT"));
+        assert!(!is_session_revoked_rpc_error("AUTH_KEY_DUPLICATED_X"));
+    }
+
+    #[test]
+    fn message_not_modified_is_recognized_by_name() {
+        assert!(is_message_not_modified_rpc_error("MESSAGE_NOT_MODIFIED"));
+        assert!(!is_message_not_modified_rpc_error("MESSAGE_ID_INVALID"));
+        assert!(!is_message_not_modified_rpc_error("MESSAGE_NOT_MODIFIED_X"));
+    }
+
+    #[test]
+    fn only_transient_edit_errors_are_retried_with_growing_backoff() {
+        assert_eq!(
+            transient_edit_backoff(EditFailure::Transient, 1),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            transient_edit_backoff(EditFailure::Transient, 2),
+            Some(Duration::from_millis(1_000))
+        );
+        assert_eq!(
+            transient_edit_backoff(EditFailure::Transient, TRANSIENT_EDIT_ATTEMPTS),
+            None
+        );
+        assert_eq!(transient_edit_backoff(EditFailure::Permanent, 1), None);
+        assert_eq!(transient_edit_backoff(EditFailure::Other, 1), None);
+    }
+
+    #[test]
+    fn newly_resolved_chats_are_the_sorted_unresolved_ones_now_known() {
+        let unresolved = HashSet::from([-300, -100, -200]);
+        let known = HashSet::from([-100, -300, -400]);
+        assert_eq!(newly_resolved_chats(&unresolved, &known), vec![-300, -100]);
+        assert!(newly_resolved_chats(&HashSet::new(), &known).is_empty());
+    }
+
+    #[test]
+    fn slow_mode_queue_holds_one_rewrite_per_window() {
+        let start = tokio::time::Instant::now();
+        let window = Duration::from_secs(30);
+        let mut queue = SlowModeQueue::new();
+        assert_eq!(
+            queue.admit(-100, window, start, 1),
+            SlowModeAdmission::Now(1)
+        );
+        assert_eq!(
+            queue.admit(-200, window, start, 9),
+            SlowModeAdmission::Now(9),
+            "chats have their own windows"
+        );
+        assert_eq!(queue.next_ready_at(), None);
+
+        let later = start + Duration::from_secs(5);
+        assert_eq!(
+            queue.admit(-100, window, later, 2),
+            SlowModeAdmission::Queued {
+                ready_at: start + window,
+                superseded: None,
+            }
+        );
+        assert_eq!(
+            queue.admit(-100, window, later, 3),
+            SlowModeAdmission::Queued {
+                ready_at: start + window,
+                superseded: Some(2),
+            }
+        );
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.queued(-100), Some(&3));
+        assert_eq!(queue.next_ready_at(), Some(start + window));
+        assert!(queue.take_ready(later).is_empty());
+
+        let opened = start + window;
+        assert_eq!(queue.take_ready(opened), vec![3]);
+        assert!(queue.is_empty());
+        assert_eq!(
+            queue.admit(-100, window, opened, 3),
+            SlowModeAdmission::Now(3)
+        );
+    }
+
+    #[test]
+    fn slow_mode_waits_from_telegram_hold_chats_without_a_known_window() {
+        let start = tokio::time::Instant::now();
+        let mut queue = SlowModeQueue::new();
+        assert_eq!(
+            queue.admit(-100, Duration::ZERO, start, 1),
+            SlowModeAdmission::Now(1)
+        );
+        assert_eq!(
+            queue.admit(-100, Duration::ZERO, start, 2),
+            SlowModeAdmission::Now(2)
+        );
+
+        queue.record_wait(-100, Duration::from_secs(12), start);
+        assert_eq!(
+            queue.admit(-100, Duration::ZERO, start, 3),
+            SlowModeAdmission::Queued {
+                ready_at: start + Duration::from_secs(12),
+                superseded: None,
+            }
+        );
+        // A shorter wait doesn't pull the window in.
+        queue.record_wait(-100, Duration::from_secs(1), start);
+        assert_eq!(queue.next_ready_at(), Some(start + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn channel_ids_convert_between_bare_and_bot_api_form() {
+        assert_eq!(channel_dialog_id(1234567890), -1001234567890);
+        assert_eq!(bare_channel_id(-1001234567890), Some(1234567890));
+        assert_eq!(bare_channel_id(channel_dialog_id(42)), Some(42));
+        assert_eq!(bare_channel_id(-4567), None);
+        assert_eq!(bare_channel_id(4567), None);
+    }
+
+    #[test]
+    fn chat_id_specs_resolve_bare_ids_against_known_dialogs() {
+        assert_eq!(
+            ChatIdSpec::parse(-1001234567890),
+            ChatIdSpec::Canonical(-1001234567890)
+        );
+        let bare = ChatIdSpec::parse(1234567890);
+        assert_eq!(bare, ChatIdSpec::Ambiguous(1234567890));
+        assert!(bare.matches(-1001234567890));
+        assert!(bare.matches(1234567890));
+        assert!(!ChatIdSpec::parse(-1001234567890).matches(1234567890));
+
+        let channel_known = HashSet::from([-1001234567890]);
+        assert_eq!(bare.normalize(&channel_known), -1001234567890);
+        let user_known = HashSet::from([1234567890]);
+        assert_eq!(bare.normalize(&user_known), 1234567890);
+        assert_eq!(bare.normalize(&HashSet::new()), 1234567890);
+
+        let chats = HashSet::from([1234567890, -1009, 777]);
+        let known = HashSet::from([-1001234567890, 777]);
+        assert_eq!(
+            normalize_chat_ids(&chats, &known),
+            HashSet::from([-1001234567890, -1009, 777])
+        );
+    }
+
     #[test]
     fn unresolved_monitored_chats_returns_sorted_missing_chat_ids() {
         let monitored = HashSet::from([-1003, -1001, -1002]);
@@ -0,0 +1,119 @@
+use crate::config::ParseMode;
+use std::collections::{HashMap, VecDeque};
+
+/// Originals of applied rewrites, kept per chat for `.rw undo`. Each chat keeps its newest
+/// `limit` entries and drops the oldest first; a limit of 0 keeps nothing.
+#[derive(Debug)]
+pub struct UndoHistory {
+    limit: usize,
+    chats: HashMap<i64, VecDeque<UndoEntry>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoEntry {
+    /// The rewritten message: the edited original, or the message a resend sent.
+    pub message_id: i32,
+    pub original: String,
+    /// Markup of `original`, Markdown when it was taken with `rewrite.preserve_formatting`.
+    pub parse_mode: ParseMode,
+}
+
+impl UndoHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            chats: HashMap::new(),
+        }
+    }
+
+    /// Applies a reloaded `rewrite.undo_history`, dropping the oldest entries over it.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.chats.retain(|_, entries| {
+            while entries.len() > limit {
+                entries.pop_front();
+            }
+            !entries.is_empty()
+        });
+    }
+
+    /// Keeps `entry` as the chat's newest, replacing an older entry for the same message.
+    pub fn record(&mut self, chat_id: i64, entry: UndoEntry) {
+        if self.limit == 0 {
+            return;
+        }
+        let entries = self.chats.entry(chat_id).or_default();
+        entries.retain(|kept| kept.message_id != entry.message_id);
+        if entries.len() == self.limit {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Removes and returns the entry for `message_id`, or the chat's newest one without it.
+    pub fn take(&mut self, chat_id: i64, message_id: Option<i32>) -> Option<UndoEntry> {
+        let entries = self.chats.get_mut(&chat_id)?;
+        let entry = match message_id {
+            Some(message_id) => {
+                let index = entries
+                    .iter()
+                    .position(|entry| entry.message_id == message_id)?;
+                entries.remove(index)
+            }
+            None => entries.pop_back(),
+        };
+        if entries.is_empty() {
+            self.chats.remove(&chat_id);
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UndoEntry, UndoHistory};
+    use crate::config::ParseMode;
+
+    fn entry(message_id: i32) -> UndoEntry {
+        UndoEntry {
+            message_id,
+            original: format!("original {message_id}"),
+            parse_mode: ParseMode::Plain,
+        }
+    }
+
+    #[test]
+    fn undo_takes_the_newest_rewrite_or_the_named_one() {
+        let mut history = UndoHistory::new(5);
+        for message_id in [1, 2, 3] {
+            history.record(-100, entry(message_id));
+        }
+        history.record(-200, entry(9));
+
+        assert_eq!(history.take(-100, Some(2)), Some(entry(2)));
+        assert_eq!(history.take(-100, Some(2)), None);
+        assert_eq!(history.take(-100, None), Some(entry(3)));
+        assert_eq!(history.take(-100, None), Some(entry(1)));
+        assert_eq!(history.take(-100, None), None);
+        assert_eq!(history.take(-200, None), Some(entry(9)));
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_first() {
+        let mut history = UndoHistory::new(2);
+        for message_id in [1, 2, 3] {
+            history.record(-100, entry(message_id));
+        }
+        assert_eq!(history.take(-100, Some(1)), None);
+
+        // Rewriting a message again keeps only its latest original.
+        history.record(-100, entry(2));
+        history.set_limit(1);
+        assert_eq!(history.take(-100, None), Some(entry(2)));
+        assert_eq!(history.take(-100, None), None);
+
+        let mut disabled = UndoHistory::new(0);
+        disabled.record(-100, entry(1));
+        assert_eq!(disabled.take(-100, None), None);
+    }
+}
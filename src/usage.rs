@@ -0,0 +1,197 @@
+use crate::llm::TokenUsage;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use tracing::{debug, info};
+
+/// Summary is logged after this many recorded rewrites, and again on shutdown.
+pub const USAGE_SUMMARY_EVERY_REWRITES: u64 = 25;
+
+/// Dollar prices per million tokens, from `openai.cost_per_million_input/output`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenPricing {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl TokenPricing {
+    fn cost(&self, totals: &UsageTotals) -> f64 {
+        (totals.input_tokens as f64 * self.input_per_million
+            + totals.output_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    pub rewrites: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: TokenUsage) {
+        self.rewrites += 1;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+    }
+}
+
+/// Running token totals per model and per chat for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct UsageTracker {
+    by_model: BTreeMap<String, UsageTotals>,
+    by_chat: BTreeMap<i64, UsageTotals>,
+    total: UsageTotals,
+    pricing: Option<TokenPricing>,
+}
+
+impl UsageTracker {
+    pub fn new(pricing: Option<TokenPricing>) -> Self {
+        Self {
+            pricing,
+            ..Default::default()
+        }
+    }
+
+    /// Pricing follows the active config; the totals themselves are kept across reloads.
+    pub fn set_pricing(&mut self, pricing: Option<TokenPricing>) {
+        self.pricing = pricing;
+    }
+
+    /// Records one rewrite and logs the summary every [`USAGE_SUMMARY_EVERY_REWRITES`] rewrites.
+    pub fn record(&mut self, chat_id: i64, model: &str, usage: TokenUsage) {
+        self.by_model
+            .entry(model.to_owned())
+            .or_default()
+            .add(usage);
+        self.by_chat.entry(chat_id).or_default().add(usage);
+        self.total.add(usage);
+        if self
+            .total
+            .rewrites
+            .is_multiple_of(USAGE_SUMMARY_EVERY_REWRITES)
+        {
+            self.log_summary();
+        }
+    }
+
+    pub fn total(&self) -> UsageTotals {
+        self.total
+    }
+
+    pub fn model_totals(&self, model: &str) -> Option<UsageTotals> {
+        self.by_model.get(model).copied()
+    }
+
+    pub fn chat_totals(&self, chat_id: i64) -> Option<UsageTotals> {
+        self.by_chat.get(&chat_id).copied()
+    }
+
+    pub fn estimated_cost_usd(&self) -> Option<f64> {
+        self.pricing.map(|pricing| pricing.cost(&self.total))
+    }
+
+    pub fn log_summary(&self) {
+        if self.total.rewrites == 0 {
+            return;
+        }
+        info!(
+            rewrites = self.total.rewrites,
+            input_tokens = self.total.input_tokens,
+            output_tokens = self.total.output_tokens,
+            estimated_cost_usd = self.estimated_cost_usd(),
+            by_model = %self.render_by_model(),
+            "llm token usage summary"
+        );
+        for (chat_id, totals) in &self.by_chat {
+            debug!(
+                chat_id,
+                rewrites = totals.rewrites,
+                input_tokens = totals.input_tokens,
+                output_tokens = totals.output_tokens,
+                "llm token usage for chat"
+            );
+        }
+    }
+
+    fn render_by_model(&self) -> String {
+        let mut rendered = String::new();
+        for (model, totals) in &self.by_model {
+            if !rendered.is_empty() {
+                rendered.push_str("; ");
+            }
+            let _ = write!(
+                rendered,
+                "{model}: {} rewrites, {} in / {} out",
+                totals.rewrites, totals.input_tokens, totals.output_tokens
+            );
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenPricing, UsageTotals, UsageTracker};
+    use crate::llm::TokenUsage;
+
+    fn usage(input_tokens: u64, output_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            input_tokens,
+            output_tokens,
+        }
+    }
+
+    #[test]
+    fn record_accumulates_per_model_and_per_chat() {
+        let mut tracker = UsageTracker::new(None);
+        tracker.record(1, "gpt-4.1-mini", usage(100, 20));
+        tracker.record(2, "gpt-4.1-mini", usage(50, 10));
+        tracker.record(1, "gpt-4o-mini", usage(10, 5));
+
+        assert_eq!(
+            tracker.total(),
+            UsageTotals {
+                rewrites: 3,
+                input_tokens: 160,
+                output_tokens: 35,
+            }
+        );
+        assert_eq!(
+            tracker.model_totals("gpt-4.1-mini"),
+            Some(UsageTotals {
+                rewrites: 2,
+                input_tokens: 150,
+                output_tokens: 30,
+            })
+        );
+        assert_eq!(
+            tracker.chat_totals(1),
+            Some(UsageTotals {
+                rewrites: 2,
+                input_tokens: 110,
+                output_tokens: 25,
+            })
+        );
+        assert_eq!(tracker.chat_totals(3), None);
+        assert_eq!(
+            tracker.render_by_model(),
+            "gpt-4.1-mini: 2 rewrites, 150 in / 30 out; gpt-4o-mini: 1 rewrites, 10 in / 5 out"
+        );
+    }
+
+    #[test]
+    fn estimated_cost_uses_current_pricing_and_keeps_totals() {
+        let mut tracker = UsageTracker::new(None);
+        tracker.record(1, "gpt-4.1-mini", usage(2_000_000, 500_000));
+        assert_eq!(tracker.estimated_cost_usd(), None);
+
+        tracker.set_pricing(Some(TokenPricing {
+            input_per_million: 0.4,
+            output_per_million: 1.6,
+        }));
+        let cost = tracker.estimated_cost_usd().expect("pricing is set");
+        assert!((cost - 1.6).abs() < 1e-9, "{cost}");
+        assert_eq!(tracker.total().rewrites, 1);
+    }
+}
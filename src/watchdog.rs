@@ -0,0 +1,115 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What to do once an account's update stream is found stalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    /// Rebuild the connection, as after a stream error.
+    Reconnect,
+    /// Stop the rewriter with an error (`runtime.exit_on_stall`).
+    Exit,
+}
+
+/// Notices an update stream that went quiet without reporting an error.
+#[derive(Debug)]
+pub struct StallWatchdog {
+    threshold: Duration,
+    action: StallAction,
+    last_update: Instant,
+    /// The last time Telegram answered a probe, or the connection was rebuilt.
+    last_heartbeat: Instant,
+}
+
+impl StallWatchdog {
+    pub fn new(threshold: Duration, action: StallAction, now: Instant) -> Self {
+        Self {
+            threshold,
+            action,
+            last_update: now,
+            last_heartbeat: now,
+        }
+    }
+
+    pub fn record_update(&mut self, now: Instant) {
+        self.last_update = now;
+    }
+
+    pub fn record_heartbeat(&mut self, now: Instant) {
+        self.last_heartbeat = now;
+    }
+
+    /// When a probe is due if nothing arrives meanwhile.
+    pub fn probe_at(&self) -> Instant {
+        self.last_update.max(self.last_heartbeat) + self.threshold
+    }
+
+    /// How long Telegram has been silent, once that calls for a probe.
+    pub fn due(&self, now: Instant) -> Option<Duration> {
+        (self.probe_at() <= now).then(|| self.since_update(now))
+    }
+
+    fn since_update(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_update)
+    }
+
+    /// Telegram answered the probe; the next one waits another `threshold` of silence.
+    pub fn probe_answered(&mut self, now: Instant) {
+        self.record_heartbeat(now);
+    }
+
+    /// The probe failed as well; returns the configured action.
+    pub fn probe_failed(&mut self, now: Instant) -> StallAction {
+        self.record_heartbeat(now);
+        self.action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StallAction, StallWatchdog};
+    use std::time::Duration;
+    use tokio::time::{Instant, advance};
+
+    const THRESHOLD: Duration = Duration::from_secs(300);
+
+    #[tokio::test(start_paused = true)]
+    async fn silence_past_the_threshold_calls_for_a_probe() {
+        let mut watchdog = StallWatchdog::new(THRESHOLD, StallAction::Reconnect, Instant::now());
+        advance(Duration::from_secs(200)).await;
+        assert_eq!(watchdog.due(Instant::now()), None);
+        watchdog.record_update(Instant::now());
+
+        advance(Duration::from_secs(299)).await;
+        assert_eq!(watchdog.due(Instant::now()), None);
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(watchdog.due(Instant::now()), Some(THRESHOLD));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answered_probe_waits_another_threshold_before_the_next() {
+        let mut watchdog = StallWatchdog::new(THRESHOLD, StallAction::Reconnect, Instant::now());
+        advance(THRESHOLD).await;
+        assert!(watchdog.due(Instant::now()).is_some());
+        watchdog.probe_answered(Instant::now());
+        assert_eq!(watchdog.due(Instant::now()), None);
+
+        advance(THRESHOLD).await;
+        // Still counted from the last update, so the log shows how long the stream was quiet.
+        assert_eq!(watchdog.due(Instant::now()), Some(THRESHOLD * 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_escalates_to_the_configured_action() {
+        let mut reconnecting =
+            StallWatchdog::new(THRESHOLD, StallAction::Reconnect, Instant::now());
+        let mut exiting = StallWatchdog::new(THRESHOLD, StallAction::Exit, Instant::now());
+        advance(THRESHOLD).await;
+        assert_eq!(
+            reconnecting.probe_failed(Instant::now()),
+            StallAction::Reconnect
+        );
+        assert_eq!(exiting.probe_failed(Instant::now()), StallAction::Exit);
+        assert_eq!(reconnecting.due(Instant::now()), None);
+        assert_eq!(reconnecting.probe_at(), Instant::now() + THRESHOLD);
+    }
+}
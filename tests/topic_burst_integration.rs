@@ -5,6 +5,7 @@ use brainrot_tg_llm_rewrite::app::{
 use brainrot_tg_llm_rewrite::config::{
     Config, ConfigMode, OpenAiConfig, RewriteConfig, load_config_for_mode,
 };
+use brainrot_tg_llm_rewrite::telegram::ChatIdSpec;
 use grammers_client::Client;
 use grammers_client::message::InputMessage;
 use grammers_session::types::PeerRef;
@@ -55,21 +56,20 @@ async fn topic_burst_messages_are_all_processed() -> Result<()> {
         run_rewrite_mode_with_shutdown_and_hooks(
             &runtime_config,
             &runtime_config_path,
-            async move {
-                let _ = shutdown_rx.await;
-            },
+            Some(shutdown_rx),
             hooks,
             RewriteRuntimeOptions {
                 catch_up_enabled: true,
                 skip_historical_catch_up_messages: false,
                 rewrite_override: Some(TEST_REWRITE_TEXT.to_owned()),
+                dry_run: false,
             },
         )
         .await
     });
 
     let test_result = async {
-        let runtime_client = wait_for_runtime_ready(client_rx).await?;
+        let runtime_client = wait_for_runtime_ready(client_rx, &mut event_rx).await?;
         eprintln!(
             "[it] rewriter started in-process; chat_id={} topic_a_root_id={} topic_b_root_id={}",
             integration.chat_id, integration.topic_a_root_id, integration.topic_b_root_id
@@ -126,9 +126,10 @@ async fn topic_burst_messages_are_all_processed() -> Result<()> {
         );
         sent.push(trigger);
 
-        let (pending, recent_events) = wait_until_all_edited_events(&mut event_rx, &sent).await;
-        if pending.is_empty() {
-            return Ok(());
+        let (pending, failed, recent_events) =
+            wait_until_all_edited_events(&mut event_rx, &sent).await;
+        if pending.is_empty() && failed.is_empty() {
+            return Ok(sent.len());
         }
 
         let mut pending_topic_a = Vec::new();
@@ -147,7 +148,8 @@ async fn topic_burst_messages_are_all_processed() -> Result<()> {
         pending_topic_b.sort_unstable();
         pending_other.sort_unstable();
         bail!(
-            "timed out waiting for rewrites; pending topic_a ids: {:?}; pending topic_b ids: {:?}; pending other ids: {:?}\n\nrecent runtime events:\n{}",
+            "rewrites did not all land; failed: {}; pending topic_a ids: {:?}; pending topic_b ids: {:?}; pending other ids: {:?}\n\nrecent runtime events:\n{}",
+            failed.join(", "),
             pending_topic_a,
             pending_topic_b,
             pending_other,
@@ -162,36 +164,86 @@ async fn topic_burst_messages_are_all_processed() -> Result<()> {
         .context("timed out waiting for in-process rewriter shutdown")?
         .context("in-process rewriter task panicked")?;
 
-    if let Err(test_err) = test_result {
-        if let Err(runtime_err) = shutdown_result {
-            bail!("{test_err}\n\nrewriter task error during shutdown: {runtime_err}");
+    let expected_rewrites = match test_result {
+        Ok(expected_rewrites) => expected_rewrites,
+        Err(test_err) => {
+            if let Err(runtime_err) = shutdown_result {
+                bail!("{test_err}\n\nrewriter task error during shutdown: {runtime_err}");
+            }
+            bail!("{test_err}");
         }
-        bail!("{test_err}");
-    }
+    };
 
     shutdown_result.context("in-process rewriter returned error")?;
 
+    check_rewritten_stats(&mut event_rx, integration.chat_id, expected_rewrites)
+}
+
+/// Checks that the chat stats reported at shutdown count every rewrite the test saw. Catch-up
+/// can rewrite messages from earlier runs too, so the count may be higher.
+fn check_rewritten_stats(
+    event_rx: &mut mpsc::UnboundedReceiver<RewriteEvent>,
+    chat_id: i64,
+    expected_rewrites: usize,
+) -> Result<()> {
+    let chat = ChatIdSpec::parse(chat_id);
+    let mut rewritten = None;
+    while let Ok(event) = event_rx.try_recv() {
+        if let RewriteEvent::ChatStats {
+            chat_id: stats_chat_id,
+            totals,
+            ..
+        } = event
+            && chat.matches(stats_chat_id)
+        {
+            rewritten = Some(totals.rewritten);
+        }
+    }
+    let rewritten = rewritten.context("no chat stats were reported for the test chat")?;
+    if rewritten < expected_rewrites as u64 {
+        bail!("chat stats counted {rewritten} rewrites, expected at least {expected_rewrites}");
+    }
+    eprintln!("[it] chat stats counted {rewritten} rewrites");
     Ok(())
 }
 
 fn ensure_override_runtime_config(config: &Config, chat_id: i64) -> Result<Config> {
     let mut runtime_config = config.clone();
     let openai = runtime_config.openai.get_or_insert_with(|| OpenAiConfig {
-        api_key: TEST_DEFAULT_OPENAI_API_KEY.to_owned(),
+        api_key: TEST_DEFAULT_OPENAI_API_KEY.into(),
         model: TEST_DEFAULT_OPENAI_MODEL.to_owned(),
-        timeout_seconds: 20,
+        request_timeout_seconds: 20,
+        connect_timeout_seconds: 10,
+        retry: Default::default(),
+        fallback_models: Vec::new(),
+        api_base: "https://api.openai.com/v1".to_owned(),
+        api_flavor: Default::default(),
+        stream: false,
+        structured_output: false,
+        rate_limit: None,
+        cost_per_million_input: None,
+        cost_per_million_output: None,
+        cache_entries: 0,
+        cache_ttl_seconds: 3_600,
+        require_healthy_at_startup: false,
+        context_style: Default::default(),
+        organization: None,
+        project: None,
+        extra_headers: Default::default(),
+        reasoning_effort: None,
+        stop: Vec::new(),
     });
-    if openai.api_key.trim().is_empty() {
-        openai.api_key = TEST_DEFAULT_OPENAI_API_KEY.to_owned();
+    if openai.api_key.expose().trim().is_empty() {
+        openai.api_key = TEST_DEFAULT_OPENAI_API_KEY.into();
     }
     if openai.model.trim().is_empty() {
         openai.model = TEST_DEFAULT_OPENAI_MODEL.to_owned();
     }
 
     let rewrite = runtime_config.rewrite.get_or_insert_with(|| RewriteConfig {
-        chats: Vec::new(),
         system_prompt: "rewrite".to_owned(),
         context_messages: TEST_DEFAULT_CONTEXT_MESSAGES,
+        ..Default::default()
     });
     if rewrite.system_prompt.trim().is_empty() {
         rewrite.system_prompt = "rewrite".to_owned();
@@ -202,18 +254,40 @@ fn ensure_override_runtime_config(config: &Config, chat_id: i64) -> Result<Confi
     if !rewrite.chats.contains(&chat_id) {
         rewrite.chats.push(chat_id);
     }
+    runtime_config.runtime.dry_run = false;
     Ok(runtime_config)
 }
 
-async fn wait_for_runtime_ready(client_rx: oneshot::Receiver<Client>) -> Result<Client> {
-    match tokio::time::timeout(STARTUP_TIMEOUT, client_rx).await {
-        Ok(Ok(client)) => Ok(client),
+/// Waits for the runtime's client, then for `RuntimeReady`, which must report that edits are
+/// applied for real.
+async fn wait_for_runtime_ready(
+    client_rx: oneshot::Receiver<Client>,
+    event_rx: &mut mpsc::UnboundedReceiver<RewriteEvent>,
+) -> Result<Client> {
+    let client = match tokio::time::timeout(STARTUP_TIMEOUT, client_rx).await {
+        Ok(Ok(client)) => client,
         Ok(Err(_)) => bail!("client channel closed before runtime sent the client"),
         Err(_) => bail!(
             "timed out waiting for in-process runtime-ready client after {} seconds",
             STARTUP_TIMEOUT.as_secs()
         ),
+    };
+    // Sent right after the client, before any message is processed.
+    let dry_run = tokio::time::timeout(STARTUP_TIMEOUT, async {
+        while let Some(event) = event_rx.recv().await {
+            if let RewriteEvent::RuntimeReady { dry_run, .. } = event {
+                return Some(dry_run);
+            }
+        }
+        None
+    })
+    .await
+    .context("timed out waiting for the runtime-ready event")?
+    .context("event channel closed before the runtime-ready event")?;
+    if dry_run {
+        bail!("runtime started in dry-run mode; edits would never land");
     }
+    Ok(client)
 }
 
 async fn resolve_dialog_peer_ref_by_chat_id(client: &Client, chat_id: i64) -> Result<PeerRef> {
@@ -223,7 +297,7 @@ async fn resolve_dialog_peer_ref_by_chat_id(client: &Client, chat_id: i64) -> Re
         .await
         .context("failed while iterating dialogs to resolve target chat")?
     {
-        if dialog.peer_id().bot_api_dialog_id() == chat_id {
+        if ChatIdSpec::parse(chat_id).matches(dialog.peer_id().bot_api_dialog_id()) {
             return Ok(dialog.peer_ref());
         }
     }
@@ -288,9 +362,11 @@ fn topic_root_from_config(value: i32) -> Option<i32> {
 async fn wait_until_all_edited_events(
     event_rx: &mut mpsc::UnboundedReceiver<RewriteEvent>,
     sent: &[SentMessage],
-) -> (Vec<SentMessage>, Vec<String>) {
+) -> (Vec<SentMessage>, Vec<String>, Vec<String>) {
     let mut pending: HashSet<i32> = sent.iter().map(|message| message.id).collect();
-    let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+    // Failed rewrites will never be edited, so they stop the wait for their message.
+    let mut failed = Vec::new();
+    let mut deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
     let mut last_report = tokio::time::Instant::now();
     let mut last_pending_count = pending.len();
     let mut recent_events: VecDeque<String> = VecDeque::with_capacity(500);
@@ -312,8 +388,36 @@ async fn wait_until_all_edited_events(
             }
             recent_events.push_back(format!("{event:?}"));
 
-            if let RewriteEvent::MessageEdited { message_id, .. } = event {
-                pending.remove(&message_id);
+            match event {
+                RewriteEvent::MessageEdited { message_id, .. } => {
+                    pending.remove(&message_id);
+                }
+                RewriteEvent::RewriteFailed {
+                    message_id,
+                    stage,
+                    reason,
+                    ..
+                } if pending.remove(&message_id) => {
+                    eprintln!(
+                        "[it] rewrite failed; message_id={message_id} stage={stage:?} reason={reason:?}"
+                    );
+                    failed.push(format!("{message_id} ({stage:?}: {reason:?})"));
+                }
+                RewriteEvent::EditDeferred {
+                    message_id,
+                    wait_seconds,
+                    ..
+                } if pending.contains(&message_id) => {
+                    // The edit is retried after Telegram's flood wait; allow for it.
+                    let retry_at = tokio::time::Instant::now()
+                        + Duration::from_secs(u64::from(wait_seconds))
+                        + POLL_INTERVAL;
+                    deadline = deadline.max(retry_at);
+                    eprintln!(
+                        "[it] edit deferred by flood wait; message_id={message_id} wait_seconds={wait_seconds}"
+                    );
+                }
+                _ => {}
             }
         }
 
@@ -347,7 +451,7 @@ async fn wait_until_all_edited_events(
         }
     }
 
-    (still_pending, recent_events.into_iter().collect())
+    (still_pending, failed, recent_events.into_iter().collect())
 }
 
 fn unique_run_id() -> String {
@@ -376,7 +480,7 @@ system_prompt = ""
     let adjusted = ensure_override_runtime_config(&config, -1002)
         .expect("runtime config should be normalized for override mode");
     let openai = adjusted.openai.expect("openai section must exist");
-    assert_eq!(openai.api_key, TEST_DEFAULT_OPENAI_API_KEY);
+    assert_eq!(openai.api_key.expose(), TEST_DEFAULT_OPENAI_API_KEY);
     assert_eq!(openai.model, TEST_DEFAULT_OPENAI_MODEL);
 
     let rewrite = adjusted.rewrite.expect("rewrite section must exist");